@@ -15,6 +15,11 @@ use ux::*;
 /// On `x86_64`, only the 48 lower bits of a virtual address can be used. The top 16 bits need
 /// to be copies of bit 47, i.e. the most significant bit. Addresses that fulfil this criterium
 /// are called “canonical”. This type guarantees that it always represents a canonical address.
+///
+/// With 5-level paging (`Cr4Flags::LA57`), the 57 lower bits of a virtual address can be used and
+/// the top 7 bits need to be copies of bit 56. Such addresses can be created through the `*_la57`
+/// constructors. The arithmetic operators and `from_ptr` require 48-bit canonical results, like
+/// `new`. Use `add_la57`, `sub_la57` and `from_ptr_la57` for addresses of 5-level paging.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(transparent)]
 pub struct VirtAddr(u64);
@@ -33,7 +38,7 @@ pub struct PhysAddr(u64);
 
 /// A passed `u64` was not a valid virtual address.
 ///
-/// This means that bits 48 to 64 are not
+/// This means that bits 48 to 64 (bits 57 to 64 for 5-level paging) are not
 /// a valid sign extension and are not null either. So automatic sign extension would have
 /// overwritten possibly meaningful bits. This likely indicates a bug, for example an invalid
 /// address calculation.
@@ -80,6 +85,44 @@ impl VirtAddr {
         VirtAddr(addr)
     }
 
+    /// Creates a new canonical virtual address for 5-level paging.
+    ///
+    /// This function performs sign extension of bit 56 to make the address canonical. Panics
+    /// if the bits in the range 57 to 64 contain data (i.e. are not null and no sign extension).
+    pub fn new_la57(addr: u64) -> VirtAddr {
+        Self::try_new_la57(addr).expect(
+            "address passed to VirtAddr::new_la57 must not contain any data \
+             in bits 57 to 64",
+        )
+    }
+
+    /// Tries to create a new canonical virtual address for 5-level paging.
+    ///
+    /// This function tries to performs sign extension of bit 56 to make the address canonical.
+    /// It succeeds if bits 57 to 64 are either a correct sign extension (i.e. copies of bit 56)
+    /// or all null. Else, an error is returned.
+    pub fn try_new_la57(addr: u64) -> Result<VirtAddr, VirtAddrNotValid> {
+        match addr.get_bits(56..64) {
+            0 | 0xff => Ok(VirtAddr(addr)),              // address is canonical
+            1 => Ok(VirtAddr::new_unchecked_la57(addr)), // address needs sign extension
+            other => Err(VirtAddrNotValid(other)),
+        }
+    }
+
+    /// Creates a new canonical virtual address for 5-level paging without checks.
+    ///
+    /// This function performs sign extension of bit 56 to make the address canonical, so
+    /// bits 57 to 64 are overwritten. If you want to check that these bits contain no data,
+    /// use `new_la57` or `try_new_la57`.
+    pub fn new_unchecked_la57(mut addr: u64) -> VirtAddr {
+        if addr.get_bit(56) {
+            addr.set_bits(57..64, 0x7f);
+        } else {
+            addr.set_bits(57..64, 0);
+        }
+        VirtAddr(addr)
+    }
+
     /// Creates a virtual address that points to `0`.
     pub const fn zero() -> VirtAddr {
         VirtAddr(0)
@@ -97,6 +140,31 @@ impl VirtAddr {
         Self::new(u64::from_usize(ptr as usize))
     }
 
+    /// Creates a virtual address for 5-level paging from the given pointer.
+    pub fn from_ptr_la57<T>(ptr: *const T) -> Self {
+        use usize_conversions::FromUsize;
+
+        Self::new_la57(u64::from_usize(ptr as usize))
+    }
+
+    /// Adds the given offset to the address, requiring the result to be canonical for 5-level
+    /// paging.
+    ///
+    /// The `+` operator requires the result to be canonical for 4-level paging instead. Panics
+    /// if the result is not canonical.
+    pub fn add_la57(self, offset: u64) -> Self {
+        VirtAddr::new_la57(self.0.checked_add(offset).unwrap())
+    }
+
+    /// Subtracts the given offset from the address, requiring the result to be canonical for
+    /// 5-level paging.
+    ///
+    /// The `-` operator requires the result to be canonical for 4-level paging instead. Panics
+    /// if the result is not canonical.
+    pub fn sub_la57(self, offset: u64) -> Self {
+        VirtAddr::new_la57(self.0.checked_sub(offset).unwrap())
+    }
+
     /// Converts the address to a raw pointer.
     #[cfg(target_pointer_width = "64")]
     pub fn as_ptr<T>(self) -> *const T {
@@ -163,6 +231,17 @@ impl VirtAddr {
     pub fn p4_index(&self) -> u9 {
         u9::new(((self.0 >> 12 >> 9 >> 9 >> 9) & 0o777).try_into().unwrap())
     }
+
+    /// Returns the 9-bit level 5 page table index.
+    ///
+    /// This index is only used with 5-level paging.
+    pub fn p5_index(&self) -> u9 {
+        u9::new(
+            ((self.0 >> 12 >> 9 >> 9 >> 9 >> 9) & 0o777)
+                .try_into()
+                .unwrap(),
+        )
+    }
 }
 
 impl fmt::Debug for VirtAddr {
@@ -438,6 +517,39 @@ pub fn align_up(addr: u64, align: u64) -> u64 {
 mod tests {
     use super::*;
 
+    #[test]
+    pub fn test_virt_addr_la57() {
+        // 48-bit canonical addresses are 57-bit canonical as well
+        assert_eq!(
+            VirtAddr::new_la57(0xffff_8000_0000_0000).as_u64(),
+            0xffff_8000_0000_0000
+        );
+        // sign extension of bit 56
+        assert_eq!(
+            VirtAddr::new_la57(0x0100_0000_0000_0000).as_u64(),
+            0xff00_0000_0000_0000
+        );
+        assert!(VirtAddr::try_new_la57(0x0200_0000_0000_0000).is_err());
+        // not 48-bit canonical, but 57-bit canonical
+        assert!(VirtAddr::try_new(0x00ff_0000_0000_0000).is_err());
+        let addr = VirtAddr::new_la57(0x00ff_0000_0000_0000);
+        assert_eq!(u16::from(addr.p5_index()), 0o377);
+        assert_eq!(u16::from(addr.p4_index()), 0);
+        assert_eq!(addr.add_la57(0x1000).as_u64(), 0x00ff_0000_0000_1000);
+        assert_eq!(addr.sub_la57(0x1000).as_u64(), 0x00fe_ffff_ffff_f000);
+        // the operators require 48-bit canonical results
+        assert_eq!(
+            (VirtAddr::new(0x7fff_ffff_f000) + 0x1000u64).as_u64(),
+            0xffff_8000_0000_0000
+        );
+        assert_eq!(
+            VirtAddr::new_la57(0x00ff_ffff_ffff_f000)
+                .add_la57(0x1000)
+                .as_u64(),
+            0xff00_0000_0000_0000
+        );
+    }
+
     #[test]
     pub fn test_align_up() {
         // align 1
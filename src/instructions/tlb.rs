@@ -8,8 +8,85 @@ pub fn flush(addr: VirtAddr) {
 }
 
 /// Invalidate the TLB completely by reloading the CR3 register.
+///
+/// Global translations are not invalidated. If the `PCIDE` flag is set in the CR4 register,
+/// the current PCID is kept and only the translations that are tagged with it are invalidated.
+/// Use `flush_pcid` with `InvPcidCommand::AllExceptGlobal` to invalidate the translations of
+/// all PCIDs.
 pub fn flush_all() {
-    use crate::registers::control::Cr3;
-    let (frame, flags) = Cr3::read();
-    unsafe { Cr3::write(frame, flags) }
+    use crate::registers::control::{Cr3, Cr4, Cr4Flags};
+    if Cr4::read().contains(Cr4Flags::PCIDE) {
+        let (frame, pcid) = Cr3::read_pcid();
+        unsafe { Cr3::write_pcid(frame, pcid) }
+    } else {
+        let (frame, flags) = Cr3::read();
+        unsafe { Cr3::write(frame, flags) }
+    }
+}
+
+/// A process-context identifier (PCID).
+///
+/// If the `PCIDE` flag is set in the CR4 register, the CPU tags each TLB entry with the PCID
+/// of the address space that it belongs to, so that switching address spaces does not require
+/// a complete TLB flush. The current PCID is stored in the lower 12 bits of the CR3 register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pcid(u16);
+
+impl Pcid {
+    /// Creates a new PCID.
+    ///
+    /// Returns an error if the value does not fit into 12 bits.
+    pub fn new(pcid: u16) -> Result<Pcid, ()> {
+        if pcid >= 4096 {
+            Err(())
+        } else {
+            Ok(Pcid(pcid))
+        }
+    }
+
+    /// Returns the value of the PCID.
+    pub fn value(&self) -> u16 {
+        self.0
+    }
+}
+
+/// The invalidation type of the `invpcid` instruction, as used by `flush_pcid`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvPcidCommand {
+    /// Invalidate the translation of the given address that is tagged with the given PCID.
+    Address(VirtAddr, Pcid),
+    /// Invalidate all translations that are tagged with the given PCID, except for global
+    /// translations.
+    Single(Pcid),
+    /// Invalidate all translations of all PCIDs, including global translations.
+    All,
+    /// Invalidate all translations of all PCIDs, except for global translations.
+    AllExceptGlobal,
+}
+
+/// The 128-bit memory operand of the `invpcid` instruction.
+#[repr(C)]
+struct InvPcidDescriptor {
+    pcid: u64,
+    address: u64,
+}
+
+/// Invalidate TLB entries using the `invpcid` instruction.
+///
+/// This function is unsafe because the `invpcid` instruction causes an invalid opcode
+/// exception if it is not supported by the CPU (see CPUID leaf 7, EBX bit 10). It causes a
+/// general protection fault if the `PCIDE` flag of the CR4 register is not set and a
+/// non-zero PCID is passed, or if the address of an `Address` command is not canonical.
+pub unsafe fn flush_pcid(command: InvPcidCommand) {
+    let (kind, descriptor) = match command {
+        InvPcidCommand::Address(addr, pcid) => (0u64, (pcid.value(), addr.as_u64())),
+        InvPcidCommand::Single(pcid) => (1, (pcid.value(), 0)),
+        InvPcidCommand::All => (2, (0, 0)),
+        InvPcidCommand::AllExceptGlobal => (3, (0, 0)),
+    };
+    let descriptor = InvPcidDescriptor {
+        pcid: u64::from(descriptor.0),
+        address: descriptor.1,
+    };
+    asm!("invpcid ($0), $1" :: "r" (&descriptor), "r" (kind) : "memory");
 }
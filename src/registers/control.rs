@@ -224,12 +224,18 @@ bitflags! {
         /// addresses with that protection key can be read or written. This bit also enables 
         /// access to the PKRU register using the RDPKRU and WRPKRU instructions.
         const PKE = 1 << 22;
+        /// Protection-Keys-for-Supervisor-Mode-Pages Enable Bit
+        ///
+        /// Enables protection keys for supervisor-mode linear addresses. The access rights of
+        /// the keys are stored in the IA32_PKRS model specific register.
+        const PKS = 1 << 24;
     }
 }
 
 #[cfg(target_arch = "x86_64")]
 mod x86_64 {
     use super::*;
+    use crate::instructions::tlb::Pcid;
     use crate::structures::paging::PhysFrame;
     use crate::{PhysAddr, VirtAddr};
 
@@ -283,18 +289,28 @@ mod x86_64 {
     }
 
     impl Cr2 {
-        /// Read the current page fault linear address from the CR3 register.
+        /// Read the current page fault linear address from the CR2 register.
+        ///
+        /// The address is canonical for the paging mode that was active when the page fault
+        /// occurred, which might be 5-level paging. It is therefore converted through
+        /// `VirtAddr::new_unchecked_la57`, which does not change canonical addresses of either
+        /// paging mode, instead of `VirtAddr::new`, which panics for 57-bit addresses.
         pub fn read() -> VirtAddr {
             let value: u64;
             unsafe {
                 asm!("mov %cr2, $0" : "=r" (value));
             }
-            VirtAddr::new(value)
+            VirtAddr::new_unchecked_la57(value)
         }
     }
 
     impl Cr3 {
         /// Read the current P4 table address from the CR3 register.
+        ///
+        /// If the `PCIDE` flag is set in the CR4 register, the lower 12 bits of CR3 contain the
+        /// current PCID instead of the `Cr3Flags`. This function truncates them to the flags, so
+        /// writing the result back through `write` switches to a different PCID. Use `read_pcid`
+        /// and `write_pcid` in this case.
         pub fn read() -> (PhysFrame, Cr3Flags) {
             let value: u64;
             unsafe {
@@ -316,6 +332,57 @@ mod x86_64 {
             let value = addr.as_u64() | flags.bits();
             asm!("mov $0, %cr3" :: "r" (value) : "memory")
         }
+
+        /// Read the current P4 table address and the current PCID from the CR3 register.
+        ///
+        /// The PCID is only used by the CPU if the `PCIDE` flag is set in the CR4 register.
+        /// Otherwise the lower 12 bits of CR3 contain the `Cr3Flags`, which are returned by `read`.
+        pub fn read_pcid() -> (PhysFrame, Pcid) {
+            let value: u64;
+            unsafe {
+                asm!("mov %cr3, $0" : "=r" (value));
+            }
+            let addr = PhysAddr::new(value & 0x_000f_ffff_ffff_f000);
+            let frame = PhysFrame::containing_address(addr);
+            let pcid = Pcid::new((value & 0xfff) as u16).unwrap();
+            (frame, pcid)
+        }
+
+        /// Write a new P4 table address and a new PCID into the CR3 register.
+        ///
+        /// All TLB entries that are tagged with the given PCID are invalidated, except for
+        /// global translations. The `PCIDE` flag must be set in the CR4 register.
+        ///
+        /// ## Safety
+        /// Changing the level 4 page table is unsafe, because it's possible to violate memory safety by
+        /// changing the page mapping.
+        pub unsafe fn write_pcid(frame: PhysFrame, pcid: Pcid) {
+            Self::write_pcid_raw(frame, pcid, false)
+        }
+
+        /// Write a new P4 table address and a new PCID into the CR3 register without
+        /// invalidating the TLB entries that are tagged with the given PCID.
+        ///
+        /// This sets the no-flush bit 63 of the written value. The `PCIDE` flag must be set in
+        /// the CR4 register.
+        ///
+        /// ## Safety
+        /// Changing the level 4 page table is unsafe, because it's possible to violate memory safety by
+        /// changing the page mapping. The caller must also ensure that the TLB contains no stale
+        /// entries for the given PCID, e.g. because the page tables of the address space were
+        /// not changed since the PCID was last used with them.
+        pub unsafe fn write_pcid_no_flush(frame: PhysFrame, pcid: Pcid) {
+            Self::write_pcid_raw(frame, pcid, true)
+        }
+
+        unsafe fn write_pcid_raw(frame: PhysFrame, pcid: Pcid, no_flush: bool) {
+            let addr = frame.start_address();
+            let mut value = addr.as_u64() | u64::from(pcid.value());
+            if no_flush {
+                value |= 1 << 63;
+            }
+            asm!("mov $0, %cr3" :: "r" (value) : "memory")
+        }
     }
 
     impl Cr4 {
@@ -2,4 +2,5 @@
 
 pub mod control;
 pub mod model_specific;
+pub mod pkru;
 pub mod rflags;
@@ -1,5 +1,7 @@
 //! Functions to read and write control registers.
 
+use crate::registers::pkru::Pkru;
+use crate::PhysAddr;
 use bitflags::bitflags;
 
 /// A model specific register.
@@ -44,6 +46,367 @@ bitflags! {
     }
 }
 
+/// The Page Attribute Table (`IA32_PAT`) register.
+///
+/// The PAT contains eight memory types. Each page table entry that maps a page selects one of
+/// them through its `WRITE_THROUGH`, `NO_CACHE` and PAT bits, see
+/// `PageTableEntry::set_pat_index`.
+#[derive(Debug)]
+pub struct Pat;
+
+impl Pat {
+    /// The underlying model specific register.
+    pub const MSR: Msr = Msr(0x277);
+}
+
+/// The `IA32_PKRS` register, which contains the access rights of the protection keys for
+/// supervisor-mode pages.
+#[derive(Debug)]
+pub struct Pkrs;
+
+impl Pkrs {
+    /// The underlying model specific register.
+    pub const MSR: Msr = Msr(0x6E1);
+}
+
+/// A memory type, which controls how accesses to memory are cached.
+///
+/// The discriminants are the encodings of the memory types in the PAT and the MTRRs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemoryType {
+    /// Uncacheable (UC). Accesses are not cached and not reordered.
+    Uncacheable = 0,
+    /// Write combining (WC). Accesses are not cached, but writes may be combined in a write
+    /// combining buffer. Useful for framebuffers.
+    WriteCombining = 1,
+    /// Write-through (WT). Reads are cached, writes are written through to memory.
+    WriteThrough = 4,
+    /// Write-protected (WP). Reads are cached, writes are not cached and invalidate cache lines.
+    WriteProtected = 5,
+    /// Write-back (WB). Reads and writes are cached.
+    WriteBack = 6,
+    /// Uncached (UC-). Like `Uncacheable`, but can be overridden by a write combining MTRR.
+    /// Only available in the PAT.
+    UncacheableMinus = 7,
+}
+
+impl MemoryType {
+    /// Converts the encoding of a memory type in the PAT to a `MemoryType`.
+    ///
+    /// Returns `None` for reserved encodings.
+    pub fn from_bits(bits: u8) -> Option<MemoryType> {
+        match bits {
+            0 => Some(MemoryType::Uncacheable),
+            1 => Some(MemoryType::WriteCombining),
+            4 => Some(MemoryType::WriteThrough),
+            5 => Some(MemoryType::WriteProtected),
+            6 => Some(MemoryType::WriteBack),
+            7 => Some(MemoryType::UncacheableMinus),
+            _ => None,
+        }
+    }
+
+    /// Returns the encoding of the memory type.
+    pub fn bits(self) -> u8 {
+        self as u8
+    }
+}
+
+/// The eight memory types of the Page Attribute Table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageAttributeTable([MemoryType; 8]);
+
+impl PageAttributeTable {
+    /// The content of the PAT after a reset.
+    pub const DEFAULT: PageAttributeTable = PageAttributeTable([
+        MemoryType::WriteBack,
+        MemoryType::WriteThrough,
+        MemoryType::UncacheableMinus,
+        MemoryType::Uncacheable,
+        MemoryType::WriteBack,
+        MemoryType::WriteThrough,
+        MemoryType::UncacheableMinus,
+        MemoryType::Uncacheable,
+    ]);
+
+    /// Creates a new table from the given memory types.
+    pub const fn new(entries: [MemoryType; 8]) -> Self {
+        PageAttributeTable(entries)
+    }
+
+    /// Creates a table from the raw value of the `IA32_PAT` register.
+    ///
+    /// Returns `None` if one of the entries has a reserved encoding.
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        let mut entries = [MemoryType::Uncacheable; 8];
+        for (index, entry) in entries.iter_mut().enumerate() {
+            *entry = MemoryType::from_bits((bits >> (index * 8)) as u8)?;
+        }
+        Some(PageAttributeTable(entries))
+    }
+
+    /// Returns the raw value of the `IA32_PAT` register for this table.
+    pub fn bits(&self) -> u64 {
+        self.0.iter().enumerate().fold(0, |bits, (index, entry)| {
+            bits | u64::from(entry.bits()) << (index * 8)
+        })
+    }
+
+    /// Returns the memory type of the given entry.
+    ///
+    /// Panics if the index is not smaller than 8.
+    pub fn entry(&self, index: u8) -> MemoryType {
+        self.0[usize::from(index)]
+    }
+
+    /// Sets the memory type of the given entry.
+    ///
+    /// Panics if the index is not smaller than 8.
+    pub fn set_entry(&mut self, index: u8, memory_type: MemoryType) {
+        self.0[usize::from(index)] = memory_type;
+    }
+
+    /// Returns the index of the first entry with the given memory type.
+    pub fn index_of(&self, memory_type: MemoryType) -> Option<u8> {
+        self.0
+            .iter()
+            .position(|&entry| entry == memory_type)
+            .map(|index| index as u8)
+    }
+}
+
+impl MemoryType {
+    /// Combines the memory type of a physical address that is specified by the MTRRs with the
+    /// memory type that is selected through the PAT, as listed in table 11-7 of the Intel SDM,
+    /// volume 3, section 11.5.2.2.
+    ///
+    /// The MTRRs have no `UncacheableMinus` type, so it is treated like `Uncacheable` if it is
+    /// passed as `mtrr_type`.
+    pub fn combine(mtrr_type: MemoryType, pat_type: MemoryType) -> MemoryType {
+        use self::MemoryType::*;
+
+        match (mtrr_type, pat_type) {
+            (_, Uncacheable) => Uncacheable,
+            (_, WriteCombining) => WriteCombining,
+            (WriteCombining, UncacheableMinus) | (WriteProtected, UncacheableMinus) => {
+                WriteCombining
+            }
+            (_, UncacheableMinus) => Uncacheable,
+            (Uncacheable, _) | (UncacheableMinus, _) => Uncacheable,
+            (WriteCombining, WriteBack) => WriteCombining,
+            (WriteCombining, _) => Uncacheable,
+            (WriteThrough, WriteProtected) => WriteProtected,
+            (WriteThrough, _) => WriteThrough,
+            (WriteProtected, WriteThrough) => WriteThrough,
+            (WriteProtected, _) => WriteProtected,
+            (WriteBack, pat_type) => pat_type,
+        }
+    }
+}
+
+/// The `IA32_MTRRCAP` register, which describes the MTRR features of the CPU.
+#[derive(Debug)]
+pub struct MtrrCap;
+
+impl MtrrCap {
+    /// The underlying model specific register.
+    pub const MSR: Msr = Msr(0xFE);
+}
+
+bitflags! {
+    /// Flags of the `IA32_MTRRCAP` register.
+    pub struct MtrrCapFlags: u64 {
+        /// The fixed range MTRRs are supported.
+        const FIXED_RANGES = 1 << 8;
+        /// The write combining memory type is supported.
+        const WRITE_COMBINING = 1 << 10;
+        /// The system management range register is supported.
+        const SMRR = 1 << 11;
+    }
+}
+
+/// The `IA32_MTRR_DEF_TYPE` register, which enables the MTRRs and contains the memory type of
+/// all physical addresses that are not covered by an MTRR.
+#[derive(Debug)]
+pub struct MtrrDefType;
+
+impl MtrrDefType {
+    /// The underlying model specific register.
+    pub const MSR: Msr = Msr(0x2FF);
+}
+
+bitflags! {
+    /// Flags of the `IA32_MTRR_DEF_TYPE` register.
+    pub struct MtrrDefTypeFlags: u64 {
+        /// Enables the fixed range MTRRs.
+        const FIXED_RANGE_ENABLE = 1 << 10;
+        /// Enables the MTRRs. If this flag is not set, all physical memory is uncacheable.
+        const MTRR_ENABLE = 1 << 11;
+    }
+}
+
+/// The fixed range MTRRs, which specify the memory types of the first megabyte of physical
+/// memory.
+///
+/// Each of the eleven registers contains the memory types of eight consecutive ranges. The
+/// first register covers 64KiB ranges starting at 0x0, the next two registers cover 16KiB
+/// ranges starting at 0x80000 and the remaining eight registers cover 4KiB ranges starting at
+/// 0xC0000.
+#[derive(Debug)]
+pub struct MtrrFixed;
+
+impl MtrrFixed {
+    /// The underlying model specific registers, in ascending order of the covered addresses.
+    pub const MSRS: [Msr; 11] = [
+        Msr(0x250),
+        Msr(0x258),
+        Msr(0x259),
+        Msr(0x268),
+        Msr(0x269),
+        Msr(0x26A),
+        Msr(0x26B),
+        Msr(0x26C),
+        Msr(0x26D),
+        Msr(0x26E),
+        Msr(0x26F),
+    ];
+
+    /// Returns the index of the register in `MSRS` and the index of the range within the
+    /// register that cover the given address, or `None` if the address is not below 1MiB.
+    pub fn range_index(addr: PhysAddr) -> Option<(usize, usize)> {
+        let addr = addr.as_u64();
+        let (register, range) = match addr {
+            0x0..=0x7_FFFF => (0, addr / 0x1_0000),
+            0x8_0000..=0xB_FFFF => (1 + (addr - 0x8_0000) / 0x2_0000, addr / 0x4000 % 8),
+            0xC_0000..=0xF_FFFF => (3 + (addr - 0xC_0000) / 0x8000, addr / 0x1000 % 8),
+            _ => return None,
+        };
+        Some((register as usize, range as usize))
+    }
+}
+
+/// A variable range MTRR, i.e. a pair of `IA32_MTRR_PHYSBASE` and `IA32_MTRR_PHYSMASK`
+/// registers.
+///
+/// The number of variable range MTRRs is reported by `MtrrCap::variable_range_count`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VariableRangeMtrr {
+    /// The raw value of the `IA32_MTRR_PHYSBASE` register, which contains the base address and
+    /// the memory type of the range.
+    pub base: u64,
+    /// The raw value of the `IA32_MTRR_PHYSMASK` register, which contains the address mask and
+    /// the valid flag of the range.
+    pub mask: u64,
+}
+
+impl VariableRangeMtrr {
+    /// The address bits of the `base` and `mask` registers.
+    const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+    /// The valid flag of the `mask` register.
+    const VALID: u64 = 1 << 11;
+
+    /// Returns the `IA32_MTRR_PHYSBASE` register of the variable range MTRR with the given
+    /// index. The `IA32_MTRR_PHYSMASK` register follows directly after it.
+    pub fn base_msr(index: u8) -> Msr {
+        Msr(0x200 + 2 * u32::from(index))
+    }
+
+    /// Returns the `IA32_MTRR_PHYSMASK` register of the variable range MTRR with the given
+    /// index.
+    pub fn mask_msr(index: u8) -> Msr {
+        Msr(0x201 + 2 * u32::from(index))
+    }
+
+    /// Returns the address bits of the `base` and `mask` registers for a processor with the
+    /// given physical address width. The higher bits are reserved.
+    ///
+    /// Panics if `phys_addr_bits` is not between 12 and 52.
+    fn address_mask(phys_addr_bits: u8) -> u64 {
+        assert!(
+            phys_addr_bits >= 12 && phys_addr_bits <= 52,
+            "invalid physical address width"
+        );
+        Self::ADDRESS_MASK & ((1 << phys_addr_bits) - 1)
+    }
+
+    /// Creates a valid range that covers `size` bytes starting at `base`.
+    ///
+    /// `phys_addr_bits` is the physical address width of the processor, which is reported by
+    /// `cpuid` leaf `0x8000_0008`. The mask bits above it are reserved, so they are left clear.
+    ///
+    /// Panics if `size` is not a power of two of at least 4KiB, if `base` is not aligned to
+    /// `size` or if the range exceeds the physical address width.
+    pub fn new(base: PhysAddr, size: u64, memory_type: MemoryType, phys_addr_bits: u8) -> Self {
+        let address_mask = Self::address_mask(phys_addr_bits);
+        assert!(
+            size.is_power_of_two() && size >= 0x1000,
+            "invalid MTRR size"
+        );
+        assert!(
+            base.is_aligned(size),
+            "MTRR base must be aligned to its size"
+        );
+        assert!(
+            (base.as_u64() | (size - 1)) & !address_mask & !0xfff == 0,
+            "MTRR range exceeds the physical address width"
+        );
+        VariableRangeMtrr {
+            base: base.as_u64() | u64::from(memory_type.bits()),
+            mask: (!(size - 1) & address_mask) | Self::VALID,
+        }
+    }
+
+    /// Returns whether the range is enabled.
+    pub fn is_valid(&self) -> bool {
+        self.mask & Self::VALID != 0
+    }
+
+    /// Returns the memory type of the range, or `None` if the register contains a reserved
+    /// encoding.
+    pub fn memory_type(&self) -> Option<MemoryType> {
+        match MemoryType::from_bits(self.base as u8) {
+            Some(MemoryType::UncacheableMinus) | None => None,
+            memory_type => memory_type,
+        }
+    }
+
+    /// Returns the base address of the range.
+    pub fn base_address(&self) -> PhysAddr {
+        PhysAddr::new(self.base & Self::ADDRESS_MASK)
+    }
+
+    /// Returns whether the range is valid and contains the given address on a processor with
+    /// the given physical address width (see `new`).
+    ///
+    /// Panics if `phys_addr_bits` is not between 12 and 52.
+    pub fn contains(&self, addr: PhysAddr, phys_addr_bits: u8) -> bool {
+        let mask = self.mask & Self::address_mask(phys_addr_bits);
+        self.is_valid() && addr.as_u64() & mask == self.base & mask
+    }
+}
+
+/// Combines the memory type of the variable range MTRRs that cover an address so far, if any,
+/// with the memory type of another variable range MTRR that covers it.
+///
+/// Overlapping variable ranges result in the uncacheable type if one of them is uncacheable
+/// and in the write-through type if they are write-through and write-back. The behavior of
+/// other overlaps is undefined, so they are reported as uncacheable.
+fn combine_overlapping(current: Option<MemoryType>, next: MemoryType) -> MemoryType {
+    use self::MemoryType::*;
+
+    match (current, next) {
+        (None, next) => next,
+        (Some(current), next) if current == next => next,
+        (Some(WriteThrough), WriteBack) | (Some(WriteBack), WriteThrough) => WriteThrough,
+        _ => Uncacheable,
+    }
+}
+
+/// Access to the complete set of memory type range registers (MTRRs).
+#[derive(Debug)]
+pub struct Mtrr;
+
 #[cfg(target_arch = "x86_64")]
 mod x86_64 {
     use super::*;
@@ -64,6 +427,189 @@ mod x86_64 {
         }
     }
 
+    impl Pat {
+        /// Read the current page attribute table.
+        pub fn read() -> PageAttributeTable {
+            let bits = unsafe { Self::MSR.read() };
+            PageAttributeTable::from_bits(bits).expect("IA32_PAT contains a reserved memory type")
+        }
+
+        /// Write a new page attribute table.
+        ///
+        /// Unsafe because changing the memory type of memory that is in use can break memory
+        /// safety, e.g. by making memory uncached that is used for device communication. The
+        /// Intel SDM additionally recommends flushing the caches and the TLB on all processors
+        /// when the PAT is changed.
+        pub unsafe fn write(table: PageAttributeTable) {
+            Self::MSR.write(table.bits());
+        }
+    }
+
+    impl Pkrs {
+        /// Read the current access rights of supervisor-mode pages.
+        pub fn read() -> Pkru {
+            Pkru::from_bits(unsafe { Self::MSR.read() } as u32)
+        }
+
+        /// Write the access rights of supervisor-mode pages.
+        ///
+        /// Unsafe because changing the access rights of protection keys can break memory
+        /// safety.
+        pub unsafe fn write(pkrs: Pkru) {
+            Self::MSR.write(u64::from(pkrs.bits()));
+        }
+    }
+
+    impl MtrrCap {
+        /// Read the current MTRR capability flags.
+        pub fn read() -> MtrrCapFlags {
+            MtrrCapFlags::from_bits_truncate(Self::read_raw())
+        }
+
+        /// Read the raw value of the `IA32_MTRRCAP` register.
+        pub fn read_raw() -> u64 {
+            unsafe { Self::MSR.read() }
+        }
+
+        /// Returns the number of variable range MTRRs.
+        pub fn variable_range_count() -> u8 {
+            Self::read_raw() as u8
+        }
+    }
+
+    impl MtrrDefType {
+        /// Read the default memory type and the flags.
+        ///
+        /// Reserved encodings of the default memory type are reported as `Uncacheable`.
+        pub fn read() -> (MemoryType, MtrrDefTypeFlags) {
+            let value = unsafe { Self::MSR.read() };
+            let default_type = match MemoryType::from_bits(value as u8) {
+                Some(MemoryType::UncacheableMinus) | None => MemoryType::Uncacheable,
+                Some(memory_type) => memory_type,
+            };
+            (default_type, MtrrDefTypeFlags::from_bits_truncate(value))
+        }
+
+        /// Write the default memory type and the flags.
+        ///
+        /// Preserves the value of reserved fields. Unsafe because changing memory types can
+        /// break memory safety. The Intel SDM describes the steps that are required for
+        /// changing the MTRRs, e.g. disabling and flushing the caches.
+        ///
+        /// Panics if `default_type` is `UncacheableMinus`, which is not valid for MTRRs.
+        pub unsafe fn write(default_type: MemoryType, flags: MtrrDefTypeFlags) {
+            assert_ne!(default_type, MemoryType::UncacheableMinus);
+            let old_value = Self::MSR.read();
+            let reserved = old_value & !(MtrrDefTypeFlags::all().bits() | 0xff);
+            let new_value = reserved | flags.bits() | u64::from(default_type.bits());
+            Self::MSR.write(new_value);
+        }
+    }
+
+    impl MtrrFixed {
+        /// Read the memory types of the eight ranges of the register with the given index.
+        ///
+        /// Returns `None` for ranges with a reserved encoding. Panics if the index is not
+        /// smaller than 11.
+        pub fn read(index: usize) -> [Option<MemoryType>; 8] {
+            let value = unsafe { Self::MSRS[index].read() };
+            let mut types = [None; 8];
+            for (range, memory_type) in types.iter_mut().enumerate() {
+                *memory_type = match MemoryType::from_bits((value >> (range * 8)) as u8) {
+                    Some(MemoryType::UncacheableMinus) => None,
+                    memory_type => memory_type,
+                };
+            }
+            types
+        }
+
+        /// Write the memory types of the eight ranges of the register with the given index.
+        ///
+        /// Unsafe because changing memory types can break memory safety. The Intel SDM
+        /// describes the steps that are required for changing the MTRRs.
+        ///
+        /// Panics if one of the types is `UncacheableMinus` or if the index is not smaller
+        /// than 11.
+        pub unsafe fn write(index: usize, types: [MemoryType; 8]) {
+            let mut value = 0;
+            for (range, &memory_type) in types.iter().enumerate() {
+                assert_ne!(memory_type, MemoryType::UncacheableMinus);
+                value |= u64::from(memory_type.bits()) << (range * 8);
+            }
+            let mut msr = Msr(Self::MSRS[index].0);
+            msr.write(value);
+        }
+    }
+
+    impl VariableRangeMtrr {
+        /// Read the variable range MTRR with the given index.
+        pub fn read(index: u8) -> Self {
+            unsafe {
+                VariableRangeMtrr {
+                    base: Self::base_msr(index).read(),
+                    mask: Self::mask_msr(index).read(),
+                }
+            }
+        }
+
+        /// Write the variable range MTRR with the given index.
+        ///
+        /// Unsafe because changing memory types can break memory safety. The Intel SDM
+        /// describes the steps that are required for changing the MTRRs.
+        pub unsafe fn write(&self, index: u8) {
+            Self::base_msr(index).write(self.base);
+            Self::mask_msr(index).write(self.mask);
+        }
+    }
+
+    impl Mtrr {
+        /// Returns the memory type that the MTRRs specify for the given physical address.
+        ///
+        /// The type is looked up in the fixed range MTRRs for addresses below 1MiB if they are
+        /// supported and enabled, and in the variable range MTRRs otherwise. Addresses that
+        /// are not covered by any range get the default type. If the MTRRs are disabled, all
+        /// memory is uncacheable.
+        pub fn memory_type(addr: PhysAddr) -> MemoryType {
+            let (default_type, flags) = MtrrDefType::read();
+            if !flags.contains(MtrrDefTypeFlags::MTRR_ENABLE) {
+                return MemoryType::Uncacheable;
+            }
+
+            let capabilities = MtrrCap::read();
+            if flags.contains(MtrrDefTypeFlags::FIXED_RANGE_ENABLE)
+                && capabilities.contains(MtrrCapFlags::FIXED_RANGES)
+            {
+                if let Some((register, range)) = MtrrFixed::range_index(addr) {
+                    return MtrrFixed::read(register)[range].unwrap_or(MemoryType::Uncacheable);
+                }
+            }
+
+            let phys_addr_bits = raw_cpuid::CpuId::new()
+                .get_extended_function_info()
+                .and_then(|info| info.physical_address_bits())
+                .unwrap_or(36);
+            let mut memory_type = None;
+            for index in 0..MtrrCap::variable_range_count() {
+                let mtrr = VariableRangeMtrr::read(index);
+                if mtrr.contains(addr, phys_addr_bits) {
+                    let next = mtrr.memory_type().unwrap_or(MemoryType::Uncacheable);
+                    memory_type = Some(combine_overlapping(memory_type, next));
+                }
+            }
+            memory_type.unwrap_or(default_type)
+        }
+
+        /// Returns the effective memory type of an access to the given physical address
+        /// through a page that selects `pat_type` from the page attribute table.
+        ///
+        /// This combines the result of `memory_type` with `pat_type` through
+        /// `MemoryType::combine`. The PAT type of a page can be looked up through
+        /// `PageTableEntry::memory_type`.
+        pub fn effective_memory_type(addr: PhysAddr, pat_type: MemoryType) -> MemoryType {
+            MemoryType::combine(Self::memory_type(addr), pat_type)
+        }
+    }
+
     impl Efer {
         /// Read the current EFER flags.
         pub fn read() -> EferFlags {
@@ -109,3 +655,142 @@ mod x86_64 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combine_memory_types() {
+        use self::MemoryType::*;
+
+        let pat_types = [
+            Uncacheable,
+            UncacheableMinus,
+            WriteCombining,
+            WriteThrough,
+            WriteBack,
+            WriteProtected,
+        ];
+        // the rows of table 11-7 of the Intel SDM, in the order of `pat_types`
+        let table = [
+            (
+                Uncacheable,
+                [
+                    Uncacheable,
+                    Uncacheable,
+                    WriteCombining,
+                    Uncacheable,
+                    Uncacheable,
+                    Uncacheable,
+                ],
+            ),
+            (
+                WriteCombining,
+                [
+                    Uncacheable,
+                    WriteCombining,
+                    WriteCombining,
+                    Uncacheable,
+                    WriteCombining,
+                    Uncacheable,
+                ],
+            ),
+            (
+                WriteThrough,
+                [
+                    Uncacheable,
+                    Uncacheable,
+                    WriteCombining,
+                    WriteThrough,
+                    WriteThrough,
+                    WriteProtected,
+                ],
+            ),
+            (
+                WriteProtected,
+                [
+                    Uncacheable,
+                    WriteCombining,
+                    WriteCombining,
+                    WriteThrough,
+                    WriteProtected,
+                    WriteProtected,
+                ],
+            ),
+            (
+                WriteBack,
+                [
+                    Uncacheable,
+                    Uncacheable,
+                    WriteCombining,
+                    WriteThrough,
+                    WriteBack,
+                    WriteProtected,
+                ],
+            ),
+        ];
+        for &(mtrr_type, ref effective_types) in &table {
+            for (&pat_type, &effective_type) in pat_types.iter().zip(effective_types) {
+                assert_eq!(
+                    MemoryType::combine(mtrr_type, pat_type),
+                    effective_type,
+                    "MTRR type {:?}, PAT type {:?}",
+                    mtrr_type,
+                    pat_type
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_range_index() {
+        let index = |addr| MtrrFixed::range_index(PhysAddr::new(addr));
+        assert_eq!(index(0x0), Some((0, 0)));
+        assert_eq!(index(0x7_ffff), Some((0, 7)));
+        assert_eq!(index(0x8_0000), Some((1, 0)));
+        assert_eq!(index(0x9_c000), Some((1, 7)));
+        assert_eq!(index(0xa_0000), Some((2, 0)));
+        assert_eq!(index(0xb_ffff), Some((2, 7)));
+        assert_eq!(index(0xc_0000), Some((3, 0)));
+        assert_eq!(index(0xc_8fff), Some((4, 0)));
+        assert_eq!(index(0xf_f000), Some((10, 7)));
+        assert_eq!(index(0x10_0000), None);
+    }
+
+    #[test]
+    fn variable_range_contains() {
+        let mtrr = VariableRangeMtrr::new(
+            PhysAddr::new(0x8000_0000),
+            0x1000_0000,
+            MemoryType::WriteCombining,
+            36,
+        );
+        assert_eq!(mtrr.memory_type(), Some(MemoryType::WriteCombining));
+        assert_eq!(mtrr.base_address(), PhysAddr::new(0x8000_0000));
+        assert_eq!(mtrr.mask, 0xf_f000_0800);
+        assert!(!mtrr.contains(PhysAddr::new(0x7fff_ffff), 36));
+        assert!(mtrr.contains(PhysAddr::new(0x8000_0000), 36));
+        assert!(mtrr.contains(PhysAddr::new(0x8fff_ffff), 36));
+        assert!(!mtrr.contains(PhysAddr::new(0x9000_0000), 36));
+        // the mask includes the address bits above 4GiB
+        assert!(!mtrr.contains(PhysAddr::new(0x1_8000_0000), 36));
+
+        let disabled = VariableRangeMtrr {
+            mask: mtrr.mask & !VariableRangeMtrr::VALID,
+            ..mtrr
+        };
+        assert!(!disabled.contains(PhysAddr::new(0x8000_0000), 36));
+    }
+
+    #[test]
+    #[should_panic]
+    fn variable_range_beyond_phys_addr_width() {
+        VariableRangeMtrr::new(
+            PhysAddr::new(0x10_0000_0000),
+            0x1000_0000,
+            MemoryType::WriteBack,
+            36,
+        );
+    }
+}
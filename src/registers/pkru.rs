@@ -0,0 +1,141 @@
+//! The protection key rights registers, which control the access rights of protection keys.
+
+#[cfg(target_arch = "x86_64")]
+pub use self::x86_64::*;
+
+use ux::u4;
+
+/// The access rights of the 16 protection keys, as stored in the PKRU register for user-mode
+/// pages and in the `IA32_PKRS` register for supervisor-mode pages.
+///
+/// Each page table entry that maps a page contains a protection key, see
+/// `PageTableEntry::set_protection_key`. For each key, the register contains an access-disable
+/// bit and a write-disable bit. Protection keys are only used if the `PKE` flag (for user-mode
+/// pages) or the `PKS` flag (for supervisor-mode pages) is set in the CR4 register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct Pkru(u32);
+
+impl Pkru {
+    /// Creates a new value that allows all accesses for all protection keys.
+    pub const fn new() -> Self {
+        Pkru(0)
+    }
+
+    /// Creates a value from the raw register content.
+    pub const fn from_bits(bits: u32) -> Self {
+        Pkru(bits)
+    }
+
+    /// Returns the raw register content.
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// Returns whether all data accesses to pages with the given protection key are disabled.
+    pub fn access_disabled(&self, key: u4) -> bool {
+        self.0 & Self::access_disable_bit(key) != 0
+    }
+
+    /// Returns whether writes to pages with the given protection key are disabled.
+    pub fn write_disabled(&self, key: u4) -> bool {
+        self.0 & Self::write_disable_bit(key) != 0
+    }
+
+    /// Disables or enables all data accesses to pages with the given protection key.
+    pub fn set_access_disabled(&mut self, key: u4, disabled: bool) {
+        self.set_bit(Self::access_disable_bit(key), disabled);
+    }
+
+    /// Disables or enables writes to pages with the given protection key.
+    pub fn set_write_disabled(&mut self, key: u4, disabled: bool) {
+        self.set_bit(Self::write_disable_bit(key), disabled);
+    }
+
+    fn set_bit(&mut self, bit: u32, value: bool) {
+        if value {
+            self.0 |= bit;
+        } else {
+            self.0 &= !bit;
+        }
+    }
+
+    fn access_disable_bit(key: u4) -> u32 {
+        1 << (2 * u32::from(u8::from(key)))
+    }
+
+    fn write_disable_bit(key: u4) -> u32 {
+        1 << (2 * u32::from(u8::from(key)) + 1)
+    }
+}
+
+impl Default for Pkru {
+    /// Returns a value that allows all accesses for all protection keys.
+    fn default() -> Self {
+        Pkru::new()
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86_64 {
+    use super::*;
+
+    /// Reads the PKRU register using the `rdpkru` instruction.
+    ///
+    /// The `PKE` flag must be set in the CR4 register, otherwise an invalid opcode exception
+    /// occurs.
+    pub fn rdpkru() -> u32 {
+        let value: u32;
+        unsafe { asm!("rdpkru" : "={eax}" (value) : "{ecx}" (0) : "edx" : "volatile") };
+        value
+    }
+
+    /// Writes the PKRU register using the `wrpkru` instruction.
+    ///
+    /// The `PKE` flag must be set in the CR4 register, otherwise an invalid opcode exception
+    /// occurs. Unsafe because changing the access rights of protection keys can break memory
+    /// safety.
+    pub unsafe fn wrpkru(value: u32) {
+        asm!("wrpkru" :: "{eax}" (value), "{ecx}" (0), "{edx}" (0) : "memory" : "volatile");
+    }
+
+    impl Pkru {
+        /// Reads the current access rights of user-mode pages from the PKRU register.
+        pub fn read() -> Pkru {
+            Pkru(rdpkru())
+        }
+
+        /// Writes the access rights of user-mode pages to the PKRU register.
+        ///
+        /// Unsafe because changing the access rights of protection keys can break memory
+        /// safety.
+        pub unsafe fn write(pkru: Pkru) {
+            wrpkru(pkru.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_and_write_disable_bits() {
+        let mut pkru = Pkru::default();
+        assert_eq!(pkru, Pkru::new());
+        pkru.set_access_disabled(u4::new(0), true);
+        pkru.set_write_disabled(u4::new(1), true);
+        pkru.set_access_disabled(u4::new(15), true);
+        pkru.set_write_disabled(u4::new(15), true);
+        assert_eq!(pkru.bits(), 0xc000_0009);
+        assert!(pkru.access_disabled(u4::new(0)));
+        assert!(!pkru.write_disabled(u4::new(0)));
+        assert!(!pkru.access_disabled(u4::new(1)));
+        assert!(pkru.write_disabled(u4::new(1)));
+        assert!(pkru.access_disabled(u4::new(15)) && pkru.write_disabled(u4::new(15)));
+
+        pkru.set_write_disabled(u4::new(15), false);
+        pkru.set_access_disabled(u4::new(0), false);
+        assert_eq!(pkru, Pkru::from_bits(0x4000_0008));
+    }
+}
@@ -9,6 +9,8 @@
 
 //! Provides types for the Interrupt Descriptor Table and its entries.
 
+use crate::structures::paging::mapper::{MapperAllSizes, TranslateResult};
+use crate::structures::paging::PageTableFlags;
 use crate::{PrivilegeLevel, VirtAddr};
 use bit_field::BitField;
 use bitflags::bitflags;
@@ -749,9 +751,195 @@ bitflags! {
         /// If this flag is set, it indicates that the access that caused the page fault was an
         /// instruction fetch.
         const INSTRUCTION_FETCH = 1 << 4;
+
+        /// If this flag is set, the page fault was caused by a protection-key violation, i.e.
+        /// the access rights in the PKRU register (for user-mode addresses) or in the
+        /// `IA32_PKRS` register (for supervisor-mode addresses) do not permit the access.
+        const PROTECTION_KEY = 1 << 5;
+
+        /// If this flag is set, the page fault was caused by a shadow-stack access.
+        const SHADOW_STACK = 1 << 6;
+
+        /// If this flag is set, the page fault occurred during HLAT paging, i.e. during the
+        /// translation through the hypervisor-managed linear address translation.
+        const HLAT = 1 << 7;
+
+        /// If this flag is set, the page fault is an SGX-specific access-control violation that
+        /// is not related to the ordinary paging protections.
+        const SGX = 1 << 15;
     }
 }
 
+/// The information that is available about a page fault.
+///
+/// Combines the error code that is passed to a `PageFaultHandlerFunc` with the accessed
+/// address from the `Cr2` register, the address of the faulting instruction and optionally the
+/// current translation of the accessed address. The `cause` method uses this information to
+/// classify the fault.
+#[derive(Debug)]
+pub struct PageFaultInfo {
+    /// The virtual address whose access caused the page fault.
+    pub address: VirtAddr,
+    /// The error code of the page fault.
+    pub error_code: PageFaultErrorCode,
+    /// The address of the instruction that caused the page fault.
+    pub instruction_pointer: VirtAddr,
+    /// The translation of `address` through the page tables, if it was added through
+    /// `with_translation`.
+    ///
+    /// Note that the translation reflects the page tables at the time `with_translation` was
+    /// called, which can differ from the page tables at the time of the fault if another CPU
+    /// changed them in between.
+    pub translation: Option<TranslateResult>,
+}
+
+impl PageFaultInfo {
+    /// Creates the information for a page fault at `address` with the given error code that was
+    /// caused by the instruction at `instruction_pointer`.
+    pub fn new(
+        address: VirtAddr,
+        error_code: PageFaultErrorCode,
+        instruction_pointer: VirtAddr,
+    ) -> Self {
+        PageFaultInfo {
+            address,
+            error_code,
+            instruction_pointer,
+            translation: None,
+        }
+    }
+
+    /// Creates the information for the current page fault from the arguments of a
+    /// `PageFaultHandlerFunc` and the `Cr2` register.
+    ///
+    /// This function must be called before anything else can cause another page fault, since
+    /// that would overwrite `Cr2`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn read(stack_frame: &InterruptStackFrame, error_code: PageFaultErrorCode) -> Self {
+        use crate::registers::control::Cr2;
+
+        Self::new(Cr2::read(), error_code, stack_frame.instruction_pointer)
+    }
+
+    /// Adds the translation of the accessed address through the given mapper.
+    pub fn with_translation<M: MapperAllSizes>(mut self, mapper: &M) -> Self {
+        self.translation = Some(mapper.translate(self.address));
+        self
+    }
+
+    /// Returns the kind of the access that caused the page fault.
+    pub fn access(&self) -> PageFaultAccess {
+        if self
+            .error_code
+            .contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+        {
+            PageFaultAccess::InstructionFetch
+        } else if self
+            .error_code
+            .contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        {
+            PageFaultAccess::Write
+        } else {
+            PageFaultAccess::Read
+        }
+    }
+
+    /// Returns whether the access that caused the page fault was made in user mode (CPL=3).
+    pub fn is_user_mode(&self) -> bool {
+        self.error_code.contains(PageFaultErrorCode::USER_MODE)
+    }
+
+    /// Classifies the page fault.
+    ///
+    /// Faults that are caused by reserved bits, SGX, shadow stacks or protection keys, and
+    /// faults on non-present pages are classified through the error code alone. Other
+    /// protection violations can only be classified further if a translation of the address
+    /// was added through `with_translation`; otherwise they are reported as
+    /// `PageFaultCause::ProtectionViolation`.
+    pub fn cause(&self) -> PageFaultCause {
+        let error_code = self.error_code;
+        if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            return PageFaultCause::ReservedBitSet;
+        }
+        if error_code.contains(PageFaultErrorCode::SGX) {
+            return PageFaultCause::Sgx;
+        }
+        if error_code.contains(PageFaultErrorCode::SHADOW_STACK) {
+            return PageFaultCause::ShadowStack;
+        }
+        if error_code.contains(PageFaultErrorCode::PROTECTION_KEY) {
+            return PageFaultCause::ProtectionKey;
+        }
+        if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            return PageFaultCause::NotPresent;
+        }
+
+        let flags = match self.translation.as_ref().and_then(|t| t.effective_flags()) {
+            Some(flags) => flags,
+            None => return PageFaultCause::ProtectionViolation,
+        };
+        let user_page = flags.contains(PageTableFlags::USER_ACCESSIBLE);
+        if self.is_user_mode() && !user_page {
+            return PageFaultCause::KernelPageAccess;
+        }
+        // the access rights of the page are checked before SMEP and SMAP, so that e.g. a
+        // supervisor write to a read-only user page is reported as a write to a read-only page
+        match self.access() {
+            PageFaultAccess::InstructionFetch if flags.contains(PageTableFlags::NO_EXECUTE) => {
+                PageFaultCause::ExecuteNoExecutePage
+            }
+            PageFaultAccess::Write if !flags.contains(PageTableFlags::WRITABLE) => {
+                PageFaultCause::WriteToReadOnlyPage
+            }
+            PageFaultAccess::InstructionFetch if user_page && !self.is_user_mode() => {
+                PageFaultCause::SupervisorModeExecution
+            }
+            _ if user_page && !self.is_user_mode() => PageFaultCause::SupervisorModeAccess,
+            _ => PageFaultCause::ProtectionViolation,
+        }
+    }
+}
+
+/// The kind of memory access that caused a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultAccess {
+    /// A data read.
+    Read,
+    /// A data write.
+    Write,
+    /// An instruction fetch.
+    InstructionFetch,
+}
+
+/// The cause of a page fault, as determined by `PageFaultInfo::cause`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultCause {
+    /// The accessed page or one of its page tables is not present.
+    NotPresent,
+    /// A page table entry on the path to the accessed page has a reserved bit set.
+    ReservedBitSet,
+    /// The access violated the access rights of the protection key of the page.
+    ProtectionKey,
+    /// The access was a shadow-stack access that is not permitted for the page.
+    ShadowStack,
+    /// The access violated the SGX access-control requirements.
+    Sgx,
+    /// An access in user mode to a page that is not user accessible.
+    KernelPageAccess,
+    /// An instruction fetch in supervisor mode from a user accessible page, which is prevented
+    /// by SMEP.
+    SupervisorModeExecution,
+    /// A data access in supervisor mode to a user accessible page, which is prevented by SMAP.
+    SupervisorModeAccess,
+    /// A write to a page that is not writable.
+    WriteToReadOnlyPage,
+    /// An instruction fetch from a page that has the `NO_EXECUTE` flag set.
+    ExecuteNoExecutePage,
+    /// A protection violation that could not be classified further, e.g. because no
+    /// translation of the address is available.
+    ProtectionViolation,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -762,4 +950,147 @@ mod test {
         assert_eq!(size_of::<Entry<HandlerFunc>>(), 16);
         assert_eq!(size_of::<InterruptDescriptorTable>(), 256 * 16);
     }
+
+    #[test]
+    fn classify_page_faults() {
+        use crate::structures::paging::mapper::{Mapper, SimulatedPhysMemory};
+        use crate::structures::paging::{Page, PhysFrame, Size4KiB};
+        use crate::PhysAddr;
+
+        let ip = VirtAddr::new(0x1000);
+        let address = VirtAddr::new(0x40_0000);
+        let info = |error_code| PageFaultInfo::new(address, error_code, ip);
+        assert_eq!(
+            info(PageFaultErrorCode::CAUSED_BY_WRITE).cause(),
+            PageFaultCause::NotPresent
+        );
+        assert_eq!(
+            info(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::MALFORMED_TABLE)
+                .cause(),
+            PageFaultCause::ReservedBitSet
+        );
+        assert_eq!(
+            info(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::SHADOW_STACK)
+                .cause(),
+            PageFaultCause::ShadowStack
+        );
+        assert_eq!(
+            info(PageFaultErrorCode::PROTECTION_VIOLATION).cause(),
+            PageFaultCause::ProtectionViolation
+        );
+
+        let mut frames = SimulatedPhysMemory::buffer(8);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let mut mapper = unsafe { memory.mapper(root_frame) };
+        let executable = VirtAddr::new(0x40_1000);
+        for &(addr, flags) in &[
+            (
+                address,
+                PageTableFlags::PRESENT
+                    | PageTableFlags::USER_ACCESSIBLE
+                    | PageTableFlags::NO_EXECUTE,
+            ),
+            (
+                executable,
+                PageTableFlags::PRESENT
+                    | PageTableFlags::USER_ACCESSIBLE
+                    | PageTableFlags::WRITABLE,
+            ),
+        ] {
+            let page: Page<Size4KiB> = Page::containing_address(addr);
+            let frame = PhysFrame::containing_address(PhysAddr::new(0x20_0000));
+            unsafe { mapper.map_to(page, frame, flags, &mut memory) }
+                .unwrap()
+                .ignore();
+        }
+
+        let classify = |address, error_code| {
+            PageFaultInfo::new(
+                address,
+                error_code | PageFaultErrorCode::PROTECTION_VIOLATION,
+                ip,
+            )
+            .with_translation(&mapper)
+            .cause()
+        };
+        let user = PageFaultErrorCode::USER_MODE;
+        let write = PageFaultErrorCode::CAUSED_BY_WRITE;
+        let fetch = PageFaultErrorCode::INSTRUCTION_FETCH;
+        assert_eq!(
+            classify(address, user | write),
+            PageFaultCause::WriteToReadOnlyPage
+        );
+        assert_eq!(
+            classify(address, user | fetch),
+            PageFaultCause::ExecuteNoExecutePage
+        );
+        assert_eq!(
+            classify(address, PageFaultErrorCode::empty()),
+            PageFaultCause::SupervisorModeAccess
+        );
+        // supervisor accesses that violate the access rights of a user page
+        assert_eq!(
+            classify(address, write),
+            PageFaultCause::WriteToReadOnlyPage
+        );
+        assert_eq!(
+            classify(address, fetch),
+            PageFaultCause::ExecuteNoExecutePage
+        );
+        assert_eq!(
+            classify(executable, fetch),
+            PageFaultCause::SupervisorModeExecution
+        );
+        assert_eq!(
+            classify(executable, write),
+            PageFaultCause::SupervisorModeAccess
+        );
+        assert_eq!(
+            classify(executable, user | write),
+            PageFaultCause::ProtectionViolation
+        );
+        assert_eq!(
+            info(user | fetch).access(),
+            PageFaultAccess::InstructionFetch
+        );
+        assert!(info(user).is_user_mode());
+    }
+
+    #[test]
+    fn classify_la57_page_fault() {
+        use crate::structures::paging::mapper::{
+            MappedPageTable, Mapper, PhysToVirt, SimulatedPhysMemory,
+        };
+        use crate::structures::paging::{Page, PagingMode, PhysFrame, Size4KiB};
+        use crate::PhysAddr;
+
+        // an address that is only canonical with 5-level paging, converted like `Cr2::read`
+        let address = VirtAddr::new_unchecked_la57(0x0080_0000_0040_0123);
+        assert_eq!(address.as_u64(), 0x0080_0000_0040_0123);
+
+        let mut frames = SimulatedPhysMemory::buffer(8);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let root_table = unsafe { &mut *memory.phys_to_virt().phys_to_virt(root_frame) };
+        let mut mapper = unsafe {
+            MappedPageTable::with_paging_mode(root_table, memory.phys_to_virt(), PagingMode::Level5)
+        };
+        let page: Page<Size4KiB> = Page::containing_address(address);
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x20_0000));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        unsafe { mapper.map_to(page, frame, flags, &mut memory) }
+            .unwrap()
+            .ignore();
+
+        let info = PageFaultInfo::new(
+            address,
+            PageFaultErrorCode::PROTECTION_VIOLATION
+                | PageFaultErrorCode::CAUSED_BY_WRITE
+                | PageFaultErrorCode::USER_MODE,
+            VirtAddr::new(0x1000),
+        )
+        .with_translation(&mapper);
+        assert_eq!(info.cause(), PageFaultCause::WriteToReadOnlyPage);
+    }
 }
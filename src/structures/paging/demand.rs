@@ -0,0 +1,630 @@
+//! Demand paging through a registry of virtual memory regions.
+//!
+//! Each region of a `RegionRegistry` is a range of pages that is tagged with a
+//! `FaultResolver`. Page faults in the region are resolved by `RegionRegistry::handle_fault`,
+//! which asks the resolver how to resolve the fault, allocates and fills frames and maps them
+//! with the flags of the region.
+
+use crate::structures::idt::{PageFaultErrorCode, PageFaultInfo};
+use crate::structures::paging::{
+    mapper::{
+        FlagUpdateError, MapToError, MapperAllSizes, MapperFlush, PhysToVirt, TranslateResult,
+        COPY_ON_WRITE,
+    },
+    page::PageRange,
+    FrameAllocator, FrameDeallocator, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+};
+use core::{fmt, ptr, slice};
+
+/// A page fault within a region of a `RegionRegistry`.
+#[derive(Debug, Clone, Copy)]
+pub struct DemandFault {
+    /// The page that contains the faulting address.
+    pub page: Page,
+    /// The offset of `page` from the start of the region in bytes.
+    pub offset: u64,
+    /// The error code of the page fault.
+    pub error_code: PageFaultErrorCode,
+    /// The frame that the page is currently mapped to and the flags of the mapping, or `None`
+    /// if the page is not mapped.
+    pub mapping: Option<(PhysFrame, PageTableFlags)>,
+}
+
+/// Describes how `RegionRegistry::handle_fault` resolves a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Allocate a new frame, initialize it through `FaultResolver::fill` and map the page to
+    /// it with the flags of the region.
+    MapNewFrame,
+    /// Allocate a new frame, copy the content of the currently mapped frame to it and remap the
+    /// page writable to the new frame through `Mapper::remap`. The `COPY_ON_WRITE` flag of the
+    /// page is cleared.
+    CopyFrame,
+    /// Make the current mapping of the page writable and clear its `COPY_ON_WRITE` flag.
+    MakeWritable,
+    /// The fault can't be resolved, e.g. because the page is a guard page.
+    Reject,
+}
+
+/// Decides how page faults within a region are resolved.
+pub trait FaultResolver {
+    /// Returns how the given page fault is resolved.
+    fn resolve(&mut self, fault: &DemandFault) -> Resolution;
+
+    /// Initializes the content of a newly allocated frame for the faulting page.
+    ///
+    /// This method is called for `Resolution::MapNewFrame` before the frame is mapped. The
+    /// default implementation fills the frame with zeros. An error means that the content
+    /// couldn't be provided, in which case the frame is deallocated again.
+    fn fill(&mut self, fault: &DemandFault, content: &mut [u8]) -> Result<(), FillError> {
+        let _ = fault;
+        for byte in content.iter_mut() {
+            *byte = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Resolves faults on unmapped pages by mapping zeroed frames, e.g. for heaps and stacks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZeroFill;
+
+impl FaultResolver for ZeroFill {
+    fn resolve(&mut self, fault: &DemandFault) -> Resolution {
+        match fault.mapping {
+            None => Resolution::MapNewFrame,
+            Some(_) => Resolution::Reject,
+        }
+    }
+}
+
+/// Resolves faults on unmapped pages by mapping frames that are filled by a callback, e.g. with
+/// the content of a file.
+///
+/// The callback is called with the offset of the page in the file and the content of the new
+/// frame. The offset is the offset of the page in the region plus the `file_offset` that was
+/// passed to `new`.
+pub struct FileBacked<F>
+where
+    F: FnMut(u64, &mut [u8]) -> Result<(), FillError>,
+{
+    file_offset: u64,
+    read: F,
+}
+
+impl<F> FileBacked<F>
+where
+    F: FnMut(u64, &mut [u8]) -> Result<(), FillError>,
+{
+    /// Creates a new resolver for a region that maps the file starting at `file_offset`.
+    pub fn new(file_offset: u64, read: F) -> Self {
+        FileBacked { file_offset, read }
+    }
+}
+
+impl<F> FaultResolver for FileBacked<F>
+where
+    F: FnMut(u64, &mut [u8]) -> Result<(), FillError>,
+{
+    fn resolve(&mut self, fault: &DemandFault) -> Resolution {
+        match fault.mapping {
+            None => Resolution::MapNewFrame,
+            Some(_) => Resolution::Reject,
+        }
+    }
+
+    fn fill(&mut self, fault: &DemandFault, content: &mut [u8]) -> Result<(), FillError> {
+        (self.read)(self.file_offset + fault.offset, content)
+    }
+}
+
+impl<F> fmt::Debug for FileBacked<F>
+where
+    F: FnMut(u64, &mut [u8]) -> Result<(), FillError>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut f = f.debug_struct("FileBacked");
+        f.field("file_offset", &self.file_offset);
+        f.finish()
+    }
+}
+
+/// Rejects all faults, so that accesses to the region are reported as errors.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Guard;
+
+impl FaultResolver for Guard {
+    fn resolve(&mut self, _fault: &DemandFault) -> Resolution {
+        Resolution::Reject
+    }
+}
+
+/// Resolves writes to copy-on-write pages, e.g. after `AddressSpace::fork`.
+///
+/// The `is_shared` callback is called with the currently mapped frame of a faulting
+/// copy-on-write page. If it returns `true`, the frame is copied to a new frame. Otherwise the
+/// page is made writable in place. Unmapped pages are mapped to zeroed frames.
+pub struct CopyOnWrite<F>
+where
+    F: FnMut(PhysFrame) -> bool,
+{
+    is_shared: F,
+}
+
+impl<F> CopyOnWrite<F>
+where
+    F: FnMut(PhysFrame) -> bool,
+{
+    /// Creates a new resolver that uses the given callback to check whether a frame is shared.
+    pub fn new(is_shared: F) -> Self {
+        CopyOnWrite { is_shared }
+    }
+}
+
+impl<F> FaultResolver for CopyOnWrite<F>
+where
+    F: FnMut(PhysFrame) -> bool,
+{
+    fn resolve(&mut self, fault: &DemandFault) -> Resolution {
+        match fault.mapping {
+            None => Resolution::MapNewFrame,
+            Some((frame, flags))
+                if flags.contains(COPY_ON_WRITE)
+                    && fault
+                        .error_code
+                        .contains(PageFaultErrorCode::CAUSED_BY_WRITE) =>
+            {
+                if (self.is_shared)(frame) {
+                    Resolution::CopyFrame
+                } else {
+                    Resolution::MakeWritable
+                }
+            }
+            Some(_) => Resolution::Reject,
+        }
+    }
+}
+
+impl<F> fmt::Debug for CopyOnWrite<F>
+where
+    F: FnMut(PhysFrame) -> bool,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CopyOnWrite").finish()
+    }
+}
+
+/// A range of pages whose faults are resolved by a `FaultResolver`.
+pub struct Region<'r> {
+    pages: PageRange,
+    flags: PageTableFlags,
+    resolver: &'r mut dyn FaultResolver,
+}
+
+impl<'r> Region<'r> {
+    /// Returns the pages of the region.
+    pub fn pages(&self) -> PageRange {
+        self.pages
+    }
+
+    /// Returns the flags that new mappings in the region get.
+    pub fn flags(&self) -> PageTableFlags {
+        self.flags
+    }
+
+    /// Returns the resolver of the region.
+    pub fn resolver(&mut self) -> &mut dyn FaultResolver {
+        self.resolver
+    }
+}
+
+impl<'r> fmt::Debug for Region<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut f = f.debug_struct("Region");
+        f.field("pages", &self.pages);
+        f.field("flags", &self.flags);
+        f.finish()
+    }
+}
+
+/// A registry of non-overlapping regions whose page faults are resolved on demand.
+///
+/// The regions are stored in a slice that is provided by the caller, so the registry needs no
+/// heap. The length of the slice is the maximum number of regions.
+#[derive(Debug)]
+pub struct RegionRegistry<'a, 'r> {
+    regions: &'a mut [Option<Region<'r>>],
+}
+
+impl<'a, 'r> RegionRegistry<'a, 'r> {
+    /// Creates a new empty registry that stores its regions in the given slice.
+    ///
+    /// All regions that are already stored in the slice are removed.
+    pub fn new(storage: &'a mut [Option<Region<'r>>]) -> Self {
+        for slot in storage.iter_mut() {
+            *slot = None;
+        }
+        RegionRegistry { regions: storage }
+    }
+
+    /// Adds a region for the given pages that is resolved by the given resolver.
+    ///
+    /// New mappings in the region get the given `flags` and the `PRESENT` flag.
+    pub fn insert(
+        &mut self,
+        pages: PageRange,
+        flags: PageTableFlags,
+        resolver: &'r mut dyn FaultResolver,
+    ) -> Result<(), RegionError> {
+        if pages.is_empty() {
+            return Err(RegionError::EmptyRange);
+        }
+        let overlaps = self
+            .iter()
+            .any(|region| region.pages.start < pages.end && pages.start < region.pages.end);
+        if overlaps {
+            return Err(RegionError::Overlap);
+        }
+        let slot = self
+            .regions
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RegionError::StorageFull)?;
+        *slot = Some(Region {
+            pages,
+            flags: flags | PageTableFlags::PRESENT,
+            resolver,
+        });
+        Ok(())
+    }
+
+    /// Removes the region that starts at the given page and returns it.
+    ///
+    /// The pages that were mapped for the region stay mapped.
+    pub fn remove(&mut self, start: Page) -> Option<Region<'r>> {
+        self.regions
+            .iter_mut()
+            .find(|slot| match slot {
+                Some(region) => region.pages.start == start,
+                None => false,
+            })
+            .and_then(Option::take)
+    }
+
+    /// Returns the region that contains the given page.
+    pub fn region(&self, page: Page) -> Option<&Region<'r>> {
+        self.iter()
+            .find(|region| region.pages.start <= page && page < region.pages.end)
+    }
+
+    /// Returns an iterator over the regions of the registry.
+    pub fn iter(&self) -> impl Iterator<Item = &Region<'r>> {
+        self.regions.iter().filter_map(Option::as_ref)
+    }
+
+    /// Resolves the given page fault through the region that contains the faulting address.
+    ///
+    /// On success, the page fault is handled and the faulting access can be retried after the
+    /// returned `MapperFlush` was flushed. If the resolution replaced the mapped frame of a
+    /// copy-on-write page, the previously mapped frame is returned too, so that the caller can
+    /// update its share count.
+    ///
+    /// Frames for new mappings and page tables are allocated from the given `frame_allocator`.
+    /// If resolving the fault fails, the allocated frames are returned to it.
+    ///
+    /// This function is unsafe because the caller must guarantee that `mapper` manages the
+    /// address space in which the fault occurred, that the passed `frame_allocator` only yields
+    /// unused frames and that the `phys_to_virt` implementation can be used to access them.
+    pub unsafe fn handle_fault<M, A, P>(
+        &mut self,
+        info: &PageFaultInfo,
+        mapper: &mut M,
+        frame_allocator: &mut A,
+        phys_to_virt: &P,
+    ) -> Result<(MapperFlush<Size4KiB>, Option<PhysFrame>), DemandFaultError>
+    where
+        M: MapperAllSizes,
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+        P: PhysToVirt,
+    {
+        let page = Page::containing_address(info.address);
+        let region = self
+            .regions
+            .iter_mut()
+            .filter_map(Option::as_mut)
+            .find(|region| region.pages.start <= page && page < region.pages.end)
+            .ok_or(DemandFaultError::NoRegion)?;
+        let mapping = match mapper.translate(info.address) {
+            TranslateResult::Frame4KiB { frame, flags, .. } => Some((frame, flags)),
+            TranslateResult::PageNotMapped { .. } => None,
+            _ => return Err(DemandFaultError::UnsupportedMapping),
+        };
+        let fault = DemandFault {
+            page,
+            offset: page.start_address() - region.pages.start.start_address(),
+            error_code: info.error_code,
+            mapping,
+        };
+        let content = |frame: PhysFrame| {
+            slice::from_raw_parts_mut(
+                phys_to_virt.phys_to_virt(frame) as *mut u8,
+                Size4KiB::SIZE as usize,
+            )
+        };
+
+        match region.resolver.resolve(&fault) {
+            Resolution::MapNewFrame => {
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or(DemandFaultError::FrameAllocationFailed)?;
+                if let Err(err) = region.resolver.fill(&fault, content(frame)) {
+                    frame_allocator.deallocate_frame(frame);
+                    return Err(DemandFaultError::FillFailed(err));
+                }
+                match mapper.map_to(page, frame, region.flags, frame_allocator) {
+                    Ok(flush) => Ok((flush, None)),
+                    Err(err) => {
+                        frame_allocator.deallocate_frame(frame);
+                        Err(DemandFaultError::MapToError(err))
+                    }
+                }
+            }
+            Resolution::CopyFrame => {
+                let (old_frame, flags) = mapping.ok_or(DemandFaultError::Rejected)?;
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or(DemandFaultError::FrameAllocationFailed)?;
+                ptr::copy_nonoverlapping(
+                    content(old_frame).as_ptr(),
+                    content(frame).as_mut_ptr(),
+                    Size4KiB::SIZE as usize,
+                );
+                let new_flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+                match mapper.remap(page, frame, new_flags) {
+                    Ok((_, flush)) => Ok((flush, Some(old_frame))),
+                    Err(err) => {
+                        frame_allocator.deallocate_frame(frame);
+                        Err(DemandFaultError::FlagUpdateError(err))
+                    }
+                }
+            }
+            Resolution::MakeWritable => {
+                let (_, flags) = mapping.ok_or(DemandFaultError::Rejected)?;
+                let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+                let flush = mapper
+                    .update_flags(page, flags)
+                    .map_err(DemandFaultError::FlagUpdateError)?;
+                Ok((flush, None))
+            }
+            Resolution::Reject => Err(DemandFaultError::Rejected),
+        }
+    }
+}
+
+/// This error is returned from `RegionRegistry::insert`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    /// The page range of the region is empty.
+    EmptyRange,
+    /// The region overlaps with a region of the registry.
+    Overlap,
+    /// All slots of the storage of the registry are in use.
+    StorageFull,
+}
+
+/// This error is returned from `RegionRegistry::handle_fault` if the fault was not handled.
+#[derive(Debug)]
+pub enum DemandFaultError {
+    /// The faulting address is not part of a region.
+    NoRegion,
+    /// The faulting address is mapped by a huge page or its mapping is invalid.
+    UnsupportedMapping,
+    /// The resolver of the region rejected the fault.
+    Rejected,
+    /// A frame was needed, but the frame allocator returned `None`.
+    FrameAllocationFailed,
+    /// The resolver of the region could not fill the new frame.
+    FillFailed(FillError),
+    /// The new frame could not be mapped.
+    MapToError(MapToError),
+    /// The mapping of the copy-on-write page could not be updated.
+    FlagUpdateError(FlagUpdateError),
+}
+
+/// This error is returned from `FaultResolver::fill` if the content of a new frame couldn't be
+/// provided.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FillError {
+    /// The page is outside of the object that backs the region, e.g. beyond the end of a file.
+    OutOfBounds,
+    /// The content could not be read, e.g. because of an I/O error.
+    ReadFailed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::paging::mapper::SimulatedPhysMemory;
+    use crate::structures::paging::Mapper;
+    use crate::{PhysAddr, VirtAddr};
+
+    fn fault(addr: u64, error_code: PageFaultErrorCode) -> PageFaultInfo {
+        PageFaultInfo::new(VirtAddr::new(addr), error_code, VirtAddr::new(0x1000))
+    }
+
+    #[test]
+    fn register_regions() {
+        let page = Page::containing_address(VirtAddr::new(0x4000_0000));
+        let mut zero_fill = ZeroFill;
+        let mut guard = Guard;
+        let (mut empty, mut overlapping, mut excess) = (ZeroFill, ZeroFill, ZeroFill);
+        let mut storage = [None, None];
+        let mut registry = RegionRegistry::new(&mut storage);
+        let flags = PageTableFlags::WRITABLE;
+        registry
+            .insert(Page::range(page, page + 4), flags, &mut zero_fill)
+            .unwrap();
+        assert_eq!(
+            registry.insert(Page::range(page, page), flags, &mut empty),
+            Err(RegionError::EmptyRange)
+        );
+        assert_eq!(
+            registry.insert(Page::range(page + 3, page + 5), flags, &mut overlapping),
+            Err(RegionError::Overlap)
+        );
+        registry
+            .insert(Page::range(page + 4, page + 5), flags, &mut guard)
+            .unwrap();
+        assert_eq!(
+            registry.insert(Page::range(page + 8, page + 9), flags, &mut excess),
+            Err(RegionError::StorageFull)
+        );
+
+        let region = registry.region(page + 3).unwrap();
+        assert_eq!(region.pages(), Page::range(page, page + 4));
+        assert_eq!(region.flags(), flags | PageTableFlags::PRESENT);
+        assert_eq!(registry.region(page + 5).map(Region::pages), None);
+        assert!(registry.remove(page + 1).is_none());
+        assert!(registry.remove(page + 4).is_some());
+        assert_eq!(registry.iter().count(), 1);
+    }
+
+    #[test]
+    fn resolve_faults() {
+        let mut frames = SimulatedPhysMemory::buffer(16);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let phys_to_virt = memory.phys_to_virt();
+        let mut mapper = unsafe { memory.mapper(root_frame) };
+
+        let mut zero_fill = ZeroFill;
+        let mut guard = Guard;
+        let mut file = FileBacked::new(0x3000, |offset, content: &mut [u8]| {
+            if offset >= 0x5000 {
+                return Err(FillError::OutOfBounds);
+            }
+            for byte in content.iter_mut() {
+                *byte = (offset >> 12) as u8;
+            }
+            Ok(())
+        });
+        let mut storage = [None, None, None];
+        let mut registry = RegionRegistry::new(&mut storage);
+        let base = Page::containing_address(VirtAddr::new(0x4000_0000));
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        registry
+            .insert(Page::range(base, base + 4), flags, &mut zero_fill)
+            .unwrap();
+        registry
+            .insert(Page::range(base + 4, base + 5), flags, &mut guard)
+            .unwrap();
+        registry
+            .insert(Page::range(base + 5, base + 8), flags, &mut file)
+            .unwrap();
+
+        let write = PageFaultErrorCode::CAUSED_BY_WRITE;
+        let mut handle = |addr| unsafe {
+            registry.handle_fault(&fault(addr, write), &mut mapper, &mut memory, &phys_to_virt)
+        };
+        let (flush, replaced) = handle(0x4000_1234).unwrap();
+        flush.ignore();
+        assert_eq!(replaced, None);
+        match handle(0x4000_1234) {
+            Err(DemandFaultError::Rejected) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match handle(0x4000_4000) {
+            Err(DemandFaultError::Rejected) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match handle(0x5000_0000) {
+            Err(DemandFaultError::NoRegion) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        handle(0x4000_6000).unwrap().0.ignore();
+        match handle(0x4000_7000) {
+            Err(DemandFaultError::FillFailed(FillError::OutOfBounds)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let page_at = |addr| -> Page { Page::containing_address(VirtAddr::new(addr)) };
+        let read = |addr| {
+            let frame = mapper.translate_page(page_at(addr));
+            let content = phys_to_virt.phys_to_virt(frame.unwrap()) as *const u8;
+            unsafe { *content }
+        };
+        assert_eq!(read(0x4000_1000), 0);
+        assert_eq!(read(0x4000_6000), 4);
+        assert!(mapper.translate_page(page_at(0x4000_7000)).is_err());
+        assert_eq!(memory.allocated_frames(), 6);
+    }
+
+    #[test]
+    fn resolve_copy_on_write_faults() {
+        let mut frames = SimulatedPhysMemory::buffer(16);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let phys_to_virt = memory.phys_to_virt();
+        let mut mapper = unsafe { memory.mapper(root_frame) };
+
+        let page = Page::containing_address(VirtAddr::new(0x4000_0000));
+        let shared_frame = memory.allocate_frame().unwrap();
+        unsafe { (*phys_to_virt.phys_to_virt(shared_frame)).zero() };
+        unsafe { *(phys_to_virt.phys_to_virt(shared_frame) as *mut u8) = 42 };
+        let cow_flags = PageTableFlags::PRESENT | COPY_ON_WRITE;
+        for &p in &[page, page + 1] {
+            unsafe { mapper.map_to(p, shared_frame, cow_flags, &mut memory) }
+                .unwrap()
+                .ignore();
+        }
+
+        let mut share_count = 2;
+        let mut cow = CopyOnWrite::new(|_| {
+            share_count -= 1;
+            share_count > 0
+        });
+        let mut storage = [None];
+        let mut registry = RegionRegistry::new(&mut storage);
+        registry
+            .insert(
+                Page::range(page, page + 4),
+                PageTableFlags::WRITABLE,
+                &mut cow,
+            )
+            .unwrap();
+
+        let mut handle = |addr, error_code| unsafe {
+            registry.handle_fault(
+                &fault(addr, error_code | PageFaultErrorCode::PROTECTION_VIOLATION),
+                &mut mapper,
+                &mut memory,
+                &phys_to_virt,
+            )
+        };
+        match handle(0x4000_0000, PageFaultErrorCode::empty()) {
+            Err(DemandFaultError::Rejected) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        let (flush, replaced) = handle(0x4000_0000, PageFaultErrorCode::CAUSED_BY_WRITE).unwrap();
+        flush.ignore();
+        assert_eq!(replaced, Some(shared_frame));
+        let (flush, replaced) = handle(0x4000_1000, PageFaultErrorCode::CAUSED_BY_WRITE).unwrap();
+        flush.ignore();
+        assert_eq!(replaced, None);
+
+        let writable = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        for &p in &[page, page + 1] {
+            match mapper.translate(p.start_address()) {
+                TranslateResult::Frame4KiB { frame, flags, .. } => {
+                    assert_eq!(flags, writable);
+                    assert_eq!(
+                        unsafe { *(phys_to_virt.phys_to_virt(frame) as *const u8) },
+                        42
+                    );
+                    assert_eq!(frame == shared_frame, p == page + 1);
+                }
+                other => panic!("unexpected translation {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,317 @@
+//! Mapping of the complete physical memory at a fixed offset.
+
+use crate::structures::paging::{
+    mapper::{MapToError, MapperAllSizes},
+    memory_map::{MemoryMap, MemoryRegion, MemoryRegionKind},
+    FrameAllocator, Mapper, Page, PageSize, PageTableFlags, PagingMode, PhysFrame, Size1GiB,
+    Size2MiB, Size4KiB,
+};
+use crate::{PhysAddr, VirtAddr};
+
+/// Describes a mapping of the physical memory to the virtual addresses starting at `offset`,
+/// as it is required by `OffsetPageTable`.
+///
+/// The memory is mapped with the largest page sizes that the alignment of the physical
+/// addresses allows. 1GiB pages are only used if `giant_pages` is set, since not all processors
+/// support them. This requires far fewer page tables and TLB entries than mapping 4KiB pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirectMap {
+    /// The virtual address to which physical address 0 is mapped.
+    ///
+    /// Huge pages can only be used if the offset is aligned to their size.
+    pub offset: VirtAddr,
+    /// The flags of the mapped pages.
+    pub flags: PageTableFlags,
+    /// Whether pages of size 1GiB can be used.
+    pub giant_pages: bool,
+    /// The paging mode of the page table hierarchy, in which `offset` and the mapped virtual
+    /// addresses must be canonical.
+    pub paging_mode: PagingMode,
+}
+
+impl DirectMap {
+    /// Creates a direct map at the given offset for 4-level paging that doesn't use 1GiB
+    /// pages.
+    ///
+    /// The pages are mapped with the `PRESENT`, `WRITABLE`, `GLOBAL` and `NO_EXECUTE` flags. The
+    /// `NO_EXECUTE` flag must be removed from `flags` if `EferFlags::NO_EXECUTE_ENABLE` is not
+    /// set, since it is a reserved bit then.
+    pub fn new(offset: VirtAddr) -> Self {
+        DirectMap {
+            offset,
+            flags: PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::GLOBAL
+                | PageTableFlags::NO_EXECUTE,
+            giant_pages: false,
+            paging_mode: PagingMode::Level4,
+        }
+    }
+
+    /// Creates a direct map at the given offset for the current processor.
+    ///
+    /// 1GiB pages are used if `cpuid` reports support for them. The `NO_EXECUTE` flag is only
+    /// set if `EferFlags::NO_EXECUTE_ENABLE` is set. The paging mode is read from the CR4
+    /// register.
+    #[cfg(target_arch = "x86_64")]
+    pub fn current(offset: VirtAddr) -> Self {
+        use crate::registers::model_specific::{Efer, EferFlags};
+
+        let mut direct_map = Self::new(offset);
+        direct_map.giant_pages = raw_cpuid::CpuId::new()
+            .get_extended_function_info()
+            .map(|info| info.has_1gib_pages())
+            .unwrap_or(false);
+        if !Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+            direct_map.flags.remove(PageTableFlags::NO_EXECUTE);
+        }
+        direct_map.paging_mode = PagingMode::current();
+        direct_map
+    }
+
+    /// Returns the virtual address at which the given physical address is mapped.
+    ///
+    /// Panics if the address is not canonical in the paging mode of the direct map.
+    pub fn virt_addr(&self, phys_addr: PhysAddr) -> VirtAddr {
+        let addr = self
+            .offset
+            .as_u64()
+            .checked_add(phys_addr.as_u64())
+            .expect("physical address is not mapped at the offset");
+        self.paging_mode.virt_addr(addr)
+    }
+
+    /// Maps all memory of the given memory map that contains RAM, i.e. the regions of kind
+    /// `Usable`, `AcpiReclaimable` and `AcpiNvs`.
+    ///
+    /// Adjacent regions are mapped together, so that huge pages can span region boundaries.
+    /// Regions that don't start or end at a frame boundary are extended to the surrounding
+    /// frames. Other memory, such as the frame buffer, can be mapped through `map_range`.
+    ///
+    /// This function is unsafe for the same reasons as `map_range`.
+    pub unsafe fn map_memory_map<M, A>(
+        &self,
+        memory_map: &MemoryMap,
+        mapper: &mut M,
+        frame_allocator: &mut A,
+    ) -> Result<(), MapToError>
+    where
+        M: MapperAllSizes,
+        A: FrameAllocator<Size4KiB>,
+    {
+        let is_ram = |region: &&MemoryRegion| {
+            !region.is_empty()
+                && match region.kind {
+                    MemoryRegionKind::Usable
+                    | MemoryRegionKind::AcpiReclaimable
+                    | MemoryRegionKind::AcpiNvs => true,
+                    MemoryRegionKind::Reserved | MemoryRegionKind::BadMemory => false,
+                }
+        };
+        let regions = memory_map.regions();
+        let mut mapped_end = PhysAddr::new(0);
+        // the regions are not necessarily sorted, so find the lowest region that is not
+        // mapped yet in each iteration
+        while let Some(region) = regions
+            .iter()
+            .filter(is_ram)
+            .filter(|region| region.end.align_up(Size4KiB::SIZE) > mapped_end)
+            .min_by_key(|region| region.start)
+        {
+            let start = region.start.align_down(Size4KiB::SIZE).max(mapped_end);
+            let mut end = region.end.align_up(Size4KiB::SIZE);
+            while let Some(next) = regions
+                .iter()
+                .filter(is_ram)
+                .filter(|next| next.start.align_down(Size4KiB::SIZE) <= end)
+                .find(|next| next.end.align_up(Size4KiB::SIZE) > end)
+            {
+                end = next.end.align_up(Size4KiB::SIZE);
+            }
+            self.map_range(start, end, mapper, frame_allocator)?;
+            mapped_end = end;
+        }
+        Ok(())
+    }
+
+    /// Maps the physical memory from `start` (inclusive) to `end` (exclusive).
+    ///
+    /// Each part of the range is mapped with the largest page size that its alignment allows:
+    /// 1GiB pages if `giant_pages` is set, 2MiB pages otherwise and 4KiB pages at the edges of
+    /// the range that are not aligned to 2MiB. Frames for the page tables are allocated from the
+    /// given `frame_allocator`. Only new mappings are created, so no TLB flush is necessary. If a
+    /// page of the range is already mapped, `MapToError::PageAlreadyMapped` is returned and the
+    /// pages that were mapped before stay mapped.
+    ///
+    /// This function is unsafe because the caller must guarantee that the mapping doesn't
+    /// violate memory safety, e.g. by allowing writes to memory that must be read-only through
+    /// the direct map. Also, the passed `frame_allocator` must only yield unused frames.
+    ///
+    /// Panics if `start` or `end` is not aligned to 4KiB or if the mapped virtual addresses are
+    /// not valid.
+    pub unsafe fn map_range<M, A>(
+        &self,
+        start: PhysAddr,
+        end: PhysAddr,
+        mapper: &mut M,
+        frame_allocator: &mut A,
+    ) -> Result<(), MapToError>
+    where
+        M: MapperAllSizes,
+        A: FrameAllocator<Size4KiB>,
+    {
+        assert!(
+            start.is_aligned(Size4KiB::SIZE),
+            "`start` must be aligned to 4KiB"
+        );
+        assert!(
+            end.is_aligned(Size4KiB::SIZE),
+            "`end` must be aligned to 4KiB"
+        );
+        let mut phys_addr = start;
+        while phys_addr < end {
+            let remaining = end - phys_addr;
+            let size = if self.giant_pages && self.fits::<Size1GiB>(phys_addr, remaining) {
+                self.map_page::<Size1GiB, _, _>(phys_addr, mapper, frame_allocator)?
+            } else if self.fits::<Size2MiB>(phys_addr, remaining) {
+                self.map_page::<Size2MiB, _, _>(phys_addr, mapper, frame_allocator)?
+            } else {
+                self.map_page::<Size4KiB, _, _>(phys_addr, mapper, frame_allocator)?
+            };
+            phys_addr += size;
+        }
+        Ok(())
+    }
+
+    /// Returns whether a page of size `S` can map the physical memory at `phys_addr`.
+    fn fits<S: PageSize>(&self, phys_addr: PhysAddr, remaining: u64) -> bool {
+        phys_addr.is_aligned(S::SIZE)
+            && self.virt_addr(phys_addr).is_aligned(S::SIZE)
+            && remaining >= S::SIZE
+    }
+
+    /// Maps the page of size `S` that starts at `phys_addr` and returns its size.
+    unsafe fn map_page<S, M, A>(
+        &self,
+        phys_addr: PhysAddr,
+        mapper: &mut M,
+        frame_allocator: &mut A,
+    ) -> Result<u64, MapToError>
+    where
+        S: PageSize,
+        M: Mapper<S>,
+        A: FrameAllocator<Size4KiB>,
+    {
+        let page = Page::<S>::containing_address(self.virt_addr(phys_addr));
+        let frame = PhysFrame::containing_address(phys_addr);
+        // the page was not mapped before, so the TLB contains no entry for it
+        mapper
+            .map_to(page, frame, self.flags, frame_allocator)?
+            .ignore();
+        Ok(S::SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::paging::mapper::{
+        MappedPageTable, PhysToVirt, SimulatedPhysMemory, TranslateResult,
+    };
+
+    fn region(start: u64, end: u64, kind: MemoryRegionKind) -> MemoryRegion {
+        MemoryRegion::new(PhysAddr::new(start), PhysAddr::new(end), kind)
+    }
+
+    #[test]
+    fn map_with_largest_pages() {
+        let mut buffer = [MemoryRegion::default(); 8];
+        let mut memory_map = MemoryMap::new(&mut buffer);
+        for &region in &[
+            region(0x8000_0000, 0x8020_1000, MemoryRegionKind::Usable),
+            region(0x1000, 0x9_f000, MemoryRegionKind::Usable),
+            region(0x9_f000, 0x10_0000, MemoryRegionKind::Reserved),
+            region(0x4000_0000, 0x8000_0000, MemoryRegionKind::AcpiReclaimable),
+            region(0x10_0000, 0x4000_0000, MemoryRegionKind::Usable),
+        ] {
+            memory_map.push(region).unwrap();
+        }
+
+        for &giant_pages in &[true, false] {
+            let mut frames = SimulatedPhysMemory::buffer(8);
+            let start = PhysFrame::containing_address(PhysAddr::new(0x100_0000_0000));
+            let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+            let mut mapper = unsafe { memory.mapper(root_frame) };
+
+            let mut direct_map = DirectMap::new(VirtAddr::new(0xffff_8000_0000_0000));
+            direct_map.giant_pages = giant_pages;
+            unsafe { direct_map.map_memory_map(&memory_map, &mut mapper, &mut memory) }.unwrap();
+            // the level 4, 3 and 2 tables and two level 1 tables, plus a level 2 table for the
+            // second GiB without giant pages
+            assert_eq!(memory.allocated_frames(), if giant_pages { 6 } else { 7 });
+
+            let translate = |phys_addr| mapper.translate(direct_map.offset + phys_addr);
+            let expect_size = |phys_addr, size| match translate(phys_addr) {
+                TranslateResult::Frame4KiB { frame, flags, .. } => {
+                    assert_eq!(frame.start_address().as_u64(), phys_addr);
+                    assert_eq!(flags, direct_map.flags);
+                    assert_eq!(size, Size4KiB::SIZE);
+                }
+                TranslateResult::Frame2MiB { frame, .. } => {
+                    assert_eq!(frame.start_address().as_u64(), phys_addr);
+                    assert_eq!(size, Size2MiB::SIZE);
+                }
+                TranslateResult::Frame1GiB { frame, .. } => {
+                    assert_eq!(frame.start_address().as_u64(), phys_addr);
+                    assert_eq!(size, Size1GiB::SIZE);
+                }
+                other => panic!("unexpected translation {:?}", other),
+            };
+            expect_size(0x1000, Size4KiB::SIZE);
+            expect_size(0x10_0000, Size4KiB::SIZE);
+            expect_size(0x20_0000, Size2MiB::SIZE);
+            expect_size(0x3fe0_0000, Size2MiB::SIZE);
+            let gib_size = if giant_pages {
+                Size1GiB::SIZE
+            } else {
+                Size2MiB::SIZE
+            };
+            expect_size(0x4000_0000, gib_size);
+            expect_size(0x8000_0000, Size2MiB::SIZE);
+            expect_size(0x8020_0000, Size4KiB::SIZE);
+            for &unmapped in &[0, 0x9_f000, 0xf_f000, 0x8020_1000] {
+                match translate(unmapped) {
+                    TranslateResult::PageNotMapped { .. } => {}
+                    other => panic!("unexpected translation {:?}", other),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn map_with_5_level_paging() {
+        let mut frames = SimulatedPhysMemory::buffer(8);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x100_0000_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let root_table = unsafe { &mut *memory.phys_to_virt().phys_to_virt(root_frame) };
+        let mut mapper = unsafe {
+            MappedPageTable::with_paging_mode(root_table, memory.phys_to_virt(), PagingMode::Level5)
+        };
+
+        let mut direct_map = DirectMap::new(VirtAddr::new_la57(0xff11_0000_0000_0000));
+        direct_map.paging_mode = PagingMode::Level5;
+        assert_eq!(
+            direct_map.virt_addr(PhysAddr::new(0x1000)).as_u64(),
+            0xff11_0000_0000_1000
+        );
+        let (start, end) = (PhysAddr::new(0x1000), PhysAddr::new(0x40_0000));
+        unsafe { direct_map.map_range(start, end, &mut mapper, &mut memory) }.unwrap();
+        match mapper.translate(VirtAddr::new_la57(0xff11_0000_0020_1234)) {
+            TranslateResult::Frame2MiB { frame, offset, .. } => {
+                assert_eq!(frame.start_address(), PhysAddr::new(0x20_0000));
+                assert_eq!(offset, 0x1234);
+            }
+            other => panic!("unexpected translation {:?}", other),
+        }
+    }
+}
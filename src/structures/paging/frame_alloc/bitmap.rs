@@ -0,0 +1,234 @@
+//! A frame allocator that tracks the state of each frame in a bitmap.
+
+use super::{bitmap_words, FrameAllocator, FrameDeallocator};
+use crate::structures::paging::{
+    frame::{PhysFrame, PhysFrameRange},
+    page::{PageSize, Size4KiB},
+};
+use crate::PhysAddr;
+use core::fmt;
+
+/// A frame allocator that stores one bit per 4KiB frame.
+///
+/// The bitmap covers the physical memory from address 0 up to `bitmap.len() * 64` frames. A set
+/// bit means that the frame is free. The allocator hands out frames of all three sizes: 2MiB
+/// and 1GiB frames are allocated by searching for a naturally aligned block of free 4KiB frames.
+/// This makes the allocation of huge frames slower than with a `BuddyFrameAllocator`, but the
+/// allocator needs only half as much storage.
+///
+/// The bitmap is provided by the caller, so the allocator needs no heap. Its size can be
+/// calculated through `bitmap_len`.
+pub struct BitmapFrameAllocator<'a> {
+    bitmap: &'a mut [u64],
+    free_frames: u64,
+    /// The index of the first word of the bitmap that might contain a free frame.
+    first_free_word: usize,
+}
+
+impl<'a> BitmapFrameAllocator<'a> {
+    /// Returns the number of `u64` words that a bitmap for all physical memory below `end`
+    /// requires.
+    pub fn bitmap_len(end: PhysAddr) -> usize {
+        let frames = end.align_up(Size4KiB::SIZE).as_u64() / Size4KiB::SIZE;
+        bitmap_words(frames)
+    }
+
+    /// Creates a new allocator that hands out the frames of the given usable regions.
+    ///
+    /// All other frames are considered used. Parts of the regions that are not covered by the
+    /// bitmap are ignored. The regions must not overlap.
+    ///
+    /// ## Safety
+    ///
+    /// The caller must guarantee that the frames of `usable_regions` are really unused, e.g.
+    /// because they are marked as usable in a valid memory map and don't contain the kernel or
+    /// the boot information. Functions such as `Mapper::map_to` rely on the frame allocator to
+    /// only yield unused frames.
+    pub unsafe fn new<I>(bitmap: &'a mut [u64], usable_regions: I) -> Self
+    where
+        I: IntoIterator<Item = PhysFrameRange>,
+    {
+        for word in bitmap.iter_mut() {
+            *word = 0;
+        }
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            free_frames: 0,
+            first_free_word: 0,
+        };
+        for region in usable_regions {
+            let start = frame_number(region.start);
+            let end = frame_number(region.end).min(allocator.frame_count());
+            for frame in start..end {
+                allocator.bitmap[(frame / 64) as usize] |= 1 << (frame % 64);
+                allocator.free_frames += 1;
+            }
+        }
+        allocator
+    }
+
+    /// Returns the end of the physical memory that is covered by the bitmap.
+    pub fn end(&self) -> PhysAddr {
+        PhysAddr::new(self.frame_count() * Size4KiB::SIZE)
+    }
+
+    /// Returns the number of free 4KiB frames.
+    pub fn free_frames(&self) -> u64 {
+        self.free_frames
+    }
+
+    fn frame_count(&self) -> u64 {
+        self.bitmap.len() as u64 * 64
+    }
+
+    /// Allocates `count` contiguous frames that are aligned to `count` and returns the number
+    /// of the first frame. `count` must be 1 or a multiple of 64.
+    fn allocate(&mut self, count: u64) -> Option<u64> {
+        let frame = if count == 1 {
+            let index =
+                (self.first_free_word..self.bitmap.len()).find(|&index| self.bitmap[index] != 0)?;
+            self.first_free_word = index;
+            let bit = self.bitmap[index].trailing_zeros();
+            self.bitmap[index] &= !(1 << bit);
+            index as u64 * 64 + u64::from(bit)
+        } else {
+            let words = (count / 64) as usize;
+            let first = self.first_free_word / words * words;
+            let index = (first..self.bitmap.len()).step_by(words).find(|&index| {
+                self.bitmap
+                    .get(index..index + words)
+                    .map_or(false, |block| block.iter().all(|&word| word == !0))
+            })?;
+            for word in &mut self.bitmap[index..index + words] {
+                *word = 0;
+            }
+            index as u64 * 64
+        };
+        self.free_frames -= count;
+        Some(frame)
+    }
+
+    /// Returns `count` frames starting at frame number `frame` to the allocator.
+    ///
+    /// Panics if the frames are not covered by the bitmap or if one of them is already free.
+    fn deallocate(&mut self, frame: u64, count: u64) {
+        assert!(
+            frame + count <= self.frame_count(),
+            "frame is not covered by the bitmap"
+        );
+        let index = (frame / 64) as usize;
+        if count == 1 {
+            let bit = 1 << (frame % 64);
+            assert!(self.bitmap[index] & bit == 0, "frame is already free");
+            self.bitmap[index] |= bit;
+        } else {
+            let block = &mut self.bitmap[index..index + (count / 64) as usize];
+            assert!(block.iter().all(|&word| word == 0), "frame is already free");
+            for word in block {
+                *word = !0;
+            }
+        }
+        self.free_frames += count;
+        self.first_free_word = self.first_free_word.min(index);
+    }
+}
+
+impl<'a, S: PageSize> FrameAllocator<S> for BitmapFrameAllocator<'a> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        let frame = self.allocate(S::SIZE / Size4KiB::SIZE)?;
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            frame * Size4KiB::SIZE,
+        )))
+    }
+}
+
+impl<'a, S: PageSize> FrameDeallocator<S> for BitmapFrameAllocator<'a> {
+    fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        let start = frame.start_address().as_u64() / Size4KiB::SIZE;
+        self.deallocate(start, S::SIZE / Size4KiB::SIZE)
+    }
+}
+
+impl<'a> fmt::Debug for BitmapFrameAllocator<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut f = f.debug_struct("BitmapFrameAllocator");
+        f.field("end", &self.end());
+        f.field("free_frames", &self.free_frames);
+        f.finish()
+    }
+}
+
+/// Returns the number of the given frame, i.e. its start address divided by 4096.
+fn frame_number(frame: PhysFrame) -> u64 {
+    frame.start_address().as_u64() / Size4KiB::SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::paging::{Size1GiB, Size2MiB};
+
+    fn frame(addr: u64) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(addr))
+    }
+
+    #[test]
+    fn allocate_from_regions() {
+        let mut bitmap = [0; 2];
+        let regions = [
+            PhysFrame::range(frame(0x1000), frame(0x3000)),
+            PhysFrame::range(frame(0x7f000), frame(0x100000)),
+        ];
+        let mut allocator =
+            unsafe { BitmapFrameAllocator::new(&mut bitmap, regions.iter().cloned()) };
+        assert_eq!(allocator.end(), PhysAddr::new(0x80000));
+        assert_eq!(allocator.free_frames(), 3);
+
+        let a: PhysFrame = allocator.allocate_frame().unwrap();
+        let b: PhysFrame = allocator.allocate_frame().unwrap();
+        let c: PhysFrame = allocator.allocate_frame().unwrap();
+        assert_eq!((a, b, c), (frame(0x1000), frame(0x2000), frame(0x7f000)));
+        assert_eq!(
+            FrameAllocator::<Size4KiB>::allocate_frame(&mut allocator),
+            None
+        );
+
+        allocator.deallocate_frame(b);
+        assert_eq!(allocator.allocate_frame(), Some(b));
+    }
+
+    #[test]
+    fn allocate_huge_frames() {
+        let mut bitmap = [0; 1024];
+        let regions = [PhysFrame::range(frame(0x1000), frame(0x60_0000))];
+        let mut allocator =
+            unsafe { BitmapFrameAllocator::new(&mut bitmap, regions.iter().cloned()) };
+
+        let huge: PhysFrame<Size2MiB> = allocator.allocate_frame().unwrap();
+        assert_eq!(huge.start_address(), PhysAddr::new(0x20_0000));
+        let huge: PhysFrame<Size2MiB> = allocator.allocate_frame().unwrap();
+        assert_eq!(huge.start_address(), PhysAddr::new(0x40_0000));
+        assert_eq!(
+            FrameAllocator::<Size2MiB>::allocate_frame(&mut allocator),
+            None
+        );
+        assert_eq!(
+            FrameAllocator::<Size1GiB>::allocate_frame(&mut allocator),
+            None
+        );
+        assert_eq!(allocator.free_frames(), 511);
+
+        allocator.deallocate_frame(huge);
+        assert_eq!(allocator.free_frames(), 1023);
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free() {
+        let mut bitmap = [0; 1];
+        let regions = [PhysFrame::range(frame(0x1000), frame(0x2000))];
+        let mut allocator =
+            unsafe { BitmapFrameAllocator::new(&mut bitmap, regions.iter().cloned()) };
+        allocator.deallocate_frame(frame(0x1000));
+    }
+}
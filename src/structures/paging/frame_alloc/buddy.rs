@@ -0,0 +1,323 @@
+//! A buddy frame allocator.
+
+use super::{bitmap_words, FrameAllocator, FrameDeallocator};
+use crate::structures::paging::{
+    frame::{PhysFrame, PhysFrameRange},
+    page::{PageSize, Size4KiB},
+};
+use crate::PhysAddr;
+use core::fmt;
+
+/// The order of 1GiB blocks, i.e. the largest block size that the allocator manages.
+const MAX_ORDER: usize = 18;
+
+/// A frame allocator that manages free memory in naturally aligned blocks of `2^order` 4KiB
+/// frames, from single frames (order 0) up to 1GiB (order 18).
+///
+/// A block is allocated by splitting the smallest free block that is large enough. When a
+/// block is freed, it is merged with its “buddy”, the other half of the next larger block, as
+/// long as the buddy is free too. This keeps large blocks available, so 2MiB and 1GiB frames
+/// can be allocated quickly.
+///
+/// The free blocks of each order are tracked in a bitmap. The bitmaps are stored in a slice
+/// that is provided by the caller, so the allocator needs no heap. The required length of the
+/// slice can be calculated through `storage_len`.
+pub struct BuddyFrameAllocator<'a> {
+    storage: &'a mut [u64],
+    frame_count: u64,
+    /// The index of the first word of the bitmap of each order in `storage`.
+    offsets: [usize; MAX_ORDER + 2],
+    /// The index of the first word of each bitmap that might contain a free block.
+    first_free_words: [usize; MAX_ORDER + 1],
+    free_blocks: [u64; MAX_ORDER + 1],
+    free_frames: u64,
+}
+
+impl<'a> BuddyFrameAllocator<'a> {
+    /// Returns the number of `u64` words that the storage for an allocator that manages all
+    /// physical memory below `end` requires.
+    pub fn storage_len(end: PhysAddr) -> usize {
+        layout(frame_count(end))[MAX_ORDER + 1]
+    }
+
+    /// Creates a new allocator for the physical memory below `end` that hands out the frames
+    /// of the given usable regions.
+    ///
+    /// All other frames are considered used. Parts of the regions above `end` are ignored. The
+    /// regions must not overlap.
+    ///
+    /// Panics if `storage` is smaller than `storage_len(end)`.
+    ///
+    /// ## Safety
+    ///
+    /// The caller must guarantee that the frames of `usable_regions` are really unused, e.g.
+    /// because they are marked as usable in a valid memory map and don't contain the kernel or
+    /// the boot information. Functions such as `Mapper::map_to` rely on the frame allocator to
+    /// only yield unused frames.
+    pub unsafe fn new<I>(storage: &'a mut [u64], end: PhysAddr, usable_regions: I) -> Self
+    where
+        I: IntoIterator<Item = PhysFrameRange>,
+    {
+        let frame_count = frame_count(end);
+        let offsets = layout(frame_count);
+        assert!(
+            storage.len() >= offsets[MAX_ORDER + 1],
+            "storage of the buddy allocator is too small"
+        );
+        for word in storage.iter_mut() {
+            *word = 0;
+        }
+
+        let mut first_free_words = [0; MAX_ORDER + 1];
+        first_free_words.copy_from_slice(&offsets[..=MAX_ORDER]);
+        let mut allocator = BuddyFrameAllocator {
+            storage,
+            frame_count,
+            offsets,
+            first_free_words,
+            free_blocks: [0; MAX_ORDER + 1],
+            free_frames: 0,
+        };
+        for region in usable_regions {
+            let mut start = frame_number(region.start);
+            let end = frame_number(region.end).min(frame_count);
+            // free the region in the largest naturally aligned blocks that fit
+            while start < end {
+                let order = (0..=MAX_ORDER)
+                    .rev()
+                    .find(|&order| start % (1 << order) == 0 && start + (1 << order) <= end)
+                    .unwrap_or(0);
+                allocator.free_block(start, order);
+                start += 1 << order;
+            }
+        }
+        allocator
+    }
+
+    /// Returns the end of the physical memory that is managed by the allocator.
+    pub fn end(&self) -> PhysAddr {
+        PhysAddr::new(self.frame_count * Size4KiB::SIZE)
+    }
+
+    /// Returns the number of free 4KiB frames.
+    pub fn free_frames(&self) -> u64 {
+        self.free_frames
+    }
+
+    /// Returns the number of free blocks of `2^order` frames.
+    ///
+    /// Panics if `order` is larger than 18.
+    pub fn free_blocks(&self, order: usize) -> u64 {
+        self.free_blocks[order]
+    }
+
+    /// Returns the position of the bit for the given block in `storage`.
+    fn bit(&self, frame: u64, order: usize) -> (usize, u64) {
+        let block = frame >> order;
+        (
+            self.offsets[order] + (block / 64) as usize,
+            1 << (block % 64),
+        )
+    }
+
+    fn is_free(&self, frame: u64, order: usize) -> bool {
+        let (index, bit) = self.bit(frame, order);
+        index < self.offsets[order + 1] && self.storage[index] & bit != 0
+    }
+
+    fn mark_free(&mut self, frame: u64, order: usize) {
+        let (index, bit) = self.bit(frame, order);
+        self.storage[index] |= bit;
+        self.free_blocks[order] += 1;
+        self.first_free_words[order] = self.first_free_words[order].min(index);
+    }
+
+    fn mark_used(&mut self, frame: u64, order: usize) {
+        let (index, bit) = self.bit(frame, order);
+        self.storage[index] &= !bit;
+        self.free_blocks[order] -= 1;
+    }
+
+    /// Allocates a block of `2^order` frames and returns the number of its first frame.
+    fn allocate(&mut self, order: usize) -> Option<u64> {
+        let mut block_order = (order..=MAX_ORDER).find(|&order| self.free_blocks[order] > 0)?;
+        let index = (self.first_free_words[block_order]..self.offsets[block_order + 1])
+            .find(|&index| self.storage[index] != 0)
+            .expect("free block count does not match the bitmap");
+        self.first_free_words[block_order] = index;
+        let block = (index - self.offsets[block_order]) as u64 * 64
+            + u64::from(self.storage[index].trailing_zeros());
+        let frame = block << block_order;
+        self.mark_used(frame, block_order);
+
+        // split the block until it has the requested size, freeing the upper halves
+        while block_order > order {
+            block_order -= 1;
+            self.mark_free(frame + (1 << block_order), block_order);
+        }
+        self.free_frames -= 1 << order;
+        Some(frame)
+    }
+
+    /// Frees the block of `2^order` frames starting at frame number `frame` and merges it with
+    /// its buddies.
+    fn free_block(&mut self, mut frame: u64, mut order: usize) {
+        self.free_frames += 1 << order;
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if !self.is_free(buddy, order) {
+                break;
+            }
+            self.mark_used(buddy, order);
+            frame &= !(1 << order);
+            order += 1;
+        }
+        self.mark_free(frame, order);
+    }
+
+    /// Returns the block of `2^order` frames starting at frame number `frame` to the allocator.
+    ///
+    /// Panics if the block is not managed by the allocator or if it is already free.
+    fn deallocate(&mut self, frame: u64, order: usize) {
+        assert!(
+            frame + (1 << order) <= self.frame_count,
+            "frame is not managed by the allocator"
+        );
+        assert!(
+            (order..=MAX_ORDER).all(|order| !self.is_free(frame & !((1 << order) - 1), order)),
+            "frame is already free"
+        );
+        self.free_block(frame, order);
+    }
+}
+
+impl<'a, S: PageSize> FrameAllocator<S> for BuddyFrameAllocator<'a> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        let frame = self.allocate(order::<S>())?;
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            frame * Size4KiB::SIZE,
+        )))
+    }
+}
+
+impl<'a, S: PageSize> FrameDeallocator<S> for BuddyFrameAllocator<'a> {
+    fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        let start = frame.start_address().as_u64() / Size4KiB::SIZE;
+        self.deallocate(start, order::<S>())
+    }
+}
+
+impl<'a> fmt::Debug for BuddyFrameAllocator<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut f = f.debug_struct("BuddyFrameAllocator");
+        f.field("end", &self.end());
+        f.field("free_frames", &self.free_frames);
+        f.field("free_blocks", &self.free_blocks);
+        f.finish()
+    }
+}
+
+/// Returns the order of the blocks that correspond to frames of size `S`.
+fn order<S: PageSize>() -> usize {
+    (S::SIZE / Size4KiB::SIZE).trailing_zeros() as usize
+}
+
+/// Returns the number of 4KiB frames below `end`.
+fn frame_count(end: PhysAddr) -> u64 {
+    end.align_up(Size4KiB::SIZE).as_u64() / Size4KiB::SIZE
+}
+
+/// Returns the number of the given frame, i.e. its start address divided by 4096.
+fn frame_number(frame: PhysFrame) -> u64 {
+    frame.start_address().as_u64() / Size4KiB::SIZE
+}
+
+/// Returns the index of the first word of the bitmap of each order for the given number of
+/// frames. The last element is the total number of words.
+fn layout(frame_count: u64) -> [usize; MAX_ORDER + 2] {
+    let mut offsets = [0; MAX_ORDER + 2];
+    for order in 0..=MAX_ORDER {
+        let blocks = frame_count >> order;
+        offsets[order + 1] = offsets[order] + bitmap_words(blocks);
+    }
+    offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::paging::{Size1GiB, Size2MiB};
+
+    fn frame(addr: u64) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(addr))
+    }
+
+    #[test]
+    fn split_and_merge() {
+        let end = PhysAddr::new(0x80_0000);
+        let mut storage = vec![0; BuddyFrameAllocator::storage_len(end)];
+        let regions = [PhysFrame::range(frame(0x1000), frame(0x60_0000))];
+        let mut allocator =
+            unsafe { BuddyFrameAllocator::new(&mut storage, end, regions.iter().cloned()) };
+        assert_eq!(allocator.free_frames(), 0x5ff);
+        assert_eq!(allocator.free_blocks(9), 2);
+
+        let small: PhysFrame = allocator.allocate_frame().unwrap();
+        assert_eq!(small, frame(0x1000));
+        let huge: PhysFrame<Size2MiB> = allocator.allocate_frame().unwrap();
+        assert_eq!(huge.start_address(), PhysAddr::new(0x20_0000));
+        let huge2: PhysFrame<Size2MiB> = allocator.allocate_frame().unwrap();
+        assert_eq!(huge2.start_address(), PhysAddr::new(0x40_0000));
+        assert_eq!(
+            FrameAllocator::<Size2MiB>::allocate_frame(&mut allocator),
+            None
+        );
+        assert_eq!(
+            FrameAllocator::<Size1GiB>::allocate_frame(&mut allocator),
+            None
+        );
+
+        // the second frame is split from a block of two frames, its buddy stays free
+        let small2: PhysFrame = allocator.allocate_frame().unwrap();
+        assert_eq!(small2, frame(0x2000));
+        assert_eq!(allocator.free_blocks(0), 1);
+        allocator.deallocate_frame(small2);
+        assert_eq!(allocator.free_blocks(0), 0);
+        assert_eq!(allocator.free_blocks(1), 1);
+
+        allocator.deallocate_frame(huge);
+        allocator.deallocate_frame(huge2);
+        allocator.deallocate_frame(small);
+        assert_eq!(allocator.free_frames(), 0x5ff);
+        assert_eq!(allocator.free_blocks(9), 2);
+    }
+
+    #[test]
+    fn allocate_giant_frame() {
+        let end = PhysAddr::new(0x8000_0000);
+        let mut storage = vec![0; BuddyFrameAllocator::storage_len(end)];
+        let regions = [PhysFrame::range(frame(0x10_0000), frame(0x8000_0000))];
+        let mut allocator =
+            unsafe { BuddyFrameAllocator::new(&mut storage, end, regions.iter().cloned()) };
+
+        let giant: PhysFrame<Size1GiB> = allocator.allocate_frame().unwrap();
+        assert_eq!(giant.start_address(), PhysAddr::new(0x4000_0000));
+        assert_eq!(
+            FrameAllocator::<Size1GiB>::allocate_frame(&mut allocator),
+            None
+        );
+        let small: PhysFrame = allocator.allocate_frame().unwrap();
+        assert_eq!(small, frame(0x10_0000));
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free() {
+        let end = PhysAddr::new(0x10_0000);
+        let mut storage = [0; 16];
+        let regions = [PhysFrame::range(frame(0), frame(0x10_0000))];
+        let mut allocator =
+            unsafe { BuddyFrameAllocator::new(&mut storage, end, regions.iter().cloned()) };
+        allocator.deallocate_frame(frame(0x1000));
+    }
+}
@@ -1,7 +1,13 @@
 //! Traits for abstracting away frame allocation and deallocation.
 
+pub use self::bitmap::BitmapFrameAllocator;
+pub use self::buddy::BuddyFrameAllocator;
+
 use crate::structures::paging::{PageSize, PhysFrame};
 
+mod bitmap;
+mod buddy;
+
 /// A trait for types that can allocate a frame of memory.
 pub trait FrameAllocator<S: PageSize> {
     /// Allocate a frame of the appropriate size and return it if possible.
@@ -13,3 +19,8 @@ pub trait FrameDeallocator<S: PageSize> {
     /// Deallocate the given frame of memory.
     fn deallocate_frame(&mut self, frame: PhysFrame<S>);
 }
+
+/// Returns the number of `u64` words that a bitmap with the given number of bits requires.
+pub(crate) fn bitmap_words(bits: u64) -> usize {
+    (bits / 64 + if bits % 64 == 0 { 0 } else { 1 }) as usize
+}
@@ -0,0 +1,557 @@
+//! Address spaces with a shared kernel half and copy-on-write forking.
+
+use super::hierarchy::table_index;
+use super::{MappedPageTable, MapperFlush, PhysToVirt};
+use crate::structures::paging::{
+    frame::PhysFrame,
+    frame_alloc::{FrameAllocator, FrameDeallocator},
+    page::{Page, Size4KiB},
+    page_table::{PageTable, PageTableFlags, PageTableLevel, PagingMode},
+    PageSize,
+};
+use crate::VirtAddr;
+use core::ptr;
+
+/// The flag that marks a page as copy-on-write.
+///
+/// `AddressSpace::fork` sets this flag on all writable 4KiB pages of the user half and makes them
+/// read-only. It is one of the bits that are available to the OS, so it is ignored by the CPU.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// The index of the first root table entry of the kernel half of an address space.
+const KERNEL_HALF_START: usize = 256;
+
+/// A page table hierarchy that owns its root table frame.
+///
+/// The root table is split into two halves. The lower half (entries 0 to 255) contains the
+/// mappings of the user half of the address space, which are private to each address space. The
+/// upper half (entries 256 to 511) contains the kernel mappings, which are normally shared by
+/// all address spaces. Since only the root table entries are copied for sharing, changes to the
+/// kernel half below the root table are visible in all address spaces.
+///
+/// The page tables are accessed through the `PhysToVirt` implementation `P`, which is also
+/// used for accessing the frames of copy-on-write pages. The mappings of the address space can
+/// be changed through the `MappedPageTable` returned by `mapper`.
+#[derive(Debug)]
+pub struct AddressSpace<P>
+where
+    P: PhysToVirt + Clone,
+{
+    root_frame: PhysFrame,
+    phys_to_virt: P,
+    paging_mode: PagingMode,
+}
+
+impl<P> AddressSpace<P>
+where
+    P: PhysToVirt + Clone,
+{
+    /// Creates a new address space with an empty root table.
+    ///
+    /// The frame for the root table is allocated from the given `frame_allocator`.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed `phys_to_virt`
+    /// implementation is correct and that it can be used to access all frames that are used for
+    /// page tables or copy-on-write pages.
+    pub unsafe fn new<A>(
+        phys_to_virt: P,
+        paging_mode: PagingMode,
+        frame_allocator: &mut A,
+    ) -> Result<Self, AddressSpaceError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let root_frame = frame_allocator
+            .allocate_frame()
+            .ok_or(AddressSpaceError::FrameAllocationFailed)?;
+        (*phys_to_virt.phys_to_virt(root_frame)).zero();
+        Ok(Self::from_root_frame(root_frame, phys_to_virt, paging_mode))
+    }
+
+    /// Creates an address space for an existing root table, e.g. the currently active one.
+    ///
+    /// This function is unsafe because the caller must guarantee that `root_frame` contains the
+    /// root table of a valid page table hierarchy with the given paging mode and that the
+    /// passed `phys_to_virt` implementation is correct. The address space takes ownership of
+    /// the root table, so no other references to it must exist.
+    pub unsafe fn from_root_frame(
+        root_frame: PhysFrame,
+        phys_to_virt: P,
+        paging_mode: PagingMode,
+    ) -> Self {
+        AddressSpace {
+            root_frame,
+            phys_to_virt,
+            paging_mode,
+        }
+    }
+
+    /// Creates a new address space with an empty user half that shares the kernel half of
+    /// this address space.
+    pub fn new_sharing_kernel<A>(&self, frame_allocator: &mut A) -> Result<Self, AddressSpaceError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let mut address_space =
+            unsafe { Self::new(self.phys_to_virt.clone(), self.paging_mode, frame_allocator)? };
+        address_space.share_kernel_half(self);
+        Ok(address_space)
+    }
+
+    /// Returns the frame of the root table.
+    pub fn root_frame(&self) -> PhysFrame {
+        self.root_frame
+    }
+
+    /// Returns the paging mode of the page table hierarchy.
+    pub fn paging_mode(&self) -> PagingMode {
+        self.paging_mode
+    }
+
+    /// Returns a mapper for modifying the mappings of this address space.
+    pub fn mapper(&mut self) -> MappedPageTable<'_, P> {
+        unsafe {
+            MappedPageTable::with_paging_mode(
+                &mut *self.table_ptr(self.root_frame),
+                self.phys_to_virt.clone(),
+                self.paging_mode,
+            )
+        }
+    }
+
+    /// Replaces the kernel half of this address space with the kernel half of `other`.
+    ///
+    /// Only the root table entries are copied, so both address spaces share the page tables
+    /// of the kernel half afterwards.
+    ///
+    /// Panics if the address spaces have different paging modes.
+    pub fn share_kernel_half(&mut self, other: &Self) {
+        assert_eq!(self.paging_mode, other.paging_mode);
+        let root_table = unsafe { &mut *self.table_ptr(self.root_frame) };
+        let other_root_table = unsafe { &*other.table_ptr(other.root_frame) };
+        for index in KERNEL_HALF_START..512 {
+            root_table[index] = other_root_table[index].clone();
+        }
+    }
+
+    /// Loads the root table of this address space into the CR3 register.
+    ///
+    /// This function is unsafe because the caller must guarantee that the kernel half of this
+    /// address space maps the currently executing code and all data that is in use.
+    #[cfg(target_arch = "x86_64")]
+    pub unsafe fn activate(&self) {
+        use crate::registers::control::{Cr3, Cr3Flags};
+
+        Cr3::write(self.root_frame, Cr3Flags::empty());
+    }
+
+    /// Creates a copy of this address space that shares the frames of the user half through
+    /// copy-on-write.
+    ///
+    /// The page tables of the user half are copied, while the kernel half is shared. All
+    /// writable 4KiB pages of the user half are made read-only and marked with the
+    /// `COPY_ON_WRITE` flag in both address spaces, so that the first write to such a page
+    /// causes a page fault that can be resolved with `handle_cow_fault`. Read-only huge pages
+    /// are shared with their flags unchanged. Writable huge pages can't be copied on write, so
+    /// `AddressSpaceError::WritableHugePage` is returned for them. They can be split through
+    /// `MappedPageTable::split_huge_page` before forking.
+    ///
+    /// The frames for the new page tables are allocated from the given `frame_allocator`. If an
+    /// allocation fails or a writable huge page is found, all page tables that were allocated
+    /// for the copy are deallocated again and this address space is left unchanged.
+    ///
+    /// This function does not flush the TLB. If this address space is active, the caller must
+    /// flush the complete TLB (e.g. through `tlb::flush_all`) afterwards, because pages were
+    /// made read-only.
+    ///
+    /// The caller is responsible for tracking how many address spaces share a frame.
+    pub fn fork<A>(&mut self, frame_allocator: &mut A) -> Result<Self, AddressSpaceError>
+    where
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+    {
+        let root_frame = frame_allocator
+            .allocate_frame()
+            .ok_or(AddressSpaceError::FrameAllocationFailed)?;
+        let root_table = unsafe { &mut *self.table_ptr(root_frame) };
+        root_table.zero();
+
+        let level = self.paging_mode.top_level();
+        let parent_root_table = unsafe { &mut *self.table_ptr(self.root_frame) };
+        for index in 0..KERNEL_HALF_START {
+            match self.fork_entry(parent_root_table, root_table, index, level, frame_allocator) {
+                Ok(()) => {}
+                Err(err) => {
+                    self.free_tables(root_table, level, frame_allocator);
+                    frame_allocator.deallocate_frame(root_frame);
+                    return Err(err);
+                }
+            }
+        }
+        // the pages are only marked after all page tables were copied, so that a failed
+        // allocation leaves this address space unchanged
+        for index in 0..KERNEL_HALF_START {
+            self.mark_copy_on_write(parent_root_table, root_table, index, level);
+        }
+        for index in KERNEL_HALF_START..512 {
+            root_table[index] = parent_root_table[index].clone();
+        }
+
+        Ok(AddressSpace {
+            root_frame,
+            phys_to_virt: self.phys_to_virt.clone(),
+            paging_mode: self.paging_mode,
+        })
+    }
+
+    /// Resolves a page fault that was caused by a write to a copy-on-write page.
+    ///
+    /// A new frame is allocated from the given `frame_allocator`, the content of the shared
+    /// frame is copied to it and the page that contains `addr` is remapped writable to the new
+    /// frame. The `COPY_ON_WRITE` flag of the page is cleared. The previously mapped frame is
+    /// returned, so that the caller can update its share count. If the caller knows that the
+    /// frame is no longer shared, it can make the page writable through `Mapper::update_flags`
+    /// instead of copying it.
+    ///
+    /// Only pages of size 4KiB are supported.
+    pub fn handle_cow_fault<A>(
+        &mut self,
+        addr: VirtAddr,
+        frame_allocator: &mut A,
+    ) -> Result<(PhysFrame, MapperFlush<Size4KiB>), CowFaultError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let mut table = unsafe { &mut *self.table_ptr(self.root_frame) };
+        let mut level = self.paging_mode.top_level();
+        while level != PageTableLevel::One {
+            let entry = &table[table_index(addr, level)];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                return Err(CowFaultError::PageNotMapped);
+            }
+            if flags.contains(PageTableFlags::HUGE_PAGE) {
+                return Err(CowFaultError::HugePage);
+            }
+            table = unsafe { &mut *self.table_ptr(PhysFrame::containing_address(entry.addr())) };
+            level = level.next_lower_level().unwrap();
+        }
+
+        let entry = &mut table[addr.p1_index()];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return Err(CowFaultError::PageNotMapped);
+        }
+        if !flags.contains(COPY_ON_WRITE) {
+            return Err(CowFaultError::NotCopyOnWrite);
+        }
+
+        let old_frame = PhysFrame::containing_address(entry.addr());
+        let new_frame = frame_allocator
+            .allocate_frame()
+            .ok_or(CowFaultError::FrameAllocationFailed)?;
+        unsafe {
+            ptr::copy_nonoverlapping(
+                self.table_ptr(old_frame) as *const u8,
+                self.table_ptr(new_frame) as *mut u8,
+                Size4KiB::SIZE as usize,
+            );
+        }
+        let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        entry.set_addr(new_frame.start_address(), flags);
+
+        let page = Page::containing_address(addr);
+        Ok((old_frame, MapperFlush::new(page)))
+    }
+
+    /// Deallocates the page tables of the user half and the root table of this address space.
+    ///
+    /// The frames that are mapped by the user half are not deallocated. They can be enumerated
+    /// through the `mappings` method of the `mapper` before calling this function.
+    ///
+    /// This function is unsafe because the caller must guarantee that this address space is not
+    /// active and that its page tables are not used anymore.
+    pub unsafe fn free<D>(self, frame_deallocator: &mut D)
+    where
+        D: FrameDeallocator<Size4KiB>,
+    {
+        let root_table = &mut *self.table_ptr(self.root_frame);
+        for index in KERNEL_HALF_START..512 {
+            root_table[index].set_unused();
+        }
+        self.free_tables(root_table, self.paging_mode.top_level(), frame_deallocator);
+        frame_deallocator.deallocate_frame(self.root_frame);
+    }
+
+    /// Helper function for implementing `fork`.
+    ///
+    /// Copies the entry `index` of the parent table `from` at `level` to the table `to`. Page
+    /// tables are copied, while entries that map a page are shared with unchanged flags.
+    /// Returns `AddressSpaceError::WritableHugePage` for entries that map a writable huge page.
+    fn fork_entry<A>(
+        &self,
+        from: &PageTable,
+        to: &mut PageTable,
+        index: usize,
+        level: PageTableLevel,
+        frame_allocator: &mut A,
+    ) -> Result<(), AddressSpaceError>
+    where
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+    {
+        let entry = &from[index];
+        let flags = entry.flags();
+        let next_level = match level.next_lower_level() {
+            Some(next_level)
+                if flags.contains(PageTableFlags::PRESENT)
+                    && !flags.contains(PageTableFlags::HUGE_PAGE) =>
+            {
+                next_level
+            }
+            Some(_) if flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE) => {
+                return Err(AddressSpaceError::WritableHugePage);
+            }
+            _ => {
+                to[index] = entry.clone();
+                return Ok(());
+            }
+        };
+
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(AddressSpaceError::FrameAllocationFailed)?;
+        let table = unsafe { &mut *self.table_ptr(frame) };
+        table.zero();
+        to[index].set_frame(frame, flags);
+
+        let parent_table = unsafe { &*self.table_ptr(PhysFrame::containing_address(entry.addr())) };
+        for next_index in 0..512 {
+            self.fork_entry(parent_table, table, next_index, next_level, frame_allocator)?;
+        }
+        Ok(())
+    }
+
+    /// Helper function for implementing `fork`.
+    ///
+    /// Makes the writable 4KiB pages below the entry `index` of the tables `from` and `to` at
+    /// `level` read-only and marks them as copy-on-write. The tables must have been created by
+    /// `fork_entry`.
+    fn mark_copy_on_write(
+        &self,
+        from: &mut PageTable,
+        to: &mut PageTable,
+        index: usize,
+        level: PageTableLevel,
+    ) {
+        let flags = from[index].flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return;
+        }
+        match level.next_lower_level() {
+            None => {
+                if flags.contains(PageTableFlags::WRITABLE) {
+                    from[index].set_flags((flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE);
+                    to[index] = from[index].clone();
+                }
+            }
+            // `fork_entry` only shares read-only huge pages
+            Some(_) if flags.contains(PageTableFlags::HUGE_PAGE) => {}
+            Some(next_level) => {
+                let from_table = unsafe {
+                    &mut *self.table_ptr(PhysFrame::containing_address(from[index].addr()))
+                };
+                let to_table = unsafe {
+                    &mut *self.table_ptr(PhysFrame::containing_address(to[index].addr()))
+                };
+                for next_index in 0..512 {
+                    self.mark_copy_on_write(from_table, to_table, next_index, next_level);
+                }
+            }
+        }
+    }
+
+    /// Deallocates all page tables below the given table at `level`, but not the table itself.
+    ///
+    /// Used for cleaning up after a failed `fork` and by `free`.
+    fn free_tables<D>(
+        &self,
+        table: &mut PageTable,
+        level: PageTableLevel,
+        frame_deallocator: &mut D,
+    ) where
+        D: FrameDeallocator<Size4KiB>,
+    {
+        let next_level = match level.next_lower_level() {
+            Some(next_level) => next_level,
+            None => return,
+        };
+        for entry in table.iter_mut() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                continue;
+            }
+            let frame = PhysFrame::containing_address(entry.addr());
+            let next_table = unsafe { &mut *self.table_ptr(frame) };
+            self.free_tables(next_table, next_level, frame_deallocator);
+            entry.set_unused();
+            frame_deallocator.deallocate_frame(frame);
+        }
+    }
+
+    /// Returns a pointer to the content of the given frame.
+    fn table_ptr(&self, frame: PhysFrame) -> *mut PageTable {
+        self.phys_to_virt.phys_to_virt(frame)
+    }
+}
+
+/// This error is returned from `AddressSpace::new` and `AddressSpace::fork`.
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    /// An additional frame was needed for a page table, but the frame allocator
+    /// returned `None`.
+    FrameAllocationFailed,
+    /// The user half contains a writable huge page, which can't be shared through
+    /// copy-on-write.
+    WritableHugePage,
+}
+
+/// This error is returned from `AddressSpace::handle_cow_fault`.
+#[derive(Debug)]
+pub enum CowFaultError {
+    /// The given address is not mapped.
+    PageNotMapped,
+    /// The page is mapped, but it is not marked as copy-on-write.
+    NotCopyOnWrite,
+    /// The page is part of a huge page, which is not supported.
+    HugePage,
+    /// A frame for the copy of the page was needed, but the frame allocator returned `None`.
+    FrameAllocationFailed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::paging::mapper::{
+        Mapper, MapperAllSizes, SimulatedPhysMemory, TranslateResult,
+    };
+    use crate::structures::paging::Size2MiB;
+    use crate::PhysAddr;
+
+    #[test]
+    fn failed_fork_leaves_parent_unchanged() {
+        // 7 frames for the parent and 4 for the first half of the copy
+        let mut frames = SimulatedPhysMemory::buffer(11);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let mut parent = unsafe {
+            AddressSpace::from_root_frame(root_frame, memory.phys_to_virt(), PagingMode::Level4)
+        };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let pages = [
+            Page::<Size4KiB>::containing_address(VirtAddr::new(0x1000)),
+            Page::<Size4KiB>::containing_address(VirtAddr::new(0x80_0000_1000)),
+        ];
+        for &page in &pages {
+            let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+            unsafe { parent.mapper().map_to(page, frame, flags, &mut memory) }
+                .unwrap()
+                .ignore();
+        }
+        assert_eq!(memory.allocated_frames(), 7);
+
+        match parent.fork(&mut memory) {
+            Err(AddressSpaceError::FrameAllocationFailed) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(memory.allocated_frames(), 7);
+        for &page in &pages {
+            match parent.mapper().translate(page.start_address()) {
+                TranslateResult::Frame4KiB {
+                    flags: page_flags, ..
+                } => assert_eq!(page_flags, flags),
+                other => panic!("unexpected translation {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn fork_with_huge_pages() {
+        let mut frames = SimulatedPhysMemory::buffer(8);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let mut parent = unsafe {
+            AddressSpace::from_root_frame(root_frame, memory.phys_to_virt(), PagingMode::Level4)
+        };
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(0x20_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe { parent.mapper().map_to(page, frame, flags, &mut memory) }
+            .unwrap()
+            .ignore();
+        let allocated_frames = memory.allocated_frames();
+        match parent.fork(&mut memory) {
+            Err(AddressSpaceError::WritableHugePage) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(memory.allocated_frames(), allocated_frames);
+
+        let flags = PageTableFlags::PRESENT;
+        parent.mapper().update_flags(page, flags).unwrap().ignore();
+        let child = parent.fork(&mut memory).unwrap();
+        for address_space in &mut [parent, child] {
+            match address_space.mapper().translate(page.start_address()) {
+                TranslateResult::Frame2MiB {
+                    frame: mapped,
+                    flags: page_flags,
+                    ..
+                } => {
+                    assert_eq!(mapped, frame);
+                    assert_eq!(page_flags, flags | PageTableFlags::HUGE_PAGE);
+                }
+                other => panic!("unexpected translation {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn fork_and_copy_on_write() {
+        let mut frames = SimulatedPhysMemory::buffer(16);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let mut memory = SimulatedPhysMemory::new(&mut frames, start);
+        let mut parent = unsafe {
+            AddressSpace::new(memory.phys_to_virt(), PagingMode::Level4, &mut memory).unwrap()
+        };
+
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x1000));
+        let frame = memory.allocate_frame().unwrap();
+        let content = unsafe { &mut *memory.phys_to_virt().phys_to_virt(frame) };
+        content[0].set_addr(PhysAddr::new(0x5000), PageTableFlags::empty());
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe { parent.mapper().map_to(page, frame, flags, &mut memory) }
+            .unwrap()
+            .ignore();
+
+        let mut child = parent.fork(&mut memory).unwrap();
+        match child.mapper().translate(page.start_address()) {
+            TranslateResult::Frame4KiB { flags, .. } => {
+                assert!(flags.contains(COPY_ON_WRITE));
+                assert!(!flags.contains(PageTableFlags::WRITABLE));
+            }
+            other => panic!("unexpected translation {:?}", other),
+        }
+
+        let (old_frame, flush) = child
+            .handle_cow_fault(page.start_address(), &mut memory)
+            .unwrap();
+        flush.ignore();
+        assert_eq!(old_frame, frame);
+        let new_frame = child.mapper().translate_page(page).unwrap();
+        assert_ne!(new_frame, frame);
+        let copied = unsafe { &*memory.phys_to_virt().phys_to_virt(new_frame) };
+        assert_eq!(copied[0].addr(), PhysAddr::new(0x5000));
+
+        let allocated = memory.allocated_frames();
+        unsafe { child.free(&mut memory) };
+        assert_eq!(memory.allocated_frames(), allocated - 4);
+    }
+}
@@ -0,0 +1,497 @@
+//! Checks of complete page table hierarchies against a security policy.
+
+use super::hierarchy::{self, EffectiveFlags, PageTableAccess};
+use super::MappedPage;
+use crate::registers::model_specific::{MemoryType, PageAttributeTable};
+use crate::structures::paging::{
+    frame::PhysFrame,
+    page::Page,
+    page_table::{PageTable, PageTableEntry, PageTableFlags, PageTableLevel, PagingMode},
+};
+use crate::{PhysAddr, VirtAddr};
+
+/// A violation of the page table policy, as reported by the `audit` methods of the mapper
+/// types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditFinding {
+    /// The page is writable and executable, taking the flags of all parent entries into account.
+    WritableExecutable(MappedPage),
+    /// The page lies in the upper half of the address space, which is reserved for the kernel,
+    /// but it is accessible from user mode.
+    UserAccessibleKernelPage(MappedPage),
+    /// A present entry has reserved bits set. Accesses through the entry cause a page fault
+    /// with the `MALFORMED_TABLE` error code.
+    ReservedBitsSet {
+        /// The first virtual address that is translated through the entry.
+        addr: VirtAddr,
+        /// The level of the table that contains the entry.
+        level: PageTableLevel,
+        /// The reserved bits that are set.
+        bits: u64,
+    },
+    /// A level 4 or level 5 entry has the `HUGE_PAGE` flag set.
+    ///
+    /// The bit is reserved on these levels, so such an entry causes a page fault with the
+    /// `MALFORMED_TABLE` error code. On level 1, the same bit is the PAT bit, so the memory type
+    /// that it selects is looked up in `AuditPolicy::pat` instead.
+    HugePageFlag {
+        /// The first virtual address that is translated through the entry.
+        addr: VirtAddr,
+        /// The level of the table that contains the entry.
+        level: PageTableLevel,
+    },
+    /// A physical address range is mapped at two virtual addresses with different memory
+    /// types, which can lead to inconsistent caching.
+    ConflictingMemoryTypes {
+        /// The start of the physical range that is mapped by both pages.
+        phys_addr: PhysAddr,
+        /// The start address of the first page.
+        first: VirtAddr,
+        /// The memory type of the first page.
+        first_type: MemoryType,
+        /// The start address of the second page.
+        second: VirtAddr,
+        /// The memory type of the second page.
+        second_type: MemoryType,
+    },
+}
+
+/// The processor configuration that an audit checks the page tables against.
+#[derive(Debug, Clone)]
+pub struct AuditPolicy {
+    /// The page attribute table that determines the memory types of the pages.
+    pub pat: PageAttributeTable,
+    /// The number of physical address bits that the processor supports. All higher address
+    /// bits of an entry are reserved.
+    pub phys_addr_bits: u8,
+    /// Whether `EferFlags::NO_EXECUTE_ENABLE` is set. Otherwise, the `NO_EXECUTE` flag is a
+    /// reserved bit and all pages are executable.
+    pub no_execute_enabled: bool,
+}
+
+impl AuditPolicy {
+    /// Creates a policy for the current processor configuration, i.e. for the content of the
+    /// `IA32_PAT` and `EFER` registers and the physical address width reported by `cpuid`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn current() -> Self {
+        use crate::registers::model_specific::{Efer, EferFlags, Pat};
+
+        let phys_addr_bits = raw_cpuid::CpuId::new()
+            .get_extended_function_info()
+            .and_then(|info| info.physical_address_bits())
+            .unwrap_or(52);
+        AuditPolicy {
+            pat: Pat::read(),
+            phys_addr_bits,
+            no_execute_enabled: Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE),
+        }
+    }
+}
+
+impl Default for AuditPolicy {
+    /// Returns a policy for the page attribute table after reset, the maximum physical address
+    /// width of 52 bits and enabled `NO_EXECUTE` support.
+    fn default() -> Self {
+        AuditPolicy {
+            pat: PageAttributeTable::DEFAULT,
+            phys_addr_bits: 52,
+            no_execute_enabled: true,
+        }
+    }
+}
+
+/// Checks the hierarchy below `root_table` against the given policy and passes each finding
+/// to `report`. Returns the number of findings.
+///
+/// The check for conflicting memory types compares each page that does not use the
+/// write-back memory type with all other pages, so its run time grows with the product of the
+/// number of such pages and the number of all pages.
+pub(super) unsafe fn audit<A, F>(
+    access: &A,
+    root_table: &PageTable,
+    paging_mode: PagingMode,
+    policy: &AuditPolicy,
+    mut report: F,
+) -> usize
+where
+    A: PageTableAccess,
+    F: FnMut(AuditFinding),
+{
+    let walk = Walk {
+        access,
+        root_table,
+        paging_mode,
+    };
+    let mut count = 0;
+    let mut emit = |finding| {
+        count += 1;
+        report(finding);
+    };
+
+    walk.run(&mut |entry| check_entry(entry, policy, &mut emit));
+
+    walk.run(&mut |first| {
+        if !first.is_leaf() {
+            return;
+        }
+        let first_type = first.memory_type(&policy.pat);
+        if first_type == MemoryType::WriteBack {
+            return;
+        }
+        let (first_start, first_end) = first.phys_range();
+        walk.run(&mut |second| {
+            if !second.is_leaf() || second.raw_start == first.raw_start {
+                return;
+            }
+            let second_type = second.memory_type(&policy.pat);
+            // report each pair of pages with two non-write-back types only once
+            if second_type == first_type
+                || (second_type != MemoryType::WriteBack && second.raw_start < first.raw_start)
+            {
+                return;
+            }
+            let (second_start, second_end) = second.phys_range();
+            if first_start < second_end && second_start < first_end {
+                emit(AuditFinding::ConflictingMemoryTypes {
+                    phys_addr: PhysAddr::new(first_start.max(second_start)),
+                    first: first.addr,
+                    first_type,
+                    second: second.addr,
+                    second_type,
+                });
+            }
+        });
+    });
+
+    count
+}
+
+/// Reports the violations of a single entry.
+fn check_entry<F>(entry: &Entry, policy: &AuditPolicy, report: &mut F)
+where
+    F: FnMut(AuditFinding),
+{
+    let flags = entry.entry.flags();
+    let huge = flags.contains(PageTableFlags::HUGE_PAGE);
+    match entry.level {
+        PageTableLevel::Four | PageTableLevel::Five if huge => {
+            report(AuditFinding::HugePageFlag {
+                addr: entry.addr,
+                level: entry.level,
+            });
+        }
+        _ => {}
+    }
+
+    let addr = entry.entry.addr().as_u64();
+    let mut bits = addr & !((1 << policy.phys_addr_bits) - 1);
+    if huge && (entry.level == PageTableLevel::Two || entry.level == PageTableLevel::Three) {
+        // the address bits below the page size are reserved, except for the PAT bit 12
+        bits |= addr & (entry.size() - 1) & !(1 << 12);
+    }
+    if flags.contains(PageTableFlags::NO_EXECUTE) && !policy.no_execute_enabled {
+        bits |= PageTableFlags::NO_EXECUTE.bits();
+    }
+    if bits != 0 {
+        report(AuditFinding::ReservedBitsSet {
+            addr: entry.addr,
+            level: entry.level,
+            bits,
+        });
+    }
+
+    if !entry.is_leaf() {
+        return;
+    }
+    let mut path_flags = entry.path_flags;
+    path_flags.add(flags);
+    let effective_flags = path_flags.of(flags);
+    let executable =
+        !policy.no_execute_enabled || !effective_flags.contains(PageTableFlags::NO_EXECUTE);
+    if effective_flags.contains(PageTableFlags::WRITABLE) && executable {
+        report(AuditFinding::WritableExecutable(entry.mapped_page()));
+    }
+    if effective_flags.contains(PageTableFlags::USER_ACCESSIBLE) && entry.addr.as_u64() >> 63 == 1 {
+        report(AuditFinding::UserAccessibleKernelPage(entry.mapped_page()));
+    }
+}
+
+/// A walk over all present entries of a hierarchy.
+struct Walk<'a, A> {
+    access: &'a A,
+    root_table: &'a PageTable,
+    paging_mode: PagingMode,
+}
+
+/// A present entry of a hierarchy, as visited by a `Walk`.
+struct Entry<'a> {
+    entry: &'a PageTableEntry,
+    level: PageTableLevel,
+    /// The raw virtual address of the first byte that is translated through the entry.
+    raw_start: u64,
+    addr: VirtAddr,
+    /// The restrictive flags of the parent entries, without the entry itself.
+    path_flags: EffectiveFlags,
+}
+
+impl<'a, A> Walk<'a, A>
+where
+    A: PageTableAccess,
+{
+    /// Calls `visit` for each present entry, in ascending order of their addresses. Parent
+    /// entries are visited before the entries of their tables.
+    ///
+    /// The walk does not descend into entries of the root table that map the page tables
+    /// themselves and into level 4 and level 5 entries that have the `HUGE_PAGE` flag set.
+    fn run(&self, visit: &mut dyn FnMut(&Entry)) {
+        unsafe {
+            self.walk_table(
+                self.root_table,
+                self.paging_mode.top_level(),
+                0,
+                EffectiveFlags::new(),
+                visit,
+            )
+        }
+    }
+
+    unsafe fn walk_table(
+        &self,
+        table: &PageTable,
+        level: PageTableLevel,
+        table_start: u64,
+        path_flags: EffectiveFlags,
+        visit: &mut dyn FnMut(&Entry),
+    ) {
+        for (index, entry) in table.iter().enumerate() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            if level == self.paging_mode.top_level() && self.access.is_table_mapping(index) {
+                continue;
+            }
+            let raw_start = table_start + index as u64 * level.entry_address_space_size();
+            let addr = self.paging_mode.virt_addr(raw_start);
+            visit(&Entry {
+                entry,
+                level,
+                raw_start,
+                addr,
+                path_flags,
+            });
+
+            let next_level = match level.next_lower_level() {
+                Some(next_level) => next_level,
+                None => continue,
+            };
+            if flags.contains(PageTableFlags::HUGE_PAGE) {
+                continue;
+            }
+            let mut next_path_flags = path_flags;
+            next_path_flags.add(flags);
+            let next_table = &*self.access.next_table_ptr(entry, addr, level);
+            self.walk_table(next_table, next_level, raw_start, next_path_flags, visit);
+        }
+    }
+}
+
+impl<'a> Entry<'a> {
+    /// Returns whether the entry maps a page instead of a page table.
+    fn is_leaf(&self) -> bool {
+        match self.level {
+            PageTableLevel::One => true,
+            PageTableLevel::Two | PageTableLevel::Three => {
+                self.entry.flags().contains(PageTableFlags::HUGE_PAGE)
+            }
+            PageTableLevel::Four | PageTableLevel::Five => false,
+        }
+    }
+
+    /// Returns the size of the address range that is translated through the entry.
+    fn size(&self) -> u64 {
+        self.level.entry_address_space_size()
+    }
+
+    /// Returns the start and the end of the physical range that is mapped by a leaf entry.
+    fn phys_range(&self) -> (u64, u64) {
+        let start = self.entry.addr().as_u64() & !(self.size() - 1);
+        (start, start + self.size())
+    }
+
+    fn memory_type(&self, pat: &PageAttributeTable) -> MemoryType {
+        pat.entry(hierarchy::pat_index(self.entry, self.level))
+    }
+
+    /// Returns the page that is mapped by a leaf entry.
+    fn mapped_page(&self) -> MappedPage {
+        let frame = PhysAddr::new(self.phys_range().0);
+        let flags = self.entry.flags();
+        match self.level {
+            PageTableLevel::One => MappedPage::Page4KiB {
+                page: Page::containing_address(self.addr),
+                frame: PhysFrame::containing_address(frame),
+                flags,
+            },
+            PageTableLevel::Two => MappedPage::Page2MiB {
+                page: Page::containing_address(self.addr),
+                frame: PhysFrame::containing_address(frame),
+                flags,
+            },
+            _ => MappedPage::Page1GiB {
+                page: Page::containing_address(self.addr),
+                frame: PhysFrame::containing_address(frame),
+                flags,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::paging::mapper::{Mapper, PhysToVirt, SimulatedPhysMemory};
+    use crate::structures::paging::Size4KiB;
+
+    #[test]
+    fn report_violations() {
+        let mut frames = SimulatedPhysMemory::buffer(16);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let mut mapper = unsafe { memory.mapper(root_frame) };
+
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        let invalid_frame = PhysFrame::containing_address(PhysAddr::new(1 << 40));
+        let page = |addr| Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+        let mappings = [
+            (0x1000, frame, PageTableFlags::WRITABLE),
+            (
+                0x2000,
+                frame,
+                PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            ),
+            (
+                0x3000,
+                frame,
+                PageTableFlags::NO_CACHE | PageTableFlags::NO_EXECUTE,
+            ),
+            (0x5000, invalid_frame, PageTableFlags::NO_EXECUTE),
+            (
+                0xffff_8000_0000_0000,
+                frame,
+                PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE,
+            ),
+        ];
+        for &(addr, frame, flags) in mappings.iter() {
+            let flags = flags | PageTableFlags::PRESENT;
+            unsafe { mapper.map_to(page(addr), frame, flags, &mut memory) }
+                .unwrap()
+                .ignore();
+        }
+        let root_table = unsafe { &mut *memory.phys_to_virt().phys_to_virt(root_frame) };
+        root_table[5].set_addr(
+            PhysAddr::new(0x4000_0000),
+            PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE,
+        );
+
+        let policy = AuditPolicy {
+            phys_addr_bits: 36,
+            ..AuditPolicy::default()
+        };
+        let mut findings = Vec::new();
+        let count = mapper.audit(&policy, |finding| findings.push(finding));
+        assert_eq!(count, findings.len());
+
+        let flags = |index: usize| mappings[index].2 | PageTableFlags::PRESENT;
+        assert_eq!(
+            findings,
+            [
+                AuditFinding::WritableExecutable(MappedPage::Page4KiB {
+                    page: page(0x1000),
+                    frame,
+                    flags: flags(0),
+                }),
+                AuditFinding::ReservedBitsSet {
+                    addr: VirtAddr::new(0x5000),
+                    level: PageTableLevel::One,
+                    bits: 1 << 40,
+                },
+                AuditFinding::HugePageFlag {
+                    addr: VirtAddr::new(0x280_0000_0000),
+                    level: PageTableLevel::Four,
+                },
+                AuditFinding::UserAccessibleKernelPage(MappedPage::Page4KiB {
+                    page: page(0xffff_8000_0000_0000),
+                    frame,
+                    flags: flags(4),
+                }),
+                AuditFinding::ConflictingMemoryTypes {
+                    phys_addr: frame.start_address(),
+                    first: VirtAddr::new(0x3000),
+                    first_type: MemoryType::UncacheableMinus,
+                    second: VirtAddr::new(0x1000),
+                    second_type: MemoryType::WriteBack,
+                },
+                AuditFinding::ConflictingMemoryTypes {
+                    phys_addr: frame.start_address(),
+                    first: VirtAddr::new(0x3000),
+                    first_type: MemoryType::UncacheableMinus,
+                    second: VirtAddr::new(0x2000),
+                    second_type: MemoryType::WriteBack,
+                },
+                AuditFinding::ConflictingMemoryTypes {
+                    phys_addr: frame.start_address(),
+                    first: VirtAddr::new(0x3000),
+                    first_type: MemoryType::UncacheableMinus,
+                    second: VirtAddr::new(0xffff_8000_0000_0000),
+                    second_type: MemoryType::WriteBack,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn pat_bit_of_level_1_entries() {
+        let mut frames = SimulatedPhysMemory::buffer(8);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let mut mapper = unsafe { memory.mapper(root_frame) };
+
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        let page = |addr| Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        for &addr in &[0x1000, 0x2000] {
+            unsafe { mapper.map_to(page(addr), frame, flags, &mut memory) }
+                .unwrap()
+                .ignore();
+        }
+        // the PAT bit of a level 1 entry is the bit of the `HUGE_PAGE` flag
+        mapper
+            .update_flags_and_pat_index(page(0x2000), flags, 4)
+            .unwrap()
+            .ignore();
+
+        let mut pat = PageAttributeTable::DEFAULT;
+        pat.set_entry(4, MemoryType::WriteCombining);
+        let policy = AuditPolicy {
+            pat,
+            ..AuditPolicy::default()
+        };
+        let mut findings = Vec::new();
+        mapper.audit(&policy, |finding| findings.push(finding));
+        assert_eq!(
+            findings,
+            [AuditFinding::ConflictingMemoryTypes {
+                phys_addr: frame.start_address(),
+                first: VirtAddr::new(0x2000),
+                first_type: MemoryType::WriteCombining,
+                second: VirtAddr::new(0x1000),
+                second_type: MemoryType::WriteBack,
+            }]
+        );
+
+        let mut findings = Vec::new();
+        mapper.audit(&AuditPolicy::default(), |finding| findings.push(finding));
+        assert_eq!(findings, []);
+    }
+}
@@ -0,0 +1,243 @@
+//! Human-readable dumps of complete page table hierarchies.
+
+use super::MappedPage;
+use crate::structures::paging::page_table::{PageTableFlags, PageTableLevel, PagingMode};
+use core::fmt::{self, Write};
+
+/// A formatter that prints all mappings of a page table hierarchy, similar to Linux's `ptdump`.
+///
+/// Created by the `dump` methods of the mapper types. The `Display` implementation prints one
+/// line per range of pages. Consecutive pages are merged into a single range if they have the
+/// same size and identical flags apart from `ACCESSED` and `DIRTY` and if they are mapped to
+/// contiguous frames. Each line
+/// contains the virtual address range, its size, the access markers, the start address of the
+/// mapped physical range and the page size:
+///
+/// ```text
+/// 0xffff800000000000-0xffff800000400000     4M RW-UG -> 0x0000000000000000 2M
+/// ```
+///
+/// The markers are `R` for readable (always set for present pages), `W` for writable, `X` for
+/// executable, `U` for user accessible and `G` for global pages. Unset markers are printed as
+/// `-`. The dump ends with a summary of the number of page tables on each level.
+pub struct PageTableDump<I> {
+    mappings: I,
+    table_counts: [u64; 5],
+    paging_mode: PagingMode,
+}
+
+impl<I> PageTableDump<I>
+where
+    I: Iterator<Item = MappedPage> + Clone,
+{
+    /// Creates a new dump from an iterator over the mappings of a hierarchy and the number of
+    /// page tables on each level, indexed by `level - 1`.
+    pub(super) fn new(mappings: I, table_counts: [u64; 5], paging_mode: PagingMode) -> Self {
+        PageTableDump {
+            mappings,
+            table_counts,
+            paging_mode,
+        }
+    }
+
+    /// Returns the number of page tables on the given level, including the root table.
+    pub fn table_count(&self, level: PageTableLevel) -> u64 {
+        self.table_counts[level as usize - 1]
+    }
+}
+
+impl<I> fmt::Display for PageTableDump<I>
+where
+    I: Iterator<Item = MappedPage> + Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut current: Option<Range> = None;
+        for page in self.mappings.clone() {
+            let page = Range::from(page);
+            current = match current {
+                Some(mut range) => {
+                    if range.try_extend(&page) {
+                        Some(range)
+                    } else {
+                        writeln!(f, "{}", range)?;
+                        Some(page)
+                    }
+                }
+                None => Some(page),
+            };
+        }
+        if let Some(range) = current {
+            writeln!(f, "{}", range)?;
+        }
+
+        write!(f, "page tables:")?;
+        let top_level = self.paging_mode.top_level() as usize;
+        for level in (1..=top_level).rev() {
+            let separator = if level == top_level { "" } else { "," };
+            write!(
+                f,
+                "{} {} L{}",
+                separator,
+                self.table_counts[level - 1],
+                level
+            )?;
+        }
+        writeln!(f)
+    }
+}
+
+impl<I> fmt::Debug for PageTableDump<I> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut f = f.debug_struct("PageTableDump");
+        f.field("table_counts", &self.table_counts);
+        f.field("paging_mode", &self.paging_mode);
+        f.finish()
+    }
+}
+
+/// A range of pages that are mapped with identical flags to contiguous frames.
+struct Range {
+    start: u64,
+    /// The size of the range in bytes.
+    size: u64,
+    frame_start: u64,
+    flags: PageTableFlags,
+    page_size: u64,
+}
+
+impl Range {
+    /// Appends the given range to `self` if it directly follows `self` and has the same page
+    /// size and flags. Returns whether the range was appended.
+    fn try_extend(&mut self, next: &Range) -> bool {
+        let contiguous = self.start.checked_add(self.size) == Some(next.start)
+            && self.frame_start.checked_add(self.size) == Some(next.frame_start);
+        if contiguous && self.page_size == next.page_size && self.flags == next.flags {
+            self.size += next.size;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl From<MappedPage> for Range {
+    fn from(page: MappedPage) -> Self {
+        Range {
+            start: page.start_address().as_u64(),
+            size: page.size(),
+            frame_start: page.frame_start_address().as_u64(),
+            // the processor sets these flags on access, so they would split ranges arbitrarily
+            flags: page.flags() - (PageTableFlags::ACCESSED | PageTableFlags::DIRTY),
+            page_size: page.size(),
+        }
+    }
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let marker = |flag, c| if self.flags.contains(flag) { c } else { '-' };
+        let executable = if self.flags.contains(PageTableFlags::NO_EXECUTE) {
+            '-'
+        } else {
+            'X'
+        };
+        write!(
+            f,
+            "{:#018x}-{:#018x} {:>6} R{}{}{}{} -> {:#018x} {}",
+            self.start,
+            self.start.wrapping_add(self.size),
+            Size(self.size),
+            marker(PageTableFlags::WRITABLE, 'W'),
+            executable,
+            marker(PageTableFlags::USER_ACCESSIBLE, 'U'),
+            marker(PageTableFlags::GLOBAL, 'G'),
+            self.frame_start,
+            Size(self.page_size),
+        )
+    }
+}
+
+/// Formats a size in bytes with the largest binary unit that divides it evenly.
+struct Size(u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let units = [(40, 'T'), (30, 'G'), (20, 'M'), (10, 'K')];
+        let (value, unit) = units
+            .iter()
+            .find(|&&(shift, _)| self.0 >= 1 << shift && self.0 % (1 << shift) == 0)
+            .map(|&(shift, unit)| (self.0 >> shift, unit))
+            .unwrap_or((self.0, 'B'));
+
+        // pad manually because formatting to a temporary string would require an allocation
+        let mut len = 2;
+        let mut rest = value / 10;
+        while rest > 0 {
+            len += 1;
+            rest /= 10;
+        }
+        for _ in len..f.width().unwrap_or(0) {
+            f.write_char(' ')?;
+        }
+        write!(f, "{}{}", value, unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::paging::mapper::{Mapper, SimulatedPhysMemory};
+    use crate::structures::paging::{Page, PhysFrame, Size2MiB, Size4KiB};
+    use crate::{PhysAddr, VirtAddr};
+
+    #[test]
+    fn dump_format() {
+        let mut frames = SimulatedPhysMemory::buffer(8);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let mut mapper = unsafe { memory.mapper(root_frame) };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+        // the accessed and dirty flags don't prevent merging
+        for &(addr, extra_flags) in &[
+            (0x1000, PageTableFlags::empty()),
+            (0x2000, PageTableFlags::ACCESSED),
+            (0x3000, PageTableFlags::ACCESSED | PageTableFlags::DIRTY),
+            (0x5000, PageTableFlags::empty()),
+        ] {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+            let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000 + addr));
+            unsafe { mapper.map_to(page, frame, flags | extra_flags, &mut memory) }
+                .unwrap()
+                .ignore();
+        }
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(0xffff_8000_0000_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x4000_0000));
+        let global_flags = PageTableFlags::PRESENT | PageTableFlags::GLOBAL;
+        unsafe { mapper.map_to(page, frame, global_flags, &mut memory) }
+            .unwrap()
+            .ignore();
+
+        let dump = mapper.dump();
+        assert_eq!(dump.table_count(PageTableLevel::Two), 2);
+        assert_eq!(
+            format!("{}", dump),
+            "\
+0x0000000000001000-0x0000000000004000    12K RWX-- -> 0x0000000080001000 4K
+0x0000000000005000-0x0000000000006000     4K RWX-- -> 0x0000000080005000 4K
+0xffff800000000000-0xffff800000200000     2M R-X-G -> 0x0000000040000000 2M
+page tables: 1 L4, 2 L3, 2 L2, 1 L1
+"
+        );
+    }
+
+    #[test]
+    fn size_padding() {
+        assert_eq!(format!("{:>6}", Size(0x1000)), "    4K");
+        assert_eq!(format!("{:>6}", Size(0x1_0000_0000)), "    4G");
+        assert_eq!(format!("{:>6}", Size(0x3ff << 20)), " 1023M");
+        assert_eq!(format!("{:>6}", Size(0x40_0000_0000)), "  256G");
+        assert_eq!(format!("{:>3}", Size(1000)), "1000B");
+        assert_eq!(format!("{}", Size(0x2000)), "8K");
+    }
+}
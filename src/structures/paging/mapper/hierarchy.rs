@@ -0,0 +1,854 @@
+//! Generic operations on complete page table hierarchies, shared by the mapper types.
+
+use super::{
+    range_len, FlagUpdateError, MapToError, MappedPage, MergeError, SplitError, TranslateResult,
+};
+use crate::registers::model_specific::{MemoryType, PageAttributeTable};
+use crate::structures::paging::{
+    frame::PhysFrame,
+    frame_alloc::{bitmap_words, FrameAllocator, FrameDeallocator},
+    page::{Page, PageRange, PageSize},
+    page_table::{PageTable, PageTableEntry, PageTableFlags, PageTableLevel, PagingMode},
+    Size1GiB, Size2MiB, Size4KiB,
+};
+use crate::{PhysAddr, VirtAddr};
+use ux::u9;
+
+/// Describes how the page tables of a hierarchy can be accessed.
+///
+/// This trait abstracts over the different ways the mapper types reach the page tables below
+/// the root table, e.g. through a physical memory mapping or through a recursive entry.
+pub(super) trait PageTableAccess {
+    /// Returns a pointer to the page table that the given entry points to.
+    ///
+    /// The entry is part of a table at `level` and is responsible for the virtual address
+    /// `addr`. The caller must ensure that the entry is present and not a huge page.
+    unsafe fn next_table_ptr(
+        &self,
+        entry: &PageTableEntry,
+        addr: VirtAddr,
+        level: PageTableLevel,
+    ) -> *mut PageTable;
+
+    /// Returns whether the given entry of the root table maps the page tables themselves, e.g.
+    /// because it is a recursive entry. Walks over the complete hierarchy never descend into such
+    /// entries.
+    fn is_table_mapping(&self, _index: usize) -> bool {
+        false
+    }
+
+    /// Called after the entry that points to the page table at `level` that is responsible for
+    /// `addr` was changed, e.g. because the table was removed from or added to the hierarchy.
+    /// Used to flush cached translations of the table's virtual address.
+    fn table_entry_changed(&self, _addr: VirtAddr, _level: PageTableLevel) {}
+
+    /// Returns whether `with_frame_table` can currently access a page table that is not part
+    /// of the hierarchy.
+    fn can_access_frame_table(&self) -> bool;
+
+    /// Calls `f` with the page table in the given frame, which is not part of the hierarchy
+    /// yet, e.g. through a physical memory mapping or a temporary mapping.
+    ///
+    /// Panics if `can_access_frame_table` returns `false`.
+    unsafe fn with_frame_table(&self, frame: PhysFrame, f: &mut dyn FnMut(&mut PageTable));
+}
+
+impl<'a, A> PageTableAccess for &'a A
+where
+    A: PageTableAccess,
+{
+    unsafe fn next_table_ptr(
+        &self,
+        entry: &PageTableEntry,
+        addr: VirtAddr,
+        level: PageTableLevel,
+    ) -> *mut PageTable {
+        (**self).next_table_ptr(entry, addr, level)
+    }
+
+    fn is_table_mapping(&self, index: usize) -> bool {
+        (**self).is_table_mapping(index)
+    }
+
+    fn table_entry_changed(&self, addr: VirtAddr, level: PageTableLevel) {
+        (**self).table_entry_changed(addr, level)
+    }
+
+    fn can_access_frame_table(&self) -> bool {
+        (**self).can_access_frame_table()
+    }
+
+    unsafe fn with_frame_table(&self, frame: PhysFrame, f: &mut dyn FnMut(&mut PageTable)) {
+        (**self).with_frame_table(frame, f)
+    }
+}
+
+/// Returns the index of the entry of a table at `level` that is responsible for `addr`.
+pub(super) fn table_index(addr: VirtAddr, level: PageTableLevel) -> u9 {
+    match level {
+        PageTableLevel::One => addr.p1_index(),
+        PageTableLevel::Two => addr.p2_index(),
+        PageTableLevel::Three => addr.p3_index(),
+        PageTableLevel::Four => addr.p4_index(),
+        PageTableLevel::Five => addr.p5_index(),
+    }
+}
+
+/// Returns the entry of the table at `level` that maps `addr`.
+///
+/// Returns `FlagUpdateError::PageNotMapped` if one of the parent entries is not present or if
+/// the entry itself is unused. Returns `FlagUpdateError::ParentEntryHugePage` if one of the
+/// parent entries maps a huge page.
+pub(super) unsafe fn leaf_entry_mut<'b, A>(
+    access: &A,
+    root_table: &'b mut PageTable,
+    paging_mode: PagingMode,
+    addr: VirtAddr,
+    level: PageTableLevel,
+) -> Result<&'b mut PageTableEntry, FlagUpdateError>
+where
+    A: PageTableAccess,
+{
+    let mut table = root_table;
+    let mut table_level = paging_mode.top_level();
+    while table_level != level {
+        let entry = &table[table_index(addr, table_level)];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return Err(FlagUpdateError::PageNotMapped);
+        }
+        if flags.contains(PageTableFlags::HUGE_PAGE) {
+            return Err(FlagUpdateError::ParentEntryHugePage);
+        }
+        table = &mut *access.next_table_ptr(entry, addr, table_level);
+        table_level = table_level
+            .next_lower_level()
+            .expect("level 1 entries map no table");
+    }
+
+    let entry = &mut table[table_index(addr, level)];
+    if entry.is_unused() {
+        return Err(FlagUpdateError::PageNotMapped);
+    }
+    Ok(entry)
+}
+
+/// Sets the flags of the parent entry at `level` on the path to `addr`, which is mapped by an
+/// entry at `page_level`.
+///
+/// Panics if `level` is not between `page_level` (exclusive) and the top level (inclusive) or
+/// if `flags` contains `HUGE_PAGE`.
+pub(super) unsafe fn update_parent_flags<A>(
+    access: &A,
+    root_table: &mut PageTable,
+    paging_mode: PagingMode,
+    addr: VirtAddr,
+    page_level: PageTableLevel,
+    level: PageTableLevel,
+    flags: PageTableFlags,
+) -> Result<(), FlagUpdateError>
+where
+    A: PageTableAccess,
+{
+    assert!(
+        level > page_level && level <= paging_mode.top_level(),
+        "level {} has no parent entry for pages on level {}",
+        level as u8,
+        page_level as u8
+    );
+    assert!(
+        !flags.contains(PageTableFlags::HUGE_PAGE),
+        "parent entries must not have the HUGE_PAGE flag set"
+    );
+    let entry = leaf_entry_mut(access, root_table, paging_mode, addr, level)?;
+    if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return Err(FlagUpdateError::ParentEntryHugePage);
+    }
+    entry.set_flags(flags);
+    Ok(())
+}
+
+/// Returns the level of the page table entries that map pages of size `S`.
+pub(super) fn page_level<S: PageSize>() -> PageTableLevel {
+    if S::SIZE == Size1GiB::SIZE {
+        PageTableLevel::Three
+    } else if S::SIZE == Size2MiB::SIZE {
+        PageTableLevel::Two
+    } else {
+        PageTableLevel::One
+    }
+}
+
+/// Returns an entry that maps the given frame with the given flags, with the `HUGE_PAGE` flag
+/// added for huge pages.
+///
+/// Panics if `flags` contains `HUGE_PAGE` and `S` is `Size4KiB`.
+pub(super) fn page_entry<S: PageSize>(
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+) -> PageTableEntry {
+    let mut entry = PageTableEntry::new();
+    if S::SIZE == Size4KiB::SIZE {
+        assert!(!flags.contains(PageTableFlags::HUGE_PAGE));
+        entry.set_addr(frame.start_address(), flags);
+    } else {
+        entry.set_addr(frame.start_address(), flags | PageTableFlags::HUGE_PAGE);
+    }
+    entry
+}
+
+/// Returns an entry that maps the given frame with the given flags and selects the first entry
+/// of `pat` that has the given memory type.
+pub(super) fn memory_type_entry<S: PageSize>(
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+    memory_type: MemoryType,
+    pat: &PageAttributeTable,
+) -> Result<PageTableEntry, MapToError> {
+    let pat_index = pat
+        .index_of(memory_type)
+        .ok_or(MapToError::MemoryTypeUnavailable)?;
+    let mut entry = page_entry(frame, flags);
+    entry.set_pat_index::<S>(pat_index);
+    Ok(entry)
+}
+
+/// Replaces the frame and the flags of an entry that maps a page of size `S` with a single
+/// write and returns the previously mapped frame.
+///
+/// The flags are written as they are, with `HUGE_PAGE` added for huge pages. The PAT bit of a
+/// huge page entry is kept. Returns `FlagUpdateError::PageNotMapped` if the entry does not map a
+/// page of size `S`.
+pub(super) fn remap_entry<S: PageSize>(
+    entry: &mut PageTableEntry,
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+) -> Result<PhysFrame<S>, FlagUpdateError> {
+    let old_flags = entry.flags();
+    let huge = S::SIZE != Size4KiB::SIZE;
+    if !old_flags.contains(PageTableFlags::PRESENT)
+        || (huge && !old_flags.contains(PageTableFlags::HUGE_PAGE))
+    {
+        return Err(FlagUpdateError::PageNotMapped);
+    }
+    let old_frame = PhysFrame::containing_address(entry.addr());
+
+    let mut new_entry = PageTableEntry::new();
+    if huge {
+        new_entry.set_addr(frame.start_address(), flags | PageTableFlags::HUGE_PAGE);
+        let pat_index = new_entry.pat_index::<S>() | (entry.pat_index::<S>() & 0b100);
+        new_entry.set_pat_index::<S>(pat_index);
+    } else {
+        new_entry.set_addr(frame.start_address(), flags);
+    }
+    *entry = new_entry;
+    Ok(old_frame)
+}
+
+/// Replaces the flags and the PAT index of an entry that maps a page of size `S` with a single
+/// write, so that the processor never sees a mix of the old and the new values.
+///
+/// `HUGE_PAGE` is added to the flags of huge pages.
+pub(super) fn set_flags_and_pat_index<S: PageSize>(
+    entry: &mut PageTableEntry,
+    flags: PageTableFlags,
+    pat_index: u8,
+) {
+    let mut new_entry = entry.clone();
+    if S::SIZE == Size4KiB::SIZE {
+        new_entry.set_flags(flags);
+    } else {
+        new_entry.set_flags(flags | PageTableFlags::HUGE_PAGE);
+    }
+    new_entry.set_pat_index::<S>(pat_index);
+    *entry = new_entry;
+}
+
+/// Returns the PAT index of an entry of a table at `level` that maps a page.
+pub(super) fn pat_index(entry: &PageTableEntry, level: PageTableLevel) -> u8 {
+    match level {
+        PageTableLevel::One => entry.pat_index::<Size4KiB>(),
+        PageTableLevel::Two => entry.pat_index::<Size2MiB>(),
+        _ => entry.pat_index::<Size1GiB>(),
+    }
+}
+
+/// Sets the PAT index of an entry of a table at `level` that maps a page.
+fn set_pat_index(entry: &mut PageTableEntry, level: PageTableLevel, index: u8) {
+    match level {
+        PageTableLevel::One => entry.set_pat_index::<Size4KiB>(index),
+        PageTableLevel::Two => entry.set_pat_index::<Size2MiB>(index),
+        _ => entry.set_pat_index::<Size1GiB>(index),
+    }
+}
+
+/// Replaces the huge page entry at `level` that maps `addr` with a new page table of 512
+/// entries that map the same physical memory with the same flags and the same memory type.
+///
+/// The entry that pointed to the huge page keeps only the `PRESENT`, `WRITABLE` and
+/// `USER_ACCESSIBLE` flags of the huge page, so that the effective flags of the mapped memory
+/// don't change. The new table is filled before it is inserted and the entry is replaced in a
+/// single write, so the huge page stays mapped throughout. The caller is responsible for
+/// flushing the TLB entries of the complete huge page.
+pub(super) unsafe fn split_huge_page<A, F>(
+    access: &A,
+    root_table: &mut PageTable,
+    paging_mode: PagingMode,
+    addr: VirtAddr,
+    level: PageTableLevel,
+    frame_allocator: &mut F,
+) -> Result<(), SplitError>
+where
+    A: PageTableAccess,
+    F: FrameAllocator<Size4KiB>,
+{
+    let next_level = level
+        .next_lower_level()
+        .expect("level 1 entries map no huge pages");
+    let entry = match leaf_entry_mut(access, root_table, paging_mode, addr, level) {
+        Ok(entry) => entry,
+        Err(FlagUpdateError::PageNotMapped) => return Err(SplitError::PageNotMapped),
+        Err(FlagUpdateError::ParentEntryHugePage) => return Err(SplitError::ParentEntryHugePage),
+    };
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+        return Err(SplitError::PageNotMapped);
+    }
+    if !flags.contains(PageTableFlags::HUGE_PAGE) {
+        return Err(SplitError::NotHugePage);
+    }
+    if !access.can_access_frame_table() {
+        return Err(SplitError::NoTemporaryMapping);
+    }
+
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(SplitError::FrameAllocationFailed)?;
+    let start = entry.addr().as_u64() & !(level.entry_address_space_size() - 1);
+    let pat = pat_index(entry, level);
+    let table_flags = flags
+        & (PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE);
+    let page_flags = match next_level {
+        PageTableLevel::One => flags - PageTableFlags::HUGE_PAGE,
+        _ => flags,
+    };
+
+    access.with_frame_table(frame, &mut |table| {
+        let page_size = next_level.entry_address_space_size();
+        for (index, page_entry) in table.iter_mut().enumerate() {
+            page_entry.set_addr(PhysAddr::new(start + index as u64 * page_size), page_flags);
+            set_pat_index(page_entry, next_level, pat);
+        }
+    });
+    entry.set_frame(frame, table_flags);
+    access.table_entry_changed(addr, next_level);
+    Ok(())
+}
+
+/// Replaces the page table that the entry at `level` for `addr` points to with a huge page
+/// entry, if the table consists of 512 contiguous, identically flagged page mappings.
+///
+/// The `ACCESSED` and `DIRTY` flags are ignored when comparing the flags of the pages and are
+/// set on the huge page if any of the pages has them set. The frame of the removed table is
+/// passed to the given deallocator. The caller is responsible for flushing the TLB entries of
+/// the 512 pages.
+pub(super) unsafe fn merge_huge_page<A, D>(
+    access: &A,
+    root_table: &mut PageTable,
+    paging_mode: PagingMode,
+    addr: VirtAddr,
+    level: PageTableLevel,
+    frame_deallocator: &mut D,
+) -> Result<(), MergeError>
+where
+    A: PageTableAccess,
+    D: FrameDeallocator<Size4KiB>,
+{
+    let next_level = level
+        .next_lower_level()
+        .expect("level 1 entries map no huge pages");
+    let entry = match leaf_entry_mut(access, root_table, paging_mode, addr, level) {
+        Ok(entry) => entry,
+        Err(FlagUpdateError::PageNotMapped) => return Err(MergeError::PageNotMapped),
+        Err(FlagUpdateError::ParentEntryHugePage) => return Err(MergeError::ParentEntryHugePage),
+    };
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+        return Err(MergeError::PageNotMapped);
+    }
+    if flags.contains(PageTableFlags::HUGE_PAGE) {
+        return Err(MergeError::AlreadyHugePage);
+    }
+
+    let table = &*access.next_table_ptr(entry, addr, level);
+    let page_size = next_level.entry_address_space_size();
+    // the `HUGE_PAGE` bit of level 1 entries is the PAT bit, which is compared separately
+    let ignored = PageTableFlags::ACCESSED | PageTableFlags::DIRTY | PageTableFlags::HUGE_PAGE;
+    let first = &table[0];
+    let first_flags = first.flags();
+    let start = first.addr().as_u64() & !(page_size - 1);
+    let pat = pat_index(first, next_level);
+    if !first_flags.contains(PageTableFlags::PRESENT)
+        || (next_level != PageTableLevel::One && !first_flags.contains(PageTableFlags::HUGE_PAGE))
+        || start % level.entry_address_space_size() != 0
+    {
+        return Err(MergeError::NotMergeable);
+    }
+    let mut used = PageTableFlags::empty();
+    for (index, page_entry) in table.iter().enumerate() {
+        let page_flags = page_entry.flags();
+        let page_start = page_entry.addr().as_u64() & !(page_size - 1);
+        if page_flags - ignored != first_flags - ignored
+            || page_flags.contains(PageTableFlags::HUGE_PAGE)
+                != first_flags.contains(PageTableFlags::HUGE_PAGE)
+            || pat_index(page_entry, next_level) != pat
+            || page_start != start + index as u64 * page_size
+        {
+            return Err(MergeError::NotMergeable);
+        }
+        used |= page_flags & (PageTableFlags::ACCESSED | PageTableFlags::DIRTY);
+    }
+
+    let frame = PhysFrame::containing_address(entry.addr());
+    let mut huge_entry = PageTableEntry::new();
+    huge_entry.set_addr(
+        PhysAddr::new(start),
+        (first_flags - ignored) | used | PageTableFlags::HUGE_PAGE,
+    );
+    set_pat_index(&mut huge_entry, level, pat);
+    *entry = huge_entry;
+    access.table_entry_changed(addr, next_level);
+    frame_deallocator.deallocate_frame(frame);
+    Ok(())
+}
+
+/// Tests and clears `flags` in the entries that map the pages of `pages` with size `S`.
+///
+/// Sets bit `i` of `bitmap` if page `pages.start + i` had any of the flags set and clears the
+/// bits of all other pages. Pages that are not mapped or that are mapped with a different size
+/// are skipped. Returns the smallest range that contains all pages whose entries changed.
+///
+/// Panics if `bitmap` has less than one bit per page of `pages`.
+pub(super) unsafe fn harvest_flags<A, S>(
+    access: &A,
+    root_table: &mut PageTable,
+    paging_mode: PagingMode,
+    pages: PageRange<S>,
+    flags: PageTableFlags,
+    bitmap: &mut [u64],
+) -> PageRange<S>
+where
+    A: PageTableAccess,
+    S: PageSize,
+{
+    let words = bitmap_words(range_len(pages.start, pages.end));
+    assert!(
+        bitmap.len() >= words,
+        "bitmap has less than one bit per page"
+    );
+    for word in &mut bitmap[..words] {
+        *word = 0;
+    }
+
+    let level = page_level::<S>();
+    let mut changed: Option<PageRange<S>> = None;
+    for (i, page) in pages.enumerate() {
+        let addr = page.start_address();
+        let entry = match leaf_entry_mut(access, root_table, paging_mode, addr, level) {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        let entry_flags = entry.flags();
+        if !entry_flags.contains(PageTableFlags::PRESENT)
+            || (level != PageTableLevel::One && !entry_flags.contains(PageTableFlags::HUGE_PAGE))
+        {
+            continue;
+        }
+        if entry.test_and_clear_flags(flags).is_empty() {
+            continue;
+        }
+        bitmap[i / 64] |= 1 << (i % 64);
+        changed = Some(match changed {
+            Some(range) => Page::range(range.start, page + 1),
+            None => Page::range(page, page + 1),
+        });
+    }
+    changed.unwrap_or_else(|| Page::range(pages.start, pages.start))
+}
+
+/// Translates the given virtual address through the hierarchy below `root_table`.
+///
+/// Besides the frame, the result contains the flags of the entry that maps the page and the
+/// effective flags of the page, i.e. the flags combined with the restrictive flags of all
+/// parent entries.
+///
+/// Panics if a level 4 or level 5 entry on the path has the `HUGE_PAGE` flag set.
+pub(super) unsafe fn translate<A>(
+    access: &A,
+    root_table: &PageTable,
+    paging_mode: PagingMode,
+    addr: VirtAddr,
+) -> TranslateResult
+where
+    A: PageTableAccess,
+{
+    let mut table = root_table;
+    let mut level = paging_mode.top_level();
+    let mut path_flags = EffectiveFlags::new();
+    loop {
+        let entry = &table[table_index(addr, level)];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return TranslateResult::PageNotMapped { level };
+        }
+        path_flags.add(flags);
+
+        let huge = flags.contains(PageTableFlags::HUGE_PAGE);
+        match level {
+            PageTableLevel::One => {
+                let frame = match PhysFrame::from_start_address(entry.addr()) {
+                    Ok(frame) => frame,
+                    Err(()) => return TranslateResult::InvalidFrameAddress(entry.addr()),
+                };
+                return TranslateResult::Frame4KiB {
+                    frame,
+                    offset: u64::from(addr.page_offset()),
+                    flags,
+                    effective_flags: path_flags.of(flags),
+                };
+            }
+            PageTableLevel::Two if huge => {
+                return TranslateResult::Frame2MiB {
+                    frame: PhysFrame::containing_address(entry.addr()),
+                    offset: addr.as_u64() & 0o_777_7777,
+                    flags,
+                    effective_flags: path_flags.of(flags),
+                };
+            }
+            PageTableLevel::Three if huge => {
+                return TranslateResult::Frame1GiB {
+                    frame: PhysFrame::containing_address(entry.addr()),
+                    offset: addr.as_u64() & 0o_777_777_7777,
+                    flags,
+                    effective_flags: path_flags.of(flags),
+                };
+            }
+            PageTableLevel::Four | PageTableLevel::Five if huge => {
+                panic!("level {} entry has huge page bit set", level as u8)
+            }
+            _ => {}
+        }
+
+        table = &*access.next_table_ptr(entry, addr, level);
+        level = level
+            .next_lower_level()
+            .expect("level 1 entries map no table");
+    }
+}
+
+/// Accumulates the restrictive flags of the entries on the path to a page.
+///
+/// A page is only writable or user accessible if all entries on the path allow it, and it is
+/// not executable if any entry on the path has the `NO_EXECUTE` flag set.
+#[derive(Clone, Copy)]
+pub(super) struct EffectiveFlags {
+    allowed: PageTableFlags,
+    no_execute: PageTableFlags,
+}
+
+impl EffectiveFlags {
+    pub(super) fn new() -> Self {
+        EffectiveFlags {
+            allowed: PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
+            no_execute: PageTableFlags::empty(),
+        }
+    }
+
+    pub(super) fn add(&mut self, flags: PageTableFlags) {
+        self.allowed &= flags;
+        self.no_execute |= flags & PageTableFlags::NO_EXECUTE;
+    }
+
+    /// Returns the effective flags of a page that is mapped with the given leaf flags.
+    pub(super) fn of(&self, leaf_flags: PageTableFlags) -> PageTableFlags {
+        let restrictive =
+            PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+        (leaf_flags - restrictive) | self.allowed | self.no_execute
+    }
+}
+
+/// Returns the virtual address bits that are translated by a hierarchy with the given paging
+/// mode, i.e. the address without its sign extension.
+pub(super) fn raw_addr(addr: VirtAddr, paging_mode: PagingMode) -> u64 {
+    let size = 512 * paging_mode.top_level().entry_address_space_size();
+    addr.as_u64() & (size - 1)
+}
+
+/// Frees all page tables below `root_table` that contain no entries and are responsible for
+/// virtual addresses in `start..=end`.
+///
+/// The addresses are raw addresses as returned by `raw_addr`. The frames of the freed tables
+/// are passed to the given deallocator. The root table itself is never freed.
+pub(super) unsafe fn clean_up<A, D>(
+    access: &A,
+    root_table: &mut PageTable,
+    paging_mode: PagingMode,
+    start: u64,
+    end: u64,
+    frame_deallocator: &mut D,
+) where
+    A: PageTableAccess,
+    D: FrameDeallocator<Size4KiB>,
+{
+    let mut walk = CleanUp {
+        access,
+        paging_mode,
+        start,
+        end,
+        frame_deallocator,
+    };
+    walk.clean_up_table(root_table, paging_mode.top_level(), 0);
+}
+
+struct CleanUp<'a, A, D> {
+    access: &'a A,
+    paging_mode: PagingMode,
+    start: u64,
+    end: u64,
+    frame_deallocator: &'a mut D,
+}
+
+impl<'a, A, D> CleanUp<'a, A, D>
+where
+    A: PageTableAccess,
+    D: FrameDeallocator<Size4KiB>,
+{
+    /// Cleans up the tables below `table`, which is a table at `level` whose first entry is
+    /// responsible for the raw address `table_start`.
+    ///
+    /// Returns whether `table` is unused afterwards.
+    unsafe fn clean_up_table(
+        &mut self,
+        table: &mut PageTable,
+        level: PageTableLevel,
+        table_start: u64,
+    ) -> bool {
+        let next_level = match level.next_lower_level() {
+            Some(next_level) => next_level,
+            None => return table.iter().all(PageTableEntry::is_unused),
+        };
+        let entry_size = level.entry_address_space_size();
+
+        for index in 0..512 {
+            let entry_start = table_start + index as u64 * entry_size;
+            let entry_end = entry_start + (entry_size - 1);
+            if entry_end < self.start || entry_start > self.end {
+                continue;
+            }
+            if level == self.paging_mode.top_level() && self.access.is_table_mapping(index) {
+                continue;
+            }
+
+            let entry = &mut table[index];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                continue;
+            }
+
+            let addr = self.paging_mode.virt_addr(entry_start);
+            let next_table = &mut *self.access.next_table_ptr(entry, addr, level);
+            if self.clean_up_table(next_table, next_level, entry_start) {
+                let frame = PhysFrame::containing_address(entry.addr());
+                entry.set_unused();
+                self.access.table_entry_changed(addr, next_level);
+                self.frame_deallocator.deallocate_frame(frame);
+            }
+        }
+
+        table.iter().all(PageTableEntry::is_unused)
+    }
+}
+
+/// Returns the number of page tables of the hierarchy below `root_table`, indexed by
+/// `level - 1`. The root table is included in the count.
+///
+/// The same entries as in `Mappings` are skipped.
+pub(super) unsafe fn table_counts<A>(
+    access: &A,
+    root_table: &PageTable,
+    paging_mode: PagingMode,
+) -> [u64; 5]
+where
+    A: PageTableAccess,
+{
+    let mut counts = [0; 5];
+    count_tables(
+        access,
+        paging_mode,
+        root_table,
+        paging_mode.top_level(),
+        0,
+        &mut counts,
+    );
+    counts
+}
+
+unsafe fn count_tables<A>(
+    access: &A,
+    paging_mode: PagingMode,
+    table: &PageTable,
+    level: PageTableLevel,
+    table_start: u64,
+    counts: &mut [u64; 5],
+) where
+    A: PageTableAccess,
+{
+    counts[level as usize - 1] += 1;
+
+    let next_level = match level.next_lower_level() {
+        Some(next_level) => next_level,
+        None => return,
+    };
+    for (index, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            continue;
+        }
+        if level == paging_mode.top_level() && access.is_table_mapping(index) {
+            continue;
+        }
+        let entry_start = table_start + index as u64 * level.entry_address_space_size();
+        let addr = paging_mode.virt_addr(entry_start);
+        let next_table = &*access.next_table_ptr(entry, addr, level);
+        count_tables(
+            access,
+            paging_mode,
+            next_table,
+            next_level,
+            entry_start,
+            counts,
+        );
+    }
+}
+
+/// An iterator over all present pages of a page table hierarchy, in ascending order of their
+/// raw virtual addresses.
+///
+/// The iterator descends into the page tables lazily, so that it needs no allocations. Entries
+/// of the root table that map the page tables themselves are skipped. Entries of level 4 and
+/// level 5 tables that have the `HUGE_PAGE` flag set are invalid and skipped too.
+#[derive(Clone)]
+pub(super) struct Mappings<'a, A> {
+    access: A,
+    paging_mode: PagingMode,
+    /// The current level of the walk.
+    level: PageTableLevel,
+    /// The tables on the path to the current entry, indexed by `level - 1`.
+    tables: [Option<&'a PageTable>; 5],
+    /// The index of the next entry of each table on the path.
+    indices: [usize; 5],
+    /// The raw virtual address that the first entry of each table on the path is responsible for.
+    starts: [u64; 5],
+}
+
+impl<'a, A> Mappings<'a, A>
+where
+    A: PageTableAccess,
+{
+    /// Creates a new iterator over the hierarchy below `root_table`.
+    ///
+    /// This function is unsafe because the caller must guarantee that `access` returns valid
+    /// pointers for all tables of the hierarchy and that the tables are not modified while the
+    /// iterator is alive.
+    pub(super) unsafe fn new(
+        access: A,
+        root_table: &'a PageTable,
+        paging_mode: PagingMode,
+    ) -> Self {
+        let level = paging_mode.top_level();
+        let mut tables = [None; 5];
+        tables[level as usize - 1] = Some(root_table);
+        Mappings {
+            access,
+            paging_mode,
+            level,
+            tables,
+            indices: [0; 5],
+            starts: [0; 5],
+        }
+    }
+}
+
+impl<'a, A> Iterator for Mappings<'a, A>
+where
+    A: PageTableAccess,
+{
+    type Item = MappedPage;
+
+    fn next(&mut self) -> Option<MappedPage> {
+        loop {
+            let level = self.level;
+            let i = level as usize - 1;
+            let table = self.tables[i]?;
+            let index = self.indices[i];
+
+            if index == 512 {
+                // the current table is done, continue with the parent table
+                self.tables[i] = None;
+                if level == self.paging_mode.top_level() {
+                    return None;
+                }
+                self.level = level.next_higher_level()?;
+                continue;
+            }
+            self.indices[i] += 1;
+
+            let entry = &table[index];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            if level == self.paging_mode.top_level() && self.access.is_table_mapping(index) {
+                continue;
+            }
+
+            let start = self.starts[i] + index as u64 * level.entry_address_space_size();
+            let addr = self.paging_mode.virt_addr(start);
+            let huge = flags.contains(PageTableFlags::HUGE_PAGE);
+            match level {
+                PageTableLevel::One => {
+                    return Some(MappedPage::Page4KiB {
+                        page: Page::containing_address(addr),
+                        frame: PhysFrame::containing_address(entry.addr()),
+                        flags,
+                    });
+                }
+                PageTableLevel::Two if huge => {
+                    return Some(MappedPage::Page2MiB {
+                        page: Page::containing_address(addr),
+                        frame: PhysFrame::containing_address(entry.addr()),
+                        flags,
+                    });
+                }
+                PageTableLevel::Three if huge => {
+                    return Some(MappedPage::Page1GiB {
+                        page: Page::containing_address(addr),
+                        frame: PhysFrame::containing_address(entry.addr()),
+                        flags,
+                    });
+                }
+                PageTableLevel::Four | PageTableLevel::Five if huge => continue,
+                _ => {}
+            }
+
+            let next_level = level.next_lower_level()?;
+            let j = next_level as usize - 1;
+            let next_table = unsafe { &*self.access.next_table_ptr(entry, addr, level) };
+            self.tables[j] = Some(next_table);
+            self.indices[j] = 0;
+            self.starts[j] = start;
+            self.level = next_level;
+        }
+    }
+}
@@ -1,66 +1,302 @@
+use super::audit;
+use super::hierarchy::{self, PageTableAccess};
 use crate::structures::paging::{
     frame::PhysFrame,
-    frame_alloc::FrameAllocator,
+    frame_alloc::{FrameAllocator, FrameDeallocator},
     mapper::*,
-    page::{Page, Size1GiB, Size2MiB, Size4KiB},
-    page_table::{FrameError, PageTable, PageTableEntry, PageTableFlags},
+    page::{Page, PageRangeInclusive, Size1GiB, Size2MiB, Size4KiB},
+    page_table::{
+        FrameError, PageTable, PageTableEntry, PageTableFlags, PageTableLevel, PagingMode,
+    },
 };
 
+/// Converts the physical frame of a page table to a pointer to the page table.
+///
+/// This trait is used by `MappedPageTable` to access the page tables of a hierarchy. It is
+/// implemented for all closures of the form `Fn(PhysFrame) -> *mut PageTable`.
+pub trait PhysToVirt {
+    /// Returns a pointer to the page table that is stored in the given frame.
+    fn phys_to_virt(&self, phys_frame: PhysFrame) -> *mut PageTable;
+}
+
+impl<T> PhysToVirt for T
+where
+    T: Fn(PhysFrame) -> *mut PageTable,
+{
+    fn phys_to_virt(&self, phys_frame: PhysFrame) -> *mut PageTable {
+        self(phys_frame)
+    }
+}
+
 /// A Mapper implementation that relies on a PhysAddr to VirtAddr conversion function.
 ///
 /// This type requires that the all physical page table frames are mapped to some virtual
 /// address. Normally, this is done by mapping the complete physical address space into
 /// the virtual address space at some offset. Other mappings between physical and virtual
 /// memory are possible too, as long as they can be calculated as an `PhysAddr` to
-/// `VirtAddr` closure.
+/// `VirtAddr` closure or another `PhysToVirt` implementation.
+///
+/// Both 4-level and 5-level paging are supported, see `with_paging_mode`.
 #[derive(Debug)]
-pub struct MappedPageTable<'a, PhysToVirt>
+pub struct MappedPageTable<'a, P>
 where
-    PhysToVirt: Fn(PhysFrame) -> *mut PageTable,
+    P: PhysToVirt,
 {
-    page_table_walker: PageTableWalker<PhysToVirt>,
-    level_4_table: &'a mut PageTable,
+    page_table_walker: PageTableWalker<P>,
+    root_table: &'a mut PageTable,
+    paging_mode: PagingMode,
 }
 
-impl<'a, PhysToVirt> MappedPageTable<'a, PhysToVirt>
+impl<'a, P> MappedPageTable<'a, P>
 where
-    PhysToVirt: Fn(PhysFrame) -> *mut PageTable,
+    P: PhysToVirt,
 {
     /// Creates a new `MappedPageTable` that uses the passed closure for converting virtual
     /// to physical addresses.
     ///
     /// This function is unsafe because the caller must guarantee that the passed `phys_to_virt`
-    /// closure is correct. Also, the passed `level_4_table` must point to the level 4 page table
-    /// of a valid page table hierarchy. Otherwise this function might break memory safety, e.g.
-    /// by writing to an illegal memory location.
-    pub unsafe fn new(level_4_table: &'a mut PageTable, phys_to_virt: PhysToVirt) -> Self {
+    /// closure (or `PhysToVirt` implementation) is correct. Also, the passed `level_4_table`
+    /// must point to the level 4 page table of a valid page table hierarchy. Otherwise this
+    /// function might break memory safety, e.g. by writing to an illegal memory location.
+    pub unsafe fn new(level_4_table: &'a mut PageTable, phys_to_virt: P) -> Self {
+        Self::with_paging_mode(level_4_table, phys_to_virt, PagingMode::Level4)
+    }
+
+    /// Creates a new `MappedPageTable` for a hierarchy that uses the given paging mode.
+    ///
+    /// The passed `root_table` is the level 4 table for `PagingMode::Level4` and the level 5
+    /// table for `PagingMode::Level5`.
+    ///
+    /// This function is unsafe for the same reasons as `new`.
+    pub unsafe fn with_paging_mode(
+        root_table: &'a mut PageTable,
+        phys_to_virt: P,
+        paging_mode: PagingMode,
+    ) -> Self {
         Self {
-            level_4_table,
+            root_table,
+            paging_mode,
             page_table_walker: PageTableWalker::new(phys_to_virt),
         }
     }
 
+    /// Returns the paging mode of the page table hierarchy.
+    pub fn paging_mode(&self) -> PagingMode {
+        self.paging_mode
+    }
+
+    /// Returns an iterator over all present pages of the page table hierarchy.
+    ///
+    /// The iterator yields a `MappedPage` for every present entry of a level 1 table and for
+    /// every huge page entry of a level 2 or level 3 table, in ascending order of the virtual
+    /// addresses. Page tables are visited lazily, so no allocations are required.
+    pub fn mappings(&self) -> impl Iterator<Item = MappedPage> + Clone + '_ {
+        unsafe {
+            hierarchy::Mappings::new(&self.page_table_walker, self.root_table, self.paging_mode)
+        }
+    }
+
+    /// Returns a formatter that prints all mappings of the page table hierarchy.
+    ///
+    /// See `PageTableDump` for a description of the output format.
+    pub fn dump(&self) -> PageTableDump<impl Iterator<Item = MappedPage> + Clone + '_> {
+        let table_counts = unsafe {
+            hierarchy::table_counts(&self.page_table_walker, self.root_table, self.paging_mode)
+        };
+        PageTableDump::new(self.mappings(), table_counts, self.paging_mode)
+    }
+
+    /// Checks the page table hierarchy against the given policy and passes each violation to
+    /// `report`. Returns the number of violations.
+    ///
+    /// See `AuditFinding` for the checked conditions. The hierarchy is not modified.
+    pub fn audit<F>(&self, policy: &AuditPolicy, report: F) -> usize
+    where
+        F: FnMut(AuditFinding),
+    {
+        unsafe {
+            audit::audit(
+                &self.page_table_walker,
+                self.root_table,
+                self.paging_mode,
+                policy,
+                report,
+            )
+        }
+    }
+
+    /// Frees all page tables of the hierarchy that no longer contain any entries.
+    ///
+    /// See `clean_up_addr_range` for more information.
+    pub unsafe fn clean_up<D>(&mut self, frame_deallocator: &mut D)
+    where
+        D: FrameDeallocator<Size4KiB>,
+    {
+        hierarchy::clean_up(
+            &self.page_table_walker,
+            self.root_table,
+            self.paging_mode,
+            0,
+            u64::max_value(),
+            frame_deallocator,
+        )
+    }
+
+    /// Frees all page tables that no longer contain any entries and that are responsible for
+    /// addresses in the given range.
+    ///
+    /// `Mapper::unmap` never deallocates page tables, so page tables that became empty after
+    /// unmapping stay part of the hierarchy until this function is called. The frames of the
+    /// freed tables are passed to the given `frame_deallocator`. The level 4 table (the level 5
+    /// table with 5-level paging) is never freed.
+    ///
+    /// This function is unsafe because the caller must guarantee that the freed page tables are
+    /// not used anymore. In particular, they must not be shared with other page table
+    /// hierarchies, and the TLB entries of the unmapped pages in the range must be flushed
+    /// already.
+    pub unsafe fn clean_up_addr_range<D>(
+        &mut self,
+        range: PageRangeInclusive,
+        frame_deallocator: &mut D,
+    ) where
+        D: FrameDeallocator<Size4KiB>,
+    {
+        if range.is_empty() {
+            return;
+        }
+        hierarchy::clean_up(
+            &self.page_table_walker,
+            self.root_table,
+            self.paging_mode,
+            hierarchy::raw_addr(range.start.start_address(), self.paging_mode),
+            hierarchy::raw_addr(range.end.start_address(), self.paging_mode),
+            frame_deallocator,
+        )
+    }
+
+    /// Splits the given huge page into 512 pages of the next smaller page size.
+    ///
+    /// The new pages map the same frames with the same flags and memory type as the huge page.
+    /// The frame for the new page table is taken from `frame_allocator`. The returned
+    /// `MapperFlushRange` flushes the TLB entries of all 512 pages.
+    ///
+    /// This function is unsafe because the caller must guarantee that the frame allocator only
+    /// returns unused frames.
+    pub unsafe fn split_huge_page<S, A>(
+        &mut self,
+        page: Page<S>,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange<S::SplitSize>, SplitError>
+    where
+        S: HugePageSize,
+        A: FrameAllocator<Size4KiB>,
+    {
+        hierarchy::split_huge_page(
+            &self.page_table_walker,
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+            hierarchy::page_level::<S>(),
+            frame_allocator,
+        )?;
+        let start = Page::containing_address(page.start_address());
+        Ok(MapperFlushRange::new(Page::range(start, start + 512)))
+    }
+
+    /// Merges the 512 pages that make up the given huge page into a single huge page mapping.
+    ///
+    /// This is the reverse of `split_huge_page`. It only succeeds if the page table for `page`
+    /// maps 512 contiguous frames, starting at a frame that is aligned to the size of `S`, with
+    /// identical flags and memory type. The `ACCESSED` and `DIRTY` flags are ignored for the
+    /// comparison. The frame of the removed page table is passed to `frame_deallocator`. The
+    /// returned `MapperFlushRange` flushes the TLB entries of the merged pages.
+    ///
+    /// This function is unsafe because the caller must guarantee that the removed page table
+    /// is not used anymore. In particular, it must not be shared with other page table
+    /// hierarchies.
+    pub unsafe fn merge_huge_page<S, D>(
+        &mut self,
+        page: Page<S>,
+        frame_deallocator: &mut D,
+    ) -> Result<MapperFlushRange<S::SplitSize>, MergeError>
+    where
+        S: HugePageSize,
+        D: FrameDeallocator<Size4KiB>,
+    {
+        hierarchy::merge_huge_page(
+            &self.page_table_walker,
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+            hierarchy::page_level::<S>(),
+            frame_deallocator,
+        )?;
+        let start = Page::containing_address(page.start_address());
+        Ok(MapperFlushRange::new(Page::range(start, start + 512)))
+    }
+
+    /// Tests and clears the given flags in the entries of the pages of `pages`, e.g. to find
+    /// the pages that were accessed or written since the last scan.
+    ///
+    /// Bit `i` of `bitmap` (i.e. bit `i % 64` of word `i / 64`) is set if page
+    /// `pages.start + i` had any of the flags set, and cleared otherwise. Pages that are not
+    /// mapped or that are mapped with a different page size are skipped, so their bits are
+    /// cleared too. The flags are cleared atomically, so concurrent updates by the CPU are not
+    /// lost.
+    ///
+    /// The CPU only sets the `ACCESSED` and `DIRTY` flags again if the page is not cached in
+    /// the TLB, so the returned `MapperFlushRange`, which covers all changed entries, should be
+    /// flushed before the next scan.
+    ///
+    /// Panics if `bitmap` has less than one bit per page of `pages`.
+    pub fn harvest_flags<S: PageSize>(
+        &mut self,
+        pages: PageRange<S>,
+        flags: PageTableFlags,
+        bitmap: &mut [u64],
+    ) -> MapperFlushRange<S> {
+        let changed = unsafe {
+            hierarchy::harvest_flags(
+                &self.page_table_walker,
+                self.root_table,
+                self.paging_mode,
+                pages,
+                flags,
+                bitmap,
+            )
+        };
+        MapperFlushRange::new(changed)
+    }
+
     /// Helper function for implementing Mapper. Safe to limit the scope of unsafe, see
     /// https://github.com/rust-lang/rfcs/pull/2585.
     fn map_to_1gib<A>(
         &mut self,
         page: Page<Size1GiB>,
-        frame: PhysFrame<Size1GiB>,
-        flags: PageTableFlags,
+        entry: PageTableEntry,
+        parent_table_flags: PageTableFlags,
         allocator: &mut A,
     ) -> Result<MapperFlush<Size1GiB>, MapToError>
     where
         A: FrameAllocator<Size4KiB>,
     {
-        let p4 = &mut self.level_4_table;
-        let p3 = self
-            .page_table_walker
-            .create_next_table(&mut p4[page.p4_index()], allocator)?;
+        let p4 = self.page_table_walker.create_level_4_table(
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+            parent_table_flags,
+            allocator,
+        )?;
+        let p3 = self.page_table_walker.create_next_table(
+            &mut p4[page.p4_index()],
+            parent_table_flags,
+            allocator,
+        )?;
 
         if !p3[page.p3_index()].is_unused() {
             return Err(MapToError::PageAlreadyMapped);
         }
-        p3[page.p3_index()].set_addr(frame.start_address(), flags | PageTableFlags::HUGE_PAGE);
+        p3[page.p3_index()] = entry;
 
         Ok(MapperFlush::new(page))
     }
@@ -70,25 +306,35 @@ where
     fn map_to_2mib<A>(
         &mut self,
         page: Page<Size2MiB>,
-        frame: PhysFrame<Size2MiB>,
-        flags: PageTableFlags,
+        entry: PageTableEntry,
+        parent_table_flags: PageTableFlags,
         allocator: &mut A,
     ) -> Result<MapperFlush<Size2MiB>, MapToError>
     where
         A: FrameAllocator<Size4KiB>,
     {
-        let p4 = &mut self.level_4_table;
-        let p3 = self
-            .page_table_walker
-            .create_next_table(&mut p4[page.p4_index()], allocator)?;
-        let p2 = self
-            .page_table_walker
-            .create_next_table(&mut p3[page.p3_index()], allocator)?;
+        let p4 = self.page_table_walker.create_level_4_table(
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+            parent_table_flags,
+            allocator,
+        )?;
+        let p3 = self.page_table_walker.create_next_table(
+            &mut p4[page.p4_index()],
+            parent_table_flags,
+            allocator,
+        )?;
+        let p2 = self.page_table_walker.create_next_table(
+            &mut p3[page.p3_index()],
+            parent_table_flags,
+            allocator,
+        )?;
 
         if !p2[page.p2_index()].is_unused() {
             return Err(MapToError::PageAlreadyMapped);
         }
-        p2[page.p2_index()].set_addr(frame.start_address(), flags | PageTableFlags::HUGE_PAGE);
+        p2[page.p2_index()] = entry;
 
         Ok(MapperFlush::new(page))
     }
@@ -98,55 +344,98 @@ where
     fn map_to_4kib<A>(
         &mut self,
         page: Page<Size4KiB>,
-        frame: PhysFrame<Size4KiB>,
-        flags: PageTableFlags,
+        entry: PageTableEntry,
+        parent_table_flags: PageTableFlags,
         allocator: &mut A,
     ) -> Result<MapperFlush<Size4KiB>, MapToError>
     where
         A: FrameAllocator<Size4KiB>,
     {
-        let p4 = &mut self.level_4_table;
-        let p3 = self
-            .page_table_walker
-            .create_next_table(&mut p4[page.p4_index()], allocator)?;
-        let p2 = self
-            .page_table_walker
-            .create_next_table(&mut p3[page.p3_index()], allocator)?;
-        let p1 = self
-            .page_table_walker
-            .create_next_table(&mut p2[page.p2_index()], allocator)?;
+        let p4 = self.page_table_walker.create_level_4_table(
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+            parent_table_flags,
+            allocator,
+        )?;
+        let p3 = self.page_table_walker.create_next_table(
+            &mut p4[page.p4_index()],
+            parent_table_flags,
+            allocator,
+        )?;
+        let p2 = self.page_table_walker.create_next_table(
+            &mut p3[page.p3_index()],
+            parent_table_flags,
+            allocator,
+        )?;
+        let p1 = self.page_table_walker.create_next_table(
+            &mut p2[page.p2_index()],
+            parent_table_flags,
+            allocator,
+        )?;
 
         if !p1[page.p1_index()].is_unused() {
             return Err(MapToError::PageAlreadyMapped);
         }
-        p1[page.p1_index()].set_frame(frame, flags);
+        p1[page.p1_index()] = entry;
 
         Ok(MapperFlush::new(page))
     }
 }
 
-impl<'a, PhysToVirt> Mapper<Size1GiB> for MappedPageTable<'a, PhysToVirt>
+impl<'a, P> Mapper<Size1GiB> for MappedPageTable<'a, P>
 where
-    PhysToVirt: Fn(PhysFrame) -> *mut PageTable,
+    P: PhysToVirt,
 {
-    unsafe fn map_to<A>(
+    unsafe fn map_to_with_table_flags<A>(
         &mut self,
         page: Page<Size1GiB>,
         frame: PhysFrame<Size1GiB>,
         flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
         allocator: &mut A,
     ) -> Result<MapperFlush<Size1GiB>, MapToError>
     where
         A: FrameAllocator<Size4KiB>,
     {
-        self.map_to_1gib(page, frame, flags, allocator)
+        self.map_to_1gib(
+            page,
+            hierarchy::page_entry(frame, flags),
+            parent_table_flags,
+            allocator,
+        )
+    }
+
+    unsafe fn map_to_with_memory_type<A>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame: PhysFrame<Size1GiB>,
+        flags: PageTableFlags,
+        memory_type: MemoryType,
+        pat: &PageAttributeTable,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlush<Size1GiB>, MapToError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let entry = hierarchy::memory_type_entry(frame, flags, memory_type, pat)?;
+        self.map_to_1gib(
+            page,
+            entry,
+            default_parent_table_flags(flags),
+            frame_allocator,
+        )
     }
 
     fn unmap(
         &mut self,
         page: Page<Size1GiB>,
     ) -> Result<(PhysFrame<Size1GiB>, MapperFlush<Size1GiB>), UnmapError> {
-        let p4 = &mut self.level_4_table;
+        let p4 = self.page_table_walker.level_4_table_mut(
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+        )?;
         let p3 = self
             .page_table_walker
             .next_table_mut(&mut p4[page.p4_index()])?;
@@ -161,8 +450,9 @@ where
             return Err(UnmapError::ParentEntryHugePage);
         }
 
-        let frame = PhysFrame::from_start_address(p3_entry.addr())
-            .map_err(|()| UnmapError::InvalidFrameAddress(p3_entry.addr()))?;
+        let frame = p3_entry
+            .huge_frame::<Size1GiB>()
+            .map_err(UnmapError::InvalidFrameAddress)?;
 
         p3_entry.set_unused();
         Ok((frame, MapperFlush::new(page)))
@@ -173,7 +463,11 @@ where
         page: Page<Size1GiB>,
         flags: PageTableFlags,
     ) -> Result<MapperFlush<Size1GiB>, FlagUpdateError> {
-        let p4 = &mut self.level_4_table;
+        let p4 = self.page_table_walker.level_4_table_mut(
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+        )?;
         let p3 = self
             .page_table_walker
             .next_table_mut(&mut p4[page.p4_index()])?;
@@ -186,8 +480,70 @@ where
         Ok(MapperFlush::new(page))
     }
 
+    fn update_flags_and_pat_index(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableFlags,
+        pat_index: u8,
+    ) -> Result<MapperFlush<Size1GiB>, FlagUpdateError> {
+        let entry = unsafe {
+            hierarchy::leaf_entry_mut(
+                &self.page_table_walker,
+                self.root_table,
+                self.paging_mode,
+                page.start_address(),
+                PageTableLevel::Three,
+            )?
+        };
+        hierarchy::set_flags_and_pat_index::<Size1GiB>(entry, flags, pat_index);
+
+        Ok(MapperFlush::new(page))
+    }
+
+    unsafe fn remap(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame: PhysFrame<Size1GiB>,
+        flags: PageTableFlags,
+    ) -> Result<(PhysFrame<Size1GiB>, MapperFlush<Size1GiB>), FlagUpdateError> {
+        let entry = hierarchy::leaf_entry_mut(
+            &self.page_table_walker,
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+            PageTableLevel::Three,
+        )?;
+        let old_frame = hierarchy::remap_entry(entry, frame, flags)?;
+
+        Ok((old_frame, MapperFlush::new(page)))
+    }
+
+    fn update_parent_flags(
+        &mut self,
+        page: Page<Size1GiB>,
+        level: PageTableLevel,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        unsafe {
+            hierarchy::update_parent_flags(
+                &self.page_table_walker,
+                self.root_table,
+                self.paging_mode,
+                page.start_address(),
+                PageTableLevel::Three,
+                level,
+                flags,
+            )?;
+        }
+        Ok(MapperFlushAll::new())
+    }
+
     fn translate_page(&self, page: Page<Size1GiB>) -> Result<PhysFrame<Size1GiB>, TranslateError> {
-        let p4 = &self.level_4_table;
+        let p4 = self.page_table_walker.level_4_table(
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+        )?;
         let p3 = self.page_table_walker.next_table(&p4[page.p4_index()])?;
 
         let p3_entry = &p3[page.p3_index()];
@@ -196,33 +552,65 @@ where
             return Err(TranslateError::PageNotMapped);
         }
 
-        PhysFrame::from_start_address(p3_entry.addr())
-            .map_err(|()| TranslateError::InvalidFrameAddress(p3_entry.addr()))
+        p3_entry
+            .huge_frame()
+            .map_err(TranslateError::InvalidFrameAddress)
     }
 }
 
-impl<'a, PhysToVirt> Mapper<Size2MiB> for MappedPageTable<'a, PhysToVirt>
+impl<'a, P> Mapper<Size2MiB> for MappedPageTable<'a, P>
 where
-    PhysToVirt: Fn(PhysFrame) -> *mut PageTable,
+    P: PhysToVirt,
 {
-    unsafe fn map_to<A>(
+    unsafe fn map_to_with_table_flags<A>(
         &mut self,
         page: Page<Size2MiB>,
         frame: PhysFrame<Size2MiB>,
         flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
         allocator: &mut A,
     ) -> Result<MapperFlush<Size2MiB>, MapToError>
     where
         A: FrameAllocator<Size4KiB>,
     {
-        self.map_to_2mib(page, frame, flags, allocator)
+        self.map_to_2mib(
+            page,
+            hierarchy::page_entry(frame, flags),
+            parent_table_flags,
+            allocator,
+        )
+    }
+
+    unsafe fn map_to_with_memory_type<A>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame: PhysFrame<Size2MiB>,
+        flags: PageTableFlags,
+        memory_type: MemoryType,
+        pat: &PageAttributeTable,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlush<Size2MiB>, MapToError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let entry = hierarchy::memory_type_entry(frame, flags, memory_type, pat)?;
+        self.map_to_2mib(
+            page,
+            entry,
+            default_parent_table_flags(flags),
+            frame_allocator,
+        )
     }
 
     fn unmap(
         &mut self,
         page: Page<Size2MiB>,
     ) -> Result<(PhysFrame<Size2MiB>, MapperFlush<Size2MiB>), UnmapError> {
-        let p4 = &mut self.level_4_table;
+        let p4 = self.page_table_walker.level_4_table_mut(
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+        )?;
         let p3 = self
             .page_table_walker
             .next_table_mut(&mut p4[page.p4_index()])?;
@@ -240,8 +628,9 @@ where
             return Err(UnmapError::ParentEntryHugePage);
         }
 
-        let frame = PhysFrame::from_start_address(p2_entry.addr())
-            .map_err(|()| UnmapError::InvalidFrameAddress(p2_entry.addr()))?;
+        let frame = p2_entry
+            .huge_frame::<Size2MiB>()
+            .map_err(UnmapError::InvalidFrameAddress)?;
 
         p2_entry.set_unused();
         Ok((frame, MapperFlush::new(page)))
@@ -252,7 +641,11 @@ where
         page: Page<Size2MiB>,
         flags: PageTableFlags,
     ) -> Result<MapperFlush<Size2MiB>, FlagUpdateError> {
-        let p4 = &mut self.level_4_table;
+        let p4 = self.page_table_walker.level_4_table_mut(
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+        )?;
         let p3 = self
             .page_table_walker
             .next_table_mut(&mut p4[page.p4_index()])?;
@@ -269,8 +662,70 @@ where
         Ok(MapperFlush::new(page))
     }
 
+    fn update_flags_and_pat_index(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+        pat_index: u8,
+    ) -> Result<MapperFlush<Size2MiB>, FlagUpdateError> {
+        let entry = unsafe {
+            hierarchy::leaf_entry_mut(
+                &self.page_table_walker,
+                self.root_table,
+                self.paging_mode,
+                page.start_address(),
+                PageTableLevel::Two,
+            )?
+        };
+        hierarchy::set_flags_and_pat_index::<Size2MiB>(entry, flags, pat_index);
+
+        Ok(MapperFlush::new(page))
+    }
+
+    unsafe fn remap(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame: PhysFrame<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<(PhysFrame<Size2MiB>, MapperFlush<Size2MiB>), FlagUpdateError> {
+        let entry = hierarchy::leaf_entry_mut(
+            &self.page_table_walker,
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+            PageTableLevel::Two,
+        )?;
+        let old_frame = hierarchy::remap_entry(entry, frame, flags)?;
+
+        Ok((old_frame, MapperFlush::new(page)))
+    }
+
+    fn update_parent_flags(
+        &mut self,
+        page: Page<Size2MiB>,
+        level: PageTableLevel,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        unsafe {
+            hierarchy::update_parent_flags(
+                &self.page_table_walker,
+                self.root_table,
+                self.paging_mode,
+                page.start_address(),
+                PageTableLevel::Two,
+                level,
+                flags,
+            )?;
+        }
+        Ok(MapperFlushAll::new())
+    }
+
     fn translate_page(&self, page: Page<Size2MiB>) -> Result<PhysFrame<Size2MiB>, TranslateError> {
-        let p4 = &self.level_4_table;
+        let p4 = self.page_table_walker.level_4_table(
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+        )?;
         let p3 = self.page_table_walker.next_table(&p4[page.p4_index()])?;
         let p2 = self.page_table_walker.next_table(&p3[page.p3_index()])?;
 
@@ -280,33 +735,65 @@ where
             return Err(TranslateError::PageNotMapped);
         }
 
-        PhysFrame::from_start_address(p2_entry.addr())
-            .map_err(|()| TranslateError::InvalidFrameAddress(p2_entry.addr()))
+        p2_entry
+            .huge_frame()
+            .map_err(TranslateError::InvalidFrameAddress)
     }
 }
 
-impl<'a, PhysToVirt> Mapper<Size4KiB> for MappedPageTable<'a, PhysToVirt>
+impl<'a, P> Mapper<Size4KiB> for MappedPageTable<'a, P>
 where
-    PhysToVirt: Fn(PhysFrame) -> *mut PageTable,
+    P: PhysToVirt,
 {
-    unsafe fn map_to<A>(
+    unsafe fn map_to_with_table_flags<A>(
         &mut self,
         page: Page<Size4KiB>,
         frame: PhysFrame<Size4KiB>,
         flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
         allocator: &mut A,
     ) -> Result<MapperFlush<Size4KiB>, MapToError>
     where
         A: FrameAllocator<Size4KiB>,
     {
-        self.map_to_4kib(page, frame, flags, allocator)
+        self.map_to_4kib(
+            page,
+            hierarchy::page_entry(frame, flags),
+            parent_table_flags,
+            allocator,
+        )
+    }
+
+    unsafe fn map_to_with_memory_type<A>(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+        memory_type: MemoryType,
+        pat: &PageAttributeTable,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlush<Size4KiB>, MapToError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let entry = hierarchy::memory_type_entry(frame, flags, memory_type, pat)?;
+        self.map_to_4kib(
+            page,
+            entry,
+            default_parent_table_flags(flags),
+            frame_allocator,
+        )
     }
 
     fn unmap(
         &mut self,
         page: Page<Size4KiB>,
     ) -> Result<(PhysFrame<Size4KiB>, MapperFlush<Size4KiB>), UnmapError> {
-        let p4 = &mut self.level_4_table;
+        let p4 = self.page_table_walker.level_4_table_mut(
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+        )?;
         let p3 = self
             .page_table_walker
             .next_table_mut(&mut p4[page.p4_index()])?;
@@ -333,7 +820,11 @@ where
         page: Page<Size4KiB>,
         flags: PageTableFlags,
     ) -> Result<MapperFlush<Size4KiB>, FlagUpdateError> {
-        let p4 = &mut self.level_4_table;
+        let p4 = self.page_table_walker.level_4_table_mut(
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+        )?;
         let p3 = self
             .page_table_walker
             .next_table_mut(&mut p4[page.p4_index()])?;
@@ -353,8 +844,70 @@ where
         Ok(MapperFlush::new(page))
     }
 
+    fn update_flags_and_pat_index(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+        pat_index: u8,
+    ) -> Result<MapperFlush<Size4KiB>, FlagUpdateError> {
+        let entry = unsafe {
+            hierarchy::leaf_entry_mut(
+                &self.page_table_walker,
+                self.root_table,
+                self.paging_mode,
+                page.start_address(),
+                PageTableLevel::One,
+            )?
+        };
+        hierarchy::set_flags_and_pat_index::<Size4KiB>(entry, flags, pat_index);
+
+        Ok(MapperFlush::new(page))
+    }
+
+    unsafe fn remap(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<(PhysFrame<Size4KiB>, MapperFlush<Size4KiB>), FlagUpdateError> {
+        let entry = hierarchy::leaf_entry_mut(
+            &self.page_table_walker,
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+            PageTableLevel::One,
+        )?;
+        let old_frame = hierarchy::remap_entry(entry, frame, flags)?;
+
+        Ok((old_frame, MapperFlush::new(page)))
+    }
+
+    fn update_parent_flags(
+        &mut self,
+        page: Page<Size4KiB>,
+        level: PageTableLevel,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        unsafe {
+            hierarchy::update_parent_flags(
+                &self.page_table_walker,
+                self.root_table,
+                self.paging_mode,
+                page.start_address(),
+                PageTableLevel::One,
+                level,
+                flags,
+            )?;
+        }
+        Ok(MapperFlushAll::new())
+    }
+
     fn translate_page(&self, page: Page<Size4KiB>) -> Result<PhysFrame<Size4KiB>, TranslateError> {
-        let p4 = &self.level_4_table;
+        let p4 = self.page_table_walker.level_4_table(
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+        )?;
         let p3 = self.page_table_walker.next_table(&p4[page.p4_index()])?;
         let p2 = self.page_table_walker.next_table(&p3[page.p3_index()])?;
         let p1 = self.page_table_walker.next_table(&p2[page.p2_index()])?;
@@ -370,66 +923,35 @@ where
     }
 }
 
-impl<'a, PhysToVirt> MapperAllSizes for MappedPageTable<'a, PhysToVirt>
+impl<'a, P> MapperAllSizes for MappedPageTable<'a, P>
 where
-    PhysToVirt: Fn(PhysFrame) -> *mut PageTable,
+    P: PhysToVirt,
 {
     fn translate(&self, addr: VirtAddr) -> TranslateResult {
-        let p4 = &self.level_4_table;
-        let p3 = match self.page_table_walker.next_table(&p4[addr.p4_index()]) {
-            Ok(page_table) => page_table,
-            Err(PageTableWalkError::NotMapped) => return TranslateResult::PageNotMapped,
-            Err(PageTableWalkError::MappedToHugePage) => {
-                panic!("level 4 entry has huge page bit set")
-            }
-        };
-        let p2 = match self.page_table_walker.next_table(&p3[addr.p3_index()]) {
-            Ok(page_table) => page_table,
-            Err(PageTableWalkError::NotMapped) => return TranslateResult::PageNotMapped,
-            Err(PageTableWalkError::MappedToHugePage) => {
-                let frame = PhysFrame::containing_address(p3[addr.p3_index()].addr());
-                let offset = addr.as_u64() & 0o_777_777_7777;
-                return TranslateResult::Frame1GiB { frame, offset };
-            }
-        };
-        let p1 = match self.page_table_walker.next_table(&p2[addr.p2_index()]) {
-            Ok(page_table) => page_table,
-            Err(PageTableWalkError::NotMapped) => return TranslateResult::PageNotMapped,
-            Err(PageTableWalkError::MappedToHugePage) => {
-                let frame = PhysFrame::containing_address(p2[addr.p2_index()].addr());
-                let offset = addr.as_u64() & 0o_777_7777;
-                return TranslateResult::Frame2MiB { frame, offset };
-            }
-        };
-
-        let p1_entry = &p1[addr.p1_index()];
-
-        if p1_entry.is_unused() {
-            return TranslateResult::PageNotMapped;
+        unsafe {
+            hierarchy::translate(
+                &self.page_table_walker,
+                self.root_table,
+                self.paging_mode,
+                addr,
+            )
         }
-
-        let frame = match PhysFrame::from_start_address(p1_entry.addr()) {
-            Ok(frame) => frame,
-            Err(()) => return TranslateResult::InvalidFrameAddress(p1_entry.addr()),
-        };
-        let offset = u64::from(addr.page_offset());
-        TranslateResult::Frame4KiB { frame, offset }
     }
 }
 
 #[derive(Debug)]
-struct PageTableWalker<PhysToVirt>
+struct PageTableWalker<P>
 where
-    PhysToVirt: Fn(PhysFrame) -> *mut PageTable,
+    P: PhysToVirt,
 {
-    phys_to_virt: PhysToVirt,
+    phys_to_virt: P,
 }
 
-impl<PhysToVirt> PageTableWalker<PhysToVirt>
+impl<P> PageTableWalker<P>
 where
-    PhysToVirt: Fn(PhysFrame) -> *mut PageTable,
+    P: PhysToVirt,
 {
-    pub unsafe fn new(phys_to_virt: PhysToVirt) -> Self {
+    pub unsafe fn new(phys_to_virt: P) -> Self {
         Self { phys_to_virt }
     }
 
@@ -442,7 +964,7 @@ where
         &self,
         entry: &'b PageTableEntry,
     ) -> Result<&'b PageTable, PageTableWalkError> {
-        let page_table_ptr = (self.phys_to_virt)(entry.frame()?);
+        let page_table_ptr = self.phys_to_virt.phys_to_virt(entry.frame()?);
         let page_table: &PageTable = unsafe { &*page_table_ptr };
 
         Ok(page_table)
@@ -457,17 +979,73 @@ where
         &self,
         entry: &'b mut PageTableEntry,
     ) -> Result<&'b mut PageTable, PageTableWalkError> {
-        let page_table_ptr = (self.phys_to_virt)(entry.frame()?);
+        let page_table_ptr = self.phys_to_virt.phys_to_virt(entry.frame()?);
         let page_table: &mut PageTable = unsafe { &mut *page_table_ptr };
 
         Ok(page_table)
     }
 
+    /// Internal helper function to get a reference to the level 4 table for the given address.
+    ///
+    /// With 4-level paging, this is the passed root table. With 5-level paging, the level 4
+    /// table is looked up through the entry of the root table that covers the given address.
+    fn level_4_table<'b>(
+        &self,
+        root_table: &'b PageTable,
+        paging_mode: PagingMode,
+        addr: VirtAddr,
+    ) -> Result<&'b PageTable, PageTableWalkError> {
+        match paging_mode {
+            PagingMode::Level4 => Ok(root_table),
+            PagingMode::Level5 => self.next_table(&root_table[addr.p5_index()]),
+        }
+    }
+
+    /// Internal helper function to get a mutable reference to the level 4 table for the given
+    /// address.
+    ///
+    /// See `level_4_table` for more information.
+    fn level_4_table_mut<'b>(
+        &self,
+        root_table: &'b mut PageTable,
+        paging_mode: PagingMode,
+        addr: VirtAddr,
+    ) -> Result<&'b mut PageTable, PageTableWalkError> {
+        match paging_mode {
+            PagingMode::Level4 => Ok(root_table),
+            PagingMode::Level5 => self.next_table_mut(&mut root_table[addr.p5_index()]),
+        }
+    }
+
+    /// Internal helper function to get the level 4 table for the given address, creating it if
+    /// needed.
+    ///
+    /// See `level_4_table` and `create_next_table` for more information.
+    fn create_level_4_table<'b, A>(
+        &self,
+        root_table: &'b mut PageTable,
+        paging_mode: PagingMode,
+        addr: VirtAddr,
+        insert_flags: PageTableFlags,
+        allocator: &mut A,
+    ) -> Result<&'b mut PageTable, PageTableCreateError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        match paging_mode {
+            PagingMode::Level4 => Ok(root_table),
+            PagingMode::Level5 => {
+                self.create_next_table(&mut root_table[addr.p5_index()], insert_flags, allocator)
+            }
+        }
+    }
+
     /// Internal helper function to create the page table of the next level if needed.
     ///
     /// If the passed entry is unused, a new frame is allocated from the given allocator, zeroed,
-    /// and the entry is updated to that address. If the passed entry is already mapped, the next
-    /// table is returned directly.
+    /// and the entry is updated to that address with the given `insert_flags`. If the passed
+    /// entry is already mapped, the `insert_flags` that it lacks are added and the next table is
+    /// returned directly.
     ///
     /// Returns `MapToError::FrameAllocationFailed` if the entry is unused and the allocator
     /// returned `None`. Returns `MapToError::ParentEntryHugePage` if the `HUGE_PAGE` flag is set
@@ -475,6 +1053,7 @@ where
     fn create_next_table<'b, A>(
         &self,
         entry: &'b mut PageTableEntry,
+        insert_flags: PageTableFlags,
         allocator: &mut A,
     ) -> Result<&'b mut PageTable, PageTableCreateError>
     where
@@ -484,12 +1063,16 @@ where
 
         if entry.is_unused() {
             if let Some(frame) = allocator.allocate_frame() {
-                entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+                entry.set_frame(frame, insert_flags);
                 created = true;
             } else {
                 return Err(PageTableCreateError::FrameAllocationFailed);
             }
         } else {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::HUGE_PAGE) && !flags.contains(insert_flags) {
+                entry.set_flags(flags | insert_flags);
+            }
             created = false;
         }
 
@@ -508,6 +1091,29 @@ where
     }
 }
 
+impl<P> PageTableAccess for PageTableWalker<P>
+where
+    P: PhysToVirt,
+{
+    unsafe fn next_table_ptr(
+        &self,
+        entry: &PageTableEntry,
+        _addr: VirtAddr,
+        _level: PageTableLevel,
+    ) -> *mut PageTable {
+        self.phys_to_virt
+            .phys_to_virt(PhysFrame::containing_address(entry.addr()))
+    }
+
+    fn can_access_frame_table(&self) -> bool {
+        true
+    }
+
+    unsafe fn with_frame_table(&self, frame: PhysFrame, f: &mut dyn FnMut(&mut PageTable)) {
+        f(&mut *self.phys_to_virt.phys_to_virt(frame))
+    }
+}
+
 #[derive(Debug)]
 enum PageTableWalkError {
     NotMapped,
@@ -564,3 +1170,628 @@ impl From<PageTableWalkError> for TranslateError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registers::model_specific::{MemoryType, PageAttributeTable};
+    use crate::PhysAddr;
+
+    #[test]
+    fn huge_pages_with_pat_bit() {
+        let mut frames = SimulatedPhysMemory::buffer(8);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let mut mapper = unsafe { memory.mapper(root_frame) };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(0x4000_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        unsafe { mapper.map_to(page, frame, flags, &mut memory) }
+            .unwrap()
+            .ignore();
+        mapper
+            .update_flags_and_pat_index(page, flags, 5)
+            .unwrap()
+            .ignore();
+        assert_eq!(mapper.translate_page(page).unwrap(), frame);
+        let (unmapped, flush) = mapper.unmap(page).unwrap();
+        flush.ignore();
+        assert_eq!(unmapped, frame);
+
+        let page = Page::<Size1GiB>::containing_address(VirtAddr::new(0x80_0000_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x1_0000_0000));
+        unsafe { mapper.map_to(page, frame, flags, &mut memory) }
+            .unwrap()
+            .ignore();
+        mapper
+            .update_flags_and_pat_index(page, flags, 7)
+            .unwrap()
+            .ignore();
+        assert_eq!(mapper.translate_page(page).unwrap(), frame);
+        let (unmapped, flush) = mapper.unmap(page).unwrap();
+        flush.ignore();
+        assert_eq!(unmapped, frame);
+    }
+
+    #[test]
+    fn map_with_user_accessible_parents() {
+        let mut frames = SimulatedPhysMemory::buffer(12);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let phys_to_virt = memory.phys_to_virt();
+        let mut mapper = unsafe { memory.mapper(root_frame) };
+        let table = |frame: PhysFrame| unsafe { &*phys_to_virt.phys_to_virt(frame) };
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        let kernel_parent = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let user_parent = kernel_parent | PageTableFlags::USER_ACCESSIBLE;
+        let user_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+        let kernel_page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x1000));
+        unsafe {
+            mapper.map_to_with_table_flags(
+                kernel_page,
+                frame,
+                PageTableFlags::PRESENT,
+                kernel_parent,
+                &mut memory,
+            )
+        }
+        .unwrap()
+        .ignore();
+
+        // new parent tables get exactly the given flags
+        let user_page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x80_0000_0000));
+        unsafe {
+            mapper.map_to_with_table_flags(user_page, frame, user_flags, user_parent, &mut memory)
+        }
+        .unwrap()
+        .ignore();
+        let p3 = table(table(root_frame)[1].frame().unwrap());
+        let p2 = table(p3[0].frame().unwrap());
+        assert_eq!(table(root_frame)[1].flags(), user_parent);
+        assert_eq!(p3[0].flags(), user_parent);
+        assert_eq!(p2[0].flags(), user_parent);
+
+        // existing parent entries keep their flags and gain the missing ones
+        let user_page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x2000));
+        let parent_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        unsafe {
+            mapper.map_to_with_table_flags(user_page, frame, user_flags, parent_flags, &mut memory)
+        }
+        .unwrap()
+        .ignore();
+        let p3 = table(table(root_frame)[0].frame().unwrap());
+        let p2 = table(p3[0].frame().unwrap());
+        assert_eq!(table(root_frame)[0].flags(), user_parent);
+        assert_eq!(p3[0].flags(), user_parent);
+        assert_eq!(p2[0].flags(), user_parent);
+
+        // `update_parent_flags` only changes the entry at the given level
+        let kernel_page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x4000_0000));
+        unsafe {
+            mapper.map_to_with_table_flags(
+                kernel_page,
+                frame,
+                PageTableFlags::PRESENT,
+                kernel_parent,
+                &mut memory,
+            )
+        }
+        .unwrap()
+        .ignore();
+        let p3 = table(table(root_frame)[0].frame().unwrap());
+        assert_eq!(p3[1].flags(), kernel_parent);
+        mapper
+            .update_parent_flags(kernel_page, PageTableLevel::Three, user_parent)
+            .unwrap()
+            .ignore();
+        let p3 = table(table(root_frame)[0].frame().unwrap());
+        assert_eq!(p3[1].flags(), user_parent);
+        assert_eq!(table(p3[1].frame().unwrap())[0].flags(), kernel_parent);
+    }
+
+    #[test]
+    fn map_and_unmap_ranges() {
+        let mut frames = SimulatedPhysMemory::buffer(12);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let mut mapper = unsafe { memory.mapper(root_frame) };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let page = |addr| Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+        let frame = |addr| PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(addr));
+
+        // the page at 0x4000_3000 is in the way, so the first three pages are unmapped again
+        unsafe { mapper.map_to(page(0x4000_3000), frame(0x9000_0000), flags, &mut memory) }
+            .unwrap()
+            .ignore();
+        let pages = Page::range(page(0x4000_0000), page(0x4000_5000));
+        let data_frames = PhysFrame::range(frame(0x8000_0000), frame(0x8000_5000));
+        match unsafe { mapper.map_range(pages, data_frames, flags, &mut memory) } {
+            Err(MapToError::PageAlreadyMapped) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        for page in Page::range(page(0x4000_0000), page(0x4000_3000)) {
+            match mapper.translate_page(page) {
+                Err(TranslateError::PageNotMapped) => {}
+                other => panic!("unexpected translation {:?}", other),
+            }
+        }
+        assert_eq!(
+            mapper.translate_page(page(0x4000_3000)).unwrap(),
+            frame(0x9000_0000)
+        );
+        mapper.unmap(page(0x4000_3000)).unwrap().1.ignore();
+
+        let flush = unsafe { mapper.map_range(pages, data_frames, flags, &mut memory) }.unwrap();
+        assert_eq!(flush.pages(), pages);
+        flush.ignore();
+        for (page, data_frame) in pages.zip(data_frames) {
+            assert_eq!(mapper.translate_page(page).unwrap(), data_frame);
+        }
+
+        // updating or unmapping a range with an unmapped page changes nothing
+        mapper.unmap(page(0x4000_2000)).unwrap().1.ignore();
+        let read_only = PageTableFlags::PRESENT;
+        match mapper.update_flags_range(pages, read_only) {
+            Err(FlagUpdateError::PageNotMapped) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match mapper.unmap_range(pages, &mut memory) {
+            Err(UnmapError::PageNotMapped) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        for mapped in pages.filter(|&mapped| mapped != page(0x4000_2000)) {
+            match mapper.translate(mapped.start_address()) {
+                TranslateResult::Frame4KiB {
+                    flags: mapped_flags,
+                    ..
+                } => assert_eq!(mapped_flags, flags),
+                other => panic!("unexpected translation {:?}", other),
+            }
+        }
+
+        let pages = Page::range(page(0x4000_0000), page(0x4000_2000));
+        mapper
+            .update_flags_range(pages, read_only)
+            .unwrap()
+            .ignore();
+        for page in pages {
+            match mapper.translate(page.start_address()) {
+                TranslateResult::Frame4KiB {
+                    flags: mapped_flags,
+                    ..
+                } => assert_eq!(mapped_flags, read_only),
+                other => panic!("unexpected translation {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn unmap_range_deallocates_frames() {
+        let mut frames = SimulatedPhysMemory::buffer(8);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let mut mapper = unsafe { memory.mapper(root_frame) };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+        let first = memory.allocate_frame().unwrap();
+        memory.allocate_frame().unwrap();
+        memory.allocate_frame().unwrap();
+        let data_frames = PhysFrame::range(first, first + 3);
+        let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x4000_0000));
+        let pages = Page::range(start_page, start_page + 3);
+        unsafe { mapper.map_range(pages, data_frames, flags, &mut memory) }
+            .unwrap()
+            .ignore();
+        // the root table, the three data frames and three parent tables
+        assert_eq!(memory.allocated_frames(), 7);
+
+        mapper.unmap_range(pages, &mut memory).unwrap().ignore();
+        assert_eq!(memory.allocated_frames(), 4);
+        for page in pages {
+            match mapper.translate_page(page) {
+                Err(TranslateError::PageNotMapped) => {}
+                other => panic!("unexpected translation {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn map_with_memory_type() {
+        let mut frames = SimulatedPhysMemory::buffer(8);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let mut mapper = unsafe { memory.mapper(root_frame) };
+        let mut pat = PageAttributeTable::DEFAULT;
+        pat.set_entry(5, MemoryType::WriteCombining);
+
+        // frame 0 with only the `PRESENT` flag makes an entry whose other bits are all zero
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x4000_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0));
+        let flags = PageTableFlags::PRESENT;
+        unsafe {
+            mapper.map_to_with_memory_type(
+                page,
+                frame,
+                flags,
+                MemoryType::WriteCombining,
+                &pat,
+                &mut memory,
+            )
+        }
+        .unwrap()
+        .ignore();
+        match mapper.translate(page.start_address()) {
+            TranslateResult::Frame4KiB {
+                frame: mapped,
+                flags,
+                ..
+            } => {
+                assert_eq!(mapped, frame);
+                // bit 7 is the PAT bit of a 4KiB entry
+                assert_eq!(
+                    flags,
+                    PageTableFlags::PRESENT
+                        | PageTableFlags::WRITE_THROUGH
+                        | PageTableFlags::HUGE_PAGE
+                );
+            }
+            other => panic!("unexpected translation {:?}", other),
+        }
+
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(0x8000_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x20_0000));
+        unsafe {
+            mapper.map_to_with_memory_type(
+                page,
+                frame,
+                flags,
+                MemoryType::WriteCombining,
+                &pat,
+                &mut memory,
+            )
+        }
+        .unwrap()
+        .ignore();
+        assert_eq!(mapper.translate_page(page).unwrap(), frame);
+        match mapper.translate(page.start_address()) {
+            TranslateResult::Frame2MiB { flags, .. } => assert_eq!(
+                flags,
+                PageTableFlags::PRESENT | PageTableFlags::WRITE_THROUGH | PageTableFlags::HUGE_PAGE
+            ),
+            other => panic!("unexpected translation {:?}", other),
+        }
+
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x4000_1000));
+        match unsafe {
+            mapper.map_to_with_memory_type(
+                page,
+                PhysFrame::containing_address(PhysAddr::new(0x1000)),
+                flags,
+                MemoryType::WriteProtected,
+                &pat,
+                &mut memory,
+            )
+        } {
+            Err(MapToError::MemoryTypeUnavailable) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn remap_page() {
+        let mut frames = SimulatedPhysMemory::buffer(8);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let mut mapper = unsafe { memory.mapper(root_frame) };
+
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x4000_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        let new_frame = PhysFrame::containing_address(PhysAddr::new(0x9000_0000));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { mapper.remap(page, new_frame, flags) } {
+            Err(FlagUpdateError::PageNotMapped) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        unsafe { mapper.map_to(page, frame, PageTableFlags::PRESENT, &mut memory) }
+            .unwrap()
+            .ignore();
+
+        let (old_frame, flush) = unsafe { mapper.remap(page, new_frame, flags) }.unwrap();
+        flush.ignore();
+        assert_eq!(old_frame, frame);
+        match mapper.translate(page.start_address()) {
+            TranslateResult::Frame4KiB {
+                frame: mapped,
+                flags: mapped_flags,
+                ..
+            } => {
+                assert_eq!(mapped, new_frame);
+                assert_eq!(mapped_flags, flags);
+            }
+            other => panic!("unexpected translation {:?}", other),
+        }
+    }
+
+    /// A mapper that uses the default implementation of `Mapper::remap`.
+    struct DefaultRemap<'a>(MappedPageTable<'a, SimulatedPhysToVirt<'a>>);
+
+    impl<'a> Mapper<Size4KiB> for DefaultRemap<'a> {
+        unsafe fn map_to_with_table_flags<A>(
+            &mut self,
+            page: Page<Size4KiB>,
+            frame: PhysFrame<Size4KiB>,
+            flags: PageTableFlags,
+            parent_table_flags: PageTableFlags,
+            frame_allocator: &mut A,
+        ) -> Result<MapperFlush<Size4KiB>, MapToError>
+        where
+            A: FrameAllocator<Size4KiB>,
+        {
+            self.0
+                .map_to_with_table_flags(page, frame, flags, parent_table_flags, frame_allocator)
+        }
+
+        fn unmap(
+            &mut self,
+            page: Page<Size4KiB>,
+        ) -> Result<(PhysFrame<Size4KiB>, MapperFlush<Size4KiB>), UnmapError> {
+            self.0.unmap(page)
+        }
+
+        fn update_flags(
+            &mut self,
+            page: Page<Size4KiB>,
+            flags: PageTableFlags,
+        ) -> Result<MapperFlush<Size4KiB>, FlagUpdateError> {
+            self.0.update_flags(page, flags)
+        }
+
+        fn update_parent_flags(
+            &mut self,
+            page: Page<Size4KiB>,
+            level: PageTableLevel,
+            flags: PageTableFlags,
+        ) -> Result<MapperFlushAll, FlagUpdateError> {
+            self.0.update_parent_flags(page, level, flags)
+        }
+
+        fn translate_page(
+            &self,
+            page: Page<Size4KiB>,
+        ) -> Result<PhysFrame<Size4KiB>, TranslateError> {
+            self.0.translate_page(page)
+        }
+
+        fn update_flags_and_pat_index(
+            &mut self,
+            page: Page<Size4KiB>,
+            flags: PageTableFlags,
+            pat_index: u8,
+        ) -> Result<MapperFlush<Size4KiB>, FlagUpdateError> {
+            self.0.update_flags_and_pat_index(page, flags, pat_index)
+        }
+
+        unsafe fn map_to_with_memory_type<A>(
+            &mut self,
+            page: Page<Size4KiB>,
+            frame: PhysFrame<Size4KiB>,
+            flags: PageTableFlags,
+            memory_type: MemoryType,
+            pat: &PageAttributeTable,
+            frame_allocator: &mut A,
+        ) -> Result<MapperFlush<Size4KiB>, MapToError>
+        where
+            A: FrameAllocator<Size4KiB>,
+        {
+            self.0
+                .map_to_with_memory_type(page, frame, flags, memory_type, pat, frame_allocator)
+        }
+    }
+
+    #[test]
+    fn default_remap() {
+        let mut frames = SimulatedPhysMemory::buffer(8);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let mut mapper = DefaultRemap(unsafe { memory.mapper(root_frame) });
+
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x4000_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        let new_frame = PhysFrame::containing_address(PhysAddr::new(0x9000_0000));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { mapper.remap(page, new_frame, flags) } {
+            Err(FlagUpdateError::PageNotMapped) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        unsafe { mapper.map_to(page, frame, PageTableFlags::PRESENT, &mut memory) }
+            .unwrap()
+            .ignore();
+        let allocated_frames = memory.allocated_frames();
+
+        let (old_frame, flush) = unsafe { mapper.remap(page, new_frame, flags) }.unwrap();
+        flush.ignore();
+        assert_eq!(old_frame, frame);
+        assert_eq!(memory.allocated_frames(), allocated_frames);
+        match mapper.0.translate(page.start_address()) {
+            TranslateResult::Frame4KiB {
+                frame: mapped,
+                flags: mapped_flags,
+                ..
+            } => {
+                assert_eq!(mapped, new_frame);
+                assert_eq!(mapped_flags, flags);
+            }
+            other => panic!("unexpected translation {:?}", other),
+        }
+    }
+
+    #[test]
+    fn map_huge_pages_with_5_level_paging() {
+        let mut frames = SimulatedPhysMemory::buffer(8);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let root_table = unsafe { &mut *memory.phys_to_virt().phys_to_virt(root_frame) };
+        let mut mapper = unsafe {
+            MappedPageTable::with_paging_mode(root_table, memory.phys_to_virt(), PagingMode::Level5)
+        };
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new_la57(0x0100_0000_0020_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x4020_0000));
+        unsafe { mapper.map_to(page, frame, flags, &mut memory) }
+            .unwrap()
+            .ignore();
+        let page = Page::<Size1GiB>::containing_address(VirtAddr::new_la57(0xff00_0000_4000_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        unsafe { mapper.map_to(page, frame, flags, &mut memory) }
+            .unwrap()
+            .ignore();
+
+        match mapper.translate(VirtAddr::new_la57(0x0100_0000_0021_2345)) {
+            TranslateResult::Frame2MiB { offset, .. } => assert_eq!(offset, 0x1_2345),
+            other => panic!("unexpected translation {:?}", other),
+        }
+        assert_eq!(
+            mapper.translate_addr(VirtAddr::new_la57(0xff00_0000_4123_4567)),
+            Some(PhysAddr::new(0x8123_4567))
+        );
+        assert_eq!(mapper.mappings().count(), 2);
+    }
+
+    #[test]
+    fn split_and_merge_huge_page() {
+        let mut frames = SimulatedPhysMemory::buffer(8);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let mut mapper = unsafe { memory.mapper(root_frame) };
+
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(0x4000_0000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe { mapper.map_to(page, frame, flags, &mut memory) }
+            .unwrap()
+            .ignore();
+        mapper
+            .update_flags_and_pat_index(page, flags, 5)
+            .unwrap()
+            .ignore();
+
+        let flush = unsafe { mapper.split_huge_page(page, &mut memory) }.unwrap();
+        assert_eq!(flush.pages().count(), 512);
+        flush.ignore();
+        assert_eq!(memory.allocated_frames(), 4);
+        match mapper.translate(VirtAddr::new(0x4000_3123)) {
+            TranslateResult::Frame4KiB { frame, offset, .. } => {
+                assert_eq!(frame.start_address(), PhysAddr::new(0x8000_3000));
+                assert_eq!(offset, 0x123);
+            }
+            other => panic!("unexpected translation {:?}", other),
+        }
+
+        unsafe { mapper.merge_huge_page(page, &mut memory) }
+            .unwrap()
+            .ignore();
+        assert_eq!(memory.allocated_frames(), 3);
+        match mapper.translate(VirtAddr::new(0x4000_3123)) {
+            TranslateResult::Frame2MiB { frame, offset, .. } => {
+                assert_eq!(frame.start_address(), PhysAddr::new(0x8000_0000));
+                assert_eq!(offset, 0x3123);
+            }
+            other => panic!("unexpected translation {:?}", other),
+        }
+        assert!(unsafe { mapper.merge_huge_page(page, &mut memory) }.is_err());
+    }
+
+    #[test]
+    fn parent_table_flags() {
+        let mut frames = SimulatedPhysMemory::buffer(8);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let mut mapper = unsafe { memory.mapper(root_frame) };
+        let phys_to_virt = memory.phys_to_virt();
+        let root_flags = || unsafe { &*phys_to_virt.phys_to_virt(root_frame) }[0].flags();
+
+        let kernel_page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x1000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        unsafe { mapper.map_to(kernel_page, frame, PageTableFlags::PRESENT, &mut memory) }
+            .unwrap()
+            .ignore();
+        let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        assert_eq!(root_flags(), parent_flags);
+
+        // mapping a user page below the same tables adds the user flag to all parents
+        let user_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let user_page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x2000));
+        unsafe { mapper.map_to(user_page, frame, user_flags, &mut memory) }
+            .unwrap()
+            .ignore();
+        assert_eq!(root_flags(), parent_flags | PageTableFlags::USER_ACCESSIBLE);
+
+        mapper
+            .update_parent_flags(kernel_page, PageTableLevel::Four, parent_flags)
+            .unwrap()
+            .ignore();
+        assert_eq!(root_flags(), parent_flags);
+        mapper
+            .update_parent_flags(user_page, PageTableLevel::Two, PageTableFlags::PRESENT)
+            .unwrap()
+            .ignore();
+        match mapper.translate(user_page.start_address()) {
+            TranslateResult::Frame4KiB { flags, .. } => assert_eq!(flags, user_flags),
+            other => panic!("unexpected translation {:?}", other),
+        }
+    }
+
+    #[test]
+    fn harvest_accessed_and_dirty_flags() {
+        let mut frames = SimulatedPhysMemory::buffer(8);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let mut mapper = unsafe { memory.mapper(root_frame) };
+
+        let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x10_0000));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        for (i, &extra) in [
+            PageTableFlags::empty(),
+            PageTableFlags::ACCESSED,
+            PageTableFlags::ACCESSED | PageTableFlags::DIRTY,
+        ]
+        .iter()
+        .enumerate()
+        {
+            let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000 + i as u64 * 4096));
+            unsafe { mapper.map_to(start_page + i as u64, frame, flags | extra, &mut memory) }
+                .unwrap()
+                .ignore();
+        }
+
+        let pages = Page::range(start_page, start_page + 70);
+        let mut bitmap = [!0; 2];
+        let flush = mapper.harvest_flags(pages, PageTableFlags::DIRTY, &mut bitmap);
+        assert_eq!(bitmap, [0b100, 0]);
+        assert_eq!(flush.pages(), Page::range(start_page + 2, start_page + 3));
+        flush.ignore();
+
+        let flush = mapper.harvest_flags(
+            pages,
+            PageTableFlags::ACCESSED | PageTableFlags::DIRTY,
+            &mut bitmap,
+        );
+        assert_eq!(bitmap, [0b110, 0]);
+        assert_eq!(flush.pages(), Page::range(start_page + 1, start_page + 3));
+        flush.ignore();
+
+        let flush = mapper.harvest_flags(pages, PageTableFlags::ACCESSED, &mut bitmap);
+        assert_eq!(bitmap, [0, 0]);
+        assert_eq!(flush.pages().start, flush.pages().end);
+        flush.ignore();
+        match mapper.translate(start_page.start_address() + 0x2000u64) {
+            TranslateResult::Frame4KiB {
+                flags: entry_flags, ..
+            } => assert_eq!(entry_flags, flags),
+            other => panic!("unexpected translation {:?}", other),
+        }
+    }
+}
@@ -1,17 +1,32 @@
 //! Abstractions for reading and modifying the mapping of pages.
 
-pub use self::mapped_page_table::MappedPageTable;
+pub use self::address_space::{AddressSpace, AddressSpaceError, CowFaultError, COPY_ON_WRITE};
+pub use self::audit::{AuditFinding, AuditPolicy};
+pub use self::dump::PageTableDump;
+pub use self::mapped_page_table::{MappedPageTable, PhysToVirt};
+pub use self::offset_page_table::OffsetPageTable;
 #[cfg(target_arch = "x86_64")]
-pub use self::recursive_page_table::RecursivePageTable;
+pub use self::recursive_page_table::{InactiveTableError, RecursivePageTable};
+pub use self::simulated_memory::{SimulatedPhysMemory, SimulatedPhysToVirt};
 
+use crate::registers::model_specific::{MemoryType, PageAttributeTable};
 use crate::structures::paging::{
-    frame_alloc::FrameAllocator, page_table::PageTableFlags, Page, PageSize, PhysFrame, Size1GiB,
-    Size2MiB, Size4KiB,
+    frame::PhysFrameRange,
+    frame_alloc::{FrameAllocator, FrameDeallocator},
+    page::PageRange,
+    page_table::{PageTableFlags, PageTableLevel},
+    HugePageSize, Page, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
 };
 use crate::{PhysAddr, VirtAddr};
 
+mod address_space;
+mod audit;
+mod dump;
+mod hierarchy;
 mod mapped_page_table;
+mod offset_page_table;
 mod recursive_page_table;
+mod simulated_memory;
 
 /// This trait defines page table operations that work for all page sizes of the x86_64
 /// architecture.
@@ -33,10 +48,16 @@ pub trait MapperAllSizes: Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB>
     /// [`translate`](MapperAllSizes::translate) method.
     fn translate_addr(&self, addr: VirtAddr) -> Option<PhysAddr> {
         match self.translate(addr) {
-            TranslateResult::PageNotMapped | TranslateResult::InvalidFrameAddress(_) => None,
-            TranslateResult::Frame4KiB { frame, offset } => Some(frame.start_address() + offset),
-            TranslateResult::Frame2MiB { frame, offset } => Some(frame.start_address() + offset),
-            TranslateResult::Frame1GiB { frame, offset } => Some(frame.start_address() + offset),
+            TranslateResult::PageNotMapped { .. } | TranslateResult::InvalidFrameAddress(_) => None,
+            TranslateResult::Frame4KiB { frame, offset, .. } => {
+                Some(frame.start_address() + offset)
+            }
+            TranslateResult::Frame2MiB { frame, offset, .. } => {
+                Some(frame.start_address() + offset)
+            }
+            TranslateResult::Frame1GiB { frame, offset, .. } => {
+                Some(frame.start_address() + offset)
+            }
         }
     }
 }
@@ -45,6 +66,11 @@ pub trait MapperAllSizes: Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB>
 ///
 /// If the given address has a valid mapping, a `Frame4KiB`, `Frame2MiB`, or `Frame1GiB` variant
 /// is returned, depending on the size of the mapped page. The remaining variants indicate errors.
+///
+/// The `flags` of a mapping are the flags of the page table entry that maps the page. The
+/// `effective_flags` additionally take the parent entries into account: a page is only
+/// `WRITABLE` and `USER_ACCESSIBLE` if all entries on the path have these flags set, and it is
+/// `NO_EXECUTE` if any entry on the path has this flag set.
 #[derive(Debug)]
 pub enum TranslateResult {
     /// The page is mapped to a physical frame of size 4KiB.
@@ -53,6 +79,10 @@ pub enum TranslateResult {
         frame: PhysFrame<Size4KiB>,
         /// The offset whithin the mapped frame.
         offset: u64,
+        /// The flags of the level 1 entry.
+        flags: PageTableFlags,
+        /// The flags of the entry combined with the restrictive flags of the parent entries.
+        effective_flags: PageTableFlags,
     },
     /// The page is mapped to a physical frame of size 2MiB.
     Frame2MiB {
@@ -60,31 +90,161 @@ pub enum TranslateResult {
         frame: PhysFrame<Size2MiB>,
         /// The offset whithin the mapped frame.
         offset: u64,
+        /// The flags of the level 2 entry.
+        flags: PageTableFlags,
+        /// The flags of the entry combined with the restrictive flags of the parent entries.
+        effective_flags: PageTableFlags,
     },
-    /// The page is mapped to a physical frame of size 2MiB.
+    /// The page is mapped to a physical frame of size 1GiB.
     Frame1GiB {
         /// The mapped frame.
         frame: PhysFrame<Size1GiB>,
         /// The offset whithin the mapped frame.
         offset: u64,
+        /// The flags of the level 3 entry.
+        flags: PageTableFlags,
+        /// The flags of the entry combined with the restrictive flags of the parent entries.
+        effective_flags: PageTableFlags,
     },
     /// The given page is not mapped to a physical frame.
-    PageNotMapped,
+    PageNotMapped {
+        /// The level of the table whose entry for the address is not present.
+        level: PageTableLevel,
+    },
     /// The page table entry for the given page points to an invalid physical address.
     InvalidFrameAddress(PhysAddr),
 }
 
+impl TranslateResult {
+    /// Returns the level at which the translation stopped.
+    ///
+    /// For a mapped page this is the level of the entry that maps the page, i.e. level 1 for
+    /// 4KiB pages, level 2 for 2MiB pages and level 3 for 1GiB pages.
+    pub fn level(&self) -> PageTableLevel {
+        match self {
+            TranslateResult::Frame4KiB { .. } => PageTableLevel::One,
+            TranslateResult::Frame2MiB { .. } => PageTableLevel::Two,
+            TranslateResult::Frame1GiB { .. } => PageTableLevel::Three,
+            TranslateResult::PageNotMapped { level } => *level,
+            TranslateResult::InvalidFrameAddress(_) => PageTableLevel::One,
+        }
+    }
+
+    /// Returns the flags of the entry that maps the page or `None` if the page is not mapped.
+    pub fn flags(&self) -> Option<PageTableFlags> {
+        match self {
+            TranslateResult::Frame4KiB { flags, .. }
+            | TranslateResult::Frame2MiB { flags, .. }
+            | TranslateResult::Frame1GiB { flags, .. } => Some(*flags),
+            TranslateResult::PageNotMapped { .. } | TranslateResult::InvalidFrameAddress(_) => None,
+        }
+    }
+
+    /// Returns the effective flags of the page or `None` if the page is not mapped.
+    pub fn effective_flags(&self) -> Option<PageTableFlags> {
+        match self {
+            TranslateResult::Frame4KiB {
+                effective_flags, ..
+            }
+            | TranslateResult::Frame2MiB {
+                effective_flags, ..
+            }
+            | TranslateResult::Frame1GiB {
+                effective_flags, ..
+            } => Some(*effective_flags),
+            TranslateResult::PageNotMapped { .. } | TranslateResult::InvalidFrameAddress(_) => None,
+        }
+    }
+}
+
+/// A present page of a page table hierarchy, as returned by the `mappings` methods of the
+/// mapper types.
+///
+/// There is a variant for each page size, so that huge pages are reported as a single page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappedPage {
+    /// A page of size 4KiB.
+    Page4KiB {
+        /// The mapped page.
+        page: Page<Size4KiB>,
+        /// The frame that the page is mapped to.
+        frame: PhysFrame<Size4KiB>,
+        /// The flags of the page table entry.
+        flags: PageTableFlags,
+    },
+    /// A huge page of size 2MiB.
+    Page2MiB {
+        /// The mapped page.
+        page: Page<Size2MiB>,
+        /// The frame that the page is mapped to.
+        frame: PhysFrame<Size2MiB>,
+        /// The flags of the page table entry.
+        flags: PageTableFlags,
+    },
+    /// A huge page of size 1GiB.
+    Page1GiB {
+        /// The mapped page.
+        page: Page<Size1GiB>,
+        /// The frame that the page is mapped to.
+        frame: PhysFrame<Size1GiB>,
+        /// The flags of the page table entry.
+        flags: PageTableFlags,
+    },
+}
+
+impl MappedPage {
+    /// Returns the start address of the mapped page.
+    pub fn start_address(&self) -> VirtAddr {
+        match self {
+            MappedPage::Page4KiB { page, .. } => page.start_address(),
+            MappedPage::Page2MiB { page, .. } => page.start_address(),
+            MappedPage::Page1GiB { page, .. } => page.start_address(),
+        }
+    }
+
+    /// Returns the start address of the frame that the page is mapped to.
+    pub fn frame_start_address(&self) -> PhysAddr {
+        match self {
+            MappedPage::Page4KiB { frame, .. } => frame.start_address(),
+            MappedPage::Page2MiB { frame, .. } => frame.start_address(),
+            MappedPage::Page1GiB { frame, .. } => frame.start_address(),
+        }
+    }
+
+    /// Returns the flags of the page table entry that maps the page.
+    pub fn flags(&self) -> PageTableFlags {
+        match self {
+            MappedPage::Page4KiB { flags, .. }
+            | MappedPage::Page2MiB { flags, .. }
+            | MappedPage::Page1GiB { flags, .. } => *flags,
+        }
+    }
+
+    /// Returns the size of the page in bytes.
+    pub fn size(&self) -> u64 {
+        match self {
+            MappedPage::Page4KiB { .. } => Size4KiB::SIZE,
+            MappedPage::Page2MiB { .. } => Size2MiB::SIZE,
+            MappedPage::Page1GiB { .. } => Size1GiB::SIZE,
+        }
+    }
+}
+
 /// A trait for common page table operations on pages of size `S`.
 pub trait Mapper<S: PageSize> {
     /// Creates a new mapping in the page table.
     ///
     /// This function might need additional physical frames to create new page tables. These
-    /// frames are allocated from the `allocator` argument. At most three frames are required.
+    /// frames are allocated from the `allocator` argument. At most three frames are required
+    /// (four with 5-level paging).
     ///
     /// This function is unsafe because the caller must guarantee the following:
     ///
     /// - The passed `frame` must be unused, i.e. not used for any other mappings.
     /// - The passed `frame_allocator` must only yield unused frames.
+    ///
+    /// Newly created parent tables are mapped `PRESENT | WRITABLE`, plus `USER_ACCESSIBLE` if
+    /// `flags` contains it. Use `map_to_with_table_flags` to choose the parent table flags.
     unsafe fn map_to<A>(
         &mut self,
         page: Page<S>,
@@ -92,6 +252,35 @@ pub trait Mapper<S: PageSize> {
         flags: PageTableFlags,
         frame_allocator: &mut A,
     ) -> Result<MapperFlush<S>, MapToError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let parent_table_flags = default_parent_table_flags(flags);
+        self.map_to_with_table_flags(page, frame, flags, parent_table_flags, frame_allocator)
+    }
+
+    /// Creates a new mapping in the page table and uses the given flags for the parent table
+    /// entries on the path to the page.
+    ///
+    /// Newly created parent table entries get exactly `parent_table_flags`. Existing parent
+    /// entries keep their flags, but the flags of `parent_table_flags` that they lack are added,
+    /// so that e.g. a `USER_ACCESSIBLE` page is reachable from ring 3 even if it shares a parent
+    /// table with kernel pages. `parent_table_flags` must contain `PRESENT`.
+    ///
+    /// A `RecursivePageTable` zeroes and modifies the page tables through the parent entries, so
+    /// it returns `MapToError::ParentEntryNotWritable` if a parent entry wouldn't have the
+    /// `WRITABLE` flag afterwards. Thus `parent_table_flags` must contain `WRITABLE` for it
+    /// unless all parent entries exist and are writable.
+    ///
+    /// This function is unsafe for the same reasons as `map_to`.
+    unsafe fn map_to_with_table_flags<A>(
+        &mut self,
+        page: Page<S>,
+        frame: PhysFrame<S>,
+        flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlush<S>, MapToError>
     where
         A: FrameAllocator<Size4KiB>;
 
@@ -107,12 +296,114 @@ pub trait Mapper<S: PageSize> {
         flags: PageTableFlags,
     ) -> Result<MapperFlush<S>, FlagUpdateError>;
 
+    /// Sets the flags of the parent table entry at `level` on the path to the given page.
+    ///
+    /// `level` must be higher than the level of the entries that map pages of size `S` and
+    /// must not be higher than the top level of the hierarchy, e.g. `PageTableLevel::Two` to
+    /// `PageTableLevel::Four` for 4KiB pages with 4-level paging. The flags apply to all pages
+    /// that are mapped through the entry, so a `MapperFlushAll` is returned. With a
+    /// `RecursivePageTable`, they also apply to the recursive mapping of the page tables below
+    /// the entry, so `WRITABLE` must stay set for the mapper to be able to modify them.
+    ///
+    /// Panics if `level` is invalid or if `flags` contains `HUGE_PAGE`.
+    fn update_parent_flags(
+        &mut self,
+        page: Page<S>,
+        level: PageTableLevel,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError>;
+
     /// Return the frame that the specified page is mapped to.
     ///
     /// This function assumes that the page is mapped to a frame of size `S` and returns an
     /// error otherwise.
     fn translate_page(&self, page: Page<S>) -> Result<PhysFrame<S>, TranslateError>;
 
+    /// Updates the flags of an existing mapping and selects the given entry of the page
+    /// attribute table for it.
+    ///
+    /// The PAT index is encoded in the `WRITE_THROUGH` and `NO_CACHE` flags and the PAT bit of
+    /// the entry, which is at a different position for 4KiB pages and huge pages (see
+    /// `PageTableEntry::set_pat_index`). The `WRITE_THROUGH` and `NO_CACHE` flags of the given
+    /// `flags` are ignored. Panics if the index is not smaller than 8.
+    fn update_flags_and_pat_index(
+        &mut self,
+        page: Page<S>,
+        flags: PageTableFlags,
+        pat_index: u8,
+    ) -> Result<MapperFlush<S>, FlagUpdateError>;
+
+    /// Maps an already mapped page to the given frame with the given flags and returns the
+    /// previously mapped frame.
+    ///
+    /// The mapper types of this crate replace the page table entry with a single write, so the
+    /// page stays mapped the whole time, e.g. when a copy-on-write page is replaced with its
+    /// copy. Like for `update_flags`, the flags are written as they are. The PAT bit of a huge
+    /// page is kept.
+    ///
+    /// The default implementation unmaps the page and maps it again through
+    /// `map_to_with_table_flags`, so the page is briefly unmapped and the PAT bit of a huge page
+    /// is cleared. It panics if the page can't be mapped again.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed `frame` is
+    /// unused, i.e. not used for any other mappings.
+    unsafe fn remap(
+        &mut self,
+        page: Page<S>,
+        frame: PhysFrame<S>,
+        flags: PageTableFlags,
+    ) -> Result<(PhysFrame<S>, MapperFlush<S>), FlagUpdateError> {
+        let old_frame = match self.unmap(page) {
+            Ok((old_frame, flush)) => {
+                // the returned flush for the new mapping covers the same page
+                flush.ignore();
+                old_frame
+            }
+            Err(UnmapError::ParentEntryHugePage) => {
+                return Err(FlagUpdateError::ParentEntryHugePage)
+            }
+            Err(UnmapError::PageNotMapped) | Err(UnmapError::InvalidFrameAddress(_)) => {
+                return Err(FlagUpdateError::PageNotMapped)
+            }
+        };
+        // the parent tables of the page still exist, so no frames are allocated
+        let flush = self
+            .map_to_with_table_flags(
+                page,
+                frame,
+                flags,
+                PageTableFlags::PRESENT,
+                &mut NoFrameAllocator,
+            )
+            .expect("failed to map the page again after unmapping it");
+        Ok((old_frame, flush))
+    }
+
+    /// Creates a new mapping that uses the given memory type, e.g. write combining for a
+    /// framebuffer.
+    ///
+    /// The memory type is selected through the first entry of the given page attribute table
+    /// that has this type, so `pat` must match the content of the `IA32_PAT` register. Returns
+    /// `MapToError::MemoryTypeUnavailable` if the table does not contain the memory type. The
+    /// `WRITE_THROUGH` and `NO_CACHE` flags of the given `flags` are ignored.
+    ///
+    /// The entry is written once with its final frame, flags and PAT index, so that the page is
+    /// never accessible with a wrong memory type. Parent tables are created with the flags that
+    /// `map_to` uses.
+    ///
+    /// This function is unsafe for the same reasons as `map_to`.
+    unsafe fn map_to_with_memory_type<A>(
+        &mut self,
+        page: Page<S>,
+        frame: PhysFrame<S>,
+        flags: PageTableFlags,
+        memory_type: MemoryType,
+        pat: &PageAttributeTable,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlush<S>, MapToError>
+    where
+        A: FrameAllocator<Size4KiB>;
+
     /// Maps the given frame to the virtual page with the same address.
     ///
     /// This function is unsafe because the caller must guarantee the following:
@@ -133,6 +424,111 @@ pub trait Mapper<S: PageSize> {
         let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
         self.map_to(page, frame, flags, frame_allocator)
     }
+
+    /// Maps the given range of pages to the given range of frames.
+    ///
+    /// The first page is mapped to the first frame, the second page to the second frame, and so
+    /// on. Panics if the two ranges have a different length.
+    ///
+    /// If one of the mappings can't be created, the mappings that were already created by this
+    /// call are removed again and the error is returned.
+    ///
+    /// This function is unsafe for the same reasons as `map_to`.
+    unsafe fn map_range<A>(
+        &mut self,
+        pages: PageRange<S>,
+        frames: PhysFrameRange<S>,
+        flags: PageTableFlags,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange<S>, MapToError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        assert_eq!(
+            range_len(pages.start, pages.end),
+            range_len(frames.start, frames.end),
+            "page and frame range must have the same length"
+        );
+
+        for (page, frame) in pages.zip(frames) {
+            match self.map_to(page, frame, flags, frame_allocator) {
+                Ok(flush) => flush.ignore(), // flushed as part of the returned range
+                Err(err) => {
+                    for mapped_page in Page::range(pages.start, page) {
+                        if let Ok((_, flush)) = self.unmap(mapped_page) {
+                            // the mapping was never visible to the caller
+                            flush.ignore();
+                        }
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(MapperFlushRange::new(pages))
+    }
+
+    /// Removes the mappings of all pages in the given range.
+    ///
+    /// The frames that were mapped are passed to the given `frame_deallocator`. Note that the
+    /// pages might still be cached in the TLB until the returned `MapperFlushRange` is flushed,
+    /// so the deallocator must not hand out the frames again before that.
+    ///
+    /// Returns an error without removing any mapping if one of the pages is not mapped to a
+    /// frame of size `S`.
+    fn unmap_range<D>(
+        &mut self,
+        pages: PageRange<S>,
+        frame_deallocator: &mut D,
+    ) -> Result<MapperFlushRange<S>, UnmapError>
+    where
+        D: FrameDeallocator<S>,
+    {
+        for page in pages {
+            if let Err(err) = self.translate_page(page) {
+                return Err(match err {
+                    TranslateError::PageNotMapped => UnmapError::PageNotMapped,
+                    TranslateError::ParentEntryHugePage => UnmapError::ParentEntryHugePage,
+                    TranslateError::InvalidFrameAddress(addr) => {
+                        UnmapError::InvalidFrameAddress(addr)
+                    }
+                });
+            }
+        }
+
+        for page in pages {
+            let (frame, flush) = self.unmap(page)?;
+            flush.ignore(); // flushed as part of the returned range
+            frame_deallocator.deallocate_frame(frame);
+        }
+
+        Ok(MapperFlushRange::new(pages))
+    }
+
+    /// Updates the flags of all pages in the given range.
+    ///
+    /// Returns an error without updating any flags if one of the pages is not mapped.
+    fn update_flags_range(
+        &mut self,
+        pages: PageRange<S>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushRange<S>, FlagUpdateError> {
+        for page in pages {
+            match self.translate_page(page) {
+                Ok(_) | Err(TranslateError::InvalidFrameAddress(_)) => {}
+                Err(TranslateError::PageNotMapped) => return Err(FlagUpdateError::PageNotMapped),
+                Err(TranslateError::ParentEntryHugePage) => {
+                    return Err(FlagUpdateError::ParentEntryHugePage)
+                }
+            }
+        }
+
+        for page in pages {
+            self.update_flags(page, flags)?.ignore(); // flushed as part of the returned range
+        }
+
+        Ok(MapperFlushRange::new(pages))
+    }
 }
 
 /// This type represents a page whose mapping has changed in the page table.
@@ -160,6 +556,135 @@ impl<S: PageSize> MapperFlush<S> {
     pub fn ignore(self) {}
 }
 
+/// This type represents a page table change that affects an unknown number of pages, e.g. a
+/// change of the flags of a parent table entry.
+///
+/// It is returned by `Mapper::update_parent_flags`. Such changes require a flush of the
+/// complete TLB.
+#[derive(Debug)]
+#[must_use = "Page Table changes must be flushed or ignored."]
+pub struct MapperFlushAll(());
+
+impl MapperFlushAll {
+    /// Create a new flush promise
+    fn new() -> Self {
+        MapperFlushAll(())
+    }
+
+    /// Flush the complete TLB to ensure that the newest mappings are used.
+    ///
+    /// Since `tlb::flush_all` does not invalidate mappings with the `GLOBAL` flag, the `PGE`
+    /// flag of the CR4 register is toggled instead if it is set.
+    #[cfg(target_arch = "x86_64")]
+    pub fn flush(self) {
+        flush_all_including_global();
+    }
+
+    /// Don't flush the TLB and silence the “must be used” warning.
+    pub fn ignore(self) {}
+}
+
+/// This type represents a range of pages whose mappings have changed in the page table.
+///
+/// This is the batched counterpart of [`MapperFlush`], returned by the range methods of the
+/// [`Mapper`] trait. Invalidating many pages one by one is slower than flushing the complete TLB,
+/// so the `flush` method decides between the two based on the size of the range.
+#[derive(Debug)]
+#[must_use = "Page Table changes must be flushed or ignored."]
+pub struct MapperFlushRange<S: PageSize>(PageRange<S>);
+
+impl<S: PageSize> MapperFlushRange<S> {
+    /// The maximum number of pages that `flush` invalidates individually. Larger ranges
+    /// cause a flush of the complete TLB.
+    pub const FLUSH_ALL_THRESHOLD: u64 = 32;
+
+    /// Create a new flush promise for the given range
+    fn new(pages: PageRange<S>) -> Self {
+        MapperFlushRange(pages)
+    }
+
+    /// Returns the range of pages whose mappings have changed.
+    pub fn pages(&self) -> PageRange<S> {
+        self.0
+    }
+
+    /// Flush the pages from the TLB to ensure that the newest mappings are used.
+    ///
+    /// Ranges with more than `FLUSH_ALL_THRESHOLD` pages are flushed completely, all other
+    /// ranges through one `invlpg` per page. Since `tlb::flush_all` does not invalidate
+    /// mappings with the `GLOBAL` flag, large ranges in the higher half, which may contain
+    /// global pages, are flushed by toggling the `PGE` flag of the CR4 register instead.
+    #[cfg(target_arch = "x86_64")]
+    pub fn flush(self) {
+        if range_len(self.0.start, self.0.end) <= Self::FLUSH_ALL_THRESHOLD {
+            self.flush_each();
+            return;
+        }
+        if self.0.start.start_address().as_u64() >> 63 == 1 {
+            flush_all_including_global();
+        } else {
+            crate::instructions::tlb::flush_all();
+        }
+    }
+
+    /// Flush each page of the range individually from the TLB.
+    #[cfg(target_arch = "x86_64")]
+    pub fn flush_each(self) {
+        for page in self.0 {
+            crate::instructions::tlb::flush(page.start_address());
+        }
+    }
+
+    /// Don't flush the TLB and silence the “must be used” warning.
+    pub fn ignore(self) {}
+}
+
+/// Flushes the complete TLB, including the mappings with the `GLOBAL` flag if the `PGE` flag of
+/// the CR4 register is set.
+#[cfg(target_arch = "x86_64")]
+fn flush_all_including_global() {
+    use crate::registers::control::{Cr4, Cr4Flags};
+
+    let cr4 = Cr4::read_raw();
+    if cr4 & Cr4Flags::PGE.bits() != 0 {
+        // clearing the `PGE` flag invalidates all translations, including global ones
+        unsafe {
+            Cr4::write_raw(cr4 & !Cr4Flags::PGE.bits());
+            Cr4::write_raw(cr4);
+        }
+    } else {
+        crate::instructions::tlb::flush_all();
+    }
+}
+
+/// A frame allocator that never returns a frame, used for mapping pages whose parent tables
+/// already exist.
+#[derive(Debug)]
+struct NoFrameAllocator;
+
+impl FrameAllocator<Size4KiB> for NoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        None
+    }
+}
+
+/// Returns the parent table flags that `Mapper::map_to` uses for a page with the given flags.
+fn default_parent_table_flags(flags: PageTableFlags) -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | (flags & PageTableFlags::USER_ACCESSIBLE)
+}
+
+/// Returns the number of pages or frames between `start` (inclusive) and `end` (exclusive).
+fn range_len<T>(start: T, end: T) -> u64
+where
+    T: PartialOrd + core::ops::Sub<Output = u64>,
+{
+    if start < end {
+        end - start
+    } else {
+        0
+    }
+}
+
 /// This error is returned from `map_to` and similar methods.
 #[derive(Debug)]
 pub enum MapToError {
@@ -171,6 +696,11 @@ pub enum MapToError {
     ParentEntryHugePage,
     /// The given page is already mapped to a physical frame.
     PageAlreadyMapped,
+    /// The page attribute table does not contain the requested memory type.
+    MemoryTypeUnavailable,
+    /// A parent page table entry of a `RecursivePageTable` would not have the `WRITABLE` flag,
+    /// so the page table below it could not be modified through the recursive mapping.
+    ParentEntryNotWritable,
 }
 
 /// An error indicating that an `unmap` call failed.
@@ -195,6 +725,40 @@ pub enum FlagUpdateError {
     ParentEntryHugePage,
 }
 
+/// An error indicating that a `split_huge_page` call failed.
+#[derive(Debug)]
+pub enum SplitError {
+    /// The given page is not mapped to a physical frame.
+    PageNotMapped,
+    /// An upper level page table entry has the `HUGE_PAGE` flag set, which means that the
+    /// given page is part of an even larger page.
+    ParentEntryHugePage,
+    /// The entry for the given page points to a page table instead of mapping a huge page.
+    NotHugePage,
+    /// A frame for the new page table was needed, but the frame allocator returned `None`.
+    FrameAllocationFailed,
+    /// The new page table can't be filled before it is inserted into the hierarchy, because
+    /// the active root table has no unused entry to map it temporarily. Only returned by
+    /// `RecursivePageTable`.
+    NoTemporaryMapping,
+}
+
+/// An error indicating that a `merge_huge_page` call failed.
+#[derive(Debug)]
+pub enum MergeError {
+    /// No page table is mapped for the given page.
+    PageNotMapped,
+    /// An upper level page table entry has the `HUGE_PAGE` flag set, which means that the
+    /// given page is part of an even larger page.
+    ParentEntryHugePage,
+    /// The given page is already mapped as a huge page.
+    AlreadyHugePage,
+    /// The page table for the given page does not consist of 512 present mappings with
+    /// identical flags to contiguous frames, starting at a frame that is aligned to the huge
+    /// page size.
+    NotMergeable,
+}
+
 /// An error indicating that an `translate` call failed.
 #[derive(Debug)]
 pub enum TranslateError {
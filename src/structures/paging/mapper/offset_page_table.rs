@@ -0,0 +1,490 @@
+use crate::structures::paging::{
+    frame::PhysFrame,
+    frame_alloc::{FrameAllocator, FrameDeallocator},
+    mapper::*,
+    page::{PageRangeInclusive, Size1GiB, Size2MiB, Size4KiB},
+    page_table::{PageTable, PageTableLevel, PagingMode},
+};
+
+/// A Mapper implementation that requires that the complete physically memory is mapped at some
+/// offset in the virtual address space.
+///
+/// This is a more convenient version of `MappedPageTable` with a `PhysToVirt` implementation
+/// that adds the offset to the physical address of a page table frame. Unlike a
+/// `MappedPageTable` with a closure, this type has no generic parameter besides the lifetime.
+#[derive(Debug)]
+pub struct OffsetPageTable<'a> {
+    inner: MappedPageTable<'a, PhysOffset>,
+    phys_offset: VirtAddr,
+}
+
+impl<'a> OffsetPageTable<'a> {
+    /// Creates a new `OffsetPageTable` that uses the given offset for converting virtual
+    /// to physical addresses.
+    ///
+    /// This function is unsafe because the caller must guarantee that the complete physical
+    /// memory is mapped to the virtual address space, starting at the address `phys_offset`.
+    /// Also, the passed `level_4_table` must point to the level 4 page table of a valid page
+    /// table hierarchy. Otherwise this function might break memory safety, e.g. by writing to
+    /// an illegal memory location.
+    pub unsafe fn new(level_4_table: &'a mut PageTable, phys_offset: VirtAddr) -> Self {
+        Self::with_paging_mode(level_4_table, phys_offset, PagingMode::Level4)
+    }
+
+    /// Creates a new `OffsetPageTable` for a hierarchy that uses the given paging mode.
+    ///
+    /// The passed `root_table` is the level 4 table for `PagingMode::Level4` and the level 5
+    /// table for `PagingMode::Level5`.
+    ///
+    /// This function is unsafe for the same reasons as `new`.
+    pub unsafe fn with_paging_mode(
+        root_table: &'a mut PageTable,
+        phys_offset: VirtAddr,
+        paging_mode: PagingMode,
+    ) -> Self {
+        let phys_to_virt = PhysOffset {
+            offset: phys_offset,
+            paging_mode,
+        };
+        Self {
+            inner: MappedPageTable::with_paging_mode(root_table, phys_to_virt, paging_mode),
+            phys_offset,
+        }
+    }
+
+    /// Creates a new `OffsetPageTable` for the currently active page table hierarchy.
+    ///
+    /// The root table is read from the CR3 register and the paging mode from the CR4 register.
+    ///
+    /// This function is unsafe because the caller must guarantee that the complete physical
+    /// memory is mapped to the virtual address space, starting at the address `phys_offset`.
+    /// Also, the caller must ensure that no other reference to the active root table exists
+    /// while the returned mapper is alive.
+    #[cfg(target_arch = "x86_64")]
+    pub unsafe fn from_cr3(phys_offset: VirtAddr) -> Self {
+        use crate::registers::control::Cr3;
+
+        let (root_frame, _) = Cr3::read();
+        let paging_mode = PagingMode::current();
+        let phys_to_virt = PhysOffset {
+            offset: phys_offset,
+            paging_mode,
+        };
+        let root_table = phys_to_virt.phys_to_virt(root_frame);
+        Self::with_paging_mode(&mut *root_table, phys_offset, paging_mode)
+    }
+
+    /// Returns the offset at which the physical memory is mapped.
+    pub fn phys_offset(&self) -> VirtAddr {
+        self.phys_offset
+    }
+
+    /// Returns the paging mode of the page table hierarchy.
+    pub fn paging_mode(&self) -> PagingMode {
+        self.inner.paging_mode()
+    }
+
+    /// Returns an iterator over all present pages of the page table hierarchy.
+    ///
+    /// See `MappedPageTable::mappings` for more information.
+    pub fn mappings(&self) -> impl Iterator<Item = MappedPage> + Clone + '_ {
+        self.inner.mappings()
+    }
+
+    /// Returns a formatter that prints all mappings of the page table hierarchy.
+    ///
+    /// See `PageTableDump` for more information.
+    pub fn dump(&self) -> PageTableDump<impl Iterator<Item = MappedPage> + Clone + '_> {
+        self.inner.dump()
+    }
+
+    /// Checks the page table hierarchy against the given policy and passes each violation to
+    /// `report`.
+    ///
+    /// See `MappedPageTable::audit` for more information.
+    pub fn audit<F>(&self, policy: &AuditPolicy, report: F) -> usize
+    where
+        F: FnMut(AuditFinding),
+    {
+        self.inner.audit(policy, report)
+    }
+
+    /// Frees all page tables of the hierarchy that no longer contain any entries.
+    ///
+    /// See `MappedPageTable::clean_up` for more information.
+    pub unsafe fn clean_up<D>(&mut self, frame_deallocator: &mut D)
+    where
+        D: FrameDeallocator<Size4KiB>,
+    {
+        self.inner.clean_up(frame_deallocator)
+    }
+
+    /// Frees all page tables that no longer contain any entries and that are responsible for
+    /// addresses in the given range.
+    ///
+    /// See `MappedPageTable::clean_up_addr_range` for more information.
+    pub unsafe fn clean_up_addr_range<D>(
+        &mut self,
+        range: PageRangeInclusive,
+        frame_deallocator: &mut D,
+    ) where
+        D: FrameDeallocator<Size4KiB>,
+    {
+        self.inner.clean_up_addr_range(range, frame_deallocator)
+    }
+
+    /// Splits the given huge page into 512 pages of the next smaller page size.
+    ///
+    /// See `MappedPageTable::split_huge_page` for more information.
+    pub unsafe fn split_huge_page<S, A>(
+        &mut self,
+        page: Page<S>,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange<S::SplitSize>, SplitError>
+    where
+        S: HugePageSize,
+        A: FrameAllocator<Size4KiB>,
+    {
+        self.inner.split_huge_page(page, frame_allocator)
+    }
+
+    /// Merges the 512 pages that make up the given huge page into a single huge page mapping.
+    ///
+    /// See `MappedPageTable::merge_huge_page` for more information.
+    pub unsafe fn merge_huge_page<S, D>(
+        &mut self,
+        page: Page<S>,
+        frame_deallocator: &mut D,
+    ) -> Result<MapperFlushRange<S::SplitSize>, MergeError>
+    where
+        S: HugePageSize,
+        D: FrameDeallocator<Size4KiB>,
+    {
+        self.inner.merge_huge_page(page, frame_deallocator)
+    }
+
+    /// Tests and clears the given flags in the entries of the pages of `pages`.
+    ///
+    /// See `MappedPageTable::harvest_flags` for more information.
+    pub fn harvest_flags<S: PageSize>(
+        &mut self,
+        pages: PageRange<S>,
+        flags: PageTableFlags,
+        bitmap: &mut [u64],
+    ) -> MapperFlushRange<S> {
+        self.inner.harvest_flags(pages, flags, bitmap)
+    }
+}
+
+impl<'a> Mapper<Size1GiB> for OffsetPageTable<'a> {
+    unsafe fn map_to_with_table_flags<A>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame: PhysFrame<Size1GiB>,
+        flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
+        allocator: &mut A,
+    ) -> Result<MapperFlush<Size1GiB>, MapToError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        self.inner
+            .map_to_with_table_flags(page, frame, flags, parent_table_flags, allocator)
+    }
+
+    unsafe fn map_to_with_memory_type<A>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame: PhysFrame<Size1GiB>,
+        flags: PageTableFlags,
+        memory_type: MemoryType,
+        pat: &PageAttributeTable,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlush<Size1GiB>, MapToError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        self.inner
+            .map_to_with_memory_type(page, frame, flags, memory_type, pat, frame_allocator)
+    }
+
+    fn unmap(
+        &mut self,
+        page: Page<Size1GiB>,
+    ) -> Result<(PhysFrame<Size1GiB>, MapperFlush<Size1GiB>), UnmapError> {
+        self.inner.unmap(page)
+    }
+
+    fn update_flags(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<Size1GiB>, FlagUpdateError> {
+        self.inner.update_flags(page, flags)
+    }
+
+    fn update_flags_and_pat_index(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableFlags,
+        pat_index: u8,
+    ) -> Result<MapperFlush<Size1GiB>, FlagUpdateError> {
+        self.inner
+            .update_flags_and_pat_index(page, flags, pat_index)
+    }
+
+    unsafe fn remap(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame: PhysFrame<Size1GiB>,
+        flags: PageTableFlags,
+    ) -> Result<(PhysFrame<Size1GiB>, MapperFlush<Size1GiB>), FlagUpdateError> {
+        self.inner.remap(page, frame, flags)
+    }
+
+    fn update_parent_flags(
+        &mut self,
+        page: Page<Size1GiB>,
+        level: PageTableLevel,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.inner.update_parent_flags(page, level, flags)
+    }
+
+    fn translate_page(&self, page: Page<Size1GiB>) -> Result<PhysFrame<Size1GiB>, TranslateError> {
+        self.inner.translate_page(page)
+    }
+}
+
+impl<'a> Mapper<Size2MiB> for OffsetPageTable<'a> {
+    unsafe fn map_to_with_table_flags<A>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame: PhysFrame<Size2MiB>,
+        flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
+        allocator: &mut A,
+    ) -> Result<MapperFlush<Size2MiB>, MapToError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        self.inner
+            .map_to_with_table_flags(page, frame, flags, parent_table_flags, allocator)
+    }
+
+    unsafe fn map_to_with_memory_type<A>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame: PhysFrame<Size2MiB>,
+        flags: PageTableFlags,
+        memory_type: MemoryType,
+        pat: &PageAttributeTable,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlush<Size2MiB>, MapToError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        self.inner
+            .map_to_with_memory_type(page, frame, flags, memory_type, pat, frame_allocator)
+    }
+
+    fn unmap(
+        &mut self,
+        page: Page<Size2MiB>,
+    ) -> Result<(PhysFrame<Size2MiB>, MapperFlush<Size2MiB>), UnmapError> {
+        self.inner.unmap(page)
+    }
+
+    fn update_flags(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<Size2MiB>, FlagUpdateError> {
+        self.inner.update_flags(page, flags)
+    }
+
+    fn update_flags_and_pat_index(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+        pat_index: u8,
+    ) -> Result<MapperFlush<Size2MiB>, FlagUpdateError> {
+        self.inner
+            .update_flags_and_pat_index(page, flags, pat_index)
+    }
+
+    unsafe fn remap(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame: PhysFrame<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<(PhysFrame<Size2MiB>, MapperFlush<Size2MiB>), FlagUpdateError> {
+        self.inner.remap(page, frame, flags)
+    }
+
+    fn update_parent_flags(
+        &mut self,
+        page: Page<Size2MiB>,
+        level: PageTableLevel,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.inner.update_parent_flags(page, level, flags)
+    }
+
+    fn translate_page(&self, page: Page<Size2MiB>) -> Result<PhysFrame<Size2MiB>, TranslateError> {
+        self.inner.translate_page(page)
+    }
+}
+
+impl<'a> Mapper<Size4KiB> for OffsetPageTable<'a> {
+    unsafe fn map_to_with_table_flags<A>(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
+        allocator: &mut A,
+    ) -> Result<MapperFlush<Size4KiB>, MapToError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        self.inner
+            .map_to_with_table_flags(page, frame, flags, parent_table_flags, allocator)
+    }
+
+    unsafe fn map_to_with_memory_type<A>(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+        memory_type: MemoryType,
+        pat: &PageAttributeTable,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlush<Size4KiB>, MapToError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        self.inner
+            .map_to_with_memory_type(page, frame, flags, memory_type, pat, frame_allocator)
+    }
+
+    fn unmap(
+        &mut self,
+        page: Page<Size4KiB>,
+    ) -> Result<(PhysFrame<Size4KiB>, MapperFlush<Size4KiB>), UnmapError> {
+        self.inner.unmap(page)
+    }
+
+    fn update_flags(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<Size4KiB>, FlagUpdateError> {
+        self.inner.update_flags(page, flags)
+    }
+
+    fn update_flags_and_pat_index(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+        pat_index: u8,
+    ) -> Result<MapperFlush<Size4KiB>, FlagUpdateError> {
+        self.inner
+            .update_flags_and_pat_index(page, flags, pat_index)
+    }
+
+    unsafe fn remap(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<(PhysFrame<Size4KiB>, MapperFlush<Size4KiB>), FlagUpdateError> {
+        self.inner.remap(page, frame, flags)
+    }
+
+    fn update_parent_flags(
+        &mut self,
+        page: Page<Size4KiB>,
+        level: PageTableLevel,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        self.inner.update_parent_flags(page, level, flags)
+    }
+
+    fn translate_page(&self, page: Page<Size4KiB>) -> Result<PhysFrame<Size4KiB>, TranslateError> {
+        self.inner.translate_page(page)
+    }
+}
+
+impl<'a> MapperAllSizes for OffsetPageTable<'a> {
+    fn translate(&self, addr: VirtAddr) -> TranslateResult {
+        self.inner.translate(addr)
+    }
+}
+
+/// The `PhysToVirt` implementation of `OffsetPageTable`.
+#[derive(Debug)]
+struct PhysOffset {
+    offset: VirtAddr,
+    /// The offset and the resulting addresses must be canonical in this paging mode.
+    paging_mode: PagingMode,
+}
+
+impl PhysToVirt for PhysOffset {
+    fn phys_to_virt(&self, frame: PhysFrame) -> *mut PageTable {
+        let virt = self
+            .offset
+            .as_u64()
+            .checked_add(frame.start_address().as_u64())
+            .expect("physical address is not mapped at the offset");
+        self.paging_mode.virt_addr(virt).as_mut_ptr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PhysAddr;
+
+    #[test]
+    fn map_translate_unmap() {
+        let mut frames = SimulatedPhysMemory::buffer(8);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        // map the simulated physical memory at an offset, like a direct map
+        let offset = VirtAddr::new(frames.as_ptr() as u64 - start.start_address().as_u64());
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let root_table = unsafe { &mut *memory.phys_to_virt().phys_to_virt(root_frame) };
+        let mut mapper = unsafe { OffsetPageTable::new(root_table, offset) };
+        assert_eq!(mapper.phys_offset(), offset);
+
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x4000_1000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe { mapper.map_to(page, frame, flags, &mut memory) }
+            .unwrap()
+            .ignore();
+        assert_eq!(memory.allocated_frames(), 4);
+        assert_eq!(
+            mapper.translate_addr(VirtAddr::new(0x4000_1abc)),
+            Some(PhysAddr::new(0x8000_0abc))
+        );
+
+        let (unmapped, flush) = mapper.unmap(page).unwrap();
+        flush.ignore();
+        assert_eq!(unmapped, frame);
+        assert_eq!(mapper.translate_addr(VirtAddr::new(0x4000_1abc)), None);
+    }
+
+    #[test]
+    fn offset_with_5_level_paging() {
+        let phys_offset = PhysOffset {
+            offset: VirtAddr::new_la57(0xff11_0000_0000_0000),
+            paging_mode: PagingMode::Level5,
+        };
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x1234_5000));
+        assert_eq!(
+            phys_offset.phys_to_virt(frame) as u64,
+            0xff11_0000_1234_5000
+        );
+    }
+}
@@ -2,15 +2,20 @@
 
 //! Access the page tables through a recursively mapped level 4 table.
 
+use super::audit;
+use super::hierarchy::{self, PageTableAccess};
 use super::*;
 use crate::registers::control::Cr3;
 use crate::structures::paging::{
-    frame_alloc::FrameAllocator,
-    page::NotGiantPageSize,
-    page_table::{FrameError, PageTable, PageTableEntry, PageTableFlags},
+    frame_alloc::{FrameAllocator, FrameDeallocator},
+    page::{NotGiantPageSize, PageRangeInclusive},
+    page_table::{
+        FrameError, PageTable, PageTableEntry, PageTableFlags, PageTableLevel, PagingMode,
+    },
     Page, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
 };
 use crate::VirtAddr;
+use bit_field::BitField;
 use ux::u9;
 
 /// A recursive page table is a last level page table with an entry mapped to the table itself.
@@ -25,11 +30,15 @@ use ux::u9;
 /// - To access a level 1 page table, we “loop” once, then use the level 4 index, then the
 ///   level 3 index, then the level 2 index.
 ///
+/// With 5-level paging, the recursive entry is part of the level 5 table and each access loops
+/// one additional time.
+///
 /// This struct implements the `Mapper` trait.
 #[derive(Debug)]
 pub struct RecursivePageTable<'a> {
-    p4: &'a mut PageTable,
-    recursive_index: u9,
+    root_table: &'a mut PageTable,
+    recursive_index: RecursiveIndex,
+    paging_mode: PagingMode,
 }
 
 impl<'a> RecursivePageTable<'a> {
@@ -43,9 +52,17 @@ impl<'a> RecursivePageTable<'a> {
     ///       where `xxx` is the recursive entry.
     /// - The page table must be active, i.e. the CR3 register must contain its physical address.
     ///
+    /// If 5-level paging is active (see `PagingMode::current`), the passed table must be the
+    /// level 5 table and the reference must be of the form `0o_xxx_xxx_xxx_xxx_xxx_0000`.
+    ///
     /// Otherwise `Err(())` is returned.
     pub fn new(table: &'a mut PageTable) -> Result<Self, ()> {
-        let page = Page::containing_address(VirtAddr::new(table as *const _ as u64));
+        let paging_mode = PagingMode::current();
+        let addr = match paging_mode {
+            PagingMode::Level4 => VirtAddr::from_ptr(table as *const PageTable),
+            PagingMode::Level5 => VirtAddr::from_ptr_la57(table as *const PageTable),
+        };
+        let page = Page::containing_address(addr);
         let recursive_index = page.p4_index();
 
         if page.p3_index() != recursive_index
@@ -54,40 +71,398 @@ impl<'a> RecursivePageTable<'a> {
         {
             return Err(());
         }
+        if paging_mode == PagingMode::Level5 && page.p5_index() != recursive_index {
+            return Err(());
+        }
         if Ok(Cr3::read().0) != table[recursive_index].frame() {
             return Err(());
         }
 
         Ok(RecursivePageTable {
-            p4: table,
-            recursive_index,
+            root_table: table,
+            recursive_index: RecursiveIndex::new(recursive_index),
+            paging_mode,
         })
     }
 
     /// Creates a new RecursivePageTable without performing any checks.
     ///
     /// The `recursive_index` parameter must be the index of the recursively mapped entry.
+    /// The page table hierarchy is assumed to use 4-level paging.
     pub unsafe fn new_unchecked(table: &'a mut PageTable, recursive_index: u9) -> Self {
+        Self::new_unchecked_with_paging_mode(table, recursive_index, PagingMode::Level4)
+    }
+
+    /// Creates a new RecursivePageTable for the given paging mode without performing any checks.
+    ///
+    /// The passed `table` must be the level 4 table for `PagingMode::Level4` and the level 5
+    /// table for `PagingMode::Level5`. The `recursive_index` parameter must be the index of the
+    /// recursively mapped entry in that table.
+    pub unsafe fn new_unchecked_with_paging_mode(
+        table: &'a mut PageTable,
+        recursive_index: u9,
+        paging_mode: PagingMode,
+    ) -> Self {
         RecursivePageTable {
-            p4: table,
+            root_table: table,
+            recursive_index: RecursiveIndex::new(recursive_index),
+            paging_mode,
+        }
+    }
+
+    /// Returns the paging mode of the page table hierarchy.
+    pub fn paging_mode(&self) -> PagingMode {
+        self.paging_mode
+    }
+
+    /// Temporarily makes the inactive page table hierarchy with the given root table
+    /// accessible and calls `f` with a mapper for it.
+    ///
+    /// The inactive hierarchy is accessed through a second recursive entry: the entry `slot`
+    /// of the active root table is pointed to `root_frame` while `f` runs. Accesses that loop
+    /// through the recursive entry and then through `slot` reach the tables of the inactive
+    /// hierarchy, so the mapper works like a mapper for the active hierarchy. This allows to
+    /// build the page tables of a new process without switching `Cr3`.
+    ///
+    /// When `f` returns, the entry is cleared again and the complete TLB is flushed to remove
+    /// all cached translations through `slot`. The `MapperFlush` values returned by the mapper
+    /// of the inactive hierarchy only affect the active TLB, so they can be ignored.
+    ///
+    /// Returns an `InactiveTableError` if `slot` is the recursive index, if the entry `slot` is
+    /// in use, or if `self` is a mapper for an inactive hierarchy itself.
+    ///
+    /// This function is unsafe because the caller must guarantee that `root_frame` contains a
+    /// valid root table for the paging mode of `self` and that neither it nor the tables below
+    /// it are modified through other means while `f` runs. Since the accesses through `slot`
+    /// use the entries of the inactive tables as page table entries and `tlb::flush_all` does
+    /// not remove translations with the `GLOBAL` flag, the entries of the inactive tables that
+    /// point to page tables must not have this bit set.
+    pub unsafe fn with_inactive<F, R>(
+        &mut self,
+        root_frame: PhysFrame,
+        slot: u9,
+        f: F,
+    ) -> Result<R, InactiveTableError>
+    where
+        F: FnOnce(&mut RecursivePageTable) -> R,
+    {
+        if self.recursive_index.root != self.recursive_index.active {
+            return Err(InactiveTableError::NotActiveHierarchy);
+        }
+        if slot == self.recursive_index.active {
+            return Err(InactiveTableError::RecursiveSlot);
+        }
+        if !self.root_table[slot].is_unused() {
+            return Err(InactiveTableError::SlotInUse);
+        }
+        self.root_table[slot].set_frame(
+            root_frame,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        );
+
+        let recursive_index = RecursiveIndex {
+            active: self.recursive_index.active,
+            root: slot,
+        };
+        let root_page = table_page(
+            VirtAddr::new(0),
+            self.paging_mode.top_level(),
+            recursive_index,
+            self.paging_mode,
+        );
+        let mut inactive = RecursivePageTable {
+            root_table: &mut *root_page.start_address().as_mut_ptr(),
             recursive_index,
+            paging_mode: self.paging_mode,
+        };
+        let result = f(&mut inactive);
+
+        self.root_table[slot].set_unused();
+        crate::instructions::tlb::flush_all();
+        Ok(result)
+    }
+
+    /// Returns an iterator over all present pages of the page table hierarchy.
+    ///
+    /// The iterator yields a `MappedPage` for every present entry of a level 1 table and for
+    /// every huge page entry of a level 2 or level 3 table. The recursive entry is skipped, so
+    /// the page tables themselves are not reported.
+    pub fn mappings(&self) -> impl Iterator<Item = MappedPage> + Clone + '_ {
+        unsafe { hierarchy::Mappings::new(self.table_access(), self.root_table, self.paging_mode) }
+    }
+
+    /// Returns a formatter that prints all mappings of the page table hierarchy.
+    ///
+    /// See `PageTableDump` for a description of the output format.
+    pub fn dump(&self) -> PageTableDump<impl Iterator<Item = MappedPage> + Clone + '_> {
+        let table_counts = unsafe {
+            hierarchy::table_counts(&self.table_access(), self.root_table, self.paging_mode)
+        };
+        PageTableDump::new(self.mappings(), table_counts, self.paging_mode)
+    }
+
+    /// Checks the page table hierarchy against the given policy and passes each violation to
+    /// `report`. Returns the number of violations.
+    ///
+    /// See `AuditFinding` for the checked conditions. The hierarchy is not modified.
+    pub fn audit<F>(&self, policy: &AuditPolicy, report: F) -> usize
+    where
+        F: FnMut(AuditFinding),
+    {
+        unsafe {
+            audit::audit(
+                &self.table_access(),
+                self.root_table,
+                self.paging_mode,
+                policy,
+                report,
+            )
+        }
+    }
+
+    /// Frees all page tables of the hierarchy that no longer contain any entries.
+    ///
+    /// See `clean_up_addr_range` for more information.
+    pub unsafe fn clean_up<D>(&mut self, frame_deallocator: &mut D)
+    where
+        D: FrameDeallocator<Size4KiB>,
+    {
+        hierarchy::clean_up(
+            &self.table_access(),
+            self.root_table,
+            self.paging_mode,
+            0,
+            u64::max_value(),
+            frame_deallocator,
+        )
+    }
+
+    /// Frees all page tables that no longer contain any entries and that are responsible for
+    /// addresses in the given range.
+    ///
+    /// `Mapper::unmap` never deallocates page tables, so page tables that became empty after
+    /// unmapping stay part of the hierarchy until this function is called. The frames of the
+    /// freed tables are passed to the given `frame_deallocator` and the recursive mappings of
+    /// the freed tables are flushed from the TLB. The recursive entry and the table that
+    /// contains it are never freed.
+    ///
+    /// This function is unsafe because the caller must guarantee that the freed page tables are
+    /// not used anymore. In particular, they must not be shared with other page table
+    /// hierarchies, and the TLB entries of the unmapped pages in the range must be flushed
+    /// already.
+    pub unsafe fn clean_up_addr_range<D>(
+        &mut self,
+        range: PageRangeInclusive,
+        frame_deallocator: &mut D,
+    ) where
+        D: FrameDeallocator<Size4KiB>,
+    {
+        if range.is_empty() {
+            return;
+        }
+        hierarchy::clean_up(
+            &self.table_access(),
+            self.root_table,
+            self.paging_mode,
+            hierarchy::raw_addr(range.start.start_address(), self.paging_mode),
+            hierarchy::raw_addr(range.end.start_address(), self.paging_mode),
+            frame_deallocator,
+        )
+    }
+
+    /// Splits the given huge page into 512 pages of the next smaller page size.
+    ///
+    /// The new pages map the same frames with the same flags and memory type as the huge page.
+    /// The frame for the new page table is taken from `frame_allocator`. The returned
+    /// `MapperFlushRange` flushes the TLB entries of all 512 pages.
+    ///
+    /// The new page table is filled through a temporary mapping in an unused entry of the
+    /// active root table before it is inserted into the hierarchy. If there is no such entry,
+    /// `SplitError::NoTemporaryMapping` is returned.
+    ///
+    /// This function is unsafe because the caller must guarantee that the frame allocator only
+    /// returns unused frames.
+    pub unsafe fn split_huge_page<S, A>(
+        &mut self,
+        page: Page<S>,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlushRange<S::SplitSize>, SplitError>
+    where
+        S: HugePageSize,
+        A: FrameAllocator<Size4KiB>,
+    {
+        hierarchy::split_huge_page(
+            &self.table_access(),
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+            hierarchy::page_level::<S>(),
+            frame_allocator,
+        )?;
+        let start = Page::containing_address(page.start_address());
+        Ok(MapperFlushRange::new(Page::range(start, start + 512)))
+    }
+
+    /// Merges the 512 pages that make up the given huge page into a single huge page mapping.
+    ///
+    /// This is the reverse of `split_huge_page`. It only succeeds if the page table for `page`
+    /// maps 512 contiguous frames, starting at a frame that is aligned to the size of `S`, with
+    /// identical flags and memory type. The `ACCESSED` and `DIRTY` flags are ignored for the
+    /// comparison. The frame of the removed page table is passed to `frame_deallocator`. The
+    /// returned `MapperFlushRange` flushes the TLB entries of the merged pages.
+    ///
+    /// This function is unsafe because the caller must guarantee that the removed page table
+    /// is not used anymore. In particular, it must not be shared with other page table
+    /// hierarchies.
+    pub unsafe fn merge_huge_page<S, D>(
+        &mut self,
+        page: Page<S>,
+        frame_deallocator: &mut D,
+    ) -> Result<MapperFlushRange<S::SplitSize>, MergeError>
+    where
+        S: HugePageSize,
+        D: FrameDeallocator<Size4KiB>,
+    {
+        hierarchy::merge_huge_page(
+            &self.table_access(),
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+            hierarchy::page_level::<S>(),
+            frame_deallocator,
+        )?;
+        let start = Page::containing_address(page.start_address());
+        Ok(MapperFlushRange::new(Page::range(start, start + 512)))
+    }
+
+    /// Tests and clears the given flags in the entries of the pages of `pages`, e.g. to find
+    /// the pages that were accessed or written since the last scan.
+    ///
+    /// Bit `i` of `bitmap` (i.e. bit `i % 64` of word `i / 64`) is set if page
+    /// `pages.start + i` had any of the flags set, and cleared otherwise. Pages that are not
+    /// mapped or that are mapped with a different page size are skipped, so their bits are
+    /// cleared too. The flags are cleared atomically, so concurrent updates by the CPU are not
+    /// lost.
+    ///
+    /// The CPU only sets the `ACCESSED` and `DIRTY` flags again if the page is not cached in
+    /// the TLB, so the returned `MapperFlushRange`, which covers all changed entries, should be
+    /// flushed before the next scan.
+    ///
+    /// Panics if `bitmap` has less than one bit per page of `pages`.
+    pub fn harvest_flags<S: PageSize>(
+        &mut self,
+        pages: PageRange<S>,
+        flags: PageTableFlags,
+        bitmap: &mut [u64],
+    ) -> MapperFlushRange<S> {
+        let changed = unsafe {
+            hierarchy::harvest_flags(
+                &self.table_access(),
+                self.root_table,
+                self.paging_mode,
+                pages,
+                flags,
+                bitmap,
+            )
+        };
+        MapperFlushRange::new(changed)
+    }
+
+    /// Returns the `PageTableAccess` implementation for the generic hierarchy operations.
+    fn table_access(&self) -> RecursiveTableAccess {
+        RecursiveTableAccess {
+            recursive_index: self.recursive_index,
+            paging_mode: self.paging_mode,
+        }
+    }
+
+    /// Internal helper function to get the level 4 table for the given page.
+    ///
+    /// With 4-level paging, this is the root table itself. With 5-level paging, the root table
+    /// entry for the page is checked and the level 4 table is accessed through the recursive
+    /// mapping. An error is returned if that entry does not point to a page table.
+    fn level_4_table<'b, S: PageSize>(
+        root_table: &'b PageTable,
+        page: Page<S>,
+        recursive_index: RecursiveIndex,
+        paging_mode: PagingMode,
+    ) -> Result<&'b PageTable, FrameError> {
+        match paging_mode {
+            PagingMode::Level4 => Ok(root_table),
+            PagingMode::Level5 => {
+                root_table[page.p5_index()].frame()?;
+                Ok(unsafe { &*(p4_ptr(page, recursive_index, paging_mode)) })
+            }
+        }
+    }
+
+    /// Internal helper function to get a mutable reference to the level 4 table for the given
+    /// page.
+    ///
+    /// See `level_4_table` for more information.
+    fn level_4_table_mut<'b, S: PageSize>(
+        root_table: &'b mut PageTable,
+        page: Page<S>,
+        recursive_index: RecursiveIndex,
+        paging_mode: PagingMode,
+    ) -> Result<&'b mut PageTable, FrameError> {
+        match paging_mode {
+            PagingMode::Level4 => Ok(root_table),
+            PagingMode::Level5 => {
+                root_table[page.p5_index()].frame()?;
+                Ok(unsafe { &mut *(p4_ptr(page, recursive_index, paging_mode)) })
+            }
+        }
+    }
+
+    /// Internal helper function to get the level 4 table for the given page, creating it if
+    /// needed.
+    ///
+    /// With 4-level paging, this is the root table itself. With 5-level paging, the level 4
+    /// table is created through the root table entry for the page, see `create_next_table`.
+    unsafe fn create_level_4_table<'b, S: PageSize, A>(
+        root_table: &'b mut PageTable,
+        page: Page<S>,
+        recursive_index: RecursiveIndex,
+        paging_mode: PagingMode,
+        insert_flags: PageTableFlags,
+        allocator: &mut A,
+    ) -> Result<&'b mut PageTable, MapToError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        match paging_mode {
+            PagingMode::Level4 => Ok(root_table),
+            PagingMode::Level5 => {
+                let p4_page = p4_page(page, recursive_index, paging_mode);
+                Self::create_next_table(
+                    &mut root_table[page.p5_index()],
+                    p4_page,
+                    insert_flags,
+                    allocator,
+                )
+            }
         }
     }
 
     /// Internal helper function to create the page table of the next level if needed.
     ///
     /// If the passed entry is unused, a new frame is allocated from the given allocator, zeroed,
-    /// and the entry is updated to that address. If the passed entry is already mapped, the next
-    /// table is returned directly.
+    /// and the entry is updated to that address with the given `insert_flags`. If the passed
+    /// entry is already mapped, the `insert_flags` that it lacks are added and the next table is
+    /// returned directly.
     ///
     /// The `next_page_table` page must be the page of the next page table in the hierarchy.
     ///
     /// Returns `MapToError::FrameAllocationFailed` if the entry is unused and the allocator
     /// returned `None`. Returns `MapToError::ParentEntryHugePage` if the `HUGE_PAGE` flag is set
-    /// in the passed entry.
+    /// in the passed entry. Returns `MapToError::ParentEntryNotWritable` before allocating or
+    /// modifying anything if the entry wouldn't have the `WRITABLE` flag afterwards, since the
+    /// next table is zeroed and modified through the recursive mapping, which uses the entry.
     unsafe fn create_next_table<'b, A>(
         entry: &'b mut PageTableEntry,
         next_table_page: Page,
+        insert_flags: PageTableFlags,
         allocator: &mut A,
     ) -> Result<&'b mut PageTable, MapToError>
     where
@@ -99,6 +474,7 @@ impl<'a> RecursivePageTable<'a> {
         fn inner<'b, A>(
             entry: &'b mut PageTableEntry,
             next_table_page: Page,
+            insert_flags: PageTableFlags,
             allocator: &mut A,
         ) -> Result<&'b mut PageTable, MapToError>
         where
@@ -108,9 +484,20 @@ impl<'a> RecursivePageTable<'a> {
 
             let created;
 
+            let flags = if entry.is_unused() {
+                insert_flags
+            } else {
+                entry.flags() | insert_flags
+            };
+            if flags.contains(Flags::HUGE_PAGE) {
+                return Err(MapToError::ParentEntryHugePage);
+            }
+            if !flags.contains(Flags::WRITABLE) {
+                return Err(MapToError::ParentEntryNotWritable);
+            }
             if entry.is_unused() {
                 if let Some(frame) = allocator.allocate_frame() {
-                    entry.set_frame(frame, Flags::PRESENT | Flags::WRITABLE);
+                    entry.set_frame(frame, insert_flags);
                     created = true;
                 } else {
                     return Err(MapToError::FrameAllocationFailed);
@@ -118,8 +505,8 @@ impl<'a> RecursivePageTable<'a> {
             } else {
                 created = false;
             }
-            if entry.flags().contains(Flags::HUGE_PAGE) {
-                return Err(MapToError::ParentEntryHugePage);
+            if !entry.flags().contains(insert_flags) {
+                entry.set_flags(entry.flags() | insert_flags);
             }
 
             let page_table_ptr = next_table_page.start_address().as_mut_ptr();
@@ -130,7 +517,7 @@ impl<'a> RecursivePageTable<'a> {
             Ok(page_table)
         }
 
-        inner(entry, next_table_page, allocator)
+        inner(entry, next_table_page, insert_flags, allocator)
     }
 
     /// Helper function for implementing Mapper. Safe to limit the scope of unsafe, see
@@ -138,23 +525,38 @@ impl<'a> RecursivePageTable<'a> {
     fn map_to_1gib<A>(
         &mut self,
         page: Page<Size1GiB>,
-        frame: PhysFrame<Size1GiB>,
-        flags: PageTableFlags,
+        entry: PageTableEntry,
+        parent_table_flags: PageTableFlags,
         allocator: &mut A,
     ) -> Result<MapperFlush<Size1GiB>, MapToError>
     where
         A: FrameAllocator<Size4KiB>,
     {
-        use crate::structures::paging::PageTableFlags as Flags;
-        let p4 = &mut self.p4;
+        let p4 = unsafe {
+            Self::create_level_4_table(
+                self.root_table,
+                page,
+                self.recursive_index,
+                self.paging_mode,
+                parent_table_flags,
+                allocator,
+            )?
+        };
 
-        let p3_page = p3_page(page, self.recursive_index);
-        let p3 = unsafe { Self::create_next_table(&mut p4[page.p4_index()], p3_page, allocator)? };
+        let p3_page = p3_page(page, self.recursive_index, self.paging_mode);
+        let p3 = unsafe {
+            Self::create_next_table(
+                &mut p4[page.p4_index()],
+                p3_page,
+                parent_table_flags,
+                allocator,
+            )?
+        };
 
         if !p3[page.p3_index()].is_unused() {
             return Err(MapToError::PageAlreadyMapped);
         }
-        p3[page.p3_index()].set_addr(frame.start_address(), flags | Flags::HUGE_PAGE);
+        p3[page.p3_index()] = entry;
 
         Ok(MapperFlush::new(page))
     }
@@ -164,26 +566,48 @@ impl<'a> RecursivePageTable<'a> {
     fn map_to_2mib<A>(
         &mut self,
         page: Page<Size2MiB>,
-        frame: PhysFrame<Size2MiB>,
-        flags: PageTableFlags,
+        entry: PageTableEntry,
+        parent_table_flags: PageTableFlags,
         allocator: &mut A,
     ) -> Result<MapperFlush<Size2MiB>, MapToError>
     where
         A: FrameAllocator<Size4KiB>,
     {
-        use crate::structures::paging::PageTableFlags as Flags;
-        let p4 = &mut self.p4;
+        let p4 = unsafe {
+            Self::create_level_4_table(
+                self.root_table,
+                page,
+                self.recursive_index,
+                self.paging_mode,
+                parent_table_flags,
+                allocator,
+            )?
+        };
 
-        let p3_page = p3_page(page, self.recursive_index);
-        let p3 = unsafe { Self::create_next_table(&mut p4[page.p4_index()], p3_page, allocator)? };
+        let p3_page = p3_page(page, self.recursive_index, self.paging_mode);
+        let p3 = unsafe {
+            Self::create_next_table(
+                &mut p4[page.p4_index()],
+                p3_page,
+                parent_table_flags,
+                allocator,
+            )?
+        };
 
-        let p2_page = p2_page(page, self.recursive_index);
-        let p2 = unsafe { Self::create_next_table(&mut p3[page.p3_index()], p2_page, allocator)? };
+        let p2_page = p2_page(page, self.recursive_index, self.paging_mode);
+        let p2 = unsafe {
+            Self::create_next_table(
+                &mut p3[page.p3_index()],
+                p2_page,
+                parent_table_flags,
+                allocator,
+            )?
+        };
 
         if !p2[page.p2_index()].is_unused() {
             return Err(MapToError::PageAlreadyMapped);
         }
-        p2[page.p2_index()].set_addr(frame.start_address(), flags | Flags::HUGE_PAGE);
+        p2[page.p2_index()] = entry;
 
         Ok(MapperFlush::new(page))
     }
@@ -193,52 +617,118 @@ impl<'a> RecursivePageTable<'a> {
     fn map_to_4kib<A>(
         &mut self,
         page: Page<Size4KiB>,
-        frame: PhysFrame<Size4KiB>,
-        flags: PageTableFlags,
+        entry: PageTableEntry,
+        parent_table_flags: PageTableFlags,
         allocator: &mut A,
     ) -> Result<MapperFlush<Size4KiB>, MapToError>
     where
         A: FrameAllocator<Size4KiB>,
     {
-        let p4 = &mut self.p4;
+        let p4 = unsafe {
+            Self::create_level_4_table(
+                self.root_table,
+                page,
+                self.recursive_index,
+                self.paging_mode,
+                parent_table_flags,
+                allocator,
+            )?
+        };
 
-        let p3_page = p3_page(page, self.recursive_index);
-        let p3 = unsafe { Self::create_next_table(&mut p4[page.p4_index()], p3_page, allocator)? };
+        let p3_page = p3_page(page, self.recursive_index, self.paging_mode);
+        let p3 = unsafe {
+            Self::create_next_table(
+                &mut p4[page.p4_index()],
+                p3_page,
+                parent_table_flags,
+                allocator,
+            )?
+        };
 
-        let p2_page = p2_page(page, self.recursive_index);
-        let p2 = unsafe { Self::create_next_table(&mut p3[page.p3_index()], p2_page, allocator)? };
+        let p2_page = p2_page(page, self.recursive_index, self.paging_mode);
+        let p2 = unsafe {
+            Self::create_next_table(
+                &mut p3[page.p3_index()],
+                p2_page,
+                parent_table_flags,
+                allocator,
+            )?
+        };
 
-        let p1_page = p1_page(page, self.recursive_index);
-        let p1 = unsafe { Self::create_next_table(&mut p2[page.p2_index()], p1_page, allocator)? };
+        let p1_page = p1_page(page, self.recursive_index, self.paging_mode);
+        let p1 = unsafe {
+            Self::create_next_table(
+                &mut p2[page.p2_index()],
+                p1_page,
+                parent_table_flags,
+                allocator,
+            )?
+        };
 
         if !p1[page.p1_index()].is_unused() {
             return Err(MapToError::PageAlreadyMapped);
         }
-        p1[page.p1_index()].set_frame(frame, flags);
+        p1[page.p1_index()] = entry;
 
         Ok(MapperFlush::new(page))
     }
 }
 
 impl<'a> Mapper<Size1GiB> for RecursivePageTable<'a> {
-    unsafe fn map_to<A>(
+    unsafe fn map_to_with_table_flags<A>(
         &mut self,
         page: Page<Size1GiB>,
         frame: PhysFrame<Size1GiB>,
         flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
         allocator: &mut A,
     ) -> Result<MapperFlush<Size1GiB>, MapToError>
     where
         A: FrameAllocator<Size4KiB>,
     {
-        self.map_to_1gib(page, frame, flags, allocator)
+        self.map_to_1gib(
+            page,
+            hierarchy::page_entry(frame, flags),
+            parent_table_flags,
+            allocator,
+        )
+    }
+
+    unsafe fn map_to_with_memory_type<A>(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame: PhysFrame<Size1GiB>,
+        flags: PageTableFlags,
+        memory_type: MemoryType,
+        pat: &PageAttributeTable,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlush<Size1GiB>, MapToError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let entry = hierarchy::memory_type_entry(frame, flags, memory_type, pat)?;
+        self.map_to_1gib(
+            page,
+            entry,
+            default_parent_table_flags(flags),
+            frame_allocator,
+        )
     }
 
     fn unmap(
         &mut self,
         page: Page<Size1GiB>,
     ) -> Result<(PhysFrame<Size1GiB>, MapperFlush<Size1GiB>), UnmapError> {
-        let p4 = &mut self.p4;
+        let p4 = Self::level_4_table_mut(
+            self.root_table,
+            page,
+            self.recursive_index,
+            self.paging_mode,
+        )
+        .map_err(|err| match err {
+            FrameError::FrameNotPresent => UnmapError::PageNotMapped,
+            FrameError::HugeFrame => UnmapError::ParentEntryHugePage,
+        })?;
         let p4_entry = &p4[page.p4_index()];
 
         p4_entry.frame().map_err(|err| match err {
@@ -246,7 +736,7 @@ impl<'a> Mapper<Size1GiB> for RecursivePageTable<'a> {
             FrameError::HugeFrame => UnmapError::ParentEntryHugePage,
         })?;
 
-        let p3 = unsafe { &mut *(p3_ptr(page, self.recursive_index)) };
+        let p3 = unsafe { &mut *(p3_ptr(page, self.recursive_index, self.paging_mode)) };
         let p3_entry = &mut p3[page.p3_index()];
         let flags = p3_entry.flags();
 
@@ -257,8 +747,9 @@ impl<'a> Mapper<Size1GiB> for RecursivePageTable<'a> {
             return Err(UnmapError::ParentEntryHugePage);
         }
 
-        let frame = PhysFrame::from_start_address(p3_entry.addr())
-            .map_err(|()| UnmapError::InvalidFrameAddress(p3_entry.addr()))?;
+        let frame = p3_entry
+            .huge_frame::<Size1GiB>()
+            .map_err(UnmapError::InvalidFrameAddress)?;
 
         p3_entry.set_unused();
         Ok((frame, MapperFlush::new(page)))
@@ -270,13 +761,22 @@ impl<'a> Mapper<Size1GiB> for RecursivePageTable<'a> {
         flags: PageTableFlags,
     ) -> Result<MapperFlush<Size1GiB>, FlagUpdateError> {
         use crate::structures::paging::PageTableFlags as Flags;
-        let p4 = &mut self.p4;
+        let p4 = Self::level_4_table_mut(
+            self.root_table,
+            page,
+            self.recursive_index,
+            self.paging_mode,
+        )
+        .map_err(|err| match err {
+            FrameError::FrameNotPresent => FlagUpdateError::PageNotMapped,
+            FrameError::HugeFrame => FlagUpdateError::ParentEntryHugePage,
+        })?;
 
         if p4[page.p4_index()].is_unused() {
             return Err(FlagUpdateError::PageNotMapped);
         }
 
-        let p3 = unsafe { &mut *(p3_ptr(page, self.recursive_index)) };
+        let p3 = unsafe { &mut *(p3_ptr(page, self.recursive_index, self.paging_mode)) };
 
         if p3[page.p3_index()].is_unused() {
             return Err(FlagUpdateError::PageNotMapped);
@@ -286,58 +786,162 @@ impl<'a> Mapper<Size1GiB> for RecursivePageTable<'a> {
         Ok(MapperFlush::new(page))
     }
 
+    fn update_flags_and_pat_index(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableFlags,
+        pat_index: u8,
+    ) -> Result<MapperFlush<Size1GiB>, FlagUpdateError> {
+        let entry = unsafe {
+            hierarchy::leaf_entry_mut(
+                &self.table_access(),
+                self.root_table,
+                self.paging_mode,
+                page.start_address(),
+                PageTableLevel::Three,
+            )?
+        };
+        hierarchy::set_flags_and_pat_index::<Size1GiB>(entry, flags, pat_index);
+
+        Ok(MapperFlush::new(page))
+    }
+
+    unsafe fn remap(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame: PhysFrame<Size1GiB>,
+        flags: PageTableFlags,
+    ) -> Result<(PhysFrame<Size1GiB>, MapperFlush<Size1GiB>), FlagUpdateError> {
+        let entry = hierarchy::leaf_entry_mut(
+            &self.table_access(),
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+            PageTableLevel::Three,
+        )?;
+        let old_frame = hierarchy::remap_entry(entry, frame, flags)?;
+
+        Ok((old_frame, MapperFlush::new(page)))
+    }
+
+    fn update_parent_flags(
+        &mut self,
+        page: Page<Size1GiB>,
+        level: PageTableLevel,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        unsafe {
+            hierarchy::update_parent_flags(
+                &self.table_access(),
+                self.root_table,
+                self.paging_mode,
+                page.start_address(),
+                PageTableLevel::Three,
+                level,
+                flags,
+            )?;
+        }
+        Ok(MapperFlushAll::new())
+    }
+
     fn translate_page(&self, page: Page<Size1GiB>) -> Result<PhysFrame<Size1GiB>, TranslateError> {
-        let p4 = &self.p4;
+        let p4 = Self::level_4_table(
+            self.root_table,
+            page,
+            self.recursive_index,
+            self.paging_mode,
+        )
+        .map_err(|err| match err {
+            FrameError::FrameNotPresent => TranslateError::PageNotMapped,
+            FrameError::HugeFrame => TranslateError::ParentEntryHugePage,
+        })?;
 
         if p4[page.p4_index()].is_unused() {
             return Err(TranslateError::PageNotMapped);
         }
 
-        let p3 = unsafe { &*(p3_ptr(page, self.recursive_index)) };
+        let p3 = unsafe { &*(p3_ptr(page, self.recursive_index, self.paging_mode)) };
         let p3_entry = &p3[page.p3_index()];
 
         if p3_entry.is_unused() {
             return Err(TranslateError::PageNotMapped);
         }
 
-        PhysFrame::from_start_address(p3_entry.addr())
-            .map_err(|()| TranslateError::InvalidFrameAddress(p3_entry.addr()))
+        p3_entry
+            .huge_frame()
+            .map_err(TranslateError::InvalidFrameAddress)
     }
 }
 
 impl<'a> Mapper<Size2MiB> for RecursivePageTable<'a> {
-    unsafe fn map_to<A>(
+    unsafe fn map_to_with_table_flags<A>(
         &mut self,
         page: Page<Size2MiB>,
         frame: PhysFrame<Size2MiB>,
         flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
         allocator: &mut A,
     ) -> Result<MapperFlush<Size2MiB>, MapToError>
     where
         A: FrameAllocator<Size4KiB>,
     {
-        self.map_to_2mib(page, frame, flags, allocator)
+        self.map_to_2mib(
+            page,
+            hierarchy::page_entry(frame, flags),
+            parent_table_flags,
+            allocator,
+        )
+    }
+
+    unsafe fn map_to_with_memory_type<A>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame: PhysFrame<Size2MiB>,
+        flags: PageTableFlags,
+        memory_type: MemoryType,
+        pat: &PageAttributeTable,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlush<Size2MiB>, MapToError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let entry = hierarchy::memory_type_entry(frame, flags, memory_type, pat)?;
+        self.map_to_2mib(
+            page,
+            entry,
+            default_parent_table_flags(flags),
+            frame_allocator,
+        )
     }
 
     fn unmap(
         &mut self,
         page: Page<Size2MiB>,
     ) -> Result<(PhysFrame<Size2MiB>, MapperFlush<Size2MiB>), UnmapError> {
-        let p4 = &mut self.p4;
+        let p4 = Self::level_4_table_mut(
+            self.root_table,
+            page,
+            self.recursive_index,
+            self.paging_mode,
+        )
+        .map_err(|err| match err {
+            FrameError::FrameNotPresent => UnmapError::PageNotMapped,
+            FrameError::HugeFrame => UnmapError::ParentEntryHugePage,
+        })?;
         let p4_entry = &p4[page.p4_index()];
         p4_entry.frame().map_err(|err| match err {
             FrameError::FrameNotPresent => UnmapError::PageNotMapped,
             FrameError::HugeFrame => UnmapError::ParentEntryHugePage,
         })?;
 
-        let p3 = unsafe { &mut *(p3_ptr(page, self.recursive_index)) };
+        let p3 = unsafe { &mut *(p3_ptr(page, self.recursive_index, self.paging_mode)) };
         let p3_entry = &p3[page.p3_index()];
         p3_entry.frame().map_err(|err| match err {
             FrameError::FrameNotPresent => UnmapError::PageNotMapped,
             FrameError::HugeFrame => UnmapError::ParentEntryHugePage,
         })?;
 
-        let p2 = unsafe { &mut *(p2_ptr(page, self.recursive_index)) };
+        let p2 = unsafe { &mut *(p2_ptr(page, self.recursive_index, self.paging_mode)) };
         let p2_entry = &mut p2[page.p2_index()];
         let flags = p2_entry.flags();
 
@@ -348,8 +952,9 @@ impl<'a> Mapper<Size2MiB> for RecursivePageTable<'a> {
             return Err(UnmapError::ParentEntryHugePage);
         }
 
-        let frame = PhysFrame::from_start_address(p2_entry.addr())
-            .map_err(|()| UnmapError::InvalidFrameAddress(p2_entry.addr()))?;
+        let frame = p2_entry
+            .huge_frame::<Size2MiB>()
+            .map_err(UnmapError::InvalidFrameAddress)?;
 
         p2_entry.set_unused();
         Ok((frame, MapperFlush::new(page)))
@@ -361,19 +966,28 @@ impl<'a> Mapper<Size2MiB> for RecursivePageTable<'a> {
         flags: PageTableFlags,
     ) -> Result<MapperFlush<Size2MiB>, FlagUpdateError> {
         use crate::structures::paging::PageTableFlags as Flags;
-        let p4 = &mut self.p4;
+        let p4 = Self::level_4_table_mut(
+            self.root_table,
+            page,
+            self.recursive_index,
+            self.paging_mode,
+        )
+        .map_err(|err| match err {
+            FrameError::FrameNotPresent => FlagUpdateError::PageNotMapped,
+            FrameError::HugeFrame => FlagUpdateError::ParentEntryHugePage,
+        })?;
 
         if p4[page.p4_index()].is_unused() {
             return Err(FlagUpdateError::PageNotMapped);
         }
 
-        let p3 = unsafe { &mut *(p3_ptr(page, self.recursive_index)) };
+        let p3 = unsafe { &mut *(p3_ptr(page, self.recursive_index, self.paging_mode)) };
 
         if p3[page.p3_index()].is_unused() {
             return Err(FlagUpdateError::PageNotMapped);
         }
 
-        let p2 = unsafe { &mut *(p2_ptr(page, self.recursive_index)) };
+        let p2 = unsafe { &mut *(p2_ptr(page, self.recursive_index, self.paging_mode)) };
 
         if p2[page.p2_index()].is_unused() {
             return Err(FlagUpdateError::PageNotMapped);
@@ -384,72 +998,176 @@ impl<'a> Mapper<Size2MiB> for RecursivePageTable<'a> {
         Ok(MapperFlush::new(page))
     }
 
+    fn update_flags_and_pat_index(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+        pat_index: u8,
+    ) -> Result<MapperFlush<Size2MiB>, FlagUpdateError> {
+        let entry = unsafe {
+            hierarchy::leaf_entry_mut(
+                &self.table_access(),
+                self.root_table,
+                self.paging_mode,
+                page.start_address(),
+                PageTableLevel::Two,
+            )?
+        };
+        hierarchy::set_flags_and_pat_index::<Size2MiB>(entry, flags, pat_index);
+
+        Ok(MapperFlush::new(page))
+    }
+
+    unsafe fn remap(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame: PhysFrame<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<(PhysFrame<Size2MiB>, MapperFlush<Size2MiB>), FlagUpdateError> {
+        let entry = hierarchy::leaf_entry_mut(
+            &self.table_access(),
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+            PageTableLevel::Two,
+        )?;
+        let old_frame = hierarchy::remap_entry(entry, frame, flags)?;
+
+        Ok((old_frame, MapperFlush::new(page)))
+    }
+
+    fn update_parent_flags(
+        &mut self,
+        page: Page<Size2MiB>,
+        level: PageTableLevel,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        unsafe {
+            hierarchy::update_parent_flags(
+                &self.table_access(),
+                self.root_table,
+                self.paging_mode,
+                page.start_address(),
+                PageTableLevel::Two,
+                level,
+                flags,
+            )?;
+        }
+        Ok(MapperFlushAll::new())
+    }
+
     fn translate_page(&self, page: Page<Size2MiB>) -> Result<PhysFrame<Size2MiB>, TranslateError> {
-        let p4 = &self.p4;
+        let p4 = Self::level_4_table(
+            self.root_table,
+            page,
+            self.recursive_index,
+            self.paging_mode,
+        )
+        .map_err(|err| match err {
+            FrameError::FrameNotPresent => TranslateError::PageNotMapped,
+            FrameError::HugeFrame => TranslateError::ParentEntryHugePage,
+        })?;
 
         if p4[page.p4_index()].is_unused() {
             return Err(TranslateError::PageNotMapped);
         }
 
-        let p3 = unsafe { &*(p3_ptr(page, self.recursive_index)) };
+        let p3 = unsafe { &*(p3_ptr(page, self.recursive_index, self.paging_mode)) };
         let p3_entry = &p3[page.p3_index()];
 
         if p3_entry.is_unused() {
             return Err(TranslateError::PageNotMapped);
         }
 
-        let p2 = unsafe { &*(p2_ptr(page, self.recursive_index)) };
+        let p2 = unsafe { &*(p2_ptr(page, self.recursive_index, self.paging_mode)) };
         let p2_entry = &p2[page.p2_index()];
 
         if p2_entry.is_unused() {
             return Err(TranslateError::PageNotMapped);
         }
 
-        PhysFrame::from_start_address(p2_entry.addr())
-            .map_err(|()| TranslateError::InvalidFrameAddress(p2_entry.addr()))
+        p2_entry
+            .huge_frame()
+            .map_err(TranslateError::InvalidFrameAddress)
     }
 }
 
 impl<'a> Mapper<Size4KiB> for RecursivePageTable<'a> {
-    unsafe fn map_to<A>(
+    unsafe fn map_to_with_table_flags<A>(
         &mut self,
         page: Page<Size4KiB>,
         frame: PhysFrame<Size4KiB>,
         flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
         allocator: &mut A,
     ) -> Result<MapperFlush<Size4KiB>, MapToError>
     where
         A: FrameAllocator<Size4KiB>,
     {
-        self.map_to_4kib(page, frame, flags, allocator)
+        self.map_to_4kib(
+            page,
+            hierarchy::page_entry(frame, flags),
+            parent_table_flags,
+            allocator,
+        )
+    }
+
+    unsafe fn map_to_with_memory_type<A>(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+        memory_type: MemoryType,
+        pat: &PageAttributeTable,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlush<Size4KiB>, MapToError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let entry = hierarchy::memory_type_entry(frame, flags, memory_type, pat)?;
+        self.map_to_4kib(
+            page,
+            entry,
+            default_parent_table_flags(flags),
+            frame_allocator,
+        )
     }
 
     fn unmap(
         &mut self,
         page: Page<Size4KiB>,
     ) -> Result<(PhysFrame<Size4KiB>, MapperFlush<Size4KiB>), UnmapError> {
-        let p4 = &mut self.p4;
+        let p4 = Self::level_4_table_mut(
+            self.root_table,
+            page,
+            self.recursive_index,
+            self.paging_mode,
+        )
+        .map_err(|err| match err {
+            FrameError::FrameNotPresent => UnmapError::PageNotMapped,
+            FrameError::HugeFrame => UnmapError::ParentEntryHugePage,
+        })?;
         let p4_entry = &p4[page.p4_index()];
         p4_entry.frame().map_err(|err| match err {
             FrameError::FrameNotPresent => UnmapError::PageNotMapped,
             FrameError::HugeFrame => UnmapError::ParentEntryHugePage,
         })?;
 
-        let p3 = unsafe { &mut *(p3_ptr(page, self.recursive_index)) };
+        let p3 = unsafe { &mut *(p3_ptr(page, self.recursive_index, self.paging_mode)) };
         let p3_entry = &p3[page.p3_index()];
         p3_entry.frame().map_err(|err| match err {
             FrameError::FrameNotPresent => UnmapError::PageNotMapped,
             FrameError::HugeFrame => UnmapError::ParentEntryHugePage,
         })?;
 
-        let p2 = unsafe { &mut *(p2_ptr(page, self.recursive_index)) };
+        let p2 = unsafe { &mut *(p2_ptr(page, self.recursive_index, self.paging_mode)) };
         let p2_entry = &p2[page.p2_index()];
         p2_entry.frame().map_err(|err| match err {
             FrameError::FrameNotPresent => UnmapError::PageNotMapped,
             FrameError::HugeFrame => UnmapError::ParentEntryHugePage,
         })?;
 
-        let p1 = unsafe { &mut *(p1_ptr(page, self.recursive_index)) };
+        let p1 = unsafe { &mut *(p1_ptr(page, self.recursive_index, self.paging_mode)) };
         let p1_entry = &mut p1[page.p1_index()];
 
         let frame = p1_entry.frame().map_err(|err| match err {
@@ -466,25 +1184,34 @@ impl<'a> Mapper<Size4KiB> for RecursivePageTable<'a> {
         page: Page<Size4KiB>,
         flags: PageTableFlags,
     ) -> Result<MapperFlush<Size4KiB>, FlagUpdateError> {
-        let p4 = &mut self.p4;
+        let p4 = Self::level_4_table_mut(
+            self.root_table,
+            page,
+            self.recursive_index,
+            self.paging_mode,
+        )
+        .map_err(|err| match err {
+            FrameError::FrameNotPresent => FlagUpdateError::PageNotMapped,
+            FrameError::HugeFrame => FlagUpdateError::ParentEntryHugePage,
+        })?;
 
         if p4[page.p4_index()].is_unused() {
             return Err(FlagUpdateError::PageNotMapped);
         }
 
-        let p3 = unsafe { &mut *(p3_ptr(page, self.recursive_index)) };
+        let p3 = unsafe { &mut *(p3_ptr(page, self.recursive_index, self.paging_mode)) };
 
         if p3[page.p3_index()].is_unused() {
             return Err(FlagUpdateError::PageNotMapped);
         }
 
-        let p2 = unsafe { &mut *(p2_ptr(page, self.recursive_index)) };
+        let p2 = unsafe { &mut *(p2_ptr(page, self.recursive_index, self.paging_mode)) };
 
         if p2[page.p2_index()].is_unused() {
             return Err(FlagUpdateError::PageNotMapped);
         }
 
-        let p1 = unsafe { &mut *(p1_ptr(page, self.recursive_index)) };
+        let p1 = unsafe { &mut *(p1_ptr(page, self.recursive_index, self.paging_mode)) };
 
         if p1[page.p1_index()].is_unused() {
             return Err(FlagUpdateError::PageNotMapped);
@@ -495,28 +1222,95 @@ impl<'a> Mapper<Size4KiB> for RecursivePageTable<'a> {
         Ok(MapperFlush::new(page))
     }
 
+    fn update_flags_and_pat_index(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+        pat_index: u8,
+    ) -> Result<MapperFlush<Size4KiB>, FlagUpdateError> {
+        let entry = unsafe {
+            hierarchy::leaf_entry_mut(
+                &self.table_access(),
+                self.root_table,
+                self.paging_mode,
+                page.start_address(),
+                PageTableLevel::One,
+            )?
+        };
+        hierarchy::set_flags_and_pat_index::<Size4KiB>(entry, flags, pat_index);
+
+        Ok(MapperFlush::new(page))
+    }
+
+    unsafe fn remap(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<(PhysFrame<Size4KiB>, MapperFlush<Size4KiB>), FlagUpdateError> {
+        let entry = hierarchy::leaf_entry_mut(
+            &self.table_access(),
+            self.root_table,
+            self.paging_mode,
+            page.start_address(),
+            PageTableLevel::One,
+        )?;
+        let old_frame = hierarchy::remap_entry(entry, frame, flags)?;
+
+        Ok((old_frame, MapperFlush::new(page)))
+    }
+
+    fn update_parent_flags(
+        &mut self,
+        page: Page<Size4KiB>,
+        level: PageTableLevel,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        unsafe {
+            hierarchy::update_parent_flags(
+                &self.table_access(),
+                self.root_table,
+                self.paging_mode,
+                page.start_address(),
+                PageTableLevel::One,
+                level,
+                flags,
+            )?;
+        }
+        Ok(MapperFlushAll::new())
+    }
+
     fn translate_page(&self, page: Page<Size4KiB>) -> Result<PhysFrame<Size4KiB>, TranslateError> {
-        let p4 = &self.p4;
+        let p4 = Self::level_4_table(
+            self.root_table,
+            page,
+            self.recursive_index,
+            self.paging_mode,
+        )
+        .map_err(|err| match err {
+            FrameError::FrameNotPresent => TranslateError::PageNotMapped,
+            FrameError::HugeFrame => TranslateError::ParentEntryHugePage,
+        })?;
 
         if p4[page.p4_index()].is_unused() {
             return Err(TranslateError::PageNotMapped);
         }
 
-        let p3 = unsafe { &*(p3_ptr(page, self.recursive_index)) };
+        let p3 = unsafe { &*(p3_ptr(page, self.recursive_index, self.paging_mode)) };
         let p3_entry = &p3[page.p3_index()];
 
         if p3_entry.is_unused() {
             return Err(TranslateError::PageNotMapped);
         }
 
-        let p2 = unsafe { &*(p2_ptr(page, self.recursive_index)) };
+        let p2 = unsafe { &*(p2_ptr(page, self.recursive_index, self.paging_mode)) };
         let p2_entry = &p2[page.p2_index()];
 
         if p2_entry.is_unused() {
             return Err(TranslateError::PageNotMapped);
         }
 
-        let p1 = unsafe { &*(p1_ptr(page, self.recursive_index)) };
+        let p1 = unsafe { &*(p1_ptr(page, self.recursive_index, self.paging_mode)) };
         let p1_entry = &p1[page.p1_index()];
 
         if p1_entry.is_unused() {
@@ -530,92 +1324,303 @@ impl<'a> Mapper<Size4KiB> for RecursivePageTable<'a> {
 
 impl<'a> MapperAllSizes for RecursivePageTable<'a> {
     fn translate(&self, addr: VirtAddr) -> TranslateResult {
-        let page = Page::containing_address(addr);
-
-        let p4 = &self.p4;
-        let p4_entry = &p4[addr.p4_index()];
-        if p4_entry.is_unused() {
-            return TranslateResult::PageNotMapped;
-        }
-        if p4_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
-            panic!("level 4 entry has huge page bit set")
+        unsafe {
+            hierarchy::translate(
+                &self.table_access(),
+                self.root_table,
+                self.paging_mode,
+                addr,
+            )
         }
+    }
+}
 
-        let p3 = unsafe { &*(p3_ptr(page, self.recursive_index)) };
-        let p3_entry = &p3[addr.p3_index()];
-        if p3_entry.is_unused() {
-            return TranslateResult::PageNotMapped;
-        }
-        if p3_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
-            let frame = PhysFrame::containing_address(p3[addr.p3_index()].addr());
-            let offset = addr.as_u64() & 0o_777_777_7777;
-            return TranslateResult::Frame1GiB { frame, offset };
-        }
+/// An error indicating that a `with_inactive` call failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InactiveTableError {
+    /// The mapper is a mapper for an inactive hierarchy itself, so its root table is not the
+    /// active root table.
+    NotActiveHierarchy,
+    /// The given slot is the index of the recursive entry.
+    RecursiveSlot,
+    /// The entry of the active root table at the given slot is in use.
+    SlotInUse,
+}
 
-        let p2 = unsafe { &*(p2_ptr(page, self.recursive_index)) };
-        let p2_entry = &p2[addr.p2_index()];
-        if p2_entry.is_unused() {
-            return TranslateResult::PageNotMapped;
-        }
-        if p2_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
-            let frame = PhysFrame::containing_address(p2[addr.p2_index()].addr());
-            let offset = addr.as_u64() & 0o_777_7777;
-            return TranslateResult::Frame2MiB { frame, offset };
-        }
+/// The entries of the active root table through which the tables of a hierarchy are accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RecursiveIndex {
+    /// The index of the recursive entry of the active root table.
+    active: u9,
+    /// The index of the entry of the active root table that points to the root table of the
+    /// accessed hierarchy. Equal to `active` for the active hierarchy.
+    root: u9,
+}
 
-        let p1 = unsafe { &*(p1_ptr(page, self.recursive_index)) };
-        let p1_entry = &p1[addr.p1_index()];
-        if p1_entry.is_unused() {
-            return TranslateResult::PageNotMapped;
-        }
-        if p1_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
-            panic!("level 1 entry has huge page bit set")
+impl RecursiveIndex {
+    /// Creates the indices for the active hierarchy with the given recursive index.
+    fn new(recursive_index: u9) -> Self {
+        RecursiveIndex {
+            active: recursive_index,
+            root: recursive_index,
         }
+    }
+}
+
+/// Accesses the page tables through the recursive entry.
+#[derive(Clone, Copy)]
+struct RecursiveTableAccess {
+    recursive_index: RecursiveIndex,
+    paging_mode: PagingMode,
+}
+
+impl PageTableAccess for RecursiveTableAccess {
+    unsafe fn next_table_ptr(
+        &self,
+        _entry: &PageTableEntry,
+        addr: VirtAddr,
+        level: PageTableLevel,
+    ) -> *mut PageTable {
+        let next_level = level
+            .next_lower_level()
+            .expect("level 1 entries map no table");
+        table_page(addr, next_level, self.recursive_index, self.paging_mode)
+            .start_address()
+            .as_mut_ptr()
+    }
+
+    fn is_table_mapping(&self, index: usize) -> bool {
+        // the entry `root` of the active root table is a regular entry of the inactive root table
+        index == usize::from(u16::from(self.recursive_index.active))
+    }
+
+    fn table_entry_changed(&self, addr: VirtAddr, level: PageTableLevel) {
+        let page = table_page(addr, level, self.recursive_index, self.paging_mode);
+        crate::instructions::tlb::flush(page.start_address());
+    }
+
+    fn can_access_frame_table(&self) -> bool {
+        self.temporary_slot().is_some()
+    }
 
-        let frame = match PhysFrame::from_start_address(p1_entry.addr()) {
-            Ok(frame) => frame,
-            Err(()) => return TranslateResult::InvalidFrameAddress(p1_entry.addr()),
+    unsafe fn with_frame_table(&self, frame: PhysFrame, f: &mut dyn FnMut(&mut PageTable)) {
+        // like `with_inactive`, the frame is mapped through an unused entry of the active root
+        // table: looping through the recursive entry and then through `slot` reaches the frame
+        let slot = self
+            .temporary_slot()
+            .expect("the active root table has no unused entry");
+        let active_root = &mut *self.active_root_table();
+        active_root[slot].set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        let recursive_index = RecursiveIndex {
+            active: self.recursive_index.active,
+            root: slot,
         };
-        let offset = u64::from(addr.page_offset());
-        TranslateResult::Frame4KiB { frame, offset }
+        let page = table_page(
+            VirtAddr::new(0),
+            self.paging_mode.top_level(),
+            recursive_index,
+            self.paging_mode,
+        );
+        f(&mut *page.start_address().as_mut_ptr());
+        active_root[slot].set_unused();
+        crate::instructions::tlb::flush(page.start_address());
     }
 }
 
-fn p3_ptr<S: PageSize>(page: Page<S>, recursive_index: u9) -> *mut PageTable {
-    p3_page(page, recursive_index).start_address().as_mut_ptr()
+impl RecursiveTableAccess {
+    /// Returns a pointer to the active root table, which contains the recursive entry.
+    fn active_root_table(&self) -> *mut PageTable {
+        table_page(
+            VirtAddr::new(0),
+            self.paging_mode.top_level(),
+            RecursiveIndex::new(self.recursive_index.active),
+            self.paging_mode,
+        )
+        .start_address()
+        .as_mut_ptr()
+    }
+
+    /// Returns the index of an unused entry of the active root table.
+    fn temporary_slot(&self) -> Option<u9> {
+        let active_root = unsafe { &*self.active_root_table() };
+        (0..512)
+            .map(u9::new)
+            .find(|&index| active_root[index].is_unused())
+    }
 }
 
-fn p3_page<S: PageSize>(page: Page<S>, recursive_index: u9) -> Page {
-    Page::from_page_table_indices(
-        recursive_index,
-        recursive_index,
+fn p4_ptr<S: PageSize>(
+    page: Page<S>,
+    recursive_index: RecursiveIndex,
+    paging_mode: PagingMode,
+) -> *mut PageTable {
+    p4_page(page, recursive_index, paging_mode)
+        .start_address()
+        .as_mut_ptr()
+}
+
+fn p4_page<S: PageSize>(
+    page: Page<S>,
+    recursive_index: RecursiveIndex,
+    paging_mode: PagingMode,
+) -> Page {
+    table_page(
+        page.start_address(),
+        PageTableLevel::Four,
         recursive_index,
-        page.p4_index(),
+        paging_mode,
     )
 }
 
-fn p2_ptr<S: NotGiantPageSize>(page: Page<S>, recursive_index: u9) -> *mut PageTable {
-    p2_page(page, recursive_index).start_address().as_mut_ptr()
+fn p3_ptr<S: PageSize>(
+    page: Page<S>,
+    recursive_index: RecursiveIndex,
+    paging_mode: PagingMode,
+) -> *mut PageTable {
+    p3_page(page, recursive_index, paging_mode)
+        .start_address()
+        .as_mut_ptr()
 }
 
-fn p2_page<S: NotGiantPageSize>(page: Page<S>, recursive_index: u9) -> Page {
-    Page::from_page_table_indices(
+fn p3_page<S: PageSize>(
+    page: Page<S>,
+    recursive_index: RecursiveIndex,
+    paging_mode: PagingMode,
+) -> Page {
+    table_page(
+        page.start_address(),
+        PageTableLevel::Three,
         recursive_index,
+        paging_mode,
+    )
+}
+
+fn p2_ptr<S: NotGiantPageSize>(
+    page: Page<S>,
+    recursive_index: RecursiveIndex,
+    paging_mode: PagingMode,
+) -> *mut PageTable {
+    p2_page(page, recursive_index, paging_mode)
+        .start_address()
+        .as_mut_ptr()
+}
+
+fn p2_page<S: NotGiantPageSize>(
+    page: Page<S>,
+    recursive_index: RecursiveIndex,
+    paging_mode: PagingMode,
+) -> Page {
+    table_page(
+        page.start_address(),
+        PageTableLevel::Two,
         recursive_index,
-        page.p4_index(),
-        page.p3_index(),
+        paging_mode,
     )
 }
 
-fn p1_ptr(page: Page<Size4KiB>, recursive_index: u9) -> *mut PageTable {
-    p1_page(page, recursive_index).start_address().as_mut_ptr()
+fn p1_ptr(
+    page: Page<Size4KiB>,
+    recursive_index: RecursiveIndex,
+    paging_mode: PagingMode,
+) -> *mut PageTable {
+    p1_page(page, recursive_index, paging_mode)
+        .start_address()
+        .as_mut_ptr()
 }
 
-fn p1_page(page: Page<Size4KiB>, recursive_index: u9) -> Page {
-    Page::from_page_table_indices(
+fn p1_page(page: Page<Size4KiB>, recursive_index: RecursiveIndex, paging_mode: PagingMode) -> Page {
+    table_page(
+        page.start_address(),
+        PageTableLevel::One,
         recursive_index,
-        page.p4_index(),
-        page.p3_index(),
-        page.p2_index(),
+        paging_mode,
     )
 }
+
+/// Returns the page through which the level `level` table responsible for `addr` is accessible.
+///
+/// The address of this page loops `level` times through the recursive entry and then uses the
+/// table indices of `addr` above the given level. For example, the level 2 table is accessible
+/// through `(r, r, p4, p3)` with 4-level paging and through `(r, r, p5, p4, p3)` with 5-level
+/// paging. For an inactive hierarchy, the last loop uses the entry that points to its root
+/// table instead, e.g. `(r, s, p4, p3)`. The root table itself is accessible through the page
+/// for its own level, e.g. `(r, r, r, s)`.
+fn table_page(
+    addr: VirtAddr,
+    level: PageTableLevel,
+    recursive_index: RecursiveIndex,
+    paging_mode: PagingMode,
+) -> Page {
+    assert!(level <= paging_mode.top_level(), "invalid page table level");
+    let levels = paging_mode.top_level() as usize;
+    let level = level as usize;
+
+    let mut table_addr = 0;
+    for i in 0..level {
+        let start = 12 + 9 * (levels - 1 - i);
+        let index = if i == level - 1 {
+            recursive_index.root
+        } else {
+            recursive_index.active
+        };
+        table_addr.set_bits(start..start + 9, u64::from(index));
+    }
+    let index_bits = 9 * (levels - level);
+    if index_bits > 0 {
+        let start = 12 + 9 * level;
+        table_addr.set_bits(
+            12..12 + index_bits,
+            addr.as_u64().get_bits(start..start + index_bits),
+        );
+    }
+
+    Page::containing_address(paging_mode.virt_addr(table_addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_page() {
+        let r = u9::new(0o777);
+        let index = RecursiveIndex::new(r);
+        let addr = VirtAddr::new_la57(0o_001_002_003_004_005_0000);
+        let page: Page = Page::containing_address(addr);
+
+        let p3 = p3_page(page, index, PagingMode::Level4);
+        assert_eq!(p3, Page::from_page_table_indices(r, r, r, u9::new(0o002)));
+        let p1 = p1_page(page, index, PagingMode::Level4);
+        assert_eq!(
+            p1,
+            Page::from_page_table_indices(r, u9::new(0o002), u9::new(0o003), u9::new(0o004))
+        );
+
+        let p4 = p4_page(page, index, PagingMode::Level5);
+        assert_eq!(p4.start_address().as_u64(), 0o_177_777_777_777_777_001_0000);
+        let p2 = p2_page(page, index, PagingMode::Level5);
+        assert_eq!(p2.start_address().as_u64(), 0o_177_777_777_001_002_003_0000);
+
+        let s = u9::new(0o776);
+        let inactive = RecursiveIndex { active: r, root: s };
+        let p4 = table_page(addr, PageTableLevel::Four, inactive, PagingMode::Level4);
+        assert_eq!(p4, Page::from_page_table_indices(r, r, r, s));
+        let p2 = p2_page(page, inactive, PagingMode::Level4);
+        assert_eq!(
+            p2,
+            Page::from_page_table_indices(r, s, u9::new(0o002), u9::new(0o003))
+        );
+    }
+
+    #[test]
+    fn inactive_table_mapping() {
+        let r = u9::new(0o777);
+        let s = u9::new(0o776);
+        let access = RecursiveTableAccess {
+            recursive_index: RecursiveIndex { active: r, root: s },
+            paging_mode: PagingMode::Level4,
+        };
+        assert!(access.is_table_mapping(0o777));
+        assert!(!access.is_table_mapping(0o776));
+    }
+}
@@ -0,0 +1,295 @@
+//! Simulated physical memory for exercising the mapper types without real hardware.
+
+use crate::structures::paging::{
+    frame::PhysFrame,
+    frame_alloc::{FrameAllocator, FrameDeallocator},
+    mapper::{MappedPageTable, PhysToVirt},
+    page::{PageSize, Size4KiB},
+    page_table::PageTable,
+};
+use core::marker::PhantomData;
+
+/// Simulated physical memory that is backed by a buffer of page-sized, page-aligned frames.
+///
+/// Frame `i` of the buffer has the physical address `start + i * 4096`. The type implements
+/// `FrameAllocator` and `FrameDeallocator`, so it provides the frames for new page tables, and
+/// its `phys_to_virt` method returns a `PhysToVirt` implementation that resolves the frames to
+/// the buffer. Together, they allow to use `MappedPageTable` on a normal host, e.g. in unit
+/// tests:
+///
+/// ```
+/// use x86_64::structures::paging::{
+///     mapper::SimulatedPhysMemory, FrameAllocator, Mapper, MapperAllSizes, Page, PageTable,
+///     PageTableFlags, PhysFrame, Size4KiB,
+/// };
+/// use x86_64::{PhysAddr, VirtAddr};
+///
+/// let mut frames: Vec<PageTable> = (0..16).map(|_| PageTable::new()).collect();
+/// let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+/// let mut memory = SimulatedPhysMemory::new(&mut frames, start);
+///
+/// let root_frame = memory.allocate_frame().unwrap();
+/// let mut mapper = unsafe { memory.mapper(root_frame) };
+/// let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x4000_0000));
+/// let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+/// unsafe { mapper.map_to(page, frame, PageTableFlags::PRESENT, &mut memory) }
+///     .unwrap()
+///     .ignore();
+/// assert_eq!(
+///     mapper.translate_addr(VirtAddr::new(0x4000_0123)),
+///     Some(PhysAddr::new(0x8000_0123))
+/// );
+/// ```
+///
+/// Only the frames of the buffer can be accessed through the `PhysToVirt` implementation. The
+/// frames that are mapped by the page tables are never accessed, so they can lie outside of
+/// the buffer.
+#[derive(Debug)]
+pub struct SimulatedPhysMemory<'a> {
+    phys_to_virt: SimulatedPhysToVirt<'a>,
+    /// The index of the first frame that was never allocated.
+    next_unused: usize,
+    /// The index of the most recently deallocated frame. Each deallocated frame stores the
+    /// index of the next deallocated frame in its first 8 bytes.
+    free_list: Option<usize>,
+    allocated: usize,
+}
+
+impl<'a> SimulatedPhysMemory<'a> {
+    /// Creates new simulated physical memory from the given buffer. The first frame of the
+    /// buffer gets the physical address of `start`.
+    ///
+    /// Panics if the physical address range of the buffer would overflow.
+    pub fn new(frames: &'a mut [PageTable], start: PhysFrame) -> Self {
+        let len = frames.len();
+        start
+            .start_address()
+            .as_u64()
+            .checked_add(len as u64 * Size4KiB::SIZE)
+            .expect("simulated physical memory overflows the physical address space");
+        SimulatedPhysMemory {
+            phys_to_virt: SimulatedPhysToVirt {
+                frames: frames.as_mut_ptr(),
+                len,
+                start,
+                _marker: PhantomData,
+            },
+            next_unused: 0,
+            free_list: None,
+            allocated: 0,
+        }
+    }
+
+    /// Returns the first frame of the simulated physical memory.
+    pub fn start(&self) -> PhysFrame {
+        self.phys_to_virt.start
+    }
+
+    /// Returns the number of frames of the simulated physical memory.
+    pub fn frame_count(&self) -> usize {
+        self.phys_to_virt.len
+    }
+
+    /// Returns the number of frames that are currently allocated.
+    ///
+    /// This is useful for checking that an operation deallocated all page tables it allocated.
+    pub fn allocated_frames(&self) -> usize {
+        self.allocated
+    }
+
+    /// Returns a `PhysToVirt` implementation that resolves the frames of this memory.
+    ///
+    /// The returned value is not bound to the lifetime of `self`, so the memory can still be
+    /// used as a frame allocator while a `MappedPageTable` uses the returned value.
+    pub fn phys_to_virt(&self) -> SimulatedPhysToVirt<'a> {
+        self.phys_to_virt
+    }
+
+    /// Creates a `MappedPageTable` for the level 4 table in the given frame of this memory.
+    ///
+    /// This function is unsafe because the caller must guarantee that the given frame contains
+    /// a valid level 4 table, e.g. because it was allocated from this memory, and that the
+    /// frame is not accessed through another `MappedPageTable` or deallocated while the
+    /// returned mapper is alive.
+    pub unsafe fn mapper(
+        &self,
+        root_frame: PhysFrame,
+    ) -> MappedPageTable<'a, SimulatedPhysToVirt<'a>> {
+        let root_table = &mut *self.phys_to_virt.phys_to_virt(root_frame);
+        MappedPageTable::new(root_table, self.phys_to_virt)
+    }
+
+    fn frame(&self, index: usize) -> PhysFrame {
+        self.phys_to_virt.start + index as u64
+    }
+}
+
+#[cfg(test)]
+impl<'a> SimulatedPhysMemory<'a> {
+    /// Returns a buffer of `count` zeroed frames for a simulated physical memory.
+    pub(crate) fn buffer(count: usize) -> Vec<PageTable> {
+        (0..count).map(|_| PageTable::new()).collect()
+    }
+
+    /// Creates simulated physical memory from the given buffer and allocates the frame for a
+    /// root table from it, which can be passed to `mapper`.
+    pub(crate) fn with_root(frames: &'a mut [PageTable], start: PhysFrame) -> (Self, PhysFrame) {
+        let mut memory = Self::new(frames, start);
+        let root_frame = memory.allocate_frame().unwrap();
+        (memory, root_frame)
+    }
+}
+
+impl<'a> FrameAllocator<Size4KiB> for SimulatedPhysMemory<'a> {
+    /// Allocates a zeroed frame of the simulated physical memory.
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let index = match self.free_list {
+            Some(index) => {
+                let next = unsafe { *(self.phys_to_virt.frame_ptr(index) as *const u64) };
+                self.free_list = if next == u64::max_value() {
+                    None
+                } else {
+                    Some(next as usize)
+                };
+                index
+            }
+            None if self.next_unused < self.phys_to_virt.len => {
+                self.next_unused += 1;
+                self.next_unused - 1
+            }
+            None => return None,
+        };
+        unsafe { (*self.phys_to_virt.frame_ptr(index)).zero() };
+        self.allocated += 1;
+        Some(self.frame(index))
+    }
+}
+
+impl<'a> FrameDeallocator<Size4KiB> for SimulatedPhysMemory<'a> {
+    /// Returns the given frame to the simulated physical memory.
+    ///
+    /// Panics if the frame is not part of the simulated physical memory.
+    fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = self.phys_to_virt.index(frame);
+        let next = self.free_list.map_or(u64::max_value(), |next| next as u64);
+        unsafe { *(self.phys_to_virt.frame_ptr(index) as *mut u64) = next };
+        self.free_list = Some(index);
+        self.allocated -= 1;
+    }
+}
+
+/// Resolves the frames of a `SimulatedPhysMemory` to pointers into its buffer.
+///
+/// Created through `SimulatedPhysMemory::phys_to_virt`.
+#[derive(Debug, Clone, Copy)]
+pub struct SimulatedPhysToVirt<'a> {
+    frames: *mut PageTable,
+    len: usize,
+    start: PhysFrame,
+    _marker: PhantomData<&'a mut [PageTable]>,
+}
+
+impl<'a> SimulatedPhysToVirt<'a> {
+    /// Returns whether the given frame is part of the simulated physical memory.
+    pub fn contains(&self, frame: PhysFrame) -> bool {
+        frame >= self.start && frame - self.start < self.len as u64
+    }
+
+    /// Returns the index of the given frame in the buffer.
+    ///
+    /// Panics if the frame is not part of the simulated physical memory.
+    fn index(&self, frame: PhysFrame) -> usize {
+        assert!(
+            self.contains(frame),
+            "frame {:?} is not part of the simulated physical memory",
+            frame
+        );
+        (frame - self.start) as usize
+    }
+
+    fn frame_ptr(&self, index: usize) -> *mut PageTable {
+        debug_assert!(index < self.len);
+        unsafe { self.frames.add(index) }
+    }
+}
+
+impl<'a> PhysToVirt for SimulatedPhysToVirt<'a> {
+    /// Returns a pointer into the buffer of the simulated physical memory.
+    ///
+    /// Panics if the frame is not part of the simulated physical memory.
+    fn phys_to_virt(&self, phys_frame: PhysFrame) -> *mut PageTable {
+        self.frame_ptr(self.index(phys_frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::paging::mapper::{Mapper, MapperAllSizes};
+    use crate::structures::paging::{Page, PageTableFlags};
+    use crate::{PhysAddr, VirtAddr};
+
+    fn start() -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(0x10_0000))
+    }
+
+    #[test]
+    fn allocate_and_deallocate() {
+        let mut frames = SimulatedPhysMemory::buffer(2);
+        let mut memory = SimulatedPhysMemory::new(&mut frames, start());
+        let a = memory.allocate_frame().unwrap();
+        let b = memory.allocate_frame().unwrap();
+        assert_eq!(a, start());
+        assert_eq!(b, start() + 1);
+        assert_eq!(memory.allocate_frame(), None);
+        assert_eq!(memory.allocated_frames(), 2);
+
+        let table = unsafe { &mut *memory.phys_to_virt().phys_to_virt(a) };
+        table[3].set_addr(PhysAddr::new(0x5000), PageTableFlags::PRESENT);
+        memory.deallocate_frame(b);
+        memory.deallocate_frame(a);
+        assert_eq!(memory.allocate_frame(), Some(a));
+        assert!(unsafe { &*memory.phys_to_virt().phys_to_virt(a) }
+            .iter()
+            .all(|entry| entry.is_unused()));
+        assert_eq!(memory.allocate_frame(), Some(b));
+        assert_eq!(memory.allocated_frames(), 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn phys_to_virt_outside_of_memory() {
+        let mut frames = SimulatedPhysMemory::buffer(1);
+        let memory = SimulatedPhysMemory::new(&mut frames, start());
+        memory.phys_to_virt().phys_to_virt(start() + 1);
+    }
+
+    #[test]
+    fn map_translate_unmap() {
+        let mut frames = SimulatedPhysMemory::buffer(8);
+        let mut memory = SimulatedPhysMemory::new(&mut frames, start());
+        let root_frame = memory.allocate_frame().unwrap();
+        let mut mapper = unsafe { memory.mapper(root_frame) };
+
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x4000_1000));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe { mapper.map_to(page, frame, flags, &mut memory) }
+            .unwrap()
+            .ignore();
+        assert_eq!(memory.allocated_frames(), 4);
+        assert_eq!(
+            mapper.translate_addr(VirtAddr::new(0x4000_1abc)),
+            Some(PhysAddr::new(0x8000_0abc))
+        );
+        assert_eq!(mapper.translate_addr(VirtAddr::new(0x4000_2000)), None);
+
+        let (unmapped, flush) = mapper.unmap(page).unwrap();
+        flush.ignore();
+        assert_eq!(unmapped, frame);
+        assert_eq!(mapper.translate_addr(VirtAddr::new(0x4000_1abc)), None);
+
+        unsafe { mapper.clean_up(&mut memory) };
+        assert_eq!(memory.allocated_frames(), 1);
+    }
+}
@@ -0,0 +1,492 @@
+//! Physical memory maps as reported by the firmware or the bootloader.
+
+use crate::structures::paging::{
+    frame::{PhysFrame, PhysFrameRange},
+    page::{PageSize, Size4KiB},
+};
+use crate::PhysAddr;
+use core::fmt;
+
+/// The largest physical end address that a region can have.
+const MAX_END: u64 = 0x000f_ffff_ffff_f000;
+
+/// The kind of memory in a `MemoryRegion`.
+///
+/// The variants are ordered by priority: where regions of different kinds overlap,
+/// `MemoryMap::merge` keeps the kind that is declared later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MemoryRegionKind {
+    /// Free memory that can be used by the kernel.
+    Usable,
+    /// Memory that is reserved by the firmware or by the hardware, e.g. for memory mapped I/O.
+    Reserved,
+    /// Memory that contains ACPI tables. It can be used after the tables were read.
+    AcpiReclaimable,
+    /// Memory that is used by the ACPI firmware and must be preserved across sleep states.
+    AcpiNvs,
+    /// Memory that contains errors and must not be used.
+    BadMemory,
+}
+
+/// A contiguous region of physical memory of a single kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    /// The start address of the region, inclusive.
+    pub start: PhysAddr,
+    /// The end address of the region, exclusive.
+    pub end: PhysAddr,
+    /// The kind of memory in the region.
+    pub kind: MemoryRegionKind,
+}
+
+impl MemoryRegion {
+    /// Creates a new region for the physical addresses `start..end`.
+    pub fn new(start: PhysAddr, end: PhysAddr, kind: MemoryRegionKind) -> Self {
+        MemoryRegion { start, end, kind }
+    }
+
+    /// Returns whether the region contains no memory.
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+
+    /// Returns the size of the region in bytes.
+    pub fn size(&self) -> u64 {
+        if self.is_empty() {
+            0
+        } else {
+            self.end - self.start
+        }
+    }
+
+    /// Returns the frames that lie completely inside of the region.
+    pub fn frame_range(&self) -> PhysFrameRange {
+        let start = PhysFrame::containing_address(self.start.align_up(Size4KiB::SIZE));
+        let end = PhysFrame::containing_address(self.end.align_down(Size4KiB::SIZE));
+        if start < end {
+            PhysFrame::range(start, end)
+        } else {
+            PhysFrame::range(start, start)
+        }
+    }
+
+    /// Creates a region from a base address and a length as used by the firmware tables.
+    ///
+    /// Addresses that are not valid physical addresses are clamped.
+    fn from_base_and_length(base: u64, length: u64, kind: MemoryRegionKind) -> Self {
+        let start = base.min(MAX_END);
+        let end = base.saturating_add(length).min(MAX_END);
+        MemoryRegion::new(PhysAddr::new(start), PhysAddr::new(end), kind)
+    }
+}
+
+impl Default for MemoryRegion {
+    /// Returns an empty reserved region at address 0.
+    fn default() -> Self {
+        MemoryRegion::new(
+            PhysAddr::new(0),
+            PhysAddr::new(0),
+            MemoryRegionKind::Reserved,
+        )
+    }
+}
+
+/// A map of the physical memory, consisting of a list of `MemoryRegion`s.
+///
+/// The regions are stored in a buffer that is provided by the caller, so that no heap is
+/// required:
+///
+/// ```
+/// use x86_64::structures::paging::memory_map::{MemoryMap, MemoryRegion};
+///
+/// let mut buffer = [MemoryRegion::default(); 64];
+/// let mut memory_map = MemoryMap::new(&mut buffer);
+/// ```
+///
+/// The map can be filled from the memory maps of the BIOS (E820), of UEFI and of Multiboot2
+/// bootloaders. The reported regions might overlap and are not necessarily sorted, so `merge`
+/// should be called before the usable regions are passed to a frame allocator.
+pub struct MemoryMap<'a> {
+    regions: &'a mut [MemoryRegion],
+    len: usize,
+}
+
+impl<'a> MemoryMap<'a> {
+    /// Creates an empty memory map that stores its regions in the given buffer.
+    pub fn new(buffer: &'a mut [MemoryRegion]) -> Self {
+        MemoryMap {
+            regions: buffer,
+            len: 0,
+        }
+    }
+
+    /// Returns the maximum number of regions of the map.
+    pub fn capacity(&self) -> usize {
+        self.regions.len()
+    }
+
+    /// Returns the regions of the map.
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions[..self.len]
+    }
+
+    /// Appends the given region to the map. Empty regions are ignored.
+    pub fn push(&mut self, region: MemoryRegion) -> Result<(), MemoryMapError> {
+        if region.is_empty() {
+            return Ok(());
+        }
+        let slot = self.regions.get_mut(self.len).ok_or(MemoryMapError::Full)?;
+        *slot = region;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Appends the regions of a raw E820 memory map, as returned by the BIOS function
+    /// `int 0x15, eax=0xe820`.
+    ///
+    /// Each entry consists of a 64-bit base address, a 64-bit length and a 32-bit type. With
+    /// ACPI 3.0, an additional 32-bit field with extended attributes follows; entries whose
+    /// “enabled” attribute is not set are ignored. The `entry_size` must be at least 20 bytes.
+    pub fn add_e820_entries(
+        &mut self,
+        entries: &[u8],
+        entry_size: usize,
+    ) -> Result<(), MemoryMapError> {
+        if entry_size < 20 {
+            return Err(MemoryMapError::InvalidEntrySize);
+        }
+        for entry in entries.chunks_exact(entry_size) {
+            if entry_size >= 24 && read_u32(entry, 20) & 1 == 0 {
+                continue;
+            }
+            let kind = match read_u32(entry, 16) {
+                1 => MemoryRegionKind::Usable,
+                3 => MemoryRegionKind::AcpiReclaimable,
+                4 => MemoryRegionKind::AcpiNvs,
+                5 => MemoryRegionKind::BadMemory,
+                _ => MemoryRegionKind::Reserved,
+            };
+            self.push(MemoryRegion::from_base_and_length(
+                read_u64(entry, 0),
+                read_u64(entry, 8),
+                kind,
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Appends the regions of an array of UEFI `EFI_MEMORY_DESCRIPTOR`s, as returned by
+    /// `GetMemoryMap`.
+    ///
+    /// The descriptors are `descriptor_size` bytes apart, which might be larger than the
+    /// 40 bytes of the descriptor structure. Memory of the boot services and of the loader is
+    /// reported as usable, since it can be reused after `ExitBootServices`. The memory that
+    /// contains the kernel image must be removed through `subtract`.
+    pub fn add_uefi_descriptors(
+        &mut self,
+        descriptors: &[u8],
+        descriptor_size: usize,
+    ) -> Result<(), MemoryMapError> {
+        if descriptor_size < 40 {
+            return Err(MemoryMapError::InvalidEntrySize);
+        }
+        for descriptor in descriptors.chunks_exact(descriptor_size) {
+            let kind = match read_u32(descriptor, 0) {
+                // loader code/data, boot services code/data and conventional memory
+                1..=4 | 7 => MemoryRegionKind::Usable,
+                8 => MemoryRegionKind::BadMemory,
+                9 => MemoryRegionKind::AcpiReclaimable,
+                10 => MemoryRegionKind::AcpiNvs,
+                _ => MemoryRegionKind::Reserved,
+            };
+            let pages = read_u64(descriptor, 24);
+            self.push(MemoryRegion::from_base_and_length(
+                read_u64(descriptor, 8),
+                pages.saturating_mul(Size4KiB::SIZE),
+                kind,
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Appends the regions of a Multiboot2 memory map tag (tag type 6).
+    ///
+    /// The passed slice starts at the tag header and must contain the complete tag.
+    pub fn add_multiboot2_tag(&mut self, tag: &[u8]) -> Result<(), MemoryMapError> {
+        if tag.len() < 16 || read_u32(tag, 0) != 6 {
+            return Err(MemoryMapError::InvalidTag);
+        }
+        let size = read_u32(tag, 4) as usize;
+        let entry_size = read_u32(tag, 8) as usize;
+        if size < 16 || size > tag.len() {
+            return Err(MemoryMapError::InvalidTag);
+        }
+        if entry_size < 24 {
+            return Err(MemoryMapError::InvalidEntrySize);
+        }
+        for entry in tag[16..size].chunks_exact(entry_size) {
+            let kind = match read_u32(entry, 16) {
+                1 => MemoryRegionKind::Usable,
+                3 => MemoryRegionKind::AcpiReclaimable,
+                4 => MemoryRegionKind::AcpiNvs,
+                5 => MemoryRegionKind::BadMemory,
+                _ => MemoryRegionKind::Reserved,
+            };
+            self.push(MemoryRegion::from_base_and_length(
+                read_u64(entry, 0),
+                read_u64(entry, 8),
+                kind,
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Sorts the regions by their start address.
+    pub fn sort(&mut self) {
+        self.regions[..self.len].sort_unstable_by_key(|region| (region.start, region.kind));
+    }
+
+    /// Resolves overlaps between regions and combines adjacent regions of the same kind.
+    ///
+    /// Where regions of different kinds overlap, the overlapping part is assigned to the kind
+    /// with the higher priority (see `MemoryRegionKind`). Afterwards, the regions are sorted and
+    /// don't overlap. Returns `MemoryMapError::Full` if a region had to be split but the map is
+    /// full; the map is left in a valid but unmerged state in this case.
+    pub fn merge(&mut self) -> Result<(), MemoryMapError> {
+        let mut index = 0;
+        while index < self.len {
+            for other in 0..self.len {
+                let other = self.regions[other];
+                if other.kind > self.regions[index].kind {
+                    self.subtract_from(index, other.start, other.end)?;
+                }
+            }
+            index += 1;
+        }
+        self.remove_empty();
+        self.sort();
+
+        let mut merged = 0;
+        for index in 0..self.len {
+            let region = self.regions[index];
+            if merged > 0 {
+                let last = &mut self.regions[merged - 1];
+                if last.kind == region.kind && region.start <= last.end {
+                    last.end = last.end.max(region.end);
+                    continue;
+                }
+            }
+            self.regions[merged] = region;
+            merged += 1;
+        }
+        self.len = merged;
+        Ok(())
+    }
+
+    /// Removes the given frames from all regions, e.g. because they contain the kernel image or
+    /// the initial page tables.
+    ///
+    /// Returns `MemoryMapError::Full` if a region had to be split but the map is full.
+    pub fn subtract(&mut self, frames: PhysFrameRange) -> Result<(), MemoryMapError> {
+        if frames.is_empty() {
+            return Ok(());
+        }
+        let start = frames.start.start_address();
+        let end = frames.end.start_address();
+        for index in 0..self.len {
+            self.subtract_from(index, start, end)?;
+        }
+        self.remove_empty();
+        Ok(())
+    }
+
+    /// Returns the frames of all usable regions, one range per region.
+    ///
+    /// The ranges can be passed to the constructors of `BitmapFrameAllocator` and
+    /// `BuddyFrameAllocator`. They only don't overlap if `merge` was called before. The
+    /// constructors are unsafe because the caller must guarantee that the usable regions are
+    /// really unused, so frames in use by the kernel or the boot information must be removed
+    /// through `subtract` first.
+    pub fn usable_regions(&self) -> impl Iterator<Item = PhysFrameRange> + Clone + '_ {
+        self.regions()
+            .iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            .map(MemoryRegion::frame_range)
+            .filter(|range| !range.is_empty())
+    }
+
+    /// Returns an iterator over all usable frames.
+    pub fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + Clone + '_ {
+        self.usable_regions().flatten()
+    }
+
+    /// Removes `start..end` from the region at `index`. If the region is split into two parts,
+    /// the upper part is appended to the map.
+    fn subtract_from(
+        &mut self,
+        index: usize,
+        start: PhysAddr,
+        end: PhysAddr,
+    ) -> Result<(), MemoryMapError> {
+        let region = self.regions[index];
+        if region.is_empty() || end <= region.start || start >= region.end {
+            return Ok(());
+        }
+        let lower = MemoryRegion::new(region.start, start, region.kind);
+        let upper = MemoryRegion::new(end, region.end, region.kind);
+        if !lower.is_empty() && !upper.is_empty() {
+            self.push(upper)?;
+            self.regions[index] = lower;
+        } else if !upper.is_empty() {
+            self.regions[index] = upper;
+        } else {
+            // empty if the region is completely covered
+            self.regions[index] = lower;
+        }
+        Ok(())
+    }
+
+    fn remove_empty(&mut self) {
+        let mut kept = 0;
+        for index in 0..self.len {
+            if !self.regions[index].is_empty() {
+                self.regions[kept] = self.regions[index];
+                kept += 1;
+            }
+        }
+        self.len = kept;
+    }
+}
+
+impl<'a> fmt::Debug for MemoryMap<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.regions()).finish()
+    }
+}
+
+/// An error that occurred while building a `MemoryMap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryMapError {
+    /// The buffer of the memory map has no space for another region.
+    Full,
+    /// The entry size of a firmware memory map is smaller than the size of an entry.
+    InvalidEntrySize,
+    /// The passed Multiboot2 tag is no memory map tag or its size is invalid.
+    InvalidTag,
+}
+
+/// Reads a little endian `u32` at the given offset.
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    bytes[offset..offset + 4]
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| value << 8 | u32::from(byte))
+}
+
+/// Reads a little endian `u64` at the given offset.
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    bytes[offset..offset + 8]
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| value << 8 | u64::from(byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(buffer: &mut Vec<u8>, base: u64, length: u64, kind: u32) {
+        for i in 0..8 {
+            buffer.push((base >> (i * 8)) as u8);
+        }
+        for i in 0..8 {
+            buffer.push((length >> (i * 8)) as u8);
+        }
+        for i in 0..4 {
+            buffer.push((kind >> (i * 8)) as u8);
+        }
+    }
+
+    fn region(start: u64, end: u64, kind: MemoryRegionKind) -> MemoryRegion {
+        MemoryRegion::new(PhysAddr::new(start), PhysAddr::new(end), kind)
+    }
+
+    fn frame(addr: u64) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(addr))
+    }
+
+    #[test]
+    fn e820_merge_and_subtract() {
+        let mut entries = Vec::new();
+        entry(&mut entries, 0x10_0000, 0x70_0000, 1);
+        entry(&mut entries, 0, 0x9_fc00, 1);
+        entry(&mut entries, 0x9_fc00, 0x400, 2);
+        entry(&mut entries, 0x40_0000, 0x1000, 3);
+        entry(&mut entries, 0x80_0000, 0x10_0000, 1);
+
+        let mut buffer = [MemoryRegion::default(); 8];
+        let mut memory_map = MemoryMap::new(&mut buffer);
+        memory_map.add_e820_entries(&entries, 20).unwrap();
+        memory_map.merge().unwrap();
+        memory_map
+            .subtract(PhysFrame::range(frame(0x20_0000), frame(0x30_0000)))
+            .unwrap();
+        memory_map.sort();
+
+        use self::MemoryRegionKind::*;
+        assert_eq!(
+            memory_map.regions(),
+            &[
+                region(0, 0x9_fc00, Usable),
+                region(0x9_fc00, 0xa_0000, Reserved),
+                region(0x10_0000, 0x20_0000, Usable),
+                region(0x30_0000, 0x40_0000, Usable),
+                region(0x40_0000, 0x40_1000, AcpiReclaimable),
+                region(0x40_1000, 0x90_0000, Usable),
+            ][..]
+        );
+        assert_eq!(
+            memory_map.usable_regions().next(),
+            Some(PhysFrame::range(frame(0), frame(0x9_f000)))
+        );
+        assert_eq!(
+            memory_map.usable_frames().count(),
+            0x9f + 0x100 + 0x100 + 0x4ff
+        );
+    }
+
+    #[test]
+    fn multiboot2_and_uefi() {
+        let mut tag = vec![6, 0, 0, 0, 0, 0, 0, 0, 24, 0, 0, 0, 0, 0, 0, 0];
+        entry(&mut tag, 0x10_0000, 0x10_0000, 1);
+        tag.extend_from_slice(&[0; 4]);
+        entry(&mut tag, 0xfee0_0000, 0x1000, 2);
+        tag.extend_from_slice(&[0; 4]);
+        let size = tag.len() as u8;
+        tag[4] = size;
+
+        let mut descriptor = vec![0; 48];
+        descriptor[0] = 7;
+        descriptor[8..16].copy_from_slice(&[0, 0, 0x20, 0, 0, 0, 0, 0]);
+        descriptor[24] = 0x10;
+
+        let mut buffer = [MemoryRegion::default(); 4];
+        let mut memory_map = MemoryMap::new(&mut buffer);
+        memory_map.add_multiboot2_tag(&tag).unwrap();
+        memory_map.add_uefi_descriptors(&descriptor, 48).unwrap();
+        assert_eq!(
+            memory_map.regions(),
+            &[
+                region(0x10_0000, 0x20_0000, MemoryRegionKind::Usable),
+                region(0xfee0_0000, 0xfee0_1000, MemoryRegionKind::Reserved),
+                region(0x20_0000, 0x21_0000, MemoryRegionKind::Usable),
+            ][..]
+        );
+        memory_map.merge().unwrap();
+        assert_eq!(memory_map.regions().len(), 2);
+        assert_eq!(
+            memory_map.add_multiboot2_tag(&descriptor),
+            Err(MemoryMapError::InvalidTag)
+        );
+    }
+}
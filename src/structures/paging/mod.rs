@@ -3,16 +3,23 @@
 //! Page tables translate virtual memory “pages” to physical memory “frames”.
 
 pub use self::frame::PhysFrame;
-pub use self::frame_alloc::{FrameAllocator, FrameDeallocator};
+pub use self::frame_alloc::{
+    BitmapFrameAllocator, BuddyFrameAllocator, FrameAllocator, FrameDeallocator,
+};
 #[cfg(target_arch = "x86_64")]
 #[doc(no_inline)]
-pub use self::mapper::{MappedPageTable, RecursivePageTable};
-pub use self::mapper::{Mapper, MapperAllSizes};
-pub use self::page::{Page, PageSize, Size1GiB, Size2MiB, Size4KiB};
-pub use self::page_table::{PageTable, PageTableFlags};
+pub use self::mapper::{MappedPageTable, OffsetPageTable, RecursivePageTable};
+pub use self::mapper::{MappedPage, Mapper, MapperAllSizes};
+pub use self::page::{HugePageSize, Page, PageSize, Size1GiB, Size2MiB, Size4KiB};
+pub use self::page_table::{PageTable, PageTableFlags, PageTableLevel, PagingMode};
 
+pub mod demand;
+pub mod direct_map;
 pub mod frame;
 mod frame_alloc;
 pub mod mapper;
+pub mod memory_map;
 pub mod page;
 pub mod page_table;
+pub mod range_alloc;
+pub mod stack;
@@ -18,6 +18,12 @@ pub trait PageSize: Copy + Eq + PartialOrd + Ord {
 /// This trait is implemented for 4KiB and 2MiB pages, but not for 1GiB pages.
 pub trait NotGiantPageSize: PageSize {}
 
+/// This trait is implemented for the huge page sizes 2MiB and 1GiB.
+pub trait HugePageSize: PageSize {
+    /// The size of the 512 pages that a huge page of this size can be split into.
+    type SplitSize: PageSize;
+}
+
 /// A standard 4KiB page.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Size4KiB {}
@@ -46,11 +52,19 @@ impl PageSize for Size2MiB {
 
 impl NotGiantPageSize for Size2MiB {}
 
+impl HugePageSize for Size2MiB {
+    type SplitSize = Size4KiB;
+}
+
 impl PageSize for Size1GiB {
     const SIZE: u64 = Size2MiB::SIZE * 512;
     const SIZE_AS_DEBUG_STR: &'static str = "1GiB";
 }
 
+impl HugePageSize for Size1GiB {
+    type SplitSize = Size2MiB;
+}
+
 /// A virtual memory page.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(C)]
@@ -91,6 +105,13 @@ impl<S: PageSize> Page<S> {
         S::SIZE
     }
 
+    /// Returns the level 5 page table index of this page.
+    ///
+    /// This index is only used with 5-level paging.
+    pub fn p5_index(&self) -> u9 {
+        self.start_address().p5_index()
+    }
+
     /// Returns the level 4 page table index of this page.
     pub fn p4_index(&self) -> u9 {
         self.start_address().p4_index()
@@ -173,10 +194,26 @@ impl<S: PageSize> fmt::Debug for Page<S> {
     }
 }
 
+impl<S: PageSize> Page<S> {
+    /// Returns the page that starts at the given raw address.
+    ///
+    /// Page arithmetic doesn't know the paging mode, so the address is sign extended from bit
+    /// 56. This keeps the sign extension of both 48-bit and 57-bit canonical addresses, so
+    /// `Page` and `PageRange` work with 4-level and 5-level paging. Panics if the computation
+    /// of `addr` overflowed.
+    fn from_raw_start_address(addr: Option<u64>) -> Self {
+        let addr = addr.expect("page arithmetic overflowed");
+        Page::containing_address(VirtAddr::new_unchecked_la57(addr))
+    }
+}
+
 impl<S: PageSize> Add<u64> for Page<S> {
     type Output = Self;
     fn add(self, rhs: u64) -> Self::Output {
-        Page::containing_address(self.start_address() + rhs * u64::from(S::SIZE))
+        let offset = rhs.checked_mul(S::SIZE);
+        Page::from_raw_start_address(
+            offset.and_then(|offset| self.start_address().as_u64().checked_add(offset)),
+        )
     }
 }
 
@@ -189,7 +226,10 @@ impl<S: PageSize> AddAssign<u64> for Page<S> {
 impl<S: PageSize> Sub<u64> for Page<S> {
     type Output = Self;
     fn sub(self, rhs: u64) -> Self::Output {
-        Page::containing_address(self.start_address() - rhs * u64::from(S::SIZE))
+        let offset = rhs.checked_mul(S::SIZE);
+        Page::from_raw_start_address(
+            offset.and_then(|offset| self.start_address().as_u64().checked_sub(offset)),
+        )
     }
 }
 
@@ -327,4 +367,30 @@ mod tests {
         }
         assert_eq!(range_inclusive.next(), None);
     }
+
+    #[test]
+    fn page_ranges_with_5_level_paging() {
+        let start: Page = Page::containing_address(VirtAddr::new_la57(0x0100_0000_0000_0000));
+        assert_eq!(start.start_address().as_u64(), 0xff00_0000_0000_0000);
+        let pages: Vec<_> = Page::range(start, start + 2)
+            .map(|page| page.start_address().as_u64())
+            .collect();
+        assert_eq!(pages, [0xff00_0000_0000_0000, 0xff00_0000_0000_1000]);
+
+        // the lower half of 5-level paging extends beyond bit 47
+        let start: Page<Size2MiB> =
+            Page::containing_address(VirtAddr::new_la57(0x0000_7fff_ffe0_0000));
+        let end = start + 2;
+        assert_eq!(end.start_address().as_u64(), 0x0000_8000_0020_0000);
+        assert_eq!(Page::range(start, end).count(), 2);
+        assert_eq!(end - 2, start);
+        assert_eq!(end - start, 2);
+
+        // 4-level addresses keep their sign extension
+        let start: Page = Page::containing_address(VirtAddr::new(0xffff_8000_0000_0000));
+        assert_eq!(
+            (start + 1).start_address(),
+            VirtAddr::new(0xffff_8000_0000_1000)
+        );
+    }
 }
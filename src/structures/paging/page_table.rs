@@ -2,9 +2,11 @@
 
 use core::fmt;
 use core::ops::{Index, IndexMut};
+use core::sync::atomic::{AtomicU64, Ordering};
 
-use super::{PageSize, PhysFrame, Size4KiB};
-use crate::addr::PhysAddr;
+use super::{HugePageSize, PageSize, PhysFrame, Size4KiB};
+use crate::addr::{PhysAddr, VirtAddr};
+use crate::registers::model_specific::{MemoryType, PageAttributeTable};
 
 use bitflags::bitflags;
 use usize_conversions::usize_from;
@@ -70,6 +72,16 @@ impl PageTableEntry {
         }
     }
 
+    /// Returns the physical frame of size `S` that this entry maps as a huge page.
+    ///
+    /// Bit 12 of a huge page entry is the PAT bit, which `addr` reports as part of the address.
+    /// This method masks it out. The flags of the entry are not checked. If the remaining
+    /// address is not aligned to `S`, it is returned as error.
+    pub fn huge_frame<S: HugePageSize>(&self) -> Result<PhysFrame<S>, PhysAddr> {
+        let addr = PhysAddr::new(self.addr().as_u64() & !Self::pat_bit::<S>());
+        PhysFrame::from_start_address(addr).map_err(|()| addr)
+    }
+
     /// Map the entry to the specified physical address with the specified flags.
     pub fn set_addr(&mut self, addr: PhysAddr, flags: PageTableFlags) {
         assert!(addr.is_aligned(Size4KiB::SIZE));
@@ -86,6 +98,108 @@ impl PageTableEntry {
     pub fn set_flags(&mut self, flags: PageTableFlags) {
         self.entry = self.addr().as_u64() | flags.bits();
     }
+
+    /// Atomically clears the given flags and returns which of them were set before.
+    ///
+    /// The CPU sets the `ACCESSED` and `DIRTY` flags while the entry is in use, so clearing
+    /// them through `set_flags` could lose an update that happens between the read and the
+    /// write. This method uses a locked read-modify-write instead.
+    pub fn test_and_clear_flags(&mut self, flags: PageTableFlags) -> PageTableFlags {
+        let entry = unsafe { &*(&mut self.entry as *mut u64 as *const AtomicU64) };
+        let old = entry.fetch_and(!flags.bits(), Ordering::SeqCst);
+        PageTableFlags::from_bits_truncate(old) & flags
+    }
+
+    /// Returns the index into the page attribute table that this entry selects, assuming that
+    /// it maps a page of size `S`.
+    ///
+    /// The index is formed by the `WRITE_THROUGH` flag (bit 0 of the index), the `NO_CACHE` flag
+    /// (bit 1) and the PAT bit (bit 2). The PAT bit is bit 7 of an entry that maps a 4KiB page
+    /// and bit 12 of an entry that maps a huge page.
+    pub fn pat_index<S: PageSize>(&self) -> u8 {
+        let mut index = 0;
+        if self.entry & PageTableFlags::WRITE_THROUGH.bits() != 0 {
+            index |= 0b001;
+        }
+        if self.entry & PageTableFlags::NO_CACHE.bits() != 0 {
+            index |= 0b010;
+        }
+        if self.entry & Self::pat_bit::<S>() != 0 {
+            index |= 0b100;
+        }
+        index
+    }
+
+    /// Sets the index into the page attribute table that this entry selects, assuming that it
+    /// maps a page of size `S`.
+    ///
+    /// See `pat_index` for a description of the encoding. Panics if the index is not smaller
+    /// than 8.
+    pub fn set_pat_index<S: PageSize>(&mut self, index: u8) {
+        assert!(index < 8, "PAT index must be smaller than 8");
+        let mask = PageTableFlags::WRITE_THROUGH.bits()
+            | PageTableFlags::NO_CACHE.bits()
+            | Self::pat_bit::<S>();
+        let mut bits = 0;
+        if index & 0b001 != 0 {
+            bits |= PageTableFlags::WRITE_THROUGH.bits();
+        }
+        if index & 0b010 != 0 {
+            bits |= PageTableFlags::NO_CACHE.bits();
+        }
+        if index & 0b100 != 0 {
+            bits |= Self::pat_bit::<S>();
+        }
+        self.entry = (self.entry & !mask) | bits;
+    }
+
+    /// Returns the memory type that this entry selects from the given page attribute table,
+    /// assuming that it maps a page of size `S`.
+    ///
+    /// Note that the effective memory type also depends on the MTRRs.
+    pub fn memory_type<S: PageSize>(&self, pat: &PageAttributeTable) -> MemoryType {
+        pat.entry(self.pat_index::<S>())
+    }
+
+    /// Sets the PAT index of this entry to the first entry of the given page attribute table
+    /// that has the given memory type, assuming that this entry maps a page of size `S`.
+    ///
+    /// Returns an error if the page attribute table does not contain the memory type.
+    pub fn set_memory_type<S: PageSize>(
+        &mut self,
+        memory_type: MemoryType,
+        pat: &PageAttributeTable,
+    ) -> Result<(), ()> {
+        let index = pat.index_of(memory_type).ok_or(())?;
+        self.set_pat_index::<S>(index);
+        Ok(())
+    }
+
+    /// Returns the protection key of this entry.
+    ///
+    /// The protection key is stored in bits 59 to 62 of entries that map a page. It selects
+    /// the access rights of the page from the PKRU or the `IA32_PKRS` register.
+    pub fn protection_key(&self) -> u4 {
+        u4::new(((self.entry >> 59) & 0xf) as u8)
+    }
+
+    /// Sets the protection key of this entry.
+    ///
+    /// The protection key is only used by the CPU in entries that map a page, i.e. in level 1
+    /// entries and huge page entries. Note that the key is stored in bits that are also
+    /// accessible as `PageTableFlags::BIT_59` to `BIT_62`, so `set_flags` overwrites it.
+    pub fn set_protection_key(&mut self, key: u4) {
+        self.entry = (self.entry & !(0xf << 59)) | (u64::from(u8::from(key)) << 59);
+    }
+
+    /// Returns the position of the PAT bit in an entry that maps a page of size `S`.
+    fn pat_bit<S: PageSize>() -> u64 {
+        if S::SIZE == Size4KiB::SIZE {
+            1 << 7
+        } else {
+            1 << 12
+        }
+    }
 }
 
 impl fmt::Debug for PageTableEntry {
@@ -146,12 +260,24 @@ bitflags! {
         /// Available to the OS, can be used to store additional data, e.g. custom flags.
         const BIT_58 =          1 << 58;
         /// Available to the OS, can be used to store additional data, e.g. custom flags.
+        ///
+        /// Part of the protection key if protection keys are enabled, see
+        /// `PageTableEntry::set_protection_key`.
         const BIT_59 =          1 << 59;
         /// Available to the OS, can be used to store additional data, e.g. custom flags.
+        ///
+        /// Part of the protection key if protection keys are enabled, see
+        /// `PageTableEntry::set_protection_key`.
         const BIT_60 =          1 << 60;
         /// Available to the OS, can be used to store additional data, e.g. custom flags.
+        ///
+        /// Part of the protection key if protection keys are enabled, see
+        /// `PageTableEntry::set_protection_key`.
         const BIT_61 =          1 << 61;
         /// Available to the OS, can be used to store additional data, e.g. custom flags.
+        ///
+        /// Part of the protection key if protection keys are enabled, see
+        /// `PageTableEntry::set_protection_key`.
         const BIT_62 =          1 << 62;
         /// Forbid code execution from the mapped frames.
         ///
@@ -237,3 +363,121 @@ impl fmt::Debug for PageTable {
         self.entries[..].fmt(f)
     }
 }
+
+/// The paging mode of a page table hierarchy, i.e. the number of page table levels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+    /// 4-level paging with 48-bit virtual addresses. The root of the hierarchy is a level 4 table.
+    Level4,
+    /// 5-level paging with 57-bit virtual addresses, enabled through `Cr4Flags::LA57`. The root
+    /// of the hierarchy is a level 5 table.
+    Level5,
+}
+
+impl PagingMode {
+    /// Returns the level of the root table of a hierarchy with this paging mode.
+    pub fn top_level(self) -> PageTableLevel {
+        match self {
+            PagingMode::Level4 => PageTableLevel::Four,
+            PagingMode::Level5 => PageTableLevel::Five,
+        }
+    }
+
+    /// Returns the paging mode of the current CPU, as indicated by the `LA57` flag in CR4.
+    #[cfg(target_arch = "x86_64")]
+    pub fn current() -> Self {
+        use crate::registers::control::{Cr4, Cr4Flags};
+
+        if Cr4::read().contains(Cr4Flags::LA57) {
+            PagingMode::Level5
+        } else {
+            PagingMode::Level4
+        }
+    }
+
+    /// Creates a new virtual address that is canonical in this paging mode.
+    ///
+    /// This is `VirtAddr::new` for 4-level paging and `VirtAddr::new_la57` for 5-level paging.
+    pub fn virt_addr(self, addr: u64) -> VirtAddr {
+        match self {
+            PagingMode::Level4 => VirtAddr::new(addr),
+            PagingMode::Level5 => VirtAddr::new_la57(addr),
+        }
+    }
+}
+
+/// A level in a page table hierarchy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PageTableLevel {
+    /// A level 1 table, whose entries map 4KiB pages.
+    One = 1,
+    /// A level 2 table, whose entries map 2MiB pages or level 1 tables.
+    Two,
+    /// A level 3 table, whose entries map 1GiB pages or level 2 tables.
+    Three,
+    /// A level 4 table, whose entries map level 3 tables.
+    Four,
+    /// A level 5 table, whose entries map level 4 tables. Only used with 5-level paging.
+    Five,
+}
+
+impl PageTableLevel {
+    /// Returns the next lower level or `None` for level 1.
+    pub fn next_lower_level(self) -> Option<Self> {
+        match self {
+            PageTableLevel::One => None,
+            PageTableLevel::Two => Some(PageTableLevel::One),
+            PageTableLevel::Three => Some(PageTableLevel::Two),
+            PageTableLevel::Four => Some(PageTableLevel::Three),
+            PageTableLevel::Five => Some(PageTableLevel::Four),
+        }
+    }
+
+    /// Returns the next higher level or `None` for level 5.
+    pub fn next_higher_level(self) -> Option<Self> {
+        match self {
+            PageTableLevel::One => Some(PageTableLevel::Two),
+            PageTableLevel::Two => Some(PageTableLevel::Three),
+            PageTableLevel::Three => Some(PageTableLevel::Four),
+            PageTableLevel::Four => Some(PageTableLevel::Five),
+            PageTableLevel::Five => None,
+        }
+    }
+
+    /// Returns the size of the virtual address range that a single entry of a table at this
+    /// level is responsible for.
+    pub fn entry_address_space_size(self) -> u64 {
+        1 << (12 + 9 * (self as u64 - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protection_key_round_trip() {
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x8000_0000));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        let mut entry = PageTableEntry::new();
+        entry.set_frame(frame, flags);
+        assert_eq!(entry.protection_key(), u4::new(0));
+
+        entry.set_protection_key(u4::new(15));
+        assert_eq!(entry.protection_key(), u4::new(15));
+        assert_eq!(entry.frame().unwrap(), frame);
+        assert_eq!(
+            entry.flags(),
+            flags
+                | PageTableFlags::BIT_59
+                | PageTableFlags::BIT_60
+                | PageTableFlags::BIT_61
+                | PageTableFlags::BIT_62
+        );
+
+        entry.set_protection_key(u4::new(0));
+        assert_eq!(entry.protection_key(), u4::new(0));
+        assert_eq!(entry.frame().unwrap(), frame);
+        assert_eq!(entry.flags(), flags);
+    }
+}
@@ -0,0 +1,472 @@
+//! Allocation of virtual address ranges.
+
+use crate::structures::paging::{
+    page::PageRange, page_table::PagingMode, Page, PageSize, Size4KiB,
+};
+use crate::VirtAddr;
+use core::{fmt, marker::PhantomData};
+
+/// The strategy for choosing a free range for an allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitStrategy {
+    /// Use the free range with the lowest address that is large enough.
+    FirstFit,
+    /// Use the smallest free range that is large enough, which keeps large ranges available.
+    BestFit,
+}
+
+/// Allocates ranges of pages from the free parts of a virtual address space.
+///
+/// The allocator keeps a sorted list of free ranges. Initially, no pages are free; pages are
+/// added through `free`, e.g. the complete lower half for a user address space. Allocations are
+/// taken from the free ranges and `reserve` removes a range at a fixed address. Since the
+/// allocator only tracks free pages, any part of an allocation can be freed again, which splits
+/// the allocation.
+///
+/// The non-canonical hole between the lower and the higher half of the address space, whose
+/// size depends on the paging mode, is never part of a free range. Ranges that span the hole
+/// are split at it, so allocations are always either in the lower or in the higher half.
+///
+/// The free ranges are stored in a slice that is provided by the caller, so the allocator needs
+/// no heap. Each free range occupies two words of the slice.
+pub struct VirtualRangeAllocator<'a, S: PageSize = Size4KiB> {
+    storage: &'a mut [u64],
+    len: usize,
+    paging_mode: PagingMode,
+    size: PhantomData<S>,
+}
+
+impl<'a, S: PageSize> VirtualRangeAllocator<'a, S> {
+    /// Creates a new allocator without free pages for an address space with the given paging
+    /// mode.
+    ///
+    /// The allocator can track up to `storage.len() / 2` free ranges.
+    pub fn new(storage: &'a mut [u64], paging_mode: PagingMode) -> Self {
+        VirtualRangeAllocator {
+            storage,
+            len: 0,
+            paging_mode,
+            size: PhantomData,
+        }
+    }
+
+    /// Returns the paging mode of the address space.
+    pub fn paging_mode(&self) -> PagingMode {
+        self.paging_mode
+    }
+
+    /// Returns the maximum number of free ranges that the allocator can track.
+    pub fn capacity(&self) -> usize {
+        self.storage.len() / 2
+    }
+
+    /// Returns the number of free pages.
+    pub fn free_pages(&self) -> u64 {
+        (0..self.len)
+            .map(|index| {
+                let (start, end) = self.get(index);
+                (end - start) / S::SIZE
+            })
+            .sum()
+    }
+
+    /// Returns an iterator over the free ranges, sorted by address.
+    pub fn free_ranges(&self) -> impl Iterator<Item = PageRange<S>> + '_ {
+        (0..self.len).map(move |index| {
+            let (start, end) = self.get(index);
+            self.range(start, end)
+        })
+    }
+
+    /// Allocates a range of `page_count` pages.
+    pub fn allocate(
+        &mut self,
+        page_count: u64,
+        strategy: FitStrategy,
+    ) -> Result<PageRange<S>, RangeAllocError> {
+        self.allocate_aligned(page_count, S::SIZE, strategy)
+    }
+
+    /// Allocates a range of `page_count` pages whose start address is aligned to `align` bytes,
+    /// e.g. so that it can be mapped with huge pages.
+    ///
+    /// Panics if `page_count` is zero or if `align` is not a power of two.
+    pub fn allocate_aligned(
+        &mut self,
+        page_count: u64,
+        align: u64,
+        strategy: FitStrategy,
+    ) -> Result<PageRange<S>, RangeAllocError> {
+        assert!(
+            page_count > 0,
+            "allocations must consist of at least one page"
+        );
+        assert!(align.is_power_of_two(), "`align` must be a power of two");
+        let align = align.max(S::SIZE);
+        let size = page_count
+            .checked_mul(S::SIZE)
+            .ok_or(RangeAllocError::OutOfSpace)?;
+
+        let mut found: Option<(usize, u64)> = None;
+        for index in 0..self.len {
+            let (start, end) = self.get(index);
+            let fits = align_up(start, align)
+                .and_then(|aligned| aligned.checked_add(size).map(|end| (aligned, end)))
+                .filter(|&(_, allocation_end)| allocation_end <= end);
+            let aligned = match fits {
+                Some((aligned, _)) => aligned,
+                None => continue,
+            };
+            let better = match found {
+                None => true,
+                Some((best, _)) => {
+                    let (best_start, best_end) = self.get(best);
+                    end - start < best_end - best_start
+                }
+            };
+            if better {
+                found = Some((index, aligned));
+                if strategy == FitStrategy::FirstFit {
+                    break;
+                }
+            }
+        }
+
+        let (index, start) = found.ok_or(RangeAllocError::OutOfSpace)?;
+        self.remove_part(index, start, start + size)?;
+        Ok(self.range(start, start + size))
+    }
+
+    /// Reserves the given range, so that it is not used for allocations.
+    ///
+    /// This is used for mappings at fixed addresses, e.g. of the kernel image. All pages of the
+    /// range must be free.
+    pub fn reserve(&mut self, range: PageRange<S>) -> Result<(), RangeAllocError> {
+        if range.is_empty() {
+            return Err(RangeAllocError::EmptyRange);
+        }
+        let (start, end) = (self.linear(range.start), self.linear(range.end));
+        let index = (0..self.len)
+            .find(|&index| {
+                let (free_start, free_end) = self.get(index);
+                free_start <= start && end <= free_end
+            })
+            .ok_or(RangeAllocError::NotFree)?;
+        self.remove_part(index, start, end)
+    }
+
+    /// Returns the given range to the allocator.
+    ///
+    /// The range can be (a part of) an allocation, or a range that was never allocated, e.g.
+    /// for adding the initial free ranges. It is merged with adjacent free ranges. None of its
+    /// pages must be free already.
+    pub fn free(&mut self, range: PageRange<S>) -> Result<(), RangeAllocError> {
+        if range.is_empty() {
+            return Err(RangeAllocError::EmptyRange);
+        }
+        let (start, end) = (self.linear(range.start), self.linear(range.end));
+        let half = self.half();
+        let parts = [(start, end.min(half)), (start.max(half), end)];
+        let parts = || parts.iter().cloned().filter(|&(start, end)| start < end);
+
+        let mut needed_slots = 0;
+        for (start, end) in parts() {
+            needed_slots += match self.neighbors(start, end) {
+                Some((_, false, false)) => 1,
+                Some(_) => 0,
+                None => return Err(RangeAllocError::AlreadyFree),
+            };
+        }
+        if self.len + needed_slots > self.capacity() {
+            return Err(RangeAllocError::StorageFull);
+        }
+        for (start, end) in parts() {
+            self.insert(start, end);
+        }
+        Ok(())
+    }
+
+    /// Returns the number of virtual address bits of the paging mode.
+    fn address_bits(&self) -> u32 {
+        match self.paging_mode {
+            PagingMode::Level4 => 48,
+            PagingMode::Level5 => 57,
+        }
+    }
+
+    /// Returns the start of the higher half with the sign extension removed.
+    fn half(&self) -> u64 {
+        1 << (self.address_bits() - 1)
+    }
+
+    /// Returns the start address of the given page with the sign extension removed, so that
+    /// the lower and the higher half are adjacent.
+    fn linear(&self, page: Page<S>) -> u64 {
+        page.start_address().as_u64() & ((1 << self.address_bits()) - 1)
+    }
+
+    /// Converts the given addresses without sign extension to a page range.
+    fn range(&self, start: u64, end: u64) -> PageRange<S> {
+        // `Page` arithmetic doesn't sign extend the end of the lower half for 4-level paging, so
+        // compute it in the same way to make iterations over the range terminate
+        let end = if end == self.half() {
+            self.page(end - S::SIZE) + 1
+        } else {
+            self.page(end)
+        };
+        PageRange {
+            start: self.page(start),
+            end,
+        }
+    }
+
+    fn page(&self, linear: u64) -> Page<S> {
+        let addr = match self.paging_mode {
+            PagingMode::Level4 => VirtAddr::new_unchecked(linear),
+            PagingMode::Level5 => VirtAddr::new_unchecked_la57(linear),
+        };
+        Page::containing_address(addr)
+    }
+
+    fn get(&self, index: usize) -> (u64, u64) {
+        (self.storage[2 * index], self.storage[2 * index + 1])
+    }
+
+    fn set(&mut self, index: usize, (start, end): (u64, u64)) {
+        self.storage[2 * index] = start;
+        self.storage[2 * index + 1] = end;
+    }
+
+    /// Returns the index at which the free range `start..end` would be inserted and whether it
+    /// would be merged with the previous or the next free range, or `None` if it overlaps with
+    /// a free range.
+    ///
+    /// Ranges are never merged at the start of the higher half.
+    fn neighbors(&self, start: u64, end: u64) -> Option<(usize, bool, bool)> {
+        let index = (0..self.len)
+            .find(|&index| self.get(index).0 >= start)
+            .unwrap_or(self.len);
+        let mut merge_prev = false;
+        if index > 0 {
+            let (_, prev_end) = self.get(index - 1);
+            if prev_end > start {
+                return None;
+            }
+            merge_prev = prev_end == start && start != self.half();
+        }
+        let mut merge_next = false;
+        if index < self.len {
+            let (next_start, _) = self.get(index);
+            if next_start < end {
+                return None;
+            }
+            merge_next = next_start == end && end != self.half();
+        }
+        Some((index, merge_prev, merge_next))
+    }
+
+    /// Inserts the free range `start..end`, which must not overlap with a free range and must
+    /// fit into the storage.
+    fn insert(&mut self, start: u64, end: u64) {
+        let (index, merge_prev, merge_next) = self
+            .neighbors(start, end)
+            .expect("range overlaps with a free range");
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                let (prev_start, _) = self.get(index - 1);
+                let (_, next_end) = self.get(index);
+                self.set(index - 1, (prev_start, next_end));
+                self.remove(index);
+            }
+            (true, false) => {
+                let (prev_start, _) = self.get(index - 1);
+                self.set(index - 1, (prev_start, end));
+            }
+            (false, true) => {
+                let (_, next_end) = self.get(index);
+                self.set(index, (start, next_end));
+            }
+            (false, false) => self.insert_at(index, (start, end)),
+        }
+    }
+
+    fn insert_at(&mut self, index: usize, range: (u64, u64)) {
+        for i in (index..self.len).rev() {
+            let moved = self.get(i);
+            self.set(i + 1, moved);
+        }
+        self.len += 1;
+        self.set(index, range);
+    }
+
+    fn remove(&mut self, index: usize) {
+        for i in index + 1..self.len {
+            let moved = self.get(i);
+            self.set(i - 1, moved);
+        }
+        self.len -= 1;
+    }
+
+    /// Removes `start..end` from the free range at `index`, which must contain it.
+    fn remove_part(&mut self, index: usize, start: u64, end: u64) -> Result<(), RangeAllocError> {
+        let (free_start, free_end) = self.get(index);
+        match (free_start == start, free_end == end) {
+            (true, true) => self.remove(index),
+            (true, false) => self.set(index, (end, free_end)),
+            (false, true) => self.set(index, (free_start, start)),
+            (false, false) => {
+                // the free range is split in two
+                if self.len == self.capacity() {
+                    return Err(RangeAllocError::StorageFull);
+                }
+                self.set(index, (free_start, start));
+                self.insert_at(index + 1, (end, free_end));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, S: PageSize> fmt::Debug for VirtualRangeAllocator<'a, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut f = f.debug_struct("VirtualRangeAllocator");
+        f.field("paging_mode", &self.paging_mode);
+        f.field("free_ranges", &self.len);
+        f.field("free_pages", &self.free_pages());
+        f.finish()
+    }
+}
+
+/// Aligns the given address upwards, or returns `None` on overflow.
+fn align_up(addr: u64, align: u64) -> Option<u64> {
+    addr.checked_add(align - 1).map(|addr| addr & !(align - 1))
+}
+
+/// This error is returned from the methods of `VirtualRangeAllocator`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeAllocError {
+    /// The range is empty.
+    EmptyRange,
+    /// No free range is large enough for the allocation.
+    OutOfSpace,
+    /// A page of the range to reserve is not free.
+    NotFree,
+    /// A page of the range to free is already free.
+    AlreadyFree,
+    /// The operation would split a free range or add a new one, but the storage of the
+    /// allocator is full.
+    StorageFull,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::paging::Size2MiB;
+
+    fn page(addr: u64) -> Page {
+        Page::containing_address(VirtAddr::new(addr))
+    }
+
+    fn range(start: u64, end: u64) -> PageRange {
+        Page::range(page(start), page(end))
+    }
+
+    #[test]
+    fn allocate_and_free() {
+        let mut storage = [0; 8];
+        let mut allocator = VirtualRangeAllocator::new(&mut storage, PagingMode::Level4);
+        assert_eq!(
+            allocator.allocate(1, FitStrategy::FirstFit),
+            Err(RangeAllocError::OutOfSpace)
+        );
+        allocator.free(range(0x1000, 0x9000)).unwrap();
+        allocator.free(range(0x10_0000, 0x10_4000)).unwrap();
+        assert_eq!(
+            allocator.free(range(0x8000, 0xa000)),
+            Err(RangeAllocError::AlreadyFree)
+        );
+        assert_eq!(allocator.free_pages(), 12);
+
+        let first = allocator.allocate(2, FitStrategy::FirstFit).unwrap();
+        assert_eq!(first, range(0x1000, 0x3000));
+        let best = allocator.allocate(2, FitStrategy::BestFit).unwrap();
+        assert_eq!(best, range(0x10_0000, 0x10_2000));
+        let aligned = allocator
+            .allocate_aligned(1, 0x4000, FitStrategy::FirstFit)
+            .unwrap();
+        assert_eq!(aligned, range(0x4000, 0x5000));
+        assert_eq!(
+            allocator.free_ranges().collect::<Vec<_>>(),
+            vec![
+                range(0x3000, 0x4000),
+                range(0x5000, 0x9000),
+                range(0x10_2000, 0x10_4000)
+            ]
+        );
+
+        // freeing a part of an allocation splits it, freeing the rest merges the ranges again
+        allocator.free(range(0x2000, 0x3000)).unwrap();
+        allocator.free(aligned).unwrap();
+        allocator.free(range(0x1000, 0x2000)).unwrap();
+        allocator.free(best).unwrap();
+        assert_eq!(
+            allocator.free_ranges().collect::<Vec<_>>(),
+            vec![range(0x1000, 0x9000), range(0x10_0000, 0x10_4000)]
+        );
+    }
+
+    #[test]
+    fn reserve_fixed_ranges() {
+        let mut storage = [0; 4];
+        let mut allocator = VirtualRangeAllocator::new(&mut storage, PagingMode::Level4);
+        allocator.free(range(0x1000, 0x10_0000)).unwrap();
+        allocator.reserve(range(0x8000, 0x9000)).unwrap();
+        assert_eq!(
+            allocator.reserve(range(0x8000, 0xa000)),
+            Err(RangeAllocError::NotFree)
+        );
+        assert_eq!(
+            allocator.reserve(range(0xa000, 0xb000)),
+            Err(RangeAllocError::StorageFull)
+        );
+        allocator.reserve(range(0xf_f000, 0x10_0000)).unwrap();
+        assert_eq!(allocator.free_pages(), 0xfd);
+        assert_eq!(
+            allocator.allocate(0x100, FitStrategy::FirstFit),
+            Err(RangeAllocError::OutOfSpace)
+        );
+    }
+
+    #[test]
+    fn respect_canonical_hole() {
+        let mut storage = [0; 4];
+        let mut allocator: VirtualRangeAllocator<Size2MiB> =
+            VirtualRangeAllocator::new(&mut storage, PagingMode::Level4);
+        let lower_end = 0x0000_8000_0000_0000;
+        let start = Page::containing_address(VirtAddr::new(lower_end - 0x40_0000));
+        let end = Page::containing_address(VirtAddr::new(0xffff_8000_0040_0000));
+        allocator.free(Page::range(start, end)).unwrap();
+        assert_eq!(allocator.free_pages(), 4);
+        assert_eq!(allocator.free_ranges().count(), 2);
+
+        assert_eq!(
+            allocator.allocate(3, FitStrategy::FirstFit),
+            Err(RangeAllocError::OutOfSpace)
+        );
+        let lower = allocator.allocate(2, FitStrategy::FirstFit).unwrap();
+        assert_eq!(lower.start, start);
+        assert_eq!(lower.count(), 2);
+        assert_eq!(lower.end.start_address().as_u64(), lower_end);
+        assert_eq!(lower.last(), Some(start + 1));
+        let higher = allocator.allocate(2, FitStrategy::FirstFit).unwrap();
+        assert_eq!(
+            higher.start.start_address(),
+            VirtAddr::new(0xffff_8000_0000_0000)
+        );
+        assert_eq!(higher.end, end);
+
+        allocator.free(higher).unwrap();
+        allocator.free(lower).unwrap();
+        assert_eq!(allocator.free_ranges().count(), 2);
+    }
+}
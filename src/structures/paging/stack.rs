@@ -0,0 +1,222 @@
+//! Allocation of kernel stacks that are protected by a guard page.
+
+use crate::structures::paging::{
+    mapper::{MapToError, Mapper},
+    page::PageRange,
+    FrameAllocator, FrameDeallocator, Page, PageTableFlags, Size4KiB,
+};
+use crate::VirtAddr;
+
+/// The address range of a stack.
+///
+/// The stack grows downwards from `end`, so `end` is the initial stack pointer. It can be
+/// installed into a slot of the task state segment through `TaskStateSegment::set_stack`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackBounds {
+    start: VirtAddr,
+    end: VirtAddr,
+}
+
+impl StackBounds {
+    /// Creates the bounds of the stack that occupies the memory from `start` (inclusive) to
+    /// `end` (exclusive).
+    ///
+    /// Panics if `start` is above `end` or if `end` is not aligned to 16 bytes, which is the
+    /// stack alignment that the System V ABI requires.
+    pub fn new(start: VirtAddr, end: VirtAddr) -> Self {
+        assert!(start <= end, "stack start must not be above the stack end");
+        assert!(
+            end.is_aligned(16u64),
+            "stack end must be aligned to 16 bytes"
+        );
+        StackBounds { start, end }
+    }
+
+    /// Returns the lowest address of the stack.
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// Returns the address above the highest byte of the stack, i.e. the initial stack pointer.
+    pub fn end(&self) -> VirtAddr {
+        self.end
+    }
+
+    /// Returns the size of the stack in bytes.
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Returns whether the given address lies within the stack.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+/// Allocates stacks from a range of virtual pages and maps them through a `Mapper`.
+///
+/// Each stack is preceded by an unmapped guard page. A stack overflow thus causes a page fault
+/// instead of silently overwriting the memory below the stack. Since the page fault handler
+/// can't run on the overflowed stack, the handler for double faults should use a stack from
+/// the interrupt stack table, which can be allocated through this type as well.
+///
+/// The stacks are allocated from the bottom of the range upwards and are never freed, which
+/// suits the stacks of interrupt handlers and kernel threads that live as long as the kernel.
+#[derive(Debug)]
+pub struct StackAllocator {
+    pages: PageRange,
+}
+
+impl StackAllocator {
+    /// Creates a new allocator that allocates stacks from the given pages.
+    ///
+    /// This function is unsafe because the caller must guarantee that the pages are not mapped
+    /// and not used for any other purpose.
+    pub unsafe fn new(pages: PageRange) -> Self {
+        StackAllocator { pages }
+    }
+
+    /// Returns the pages that are not used by a stack or a guard page yet.
+    pub fn remaining_pages(&self) -> PageRange {
+        self.pages
+    }
+
+    /// Allocates a stack of `page_count` pages and maps it through the given mapper.
+    ///
+    /// The pages are mapped with the `PRESENT`, `WRITABLE` and `NO_EXECUTE` flags to frames of
+    /// the given frame allocator. The page below the stack stays unmapped as guard page.
+    ///
+    /// If the mapping fails, the pages that were already mapped are unmapped and their frames
+    /// are returned to the frame allocator. The pages of the failed stack are not reused.
+    ///
+    /// Panics if `page_count` is zero.
+    pub fn allocate_stack<M, A>(
+        &mut self,
+        page_count: u64,
+        mapper: &mut M,
+        frame_allocator: &mut A,
+    ) -> Result<StackBounds, StackAllocError>
+    where
+        M: Mapper<Size4KiB>,
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+    {
+        assert!(page_count > 0, "stacks must consist of at least one page");
+        let guard_page = self.pages.start;
+        let start = guard_page + 1;
+        let end = start + page_count;
+        if end > self.pages.end || end < start {
+            return Err(StackAllocError::RangeExhausted);
+        }
+        self.pages.start = end;
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        for page in Page::range(start, end) {
+            let result = match frame_allocator.allocate_frame() {
+                Some(frame) => unsafe { mapper.map_to(page, frame, flags, frame_allocator) }
+                    .map_err(|err| {
+                        frame_allocator.deallocate_frame(frame);
+                        StackAllocError::MapToError(err)
+                    }),
+                None => Err(StackAllocError::FrameAllocationFailed),
+            };
+            match result {
+                // the page was not mapped before, so the TLB contains no entry for it
+                Ok(flush) => flush.ignore(),
+                Err(err) => {
+                    for mapped_page in Page::range(start, page) {
+                        // the pages of a failed stack are never used, so stale TLB entries
+                        // for them are harmless
+                        if let Ok((frame, flush)) = mapper.unmap(mapped_page) {
+                            flush.ignore();
+                            frame_allocator.deallocate_frame(frame);
+                        }
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(StackBounds::new(start.start_address(), end.start_address()))
+    }
+}
+
+/// This error is returned from `StackAllocator::allocate_stack`.
+#[derive(Debug)]
+pub enum StackAllocError {
+    /// The remaining pages of the allocator are not enough for the stack and its guard page.
+    RangeExhausted,
+    /// A frame for the stack was needed, but the frame allocator returned `None`.
+    FrameAllocationFailed,
+    /// A page of the stack could not be mapped.
+    MapToError(MapToError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::paging::mapper::{MapperAllSizes, SimulatedPhysMemory};
+    use crate::structures::paging::PhysFrame;
+    use crate::structures::tss::{StackSlot, TaskStateSegment};
+    use crate::PhysAddr;
+
+    #[test]
+    fn allocate_stacks_with_guard_pages() {
+        let mut frames = SimulatedPhysMemory::buffer(16);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let mut mapper = unsafe { memory.mapper(root_frame) };
+
+        let first_page = Page::containing_address(VirtAddr::new(0xffff_9000_0000_0000));
+        let mut allocator = unsafe { StackAllocator::new(Page::range(first_page, first_page + 8)) };
+        let stack = allocator
+            .allocate_stack(4, &mut mapper, &mut memory)
+            .unwrap();
+        assert_eq!(stack.start(), (first_page + 1).start_address());
+        assert_eq!(stack.end(), (first_page + 5).start_address());
+        assert_eq!(stack.size(), 4 * 4096);
+        assert!(stack.contains(stack.end() - 1u64));
+        assert!(!stack.contains(stack.end()));
+        assert_eq!(mapper.translate_addr(first_page.start_address()), None);
+        assert!(mapper.translate_addr(stack.start()).is_some());
+        assert!(mapper.translate_addr(stack.end() - 1u64).is_some());
+
+        match allocator.allocate_stack(3, &mut mapper, &mut memory) {
+            Err(StackAllocError::RangeExhausted) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        let second = allocator
+            .allocate_stack(2, &mut mapper, &mut memory)
+            .unwrap();
+        assert_eq!(second.start(), (first_page + 6).start_address());
+        assert_eq!(mapper.translate_addr(stack.end()), None);
+
+        let mut tss = TaskStateSegment::new();
+        tss.set_stack(StackSlot::Interrupt0, stack);
+        tss.set_stack(StackSlot::Privilege0, second);
+        assert_eq!(tss.stack_pointer(StackSlot::Interrupt0), stack.end());
+        assert_eq!(tss.stack_pointer(StackSlot::Privilege0), second.end());
+        assert_eq!(StackSlot::Interrupt0.ist_index(), Some(0));
+    }
+
+    #[test]
+    fn roll_back_failed_stack() {
+        let mut frames = SimulatedPhysMemory::buffer(6);
+        let start = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let (mut memory, root_frame) = SimulatedPhysMemory::with_root(&mut frames, start);
+        let mut mapper = unsafe { memory.mapper(root_frame) };
+
+        let first_page = Page::containing_address(VirtAddr::new(0x1000_0000));
+        let mut allocator = unsafe { StackAllocator::new(Page::range(first_page, first_page + 8)) };
+        // the page tables need three frames, so only two frames are left for the stack
+        match allocator.allocate_stack(4, &mut mapper, &mut memory) {
+            Err(StackAllocError::FrameAllocationFailed) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(
+            mapper.translate_addr((first_page + 1).start_address()),
+            None
+        );
+        assert_eq!(memory.allocated_frames(), 4);
+        assert_eq!(allocator.remaining_pages().start, first_page + 5);
+    }
+}
@@ -1,5 +1,6 @@
 //! Provides a type for the task state segment structure.
 
+use crate::structures::paging::stack::StackBounds;
 use crate::VirtAddr;
 
 /// In 64-bit mode the TSS holds information that is not
@@ -35,4 +36,56 @@ impl TaskStateSegment {
             reserved_4: 0,
         }
     }
+
+    /// Sets the stack pointer of the given slot to the end of the given stack.
+    pub fn set_stack(&mut self, slot: StackSlot, stack: StackBounds) {
+        match slot.ist_index() {
+            Some(index) => self.interrupt_stack_table[usize::from(index)] = stack.end(),
+            None => self.privilege_stack_table[slot as usize] = stack.end(),
+        }
+    }
+
+    /// Returns the stack pointer that is stored in the given slot.
+    pub fn stack_pointer(&self, slot: StackSlot) -> VirtAddr {
+        match slot.ist_index() {
+            Some(index) => self.interrupt_stack_table[usize::from(index)],
+            None => self.privilege_stack_table[slot as usize],
+        }
+    }
+}
+
+/// A stack pointer slot of the task state segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackSlot {
+    /// The stack that the CPU switches to on a privilege change to ring 0.
+    Privilege0 = 0,
+    /// The stack that the CPU switches to on a privilege change to ring 1.
+    Privilege1 = 1,
+    /// The stack that the CPU switches to on a privilege change to ring 2.
+    Privilege2 = 2,
+    /// The first entry of the interrupt stack table.
+    Interrupt0 = 3,
+    /// The second entry of the interrupt stack table.
+    Interrupt1 = 4,
+    /// The third entry of the interrupt stack table.
+    Interrupt2 = 5,
+    /// The fourth entry of the interrupt stack table.
+    Interrupt3 = 6,
+    /// The fifth entry of the interrupt stack table.
+    Interrupt4 = 7,
+    /// The sixth entry of the interrupt stack table.
+    Interrupt5 = 8,
+    /// The seventh entry of the interrupt stack table.
+    Interrupt6 = 9,
+}
+
+impl StackSlot {
+    /// Returns the index that selects this slot in `EntryOptions::set_stack_index` of the IDT,
+    /// or `None` if this is a slot of the privilege stack table.
+    pub fn ist_index(self) -> Option<u16> {
+        match self {
+            StackSlot::Privilege0 | StackSlot::Privilege1 | StackSlot::Privilege2 => None,
+            _ => Some(self as u16 - StackSlot::Interrupt0 as u16),
+        }
+    }
 }